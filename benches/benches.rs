@@ -1,5 +1,28 @@
-use criterion::{black_box, criterion_group, criterion_main, Benchmark, Criterion};
-use walrus::Module;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Benchmark, Criterion};
+use walrus::ir::Nop;
+use walrus::{Module, ValidationCache};
+use wasmparser::WasmFeatures;
+
+/// Modify one function of the module, as a pass touching a single function
+/// would.
+fn touch_one_function(module: &mut Module) {
+    let id = module.funcs.iter_local().next().unwrap().0;
+    let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+    let entry = func.entry_block();
+    func.block_mut(entry)
+        .instrs
+        .insert(0, (Nop {}.into(), Default::default()));
+}
+
+/// Parse the fixture and validate it, as a pipeline would before running a
+/// pass.
+fn validated_module() -> (Module, ValidationCache) {
+    let input_wasm = include_bytes!("./fixtures/dodrio-todomvc.wasm");
+    let mut module = Module::from_buffer(input_wasm).unwrap();
+    let mut cache = ValidationCache::new(WasmFeatures::default());
+    module.validate_incremental(&mut cache).unwrap();
+    (module, cache)
+}
 
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench(
@@ -15,6 +38,50 @@ fn criterion_benchmark(c: &mut Criterion) {
             });
         }),
     );
+
+    // Both variants start every iteration from the same freshly parsed and
+    // validated module, and make the same change to it. They only differ in
+    // whether the validation after the change can reuse the earlier one.
+    // Everything is returned, so that dropping it isn't measured either.
+    c.bench(
+        "revalidate-after-changing-one-function",
+        Benchmark::new("full", |b| {
+            b.iter_batched(
+                validated_module,
+                |(mut module, warm)| {
+                    touch_one_function(&mut module);
+                    let mut cache = ValidationCache::new(WasmFeatures::default());
+                    module.validate_incremental(&mut cache).unwrap();
+                    (module, warm, cache)
+                },
+                BatchSize::LargeInput,
+            );
+        })
+        .with_function("incremental", |b| {
+            b.iter_batched(
+                validated_module,
+                |(mut module, mut cache)| {
+                    touch_one_function(&mut module);
+                    module.validate_incremental(&mut cache).unwrap();
+                    (module, cache)
+                },
+                BatchSize::LargeInput,
+            );
+        }),
+    );
+
+    // Build with `--features parallel` to count functions on every core.
+    c.bench(
+        "instr-frequency",
+        Benchmark::new("dodrio-todomvc.wasm", |b| {
+            let input_wasm = include_bytes!("./fixtures/dodrio-todomvc.wasm");
+            let module = Module::from_buffer(input_wasm).unwrap();
+            b.iter(|| {
+                let frequency = walrus::passes::instr_stats::instr_frequency(black_box(&module));
+                black_box(frequency);
+            });
+        }),
+    );
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -186,7 +186,7 @@ fn create_types(attrs: &[syn::Attribute], variants: &[WalrusVariant]) -> impl qu
             });
             quote! {
                 #( #attrs )*
-                #[derive(Clone, Debug)]
+                #[derive(Clone, Debug, PartialEq)]
                 pub struct #name {
                     #( #fields )*
                 }
@@ -302,6 +302,28 @@ fn create_types(attrs: &[syn::Attribute], variants: &[WalrusVariant]) -> impl qu
         })
         .collect();
 
+    let kinds: Vec<_> = variants
+        .iter()
+        .map(|v| {
+            let name = &v.syn.ident;
+            let doc = format!("A `{}` instruction.", name);
+            quote! {
+                #[doc=#doc]
+                #name
+            }
+        })
+        .collect();
+
+    let kind_arms: Vec<_> = variants
+        .iter()
+        .map(|v| {
+            let name = &v.syn.ident;
+            quote! {
+                Instr::#name(_) => InstrKind::#name
+            }
+        })
+        .collect();
+
     let variants: Vec<_> = variants
         .iter()
         .map(|v| {
@@ -322,8 +344,22 @@ fn create_types(attrs: &[syn::Attribute], variants: &[WalrusVariant]) -> impl qu
             #(#variants),*
         }
 
+        /// The kind of an instruction, without any of its operands.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum InstrKind {
+            #(#kinds),*
+        }
+
         impl Instr {
             #( #methods )*
+
+            /// Get the kind of this instruction.
+            #[inline]
+            pub fn kind(&self) -> InstrKind {
+                match self {
+                    #( #kind_arms ),*
+                }
+            }
         }
     }
 }
@@ -0,0 +1,51 @@
+//! Tests that the custom section `wasm-bindgen` describes its bindings with is
+//! recognized, and kept intact through a round trip.
+
+use walrus::bindgen::{is_wasm_bindgen_module, WASM_BINDGEN_SECTION};
+use walrus::Module;
+
+const WAT: &str = r#"
+    (module
+      (func $greet (export "greet") (param i32 i32))
+      (func (export "__wbindgen_describe_greet")))
+"#;
+
+/// Encode a custom section with the given name and payload.
+fn custom_section(name: &str, payload: &[u8]) -> Vec<u8> {
+    // Every length here fits in a single LEB128 byte.
+    assert!(name.len() < 0x80 && 1 + name.len() + payload.len() < 0x80);
+    let mut section = vec![0x00, (1 + name.len() + payload.len()) as u8];
+    section.push(name.len() as u8);
+    section.extend_from_slice(name.as_bytes());
+    section.extend_from_slice(payload);
+    section
+}
+
+#[test]
+fn keeps_wasm_bindgen_section() {
+    let payload = b"\x04\x00\x00\x00greet\x00\xff\x01{\"schema\":\"0.2\"}";
+    let section = custom_section(WASM_BINDGEN_SECTION, payload);
+    let mut wasm = wat::parse_str(WAT).unwrap();
+    wasm.extend_from_slice(&section);
+
+    let mut module = Module::from_buffer(&wasm).unwrap();
+    assert!(is_wasm_bindgen_module(&module));
+
+    walrus::passes::gc::run(&mut module);
+    let out = module.emit_wasm();
+    assert!(
+        out.windows(section.len()).any(|w| w == &section[..]),
+        "the section was not emitted byte for byte"
+    );
+
+    let module = Module::from_buffer(&out).unwrap();
+    assert!(is_wasm_bindgen_module(&module));
+}
+
+#[test]
+fn other_modules_are_not_wasm_bindgen_modules() {
+    let mut wasm = wat::parse_str(WAT).unwrap();
+    wasm.extend_from_slice(&custom_section("__wasm_bindgen", b"\x00"));
+    let module = Module::from_buffer(&wasm).unwrap();
+    assert!(!is_wasm_bindgen_module(&module));
+}
@@ -0,0 +1,63 @@
+//! Tests for `ModuleConfig::flatten_trivial_blocks`.
+
+use walrus::ir::{BinaryOp, Instr};
+use walrus::{Module, ModuleConfig};
+
+const WAT: &str = r#"
+    (module
+      (func (export "f") (param i32) (result i32)
+        ;; Trivial: replaced by the `i32.const`.
+        (block (result i32)
+          (i32.const 1))
+        ;; Trivial, even though it is a loop.
+        (loop (param i32) (result i32)
+          (i32.eqz))
+        ;; The target of a branch, so kept.
+        (block
+          (br_if 0 (local.get 0)))
+        ;; Holds more than one instruction, so kept.
+        (block (result i32)
+          (i32.const 2)
+          (i32.const 3)
+          (i32.add))
+        i32.add))
+"#;
+
+/// The instructions in the body of the exported function `f`.
+fn body(module: &Module) -> Vec<Instr> {
+    let func = module.exports.get_func_by_name("f").unwrap();
+    let func = module.funcs.get(func).kind.unwrap_local();
+    func.block(func.entry_block())
+        .iter()
+        .map(|(instr, _)| instr.clone())
+        .collect()
+}
+
+#[test]
+fn flattens_trivial_blocks() {
+    let wasm = wat::parse_str(WAT).unwrap();
+
+    // Off by default.
+    let module = Module::from_buffer(&wasm).unwrap();
+    let instrs = body(&module);
+    assert_eq!(instrs.len(), 5);
+    assert!(instrs[..4].iter().all(|i| i.is_block() || i.is_loop()));
+
+    let mut module = ModuleConfig::new()
+        .flatten_trivial_blocks(true)
+        .parse(&wasm)
+        .unwrap();
+    let instrs = body(&module);
+    assert_eq!(instrs.len(), 5);
+    assert!(instrs[0].is_const());
+    assert!(instrs[1].is_unop());
+    assert!(instrs[2].is_block());
+    assert!(instrs[3].is_block());
+    match &instrs[4] {
+        Instr::Binop(b) => assert_eq!(b.op, BinaryOp::I32Add),
+        other => panic!("expected an `i32.add`, got {:?}", other),
+    }
+
+    // The flattened module is still valid.
+    Module::from_buffer(&module.emit_wasm()).unwrap();
+}
@@ -0,0 +1,81 @@
+//! Tests that floating point constants keep their exact bit patterns through a
+//! round trip, including the sign of zero.
+
+use walrus::ir::{Instr, Value};
+use walrus::{GlobalKind, InitExpr, Module};
+
+const WAT: &str = r#"
+    (module
+      (global f32 (f32.const -0.0))
+      (global f64 (f64.const -0.0))
+      (func (export "zeros") (result f32 f32 f64 f64)
+        f32.const -0.0
+        f32.const 0.0
+        f64.const -0.0
+        f64.const 0.0))
+"#;
+
+/// Does `wasm` contain `needle` anywhere?
+fn contains(wasm: &[u8], needle: &[u8]) -> bool {
+    wasm.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn negative_zero_round_trips() {
+    let wasm = wat::parse_str(WAT).unwrap();
+    let mut module = Module::from_buffer(&wasm).unwrap();
+
+    let func = module.exports.get_func_by_name("zeros").unwrap();
+    let func = module.funcs.get(func).kind.unwrap_local();
+    let consts = func
+        .block(func.entry_block())
+        .iter()
+        .map(|(instr, _)| match instr {
+            Instr::Const(c) => c.value,
+            other => panic!("unexpected instruction {:?}", other),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        consts,
+        [
+            Value::F32(-0.0),
+            Value::F32(0.0),
+            Value::F64(-0.0),
+            Value::F64(0.0),
+        ]
+    );
+    // `Value`s compare bit patterns, so the signs must differ.
+    assert_ne!(consts[0], consts[1]);
+    assert_ne!(consts[2], consts[3]);
+
+    let inits = module
+        .globals
+        .iter()
+        .map(|g| match g.kind {
+            GlobalKind::Local(InitExpr::Value(value)) => value,
+            ref other => panic!("unexpected global {:?}", other),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(inits, [Value::F32(-0.0), Value::F64(-0.0)]);
+
+    let out = module.emit_wasm();
+    // `f32.const -0.0` and `f64.const -0.0`, with only the sign bit set.
+    let f32_neg_zero = [0x43, 0x00, 0x00, 0x00, 0x80];
+    let f64_neg_zero = [0x44, 0, 0, 0, 0, 0, 0, 0, 0x80];
+    assert!(contains(&out, &f32_neg_zero));
+    assert!(contains(&out, &f64_neg_zero));
+    assert!(contains(&out, &[0x43, 0x00, 0x00, 0x00, 0x00]));
+    assert!(contains(&out, &[0x44, 0, 0, 0, 0, 0, 0, 0, 0x00]));
+
+    // And it parses back the same.
+    let module = Module::from_buffer(&out).unwrap();
+    let inits = module
+        .globals
+        .iter()
+        .map(|g| match g.kind {
+            GlobalKind::Local(InitExpr::Value(value)) => value,
+            ref other => panic!("unexpected global {:?}", other),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(inits, [Value::F32(-0.0), Value::F64(-0.0)]);
+}
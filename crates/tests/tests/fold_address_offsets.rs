@@ -0,0 +1,35 @@
+//! Tests for `walrus::passes::fold_address_offsets`.
+
+use walrus::passes::fold_address_offsets;
+use walrus::Module;
+
+const WAT: &str = r#"
+    (module
+      (memory 1)
+      (func (export "f") (param i32) (result i32)
+        ;; Folded: both become an offset of 20.
+        (i32.store offset=4
+          (i32.add (local.get 0) (i32.const 16))
+          (i32.load offset=4
+            (i32.add (i32.const 16) (local.get 0))))
+        ;; Folded: two constants, the one on the right into the offset.
+        (drop (i32.load (i32.add (i32.const 64) (i32.const 8))))
+        ;; Kept: the constant is negative.
+        (i32.load (i32.add (local.get 0) (i32.const -16)))))
+"#;
+
+#[test]
+fn folding_shrinks_the_code() {
+    let wasm = wat::parse_str(WAT).unwrap();
+    let mut module = Module::from_buffer(&wasm).unwrap();
+    let before = module.emit_wasm();
+
+    assert_eq!(fold_address_offsets::run(&mut module, 1024), 3);
+    let after = module.emit_wasm();
+    // Still valid.
+    Module::from_buffer(&after).unwrap();
+
+    // Each fold drops an `i32.const` with a one byte immediate, and an
+    // `i32.add`, while the offsets' encodings stay one byte long.
+    assert_eq!(before.len() - after.len(), 3 * 3);
+}
@@ -0,0 +1,103 @@
+//! Tests that globals keep their type and mutability through a round trip,
+//! whether they are imported, defined locally, or exported.
+
+use walrus::{GlobalKind, Module, ModuleConfig, ValType};
+
+const TYPES: &[(&str, &str, ValType)] = &[
+    ("i32", "i32.const 0", ValType::I32),
+    ("i64", "i64.const 0", ValType::I64),
+    ("f32", "f32.const 0", ValType::F32),
+    ("f64", "f64.const 0", ValType::F64),
+    ("v128", "v128.const i64x2 0 0", ValType::V128),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Imported,
+    Local,
+    Exported,
+    ReExported,
+}
+
+fn wat(ty: &str, init: &str, mutable: bool, kind: Kind) -> String {
+    let global_ty = if mutable {
+        format!("(mut {})", ty)
+    } else {
+        ty.to_string()
+    };
+    let global = match kind {
+        Kind::Imported | Kind::ReExported => {
+            format!("(import \"env\" \"g\" (global {}))", global_ty)
+        }
+        Kind::Local | Kind::Exported => format!("(global {} ({}))", global_ty, init),
+    };
+    let export = match kind {
+        Kind::Exported | Kind::ReExported => "(export \"g\" (global 0))",
+        Kind::Imported | Kind::Local => "",
+    };
+    format!("(module {} {})", global, export)
+}
+
+fn check(module: &Module, ty: ValType, mutable: bool, kind: Kind) {
+    let globals = module.globals.iter().collect::<Vec<_>>();
+    assert_eq!(globals.len(), 1);
+    let global = globals[0];
+    assert_eq!(global.ty, ty);
+    assert_eq!(global.mutable, mutable);
+    match (&global.kind, kind) {
+        (GlobalKind::Import(_), Kind::Imported) | (GlobalKind::Import(_), Kind::ReExported) => {}
+        (GlobalKind::Local(_), Kind::Local) | (GlobalKind::Local(_), Kind::Exported) => {}
+        (other, _) => panic!("unexpected global kind {:?}", other),
+    }
+    let exported = module.exports.get_exported_global(global.id()).is_some();
+    assert_eq!(exported, kind == Kind::Exported || kind == Kind::ReExported);
+}
+
+#[test]
+fn globals_round_trip() {
+    for &(name, init, ty) in TYPES {
+        for &mutable in &[false, true] {
+            for &kind in &[
+                Kind::Imported,
+                Kind::Local,
+                Kind::Exported,
+                Kind::ReExported,
+            ] {
+                let context = format!("{} global, mutable: {}, {:?}", name, mutable, kind);
+                let wasm = wat::parse_str(&wat(name, init, mutable, kind)).unwrap();
+                let mut module = Module::from_buffer(&wasm).expect(&context);
+                check(&module, ty, mutable, kind);
+
+                let wasm = module.emit_wasm();
+                let module = Module::from_buffer(&wasm).expect(&context);
+                check(&module, ty, mutable, kind);
+
+                let printed = wasmprinter::print_bytes(&wasm).unwrap();
+                let expected = if mutable {
+                    format!("(global (;0;) (mut {})", name)
+                } else {
+                    format!("(global (;0;) {}", name)
+                };
+                assert!(
+                    printed.contains(&expected),
+                    "{}: expected `{}` in:\n{}",
+                    context,
+                    expected,
+                    printed
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn importing_mutable_global_requires_feature() {
+    let wasm = wat::parse_str(wat("i64", "", true, Kind::Imported)).unwrap();
+    let mut config = ModuleConfig::new();
+    config.mutable_globals(false);
+    let err = config.parse(&wasm).unwrap_err();
+    assert!(err.to_string().contains("mutable-globals"), "{}", err);
+
+    let wasm = wat::parse_str(wat("i64", "", false, Kind::Imported)).unwrap();
+    config.parse(&wasm).unwrap();
+}
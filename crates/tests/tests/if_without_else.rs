@@ -0,0 +1,51 @@
+//! `if`s without an `else` are emitted without one.
+
+use walrus::ModuleConfig;
+
+/// A function full of `if`s, most of them without an `else`.
+const WAT: &str = r#"
+    (module
+      (func (export "f") (param i32) (result i32)
+        (local i32)
+        (if (i32.and (local.get 0) (i32.const 1))
+          (then (local.set 1 (i32.add (local.get 1) (i32.const 1)))))
+        (if (i32.and (local.get 0) (i32.const 2))
+          (then
+            (if (i32.and (local.get 0) (i32.const 4))
+              (then (local.set 1 (i32.add (local.get 1) (i32.const 2)))))
+            (if (i32.and (local.get 0) (i32.const 8))
+              (then (local.set 1 (i32.add (local.get 1) (i32.const 4)))))))
+        (if (i32.and (local.get 0) (i32.const 16))
+          (then (local.set 1 (i32.add (local.get 1) (i32.const 8))))
+          (else (local.set 1 (i32.sub (local.get 1) (i32.const 8)))))
+        (loop
+          (if (i32.eqz (local.get 0))
+            (then (return (local.get 1))))
+          (local.set 0 (i32.shr_u (local.get 0) (i32.const 1)))
+          (if (local.get 0)
+            (then (br 1))))
+        (local.get 1)))
+"#;
+
+#[test]
+fn round_trip_does_not_grow() {
+    let wasm = wat::parse_str(WAT).unwrap();
+    let mut module = ModuleConfig::new()
+        .generate_producers_section(false)
+        .parse(&wasm)
+        .unwrap();
+    let out = module.emit_wasm();
+    assert!(
+        out.len() <= wasm.len(),
+        "round trip grew the module from {} to {} bytes",
+        wasm.len(),
+        out.len()
+    );
+
+    // Emitting the output again gives the same bytes.
+    let mut module = ModuleConfig::new()
+        .generate_producers_section(false)
+        .parse(&out)
+        .unwrap();
+    assert_eq!(module.emit_wasm(), out);
+}
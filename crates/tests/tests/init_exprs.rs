@@ -0,0 +1,69 @@
+//! Constant expressions in global initializers and segment offsets.
+
+use walrus::{ActiveData, ActiveDataLocation, DataKind, ElementKind, GlobalKind};
+use walrus::{ImportKind, InitExpr, Module};
+
+#[test]
+fn parses_offsets_and_initializers() {
+    let wasm = wat::parse_str(
+        r#"
+        (module
+          (import "env" "base" (global $base i32))
+          (global $copy i32 (global.get $base))
+          (global $f funcref (ref.func $f))
+          (memory 1)
+          (table 1 funcref)
+          (func $f)
+          (data (global.get $base) "a")
+          (data (i32.const 8) "b")
+          (elem (global.get $base) $f))
+        "#,
+    )
+    .unwrap();
+    let mut module = Module::from_buffer(&wasm).unwrap();
+
+    let base = module.imports.iter().next().unwrap();
+    let base = match base.kind {
+        ImportKind::Global(global) => global,
+        _ => panic!("expected a global import"),
+    };
+    let inits = module
+        .globals
+        .iter()
+        .filter_map(|g| match g.kind {
+            GlobalKind::Local(init) => Some(init),
+            GlobalKind::Import(_) => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(matches!(inits[0], InitExpr::Global(g) if g == base));
+    assert!(matches!(inits[1], InitExpr::RefFunc(_)));
+
+    let locations = module
+        .data
+        .iter()
+        .map(|d| match d.kind {
+            DataKind::Active(ActiveData { location, .. }) => location,
+            DataKind::Passive => panic!("expected an active segment"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        locations,
+        [
+            ActiveDataLocation::Relative(base),
+            ActiveDataLocation::Absolute(8)
+        ]
+    );
+    let element = module.elements.iter().next().unwrap();
+    assert!(matches!(
+        element.kind,
+        ElementKind::Active {
+            offset: InitExpr::Global(g),
+            ..
+        } if g == base
+    ));
+
+    // Everything is emitted the way it was parsed.
+    let out = module.emit_wasm();
+    let round_tripped = Module::from_buffer(&out).unwrap();
+    assert_eq!(round_tripped.data.iter().count(), 2);
+}
@@ -0,0 +1,110 @@
+//! Tests that `walrus` emits every LEB128 with its minimal length.
+
+use walrus::encoding::{self, Cursor};
+use walrus::Module;
+
+/// Read an unsigned LEB128 at `*pos`, returning its value and asserting that
+/// it isn't padded.
+fn read_minimal_u32(wasm: &[u8], pos: &mut usize, what: &str) -> u32 {
+    let start = *pos;
+    let mut cursor = Cursor::new(&wasm[start..]);
+    let value = cursor.read_u32().unwrap();
+    *pos += cursor.position();
+
+    let mut minimal = Vec::new();
+    encoding::write_u32(&mut minimal, value);
+    assert_eq!(
+        cursor.position(),
+        minimal.len(),
+        "{} at offset {:#x} is encoded in {} bytes, but {} would do",
+        what,
+        start,
+        cursor.position(),
+        minimal.len()
+    );
+    value
+}
+
+/// Rewrite every section size in `wasm` as a five byte LEB128.
+fn pad_section_sizes(wasm: &[u8]) -> Vec<u8> {
+    let mut padded = wasm[..8].to_vec();
+    let mut pos = 8;
+    while pos < wasm.len() {
+        padded.push(wasm[pos]);
+        pos += 1;
+        let size = read_minimal_u32(wasm, &mut pos, "section size") as usize;
+        for i in 0..5 {
+            let byte = (size >> (7 * i)) as u8 & 0x7f;
+            padded.push(if i < 4 { byte | 0x80 } else { byte });
+        }
+        padded.extend_from_slice(&wasm[pos..pos + size]);
+        pos += size;
+    }
+    padded
+}
+
+/// Check the section sizes, the counts that start each section, and the
+/// function body sizes and local declarations in the code section.
+fn assert_minimal_lebs(wasm: &[u8]) {
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_minimal_u32(wasm, &mut pos, "section size") as usize;
+        let end = pos + size;
+        let mut section = pos;
+        match id {
+            // Custom sections are opaque.
+            0 => {}
+            // The code section.
+            10 => {
+                let count = read_minimal_u32(wasm, &mut section, "function count");
+                for _ in 0..count {
+                    let body_size = read_minimal_u32(wasm, &mut section, "body size") as usize;
+                    let body_end = section + body_size;
+                    let decls = read_minimal_u32(wasm, &mut section, "local decl count");
+                    for _ in 0..decls {
+                        read_minimal_u32(wasm, &mut section, "local count");
+                        section += 1;
+                    }
+                    section = body_end;
+                }
+                assert_eq!(section, end);
+            }
+            // Every other section starts with either a count or, for the
+            // start section, a function index.
+            _ => {
+                read_minimal_u32(wasm, &mut section, "section count");
+            }
+        }
+        pos = end;
+    }
+}
+
+#[test]
+fn emits_minimal_lebs() {
+    let wasm = wat::parse_str(
+        r#"
+            (module
+                (import "env" "f" (func $f (param i32) (result i32)))
+                (table 1 funcref)
+                (memory 1)
+                (global $g (mut i32) (i32.const 0))
+                (func $start
+                    (local i32 i32 i64)
+                    (global.set $g (call $f (i32.const 200))))
+                (func (export "run") (param i32) (result i32)
+                    (local f32)
+                    (i32.add (local.get 0) (global.get $g)))
+                (start $start)
+                (elem (i32.const 0) $start)
+                (data (i32.const 1000) "hello"))
+        "#,
+    )
+    .unwrap();
+
+    let padded = pad_section_sizes(&wasm);
+    let mut module = Module::from_buffer(&padded).unwrap();
+    let wasm = module.emit_wasm();
+    assert_minimal_lebs(&wasm);
+}
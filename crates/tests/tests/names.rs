@@ -0,0 +1,31 @@
+//! Tests for round-tripping the `name` custom section.
+
+use walrus::{DataKind, InitExpr, Module, ValType};
+
+#[test]
+fn round_trip_extended_names() {
+    let mut module = Module::default();
+
+    let global = module.globals.add_local(
+        ValType::I32,
+        false,
+        InitExpr::Value(walrus::ir::Value::I32(0)),
+    );
+    module.globals.get_mut(global).name = Some("stack_pointer".to_string());
+    let memory = module.memories.add_local(false, 1, None);
+    module.memories.get_mut(memory).name = Some("heap".to_string());
+    let data = module.data.add(DataKind::Passive, vec![1, 2, 3]);
+    module.data.get_mut(data).name = Some(".rodata".to_string());
+    module.unknown_name_subsections.push((42, vec![1, 2, 3]));
+
+    let wasm = module.emit_wasm();
+    let module = Module::from_buffer(&wasm).unwrap();
+
+    let global = module.globals.iter().next().unwrap();
+    assert_eq!(global.name.as_deref(), Some("stack_pointer"));
+    let memory = module.memories.iter().next().unwrap();
+    assert_eq!(memory.name.as_deref(), Some("heap"));
+    let data = module.data.iter().next().unwrap();
+    assert_eq!(data.name.as_deref(), Some(".rodata"));
+    assert_eq!(module.unknown_name_subsections, vec![(42, vec![1, 2, 3])]);
+}
@@ -0,0 +1,13 @@
+(module
+  (table 1 funcref)
+  (table 2 funcref)
+  (func (export "copy")
+    ;; Copy from table 0 into table 1.
+    (table.copy 1 0
+      (i32.const 0)
+      (i32.const 0)
+      (i32.const 1)))
+  (export "a" (table 0))
+  (export "b" (table 1)))
+
+;; CHECK: table.copy 1 0
@@ -0,0 +1,13 @@
+(module
+  (table 1 funcref)
+  (table 2 externref)
+  (func (export "swap") (param i32 externref) (result externref)
+    ;; Both instructions use table 1, so its index has to be decoded and
+    ;; encoded as a LEB rather than as the reserved zero byte.
+    (table.get 1 (local.get 0))
+    (table.set 1 (local.get 0) (local.get 1)))
+  (export "a" (table 0))
+  (export "b" (table 1)))
+
+;; CHECK: table.get 1
+;; CHECK: table.set 1
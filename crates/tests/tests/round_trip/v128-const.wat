@@ -0,0 +1,12 @@
+(module
+  (func (export "ones") (result v128)
+    v128.const i32x4 0xffffffff 0xffffffff 0xffffffff 0xffffffff)
+  (func (export "alternating") (result v128)
+    ;; Lanes are little-endian, so lane 0 must come out first.
+    v128.const i32x4 0xffffffff 0 0xffffffff 0)
+  (func (export "bytes") (result v128)
+    v128.const i8x16 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15))
+
+;; CHECK: v128.const i32x4 0xffffffff 0xffffffff 0xffffffff 0xffffffff
+;; CHECK: v128.const i32x4 0xffffffff 0x00000000 0xffffffff 0x00000000
+;; CHECK: v128.const i32x4 0x03020100 0x07060504 0x0b0a0908 0x0f0e0d0c
@@ -100,6 +100,9 @@ fn main() -> walrus::Result<()> {
     // Export the `factorial` function.
     module.exports.add("factorial", factorial);
 
-    // Emit the `.wasm` binary to the `target/out.wasm` file.
-    module.emit_wasm_file("target/out.wasm")
+    // Check the module, then write the `.wasm` binary to the `target/out.wasm`
+    // file.
+    let wasm = module.emit_wasm_checked(wasmparser::WasmFeatures::default())?;
+    std::fs::write("target/out.wasm", wasm)?;
+    Ok(())
 }
@@ -31,6 +31,11 @@ impl<T: Clone + Eq + Hash> ArenaSet<T> {
         id
     }
 
+    /// Get the id of the item equal to `val`, if this set has one.
+    pub fn find(&self, val: &T) -> Option<Id<T>> {
+        self.already_in_arena.get(val).copied()
+    }
+
     /// Get the id that will be used for the next unique item added to this set.
     pub fn next_id(&self) -> Id<T> {
         self.arena.next_id()
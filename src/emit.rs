@@ -6,7 +6,8 @@ use crate::ir::Local;
 use crate::map::{IdHashMap, IdHashSet};
 use crate::{CodeTransform, Global, GlobalId, Memory, MemoryId, Module, Table, TableId};
 use crate::{Data, DataId, Element, ElementId, Function, FunctionId};
-use crate::{Type, TypeId};
+use crate::{Tag, TagId, Type, TypeId};
+use std::convert::TryFrom;
 
 pub struct EmitContext<'a> {
     pub module: &'a Module,
@@ -42,6 +43,7 @@ pub struct IdsToIndices {
     funcs: IdHashMap<Function, u32>,
     globals: IdHashMap<Global, u32>,
     memories: IdHashMap<Memory, u32>,
+    tags: IdHashMap<Tag, u32>,
     elements: IdHashMap<Element, u32>,
     data: IdHashMap<Data, u32>,
     pub(crate) locals: IdHashMap<Function, IdHashMap<Local, u32>>,
@@ -79,9 +81,16 @@ macro_rules! define_get_push_index {
             $(
                 /// Adds the given identifier to this set, assigning it the next
                 /// available index.
+                ///
+                /// Panics if that index doesn't fit in 32 bits, since the
+                /// module couldn't be encoded.
                 #[inline]
                 pub(crate) fn $push_name(&mut self, id: $id_ty) {
-                    let idx = self.$member.len() as u32;
+                    let idx = u32::try_from(self.$member.len()).unwrap_or_else(|_| panic!(
+                        "{}: too many items for 32-bit indices, cannot assign one to {:?}",
+                        stringify!($push_name),
+                        id,
+                    ));
                     log::trace!(concat!(stringify!($push_name),": assigning index {} to {:?}"), idx, id);
                     self.$member.insert(id, idx);
                 }
@@ -96,6 +105,7 @@ define_get_push_index! {
     get_func_index, push_func, FunctionId, funcs;
     get_global_index, push_global, GlobalId, globals;
     get_memory_index, push_memory, MemoryId, memories;
+    get_tag_index, push_tag, TagId, tags;
     get_element_index, push_element, ElementId, elements;
 }
 define_get_index! {
@@ -0,0 +1,461 @@
+//! Primitives for reading and writing the wasm binary encoding.
+//!
+//! These are the building blocks walrus itself uses for bytes it encodes by
+//! hand, and are exposed so that tools reading or writing their own custom
+//! sections don't have to reimplement them.
+//!
+//! Reading is done through a bounds-checked `Cursor`, which reports failures
+//! as a `DecodeError`. Writing appends to a `Vec<u8>`, always using the
+//! shortest encoding.
+
+use crate::ValType;
+use std::fmt;
+
+/// An error from decoding the wasm binary encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before the item being read was complete.
+    UnexpectedEof {
+        /// The offset at which more input was needed.
+        offset: usize,
+    },
+    /// A LEB128 used more bytes than its type allows.
+    Overlong {
+        /// The offset at which the LEB128 starts.
+        offset: usize,
+    },
+    /// A LEB128 encodes a value that doesn't fit in its type.
+    Overflow {
+        /// The offset at which the LEB128 starts.
+        offset: usize,
+    },
+    /// A string isn't valid UTF-8.
+    InvalidUtf8 {
+        /// The offset at which the string's bytes start.
+        offset: usize,
+    },
+    /// A byte doesn't encode a value type.
+    InvalidValType {
+        /// The offset of the byte.
+        offset: usize,
+        /// The byte itself.
+        byte: u8,
+    },
+    /// A limits flag byte is neither `0x00` nor `0x01`.
+    InvalidLimits {
+        /// The offset of the flag byte.
+        offset: usize,
+        /// The flag byte itself.
+        flag: u8,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof { offset } => {
+                write!(f, "unexpected end of input at offset {:#x}", offset)
+            }
+            DecodeError::Overlong { offset } => {
+                write!(f, "overlong LEB128 at offset {:#x}", offset)
+            }
+            DecodeError::Overflow { offset } => {
+                write!(f, "LEB128 at offset {:#x} is out of range", offset)
+            }
+            DecodeError::InvalidUtf8 { offset } => {
+                write!(f, "invalid UTF-8 string at offset {:#x}", offset)
+            }
+            DecodeError::InvalidValType { offset, byte } => write!(
+                f,
+                "invalid value type {:#04x} at offset {:#x}",
+                byte, offset
+            ),
+            DecodeError::InvalidLimits { offset, flag } => write!(
+                f,
+                "invalid limits flag {:#04x} at offset {:#x}",
+                flag, offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A bounds-checked reader over wasm-encoded bytes.
+#[derive(Clone, Debug)]
+pub struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Construct a new cursor at the start of the given bytes.
+    pub fn new(data: &'a [u8]) -> Cursor<'a> {
+        Cursor { data, position: 0 }
+    }
+
+    /// Get the offset of the next byte to be read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Get the bytes that haven't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+
+    /// Has every byte been read?
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    /// Read a single byte.
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self
+            .data
+            .get(self.position)
+            .ok_or(DecodeError::UnexpectedEof {
+                offset: self.position,
+            })?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    /// Read the given number of bytes.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() - self.position < len {
+            return Err(DecodeError::UnexpectedEof {
+                offset: self.data.len(),
+            });
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Read an unsigned LEB128 of up to `bits` bits.
+    fn read_unsigned(&mut self, bits: u32) -> Result<u64, DecodeError> {
+        let offset = self.position;
+        let max_len = (bits + 6) / 7;
+        let mut value = 0;
+        for i in 0..max_len {
+            let byte = self.read_u8()?;
+            let shift = 7 * i;
+            let payload = u64::from(byte & 0x7f);
+            if i == max_len - 1 {
+                // The last byte may not have bits set beyond the type's width.
+                if byte & 0x80 != 0 {
+                    return Err(DecodeError::Overlong { offset });
+                }
+                if bits - shift < 7 && payload >> (bits - shift) != 0 {
+                    return Err(DecodeError::Overflow { offset });
+                }
+            }
+            value |= payload << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Read a signed LEB128 of up to `bits` bits.
+    fn read_signed(&mut self, bits: u32) -> Result<i64, DecodeError> {
+        let offset = self.position;
+        let max_len = (bits + 6) / 7;
+        let mut value = 0;
+        for i in 0..max_len {
+            let byte = self.read_u8()?;
+            let shift = 7 * i;
+            if i == max_len - 1 {
+                if byte & 0x80 != 0 {
+                    return Err(DecodeError::Overlong { offset });
+                }
+                // The bits beyond the type's width must all be copies of its
+                // sign bit.
+                let used = bits - shift;
+                if used < 7 {
+                    let sign_and_unused = (byte as i8) << 1 >> used;
+                    if sign_and_unused != 0 && sign_and_unused != -1 {
+                        return Err(DecodeError::Overflow { offset });
+                    }
+                }
+            }
+            value |= i64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                let width = shift + 7;
+                if width < 64 {
+                    value = value << (64 - width) >> (64 - width);
+                }
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Read an unsigned 32-bit LEB128.
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        self.read_unsigned(32).map(|v| v as u32)
+    }
+
+    /// Read an unsigned 64-bit LEB128.
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        self.read_unsigned(64)
+    }
+
+    /// Read a signed 32-bit LEB128.
+    pub fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        self.read_signed(32).map(|v| v as i32)
+    }
+
+    /// Read a signed 64-bit LEB128.
+    pub fn read_i64(&mut self) -> Result<i64, DecodeError> {
+        self.read_signed(64)
+    }
+
+    /// Read a length-prefixed UTF-8 string.
+    pub fn read_string(&mut self) -> Result<&'a str, DecodeError> {
+        let len = self.read_u32()? as usize;
+        let offset = self.position;
+        let bytes = self.read_bytes(len)?;
+        std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8 { offset })
+    }
+
+    /// Read a single-byte value type.
+    pub fn read_val_type(&mut self) -> Result<ValType, DecodeError> {
+        let offset = self.position;
+        let byte = self.read_u8()?;
+        ValType::from_wasm_byte(byte).ok_or(DecodeError::InvalidValType { offset, byte })
+    }
+
+    /// Read limits, as used by memory and table types, returning the minimum
+    /// and the optional maximum.
+    pub fn read_limits(&mut self) -> Result<(u32, Option<u32>), DecodeError> {
+        let offset = self.position;
+        match self.read_u8()? {
+            0x00 => Ok((self.read_u32()?, None)),
+            0x01 => Ok((self.read_u32()?, Some(self.read_u32()?))),
+            flag => Err(DecodeError::InvalidLimits { offset, flag }),
+        }
+    }
+}
+
+/// Append an unsigned 32-bit LEB128.
+pub fn write_u32(out: &mut Vec<u8>, value: u32) {
+    write_u64(out, value.into());
+}
+
+/// Append an unsigned 64-bit LEB128.
+pub fn write_u64(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Append a signed 32-bit LEB128.
+pub fn write_i32(out: &mut Vec<u8>, value: i32) {
+    write_i64(out, value.into());
+}
+
+/// Append a signed 64-bit LEB128.
+pub fn write_i64(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let sign_bit = byte & 0x40 != 0;
+        if (value == 0 && !sign_bit) || (value == -1 && sign_bit) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Append a length-prefixed UTF-8 string.
+pub fn write_string(out: &mut Vec<u8>, string: &str) {
+    write_bytes(out, string.as_bytes());
+}
+
+/// Append a length-prefixed vector of bytes.
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Append a single-byte value type.
+pub fn write_val_type(out: &mut Vec<u8>, ty: ValType) {
+    out.push(ty.to_wasm_byte());
+}
+
+/// Append limits, as used by memory and table types.
+pub fn write_limits(out: &mut Vec<u8>, minimum: u32, maximum: Option<u32>) {
+    match maximum {
+        None => {
+            out.push(0x00);
+            write_u32(out, minimum);
+        }
+        Some(maximum) => {
+            out.push(0x01);
+            write_u32(out, minimum);
+            write_u32(out, maximum);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded<T>(write: fn(&mut Vec<u8>, T), value: T) -> Vec<u8> {
+        let mut out = Vec::new();
+        write(&mut out, value);
+        out
+    }
+
+    #[test]
+    fn unsigned_round_trip() {
+        let values = [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            0x0fff_ffff,
+            0x1000_0000,
+            u32::MAX,
+        ];
+        for &value in values.iter() {
+            let bytes = encoded(write_u32, value);
+            let mut cursor = Cursor::new(&bytes);
+            assert_eq!(cursor.read_u32(), Ok(value));
+            assert!(cursor.is_empty());
+        }
+        assert_eq!(encoded(write_u32, u32::MAX), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+
+        for &value in [0, 1 << 35, 1 << 63, u64::MAX].iter() {
+            let bytes = encoded(write_u64, value);
+            assert_eq!(Cursor::new(&bytes).read_u64(), Ok(value));
+        }
+        assert_eq!(encoded(write_u64, u64::MAX).len(), 10);
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let values = [
+            0,
+            1,
+            -1,
+            63,
+            64,
+            -64,
+            -65,
+            0x0fff_ffff,
+            -0x1000_0000,
+            i32::MAX,
+            i32::MIN,
+        ];
+        for &value in values.iter() {
+            let bytes = encoded(write_i32, value);
+            let mut cursor = Cursor::new(&bytes);
+            assert_eq!(cursor.read_i32(), Ok(value));
+            assert!(cursor.is_empty());
+        }
+        assert_eq!(encoded(write_i32, -1), [0x7f]);
+        assert_eq!(encoded(write_i32, i32::MIN), [0x80, 0x80, 0x80, 0x80, 0x78]);
+
+        for &value in [0, -1, 1 << 40, i64::MAX, i64::MIN].iter() {
+            let bytes = encoded(write_i64, value);
+            assert_eq!(Cursor::new(&bytes).read_i64(), Ok(value));
+        }
+        assert_eq!(encoded(write_i64, i64::MIN).len(), 10);
+    }
+
+    #[test]
+    fn padded_but_in_range() {
+        // Padding is allowed, as long as it stays within the maximum length.
+        let mut cursor = Cursor::new(&[0x81, 0x80, 0x80, 0x80, 0x00]);
+        assert_eq!(cursor.read_u32(), Ok(1));
+        let mut cursor = Cursor::new(&[0xff, 0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(cursor.read_i32(), Ok(-1));
+    }
+
+    #[test]
+    fn rejects_overlong_and_overflowing() {
+        let overlong = [0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+        assert_eq!(
+            Cursor::new(&overlong).read_u32(),
+            Err(DecodeError::Overlong { offset: 0 })
+        );
+        assert_eq!(
+            Cursor::new(&overlong).read_i32(),
+            Err(DecodeError::Overlong { offset: 0 })
+        );
+        assert_eq!(
+            Cursor::new(&[0xff, 0xff, 0xff, 0xff, 0x1f]).read_u32(),
+            Err(DecodeError::Overflow { offset: 0 })
+        );
+        assert_eq!(
+            Cursor::new(&[0xff, 0xff, 0xff, 0xff, 0x4f]).read_i32(),
+            Err(DecodeError::Overflow { offset: 0 })
+        );
+        assert_eq!(
+            Cursor::new(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02]).read_u64(),
+            Err(DecodeError::Overflow { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        assert_eq!(
+            Cursor::new(&[0x80, 0x80]).read_u32(),
+            Err(DecodeError::UnexpectedEof { offset: 2 })
+        );
+        assert_eq!(
+            Cursor::new(&[0x05, b'a']).read_string(),
+            Err(DecodeError::UnexpectedEof { offset: 2 })
+        );
+        assert_eq!(
+            Cursor::new(&[]).read_u8(),
+            Err(DecodeError::UnexpectedEof { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn strings_types_and_limits() {
+        let mut out = Vec::new();
+        write_string(&mut out, "walrus");
+        write_val_type(&mut out, ValType::F64);
+        write_limits(&mut out, 1, None);
+        write_limits(&mut out, 2, Some(300));
+
+        let mut cursor = Cursor::new(&out);
+        assert_eq!(cursor.read_string(), Ok("walrus"));
+        assert_eq!(cursor.read_val_type(), Ok(ValType::F64));
+        assert_eq!(cursor.read_limits(), Ok((1, None)));
+        assert_eq!(cursor.read_limits(), Ok((2, Some(300))));
+        assert!(cursor.is_empty());
+
+        assert_eq!(
+            Cursor::new(&[0x01, 0xff]).read_string(),
+            Err(DecodeError::InvalidUtf8 { offset: 1 })
+        );
+        assert_eq!(
+            Cursor::new(&[0x40]).read_val_type(),
+            Err(DecodeError::InvalidValType {
+                offset: 0,
+                byte: 0x40
+            })
+        );
+        assert_eq!(
+            Cursor::new(&[0x02, 0x00]).read_limits(),
+            Err(DecodeError::InvalidLimits { offset: 0, flag: 2 })
+        );
+    }
+}
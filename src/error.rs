@@ -14,12 +14,22 @@ pub use anyhow::Result;
 pub enum ErrorKind {
     /// Given invalid input wasm.
     InvalidWasm,
+
+    /// Two exports have the same name.
+    DuplicateExport,
+
+    /// Two imports of the same kind have the same module and name.
+    DuplicateImport,
 }
 
 impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ErrorKind::InvalidWasm => "The input WebAssembly is invalid".fmt(f),
+            ErrorKind::DuplicateExport => "Two exports have the same name".fmt(f),
+            ErrorKind::DuplicateImport => {
+                "Two imports of the same kind have the same module and name".fmt(f)
+            }
         }
     }
 }
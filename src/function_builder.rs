@@ -232,6 +232,43 @@ impl InstrSeqBuilder<'_> {
         self.const_(Value::F64(val))
     }
 
+    /// Creates a `v128.const` instruction for the specified value
+    #[inline]
+    pub fn v128_const(&mut self, val: u128) -> &mut Self {
+        self.const_(Value::V128(val))
+    }
+
+    /// Pushes the default, zero value of the given type: a `*.const 0` for
+    /// numeric types and `ref.null` for reference types.
+    #[inline]
+    pub fn default_value(&mut self, ty: ValType) -> &mut Self {
+        match ty.default_value() {
+            Some(value) => self.const_(value),
+            None => self.ref_null(ty),
+        }
+    }
+
+    /// Initializes the given local to the default, zero value of its type.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use walrus::ValType;
+    ///
+    /// let mut module = walrus::Module::default();
+    /// let tmp = module.locals.add(ValType::F64);
+    ///
+    /// let mut builder = walrus::FunctionBuilder::new(&mut module.types, &[], &[]);
+    ///
+    /// // Append `(local.set $tmp (f64.const 0))` to the function.
+    /// builder
+    ///     .func_body()
+    ///     .local_set_default(module.locals.get(tmp));
+    /// ```
+    pub fn local_set_default(&mut self, local: &Local) -> &mut Self {
+        self.default_value(local.ty()).local_set(local.id())
+    }
+
     /// Append a new, nested `block ... end` to this builder's sequence.
     ///
     /// # Example:
@@ -3,12 +3,17 @@
 use crate::emit::EmitContext;
 use crate::ir::Value;
 use crate::parse::IndicesToIds;
-use crate::ValType;
+use crate::{ActiveDataLocation, GlobalKind, Module, ValType};
 use crate::{FunctionId, GlobalId, Result};
 use anyhow::bail;
+use wasmparser::WasmFeatures;
 
-/// A constant which is produced in WebAssembly, typically used in global
-/// initializers or element/data offsets.
+/// A constant which is produced in WebAssembly, used in global initializers
+/// and in the offsets of active data and element segments.
+///
+/// All three are parsed, checked and emitted the same way. Data segment
+/// offsets are kept as an `ActiveDataLocation`, which converts to and from
+/// this type.
 #[derive(Debug, Copy, Clone)]
 pub enum InitExpr {
     /// An immediate constant value
@@ -22,6 +27,136 @@ pub enum InitExpr {
 }
 
 impl InitExpr {
+    /// An `i32.const`.
+    pub fn i32_const(value: i32) -> InitExpr {
+        InitExpr::Value(Value::I32(value))
+    }
+
+    /// An `i64.const`.
+    pub fn i64_const(value: i64) -> InitExpr {
+        InitExpr::Value(Value::I64(value))
+    }
+
+    /// An `f32.const`.
+    pub fn f32_const(value: f32) -> InitExpr {
+        InitExpr::Value(Value::F32(value))
+    }
+
+    /// An `f64.const`.
+    pub fn f64_const(value: f64) -> InitExpr {
+        InitExpr::Value(Value::F64(value))
+    }
+
+    /// A `v128.const`.
+    pub fn v128_const(value: u128) -> InitExpr {
+        InitExpr::Value(Value::V128(value))
+    }
+
+    /// A `global.get` of the given global.
+    pub fn global_get(global: GlobalId) -> InitExpr {
+        InitExpr::Global(global)
+    }
+
+    /// A `ref.null` of the given reference type.
+    pub fn ref_null(ty: ValType) -> InitExpr {
+        InitExpr::RefNull(ty)
+    }
+
+    /// A `ref.func` of the given function.
+    pub fn ref_func(func: FunctionId) -> InitExpr {
+        InitExpr::RefFunc(func)
+    }
+
+    /// The type of the value this expression produces.
+    pub fn ty(&self, module: &Module) -> ValType {
+        match self {
+            InitExpr::Value(Value::I32(_)) => ValType::I32,
+            InitExpr::Value(Value::I64(_)) => ValType::I64,
+            InitExpr::Value(Value::F32(_)) => ValType::F32,
+            InitExpr::Value(Value::F64(_)) => ValType::F64,
+            InitExpr::Value(Value::V128(_)) => ValType::V128,
+            InitExpr::Global(global) => module.globals.get(*global).ty,
+            InitExpr::RefNull(ty) => *ty,
+            InitExpr::RefFunc(_) => ValType::Funcref,
+        }
+    }
+
+    /// Check that this expression is a valid constant expression of type
+    /// `expected` in the given module, using only the given features.
+    ///
+    /// The rules are the same for global initializers and segment offsets: a
+    /// `global.get` may only refer to an immutable, imported global, since
+    /// globals defined in the module come after all the imported ones and
+    /// aren't initialized yet when constant expressions are evaluated.
+    pub fn validate(
+        &self,
+        module: &Module,
+        expected: ValType,
+        features: WasmFeatures,
+    ) -> Result<()> {
+        match self {
+            InitExpr::Value(Value::V128(_)) if !features.simd => {
+                bail!("cannot use `v128.const` in a constant expression without simd")
+            }
+            InitExpr::RefNull(_) | InitExpr::RefFunc(_) if !features.reference_types => {
+                bail!("cannot use references in a constant expression without reference types")
+            }
+            InitExpr::RefNull(ty) if !ty.is_ref() => {
+                bail!("cannot use `ref.null` of non-reference type {}", ty)
+            }
+            InitExpr::RefFunc(func) if !module.funcs.contains(*func) => {
+                bail!("cannot refer to deleted function [{:?}]", func)
+            }
+            InitExpr::Global(global) => {
+                if !module.globals.contains(*global) {
+                    bail!("cannot refer to deleted global [{:?}]", global);
+                }
+                let g = module.globals.get(*global);
+                if !matches!(g.kind, GlobalKind::Import(_)) {
+                    bail!(
+                        "cannot refer to global [{:?}] in a constant expression, it is defined \
+                         in the module rather than imported",
+                        global
+                    );
+                }
+                if g.mutable {
+                    bail!(
+                        "cannot refer to global [{:?}] in a constant expression, it is mutable",
+                        global
+                    );
+                }
+            }
+            _ => {}
+        }
+        self.expect_ty(module, expected)
+    }
+
+    /// Parse a constant expression that must produce a value of type
+    /// `expected`, and validate it against the features the module is parsed
+    /// with.
+    pub(crate) fn eval_typed(
+        init: &wasmparser::InitExpr,
+        ids: &IndicesToIds,
+        module: &Module,
+        expected: ValType,
+    ) -> Result<InitExpr> {
+        let expr = InitExpr::eval(init, ids)?;
+        expr.validate(module, expected, module.config.wasm_features())?;
+        Ok(expr)
+    }
+
+    fn expect_ty(&self, module: &Module, expected: ValType) -> Result<()> {
+        let ty = self.ty(module);
+        if ty != expected {
+            bail!(
+                "type mismatch in constant expression: expected {}, found {}",
+                expected,
+                ty
+            );
+        }
+        Ok(())
+    }
+
     pub(crate) fn eval(init: &wasmparser::InitExpr, ids: &IndicesToIds) -> Result<InitExpr> {
         use wasmparser::Operator::*;
         let mut reader = init.get_operators_reader();
@@ -32,7 +167,7 @@ impl InitExpr {
             F64Const { value } => InitExpr::Value(Value::F64(f64::from_bits(value.bits()))),
             V128Const { value } => InitExpr::Value(Value::V128(v128_to_u128(&value))),
             GlobalGet { global_index } => InitExpr::Global(ids.get_global(global_index)?),
-            RefNull { ty } => InitExpr::RefNull(ValType::parse(&ty)?),
+            RefNull { ty } => InitExpr::RefNull(ValType::from_parser(&ty)?),
             RefFunc { function_index } => InitExpr::RefFunc(ids.get_func(function_index)?),
             _ => bail!("invalid constant expression"),
         };
@@ -44,7 +179,7 @@ impl InitExpr {
         Ok(val)
     }
 
-    pub(crate) fn to_wasmencoder_type(&self, cx: &EmitContext) -> wasm_encoder::ConstExpr {
+    pub(crate) fn to_encoder(&self, cx: &EmitContext) -> wasm_encoder::ConstExpr {
         match self {
             InitExpr::Value(v) => match v {
                 Value::I32(v) => wasm_encoder::ConstExpr::i32_const(*v),
@@ -56,11 +191,7 @@ impl InitExpr {
             InitExpr::Global(g) => {
                 wasm_encoder::ConstExpr::global_get(cx.indices.get_global_index(*g))
             }
-            InitExpr::RefNull(ty) => wasm_encoder::ConstExpr::ref_null(match ty {
-                ValType::Externref => wasm_encoder::HeapType::Extern,
-                ValType::Funcref => wasm_encoder::HeapType::Func,
-                _ => unreachable!(),
-            }),
+            InitExpr::RefNull(ty) => wasm_encoder::ConstExpr::ref_null(ty.to_encoder_heap()),
             InitExpr::RefFunc(f) => {
                 wasm_encoder::ConstExpr::ref_func(cx.indices.get_func_index(*f))
             }
@@ -68,22 +199,88 @@ impl InitExpr {
     }
 }
 
+impl From<Value> for InitExpr {
+    fn from(value: Value) -> InitExpr {
+        InitExpr::Value(value)
+    }
+}
+
+impl From<ActiveDataLocation> for InitExpr {
+    fn from(location: ActiveDataLocation) -> InitExpr {
+        match location {
+            ActiveDataLocation::Absolute(offset) => InitExpr::i32_const(offset as i32),
+            ActiveDataLocation::Relative(global) => InitExpr::Global(global),
+        }
+    }
+}
+
+/// Convert a `v128` immediate to a `u128`.
+///
+/// The immediate's 16 bytes are little-endian, so its first byte is the least
+/// significant byte of lane 0.
 pub(crate) fn v128_to_u128(value: &wasmparser::V128) -> u128 {
-    let n = value.bytes();
-    ((n[0] as u128) << 0)
-        | ((n[1] as u128) << 8)
-        | ((n[2] as u128) << 16)
-        | ((n[3] as u128) << 24)
-        | ((n[4] as u128) << 32)
-        | ((n[5] as u128) << 40)
-        | ((n[6] as u128) << 48)
-        | ((n[7] as u128) << 56)
-        | ((n[8] as u128) << 64)
-        | ((n[9] as u128) << 72)
-        | ((n[10] as u128) << 80)
-        | ((n[11] as u128) << 88)
-        | ((n[12] as u128) << 96)
-        | ((n[13] as u128) << 104)
-        | ((n[14] as u128) << 112)
-        | ((n[15] as u128) << 120)
+    u128::from_le_bytes(*value.bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_types_features_and_globals() {
+        let mut module = Module::default();
+        let features = WasmFeatures::default();
+        let (imported, _) = module.add_import_global("env", "g", ValType::I32, false);
+        let (imported_mut, _) = module.add_import_global("env", "m", ValType::I32, true);
+        let local = module
+            .globals
+            .add_local(ValType::I32, false, InitExpr::i32_const(1));
+
+        InitExpr::i32_const(1)
+            .validate(&module, ValType::I32, features)
+            .unwrap();
+        InitExpr::global_get(imported)
+            .validate(&module, ValType::I32, features)
+            .unwrap();
+        InitExpr::ref_null(ValType::Externref)
+            .validate(&module, ValType::Externref, features)
+            .unwrap();
+
+        // Wrong type.
+        assert!(InitExpr::i64_const(1)
+            .validate(&module, ValType::I32, features)
+            .is_err());
+        assert!(InitExpr::global_get(imported)
+            .validate(&module, ValType::F32, features)
+            .is_err());
+
+        // Disallowed by the feature set.
+        let mvp = crate::passes::manager::mvp();
+        assert!(InitExpr::ref_null(ValType::Funcref)
+            .validate(&module, ValType::Funcref, mvp)
+            .is_err());
+        assert!(InitExpr::v128_const(0)
+            .validate(&module, ValType::V128, features)
+            .is_err());
+        InitExpr::v128_const(0)
+            .validate(
+                &module,
+                ValType::V128,
+                WasmFeatures {
+                    simd: true,
+                    ..features
+                },
+            )
+            .unwrap();
+
+        // Globals defined in the module come after the imported ones, so they
+        // can't be used; neither can mutable ones.
+        let err = InitExpr::global_get(local)
+            .validate(&module, ValType::I32, features)
+            .unwrap_err();
+        assert!(err.to_string().contains("defined in the module"));
+        assert!(InitExpr::global_get(imported_mut)
+            .validate(&module, ValType::I32, features)
+            .is_err());
+    }
 }
@@ -0,0 +1,235 @@
+//! Typed side tables that passes can attach to instructions.
+
+use super::InstrSeqId;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// The key of one kind of annotation, holding values of type `T`.
+///
+/// Each pass defines its own keys as statics, and the key's name keeps them
+/// apart, even if two passes annotate instructions with the same type:
+///
+/// ```
+/// use walrus::ir::{AnnotationKey, Value};
+///
+/// static CONST_VALUE: AnnotationKey<Value> = AnnotationKey::new("my_pass::const_value");
+/// ```
+pub struct AnnotationKey<T> {
+    name: &'static str,
+    ty: PhantomData<fn() -> T>,
+}
+
+impl<T> AnnotationKey<T> {
+    /// Create a key with the given name, which should be unique among the
+    /// keys for values of type `T`. Prefixing it with the pass's name is a
+    /// good way to make sure of that.
+    pub const fn new(name: &'static str) -> AnnotationKey<T> {
+        AnnotationKey {
+            name,
+            ty: PhantomData,
+        }
+    }
+
+    /// The key's name.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T> Clone for AnnotationKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for AnnotationKey<T> {}
+
+impl<T> fmt::Debug for AnnotationKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AnnotationKey").field(&self.name).finish()
+    }
+}
+
+/// Values attached to instructions, grouped by `AnnotationKey`.
+///
+/// An instruction is identified by its sequence and its index within it, so
+/// annotations describe positions: after instructions are inserted or removed
+/// in a sequence, its annotations should be cleared or recomputed.
+#[derive(Default)]
+pub struct ScopedAnnotations {
+    tables: HashMap<(TypeId, &'static str), Box<dyn AnyTable>>,
+}
+
+type Table<T> = HashMap<(InstrSeqId, usize), T>;
+
+/// A `Table<T>` with its `T` erased.
+trait AnyTable {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn clear_seq(&mut self, seq: InstrSeqId);
+}
+
+impl<T: 'static> AnyTable for Table<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn clear_seq(&mut self, seq: InstrSeqId) {
+        self.retain(|&(s, _), _| s != seq);
+    }
+}
+
+impl ScopedAnnotations {
+    /// Create an empty set of annotations.
+    pub fn new() -> ScopedAnnotations {
+        ScopedAnnotations::default()
+    }
+
+    /// Annotate the instruction at `index` in `seq` with `value`, replacing
+    /// and returning its previous value for `key`, if any.
+    pub fn annotate<T: 'static>(
+        &mut self,
+        key: AnnotationKey<T>,
+        seq: InstrSeqId,
+        index: usize,
+        value: T,
+    ) -> Option<T> {
+        self.tables
+            .entry((TypeId::of::<T>(), key.name))
+            .or_insert_with(|| Box::new(Table::<T>::new()))
+            .as_any_mut()
+            .downcast_mut::<Table<T>>()
+            .unwrap()
+            .insert((seq, index), value)
+    }
+
+    /// Get the value for `key` of the instruction at `index` in `seq`.
+    pub fn get<T: 'static>(
+        &self,
+        key: AnnotationKey<T>,
+        seq: InstrSeqId,
+        index: usize,
+    ) -> Option<&T> {
+        self.table(key)?.get(&(seq, index))
+    }
+
+    /// Get a mutable reference to the value for `key` of the instruction at
+    /// `index` in `seq`.
+    pub fn get_mut<T: 'static>(
+        &mut self,
+        key: AnnotationKey<T>,
+        seq: InstrSeqId,
+        index: usize,
+    ) -> Option<&mut T> {
+        self.table_mut(key)?.get_mut(&(seq, index))
+    }
+
+    /// Remove and return the value for `key` of the instruction at `index` in
+    /// `seq`.
+    pub fn remove<T: 'static>(
+        &mut self,
+        key: AnnotationKey<T>,
+        seq: InstrSeqId,
+        index: usize,
+    ) -> Option<T> {
+        self.table_mut(key)?.remove(&(seq, index))
+    }
+
+    /// Iterate over every instruction annotated for `key`, with its value, in
+    /// no particular order.
+    pub fn iter<T: 'static>(
+        &self,
+        key: AnnotationKey<T>,
+    ) -> impl Iterator<Item = (InstrSeqId, usize, &T)> {
+        self.table(key)
+            .into_iter()
+            .flat_map(|table| table.iter())
+            .map(|(&(seq, index), value)| (seq, index, value))
+    }
+
+    /// Remove every annotation for `key`.
+    pub fn clear<T: 'static>(&mut self, key: AnnotationKey<T>) {
+        self.tables.remove(&(TypeId::of::<T>(), key.name));
+    }
+
+    /// Remove every annotation, for any key, of the instructions in `seq`.
+    pub fn clear_seq(&mut self, seq: InstrSeqId) {
+        for table in self.tables.values_mut() {
+            table.clear_seq(seq);
+        }
+    }
+
+    fn table<T: 'static>(&self, key: AnnotationKey<T>) -> Option<&Table<T>> {
+        self.tables
+            .get(&(TypeId::of::<T>(), key.name))
+            .map(|table| table.as_any().downcast_ref::<Table<T>>().unwrap())
+    }
+
+    fn table_mut<T: 'static>(&mut self, key: AnnotationKey<T>) -> Option<&mut Table<T>> {
+        self.tables
+            .get_mut(&(TypeId::of::<T>(), key.name))
+            .map(|table| table.as_any_mut().downcast_mut::<Table<T>>().unwrap())
+    }
+}
+
+impl fmt::Debug for ScopedAnnotations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(self.tables.keys().map(|(_, name)| name))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Value;
+    use crate::{FunctionBuilder, Module};
+
+    static CONST_VALUE: AnnotationKey<Value> = AnnotationKey::new("a::const_value");
+    static OTHER_VALUE: AnnotationKey<Value> = AnnotationKey::new("b::const_value");
+    static COUNT: AnnotationKey<usize> = AnnotationKey::new("a::count");
+
+    #[test]
+    fn keeps_keys_apart() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let outer = builder.func_body_id();
+        let inner = builder.dangling_instr_seq(None).id();
+
+        let mut annotations = ScopedAnnotations::new();
+        assert!(annotations
+            .annotate(CONST_VALUE, outer, 0, Value::I32(1))
+            .is_none());
+        annotations.annotate(OTHER_VALUE, outer, 0, Value::I32(2));
+        annotations.annotate(COUNT, outer, 0, 3);
+        annotations.annotate(COUNT, inner, 1, 4);
+
+        assert!(matches!(
+            annotations.get(CONST_VALUE, outer, 0),
+            Some(Value::I32(1))
+        ));
+        assert!(matches!(
+            annotations.get(OTHER_VALUE, outer, 0),
+            Some(Value::I32(2))
+        ));
+        assert_eq!(annotations.get(COUNT, outer, 0), Some(&3));
+        assert!(annotations.get(CONST_VALUE, outer, 1).is_none());
+
+        *annotations.get_mut(COUNT, outer, 0).unwrap() += 10;
+        assert_eq!(annotations.remove(COUNT, outer, 0), Some(13));
+        assert_eq!(annotations.iter(COUNT).count(), 1);
+
+        annotations.clear_seq(inner);
+        assert_eq!(annotations.iter(COUNT).count(), 0);
+        annotations.clear(CONST_VALUE);
+        assert!(annotations.get(CONST_VALUE, outer, 0).is_none());
+        assert!(annotations.get(OTHER_VALUE, outer, 0).is_some());
+    }
+}
@@ -4,9 +4,12 @@
 //! the stack machine into an instruction tree. Additionally all control frames
 //! are representd as `Block`s.
 
+mod annotations;
 mod traversals;
+pub use self::annotations::{AnnotationKey, ScopedAnnotations};
 pub use self::traversals::*;
 
+use crate::tombstone_arena::Tombstone;
 use crate::{
     DataId, ElementId, FunctionId, GlobalId, LocalFunction, MemoryId, ModuleTypes, TableId, TypeId,
     ValType,
@@ -163,6 +166,15 @@ pub struct InstrSeq {
 
     /// The instructions that make up the body of this block.
     pub instrs: Vec<(Instr, InstrLocId)>,
+
+    /// The number of instructions at the start of `instrs` that make up this
+    /// block's preamble.
+    preamble_len: usize,
+
+    /// The preamble as it was last set, so that debug builds can check that
+    /// passes leave it alone.
+    #[cfg(debug_assertions)]
+    preamble: Vec<Instr>,
 }
 
 impl Deref for InstrSeq {
@@ -181,11 +193,25 @@ impl DerefMut for InstrSeq {
     }
 }
 
+impl Tombstone for InstrSeq {
+    fn on_delete(&mut self) {
+        self.instrs = Vec::new();
+        self.set_preamble_len(0);
+    }
+}
+
 impl InstrSeq {
     /// Construct a new instruction sequence.
     pub(crate) fn new(id: InstrSeqId, ty: InstrSeqType) -> InstrSeq {
         let instrs = vec![];
-        InstrSeq { id, ty, instrs }
+        InstrSeq {
+            id,
+            ty,
+            instrs,
+            preamble_len: 0,
+            #[cfg(debug_assertions)]
+            preamble: Vec::new(),
+        }
     }
 
     /// Get the id of this instruction sequence.
@@ -193,6 +219,105 @@ impl InstrSeq {
     pub fn id(&self) -> InstrSeqId {
         self.id
     }
+
+    /// Get the number of instructions at the start of this sequence that make
+    /// up its preamble.
+    ///
+    /// The preamble is where instrumentation that must run whenever the
+    /// sequence is entered goes. Passes treat it as pinned: they don't remove
+    /// its instructions, move instructions into, out of, or across it, or
+    /// merge it into another sequence, unless they remove the whole sequence
+    /// because it can never run.
+    #[inline]
+    pub fn preamble_len(&self) -> usize {
+        self.preamble_len
+    }
+
+    /// Append an instruction to the end of this sequence's preamble, after any
+    /// instructions already in it and before the rest of the sequence.
+    pub fn push_preamble(&mut self, instr: impl Into<Instr>) {
+        self.instrs
+            .insert(self.preamble_len, (instr.into(), Default::default()));
+        self.set_preamble_len(self.preamble_len + 1);
+    }
+
+    /// Set the number of instructions at the start of this sequence that make
+    /// up its preamble.
+    ///
+    /// This is for passes that insert instructions within the preamble, or
+    /// that move a whole sequence's instructions into another one.
+    ///
+    /// Panics if `len` is greater than the length of the sequence.
+    pub fn set_preamble_len(&mut self, len: usize) {
+        assert!(
+            len <= self.instrs.len(),
+            "preamble longer than its sequence"
+        );
+        self.preamble_len = len;
+        #[cfg(debug_assertions)]
+        {
+            self.preamble = self.instrs[..len].iter().map(|(i, _)| i.clone()).collect();
+        }
+    }
+
+    /// Whether this sequence still starts with its preamble as it was last
+    /// set with `push_preamble` or `set_preamble_len`.
+    ///
+    /// Only debug builds keep a copy of the preamble to compare with, so
+    /// release builds only check that the sequence is long enough to hold it.
+    pub(crate) fn preamble_intact(&self) -> bool {
+        if self.preamble_len > self.instrs.len() {
+            return false;
+        }
+        #[cfg(debug_assertions)]
+        {
+            let preamble = self.instrs[..self.preamble_len].iter().map(|(i, _)| i);
+            if !preamble.eq(self.preamble.iter()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Get the number of instructions at the start of this sequence that can
+    /// run, up to and including the first unconditional branch, `return` or
+    /// `unreachable`, if any.
+    ///
+    /// The instructions after it are only there if they were kept with
+    /// `ModuleConfig::preserve_unreachable_code`, and are typed with the
+    /// stack-polymorphic rules of validation.
+    pub fn reachable_len(&self) -> usize {
+        self.instrs
+            .iter()
+            .position(|(instr, _)| instr.following_instructions_are_unreachable())
+            .map_or(self.instrs.len(), |i| i + 1)
+    }
+}
+
+/// A point at which control leaves an instruction sequence, supplying the
+/// values that the sequence produces.
+///
+/// There is no explicit "phi" node in the IR: the results of a `block` or
+/// `if/else` are whatever values are on top of the stack at each exit. When
+/// several exits reach the same sequence, each one supplies the results
+/// independently. See `LocalFunction::result_suppliers` to enumerate the
+/// exits of a sequence and `LocalFunction::supplied_value` to find which
+/// instruction produced a particular result at a given exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultSupplier {
+    /// Control falls off the end of the instruction sequence `seq`.
+    Fallthrough {
+        /// The sequence that is being fallen out of.
+        seq: InstrSeqId,
+    },
+    /// A `br`, `br_if`, or `br_table` at `index` within `seq` branches to the
+    /// end of the sequence.
+    Branch {
+        /// The sequence containing the branch instruction.
+        seq: InstrSeqId,
+        /// The index of the branch instruction within `seq`.
+        index: usize,
+    },
 }
 
 /// Different kinds of blocks.
@@ -239,7 +364,7 @@ pub(crate) enum BlockKind {
 /// }
 /// ```
 #[walrus_instr]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Instr {
     /// `block ... end`
     #[walrus(skip_builder)]
@@ -330,6 +455,9 @@ pub enum Instr {
     /// `unreachable`
     Unreachable {},
 
+    /// `nop`
+    Nop {},
+
     /// `br`
     Br {
         /// The target block to branch to.
@@ -576,6 +704,9 @@ pub enum Instr {
     },
 
     /// `table.copy`
+    ///
+    /// Note that in the binary encoding the destination table's index comes
+    /// before the source table's.
     TableCopy {
         /// The source table
         src: TableId,
@@ -602,6 +733,62 @@ pub enum Value {
     V128(u128),
 }
 
+macro_rules! define_trunc_sat {
+    ($( $name:ident: $from:ident($x:ident: $float:literal) -> $to:ident($convert:expr), $op:literal; )*) => {
+        $(
+            #[doc = concat!("Convert this value as `", $op, "` does, or return `None` if")]
+            #[doc = concat!("it isn't an `", $float, "`.")]
+            ///
+            /// NaN becomes zero, and values outside of the integer's range
+            /// saturate to its minimum or maximum.
+            pub fn $name(&self) -> Option<Value> {
+                match *self {
+                    Value::$from($x) => Some(Value::$to($convert)),
+                    _ => None,
+                }
+            }
+        )*
+    };
+}
+
+impl Value {
+    // Float to integer `as` casts already saturate, and turn NaN into zero.
+    define_trunc_sat! {
+        f32_to_i32_sat: F32(x: "f32") -> I32(x as i32), "i32.trunc_sat_f32_s";
+        f32_to_u32_sat: F32(x: "f32") -> I32(x as u32 as i32), "i32.trunc_sat_f32_u";
+        f64_to_i32_sat: F64(x: "f64") -> I32(x as i32), "i32.trunc_sat_f64_s";
+        f64_to_u32_sat: F64(x: "f64") -> I32(x as u32 as i32), "i32.trunc_sat_f64_u";
+        f32_to_i64_sat: F32(x: "f32") -> I64(x as i64), "i64.trunc_sat_f32_s";
+        f32_to_u64_sat: F32(x: "f32") -> I64(x as u64 as i64), "i64.trunc_sat_f32_u";
+        f64_to_i64_sat: F64(x: "f64") -> I64(x as i64), "i64.trunc_sat_f64_s";
+        f64_to_u64_sat: F64(x: "f64") -> I64(x as u64 as i64), "i64.trunc_sat_f64_u";
+    }
+
+    /// Convert this value as `i32.wrap_i64` does, keeping its low 32 bits, or
+    /// return `None` if it isn't an `i64`.
+    pub fn i64_to_i32_wrap(&self) -> Option<Value> {
+        match *self {
+            Value::I64(x) => Some(Value::I32(x as i32)),
+            _ => None,
+        }
+    }
+}
+
+/// Values are equal when they are the same constant: floats are compared by
+/// their bit patterns, so `0.0` and `-0.0` differ and a NaN equals itself.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::I32(a), Value::I32(b)) => a == b,
+            (Value::I64(a), Value::I64(b)) => a == b,
+            (Value::F32(a), Value::F32(b)) => a.to_bits() == b.to_bits(),
+            (Value::F64(a), Value::F64(b)) => a.to_bits() == b.to_bits(),
+            (Value::V128(a), Value::V128(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -609,14 +796,14 @@ impl fmt::Display for Value {
             Value::I64(i) => i.fmt(f),
             Value::F32(i) => i.fmt(f),
             Value::F64(i) => i.fmt(f),
-            Value::V128(i) => i.fmt(f),
+            Value::V128(i) => write!(f, "0x{:032x}", i),
         }
     }
 }
 
 /// Possible binary operations in wasm
 #[allow(missing_docs)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum BinaryOp {
     I32Eq,
     I32Ne,
@@ -854,9 +1041,44 @@ pub enum BinaryOp {
     I64x2ExtMulHighI32x4U,
 }
 
+impl BinaryOp {
+    /// Does swapping this operator's operands leave its result unchanged?
+    ///
+    /// Floating point addition and multiplication are left out, since the NaN
+    /// they produce may depend on the order of their operands.
+    pub fn is_commutative(&self) -> bool {
+        use self::BinaryOp::*;
+        match self {
+            I32Eq | I32Ne | I32Add | I32Mul | I32And | I32Or | I32Xor => true,
+            I64Eq | I64Ne | I64Add | I64Mul | I64And | I64Or | I64Xor => true,
+            F32Eq | F32Ne | F64Eq | F64Ne => true,
+            _ => false,
+        }
+    }
+
+    /// The type of this operator's result.
+    pub fn result_ty(&self) -> ValType {
+        use self::BinaryOp::*;
+        match self {
+            I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS
+            | I32GeU | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU
+            | I64GeS | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne
+            | F64Lt | F64Gt | F64Le | F64Ge => ValType::I32,
+            I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or
+            | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr => ValType::I32,
+            I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or
+            | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => ValType::I64,
+            F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => ValType::F32,
+            F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => ValType::F64,
+            // Every other binary operator is a SIMD operator.
+            _ => ValType::V128,
+        }
+    }
+}
+
 /// Possible unary operations in wasm
 #[allow(missing_docs)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum UnaryOp {
     I32Eqz,
     I32Clz,
@@ -1008,8 +1230,50 @@ pub enum UnaryOp {
     I32x4WidenHighI16x8U,
 }
 
+impl UnaryOp {
+    /// The type of this operator's result.
+    pub fn result_ty(&self) -> ValType {
+        use self::UnaryOp::*;
+        match self {
+            I32Eqz | I32Clz | I32Ctz | I32Popcnt | I64Eqz => ValType::I32,
+            I64Clz | I64Ctz | I64Popcnt => ValType::I64,
+            F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt => ValType::F32,
+            F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => ValType::F64,
+            I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64
+            | I32ReinterpretF32 | I32Extend8S | I32Extend16S | I32TruncSSatF32
+            | I32TruncUSatF32 | I32TruncSSatF64 | I32TruncUSatF64 => ValType::I32,
+            I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64
+            | I64TruncUF64 | I64ReinterpretF64 | I64Extend8S | I64Extend16S | I64Extend32S
+            | I64TruncSSatF32 | I64TruncUSatF32 | I64TruncSSatF64 | I64TruncUSatF64 => ValType::I64,
+            F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64
+            | F32ReinterpretI32 => ValType::F32,
+            F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32
+            | F64ReinterpretI64 => ValType::F64,
+            I8x16ExtractLaneS { .. }
+            | I8x16ExtractLaneU { .. }
+            | I16x8ExtractLaneS { .. }
+            | I16x8ExtractLaneU { .. }
+            | I32x4ExtractLane { .. }
+            | V128AnyTrue
+            | I8x16AllTrue
+            | I8x16Bitmask
+            | I16x8AllTrue
+            | I16x8Bitmask
+            | I32x4AllTrue
+            | I32x4Bitmask
+            | I64x2AllTrue
+            | I64x2Bitmask => ValType::I32,
+            I64x2ExtractLane { .. } => ValType::I64,
+            F32x4ExtractLane { .. } => ValType::F32,
+            F64x2ExtractLane { .. } => ValType::F64,
+            // Every other unary operator produces a vector.
+            _ => ValType::V128,
+        }
+    }
+}
+
 /// The different kinds of load instructions that are part of a `Load` IR node
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(missing_docs)]
 pub enum LoadKind {
     // TODO: much of this is probably redundant with type information already
@@ -1029,7 +1293,7 @@ pub enum LoadKind {
 }
 
 /// The different kinds of load instructions that are part of a `LoadSimd` IR node
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(missing_docs)]
 pub enum LoadSimdKind {
     Splat8,
@@ -1056,8 +1320,23 @@ pub enum LoadSimdKind {
     V128Store64Lane(u8),
 }
 
+impl LoadSimdKind {
+    /// Returns the number of bytes loaded or stored
+    pub fn width(&self) -> u32 {
+        use self::LoadSimdKind::*;
+        match self {
+            Splat8 | V128Load8Lane(_) | V128Store8Lane(_) => 1,
+            Splat16 | V128Load16Lane(_) | V128Store16Lane(_) => 2,
+            Splat32 | V128Load32Zero | V128Load32Lane(_) | V128Store32Lane(_) => 4,
+            Splat64 | V128Load8x8S | V128Load8x8U | V128Load16x4S | V128Load16x4U
+            | V128Load32x2S | V128Load32x2U | V128Load64Zero | V128Load64Lane(_)
+            | V128Store64Lane(_) => 8,
+        }
+    }
+}
+
 /// The kinds of extended loads which can happen
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(missing_docs)]
 pub enum ExtendedLoad {
     SignExtend,
@@ -1104,7 +1383,7 @@ impl ExtendedLoad {
 }
 
 /// The different kinds of store instructions that are part of a `Store` IR node
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(missing_docs)]
 pub enum StoreKind {
     I32 { atomic: bool },
@@ -1151,7 +1430,7 @@ impl StoreKind {
 
 /// Arguments to memory operations, containing a constant offset from a dynamic
 /// address as well as a predicted alignment.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct MemArg {
     /// The alignment of the memory operation, must be a power of two
     pub align: u32,
@@ -1159,8 +1438,26 @@ pub struct MemArg {
     pub offset: u32,
 }
 
+impl MemArg {
+    /// Get the `[lo, hi)` range of bytes accessed by a memory operation of
+    /// `access_size` bytes using these arguments.
+    ///
+    /// Returns `None` if the dynamic address isn't statically known. The
+    /// effective address is computed without wrapping, so the range may end
+    /// beyond `2^32`; such an access always traps at runtime.
+    pub fn effective_range(
+        &self,
+        address_value: Option<u32>,
+        access_size: u32,
+    ) -> Option<(u64, u64)> {
+        let lo = u64::from(address_value?) + u64::from(self.offset);
+        let hi = lo + u64::from(access_size);
+        Some((lo, hi))
+    }
+}
+
 /// The different kinds of atomic rmw operations
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(missing_docs)]
 pub enum AtomicOp {
     Add,
@@ -1172,7 +1469,7 @@ pub enum AtomicOp {
 }
 
 /// The different kinds of atomic rmw operations
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(missing_docs)]
 pub enum AtomicWidth {
     I32,
@@ -1253,6 +1550,7 @@ impl Instr {
             | Instr::TableInit(..)
             | Instr::TableCopy(..)
             | Instr::ElemDrop(..)
+            | Instr::Nop(..)
             | Instr::Drop(..) => false,
         }
     }
@@ -1295,3 +1593,103 @@ impl VisitMut for InstrSeq {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trunc_sat() {
+        assert_eq!(Value::F32(f32::NAN).f32_to_i32_sat(), Some(Value::I32(0)));
+        assert_eq!(
+            Value::F32(1e30).f32_to_i32_sat(),
+            Some(Value::I32(i32::MAX))
+        );
+        assert_eq!(
+            Value::F32(-1e30).f32_to_i32_sat(),
+            Some(Value::I32(i32::MIN))
+        );
+        assert_eq!(Value::F32(-7.9).f32_to_i32_sat(), Some(Value::I32(-7)));
+        assert_eq!(Value::F64(-1.0).f64_to_u32_sat(), Some(Value::I32(0)));
+        assert_eq!(Value::F64(1e30).f64_to_u32_sat(), Some(Value::I32(-1)));
+        assert_eq!(
+            Value::F64(f64::INFINITY).f64_to_i64_sat(),
+            Some(Value::I64(i64::MAX))
+        );
+        assert_eq!(Value::F32(f32::NAN).f32_to_u64_sat(), Some(Value::I64(0)));
+        assert_eq!(Value::I32(1).f32_to_i32_sat(), None);
+    }
+
+    #[test]
+    fn wrap() {
+        assert_eq!(
+            Value::I64(0x1_2345_6789).i64_to_i32_wrap(),
+            Some(Value::I32(0x2345_6789))
+        );
+        assert_eq!(Value::I64(-1).i64_to_i32_wrap(), Some(Value::I32(-1)));
+        assert_eq!(Value::I32(1).i64_to_i32_wrap(), None);
+    }
+
+    #[test]
+    fn access_widths() {
+        let sign = ExtendedLoad::SignExtend;
+        assert_eq!(LoadKind::I32_8 { kind: sign }.width(), 1);
+        assert_eq!(LoadKind::I64_16 { kind: sign }.width(), 2);
+        assert_eq!(LoadKind::I64_32 { kind: sign }.width(), 4);
+        assert_eq!(LoadKind::F64.width(), 8);
+        assert_eq!(LoadKind::V128.width(), 16);
+        assert_eq!(StoreKind::I32_8 { atomic: false }.width(), 1);
+        assert_eq!(StoreKind::I32_16 { atomic: true }.width(), 2);
+        assert_eq!(StoreKind::F32.width(), 4);
+        assert_eq!(StoreKind::I64 { atomic: false }.width(), 8);
+        assert_eq!(StoreKind::V128.width(), 16);
+    }
+
+    #[test]
+    fn preamble_changes_are_noticed() {
+        let mut seq = InstrSeq::new(id_arena::Arena::new().next_id(), InstrSeqType::Simple(None));
+        seq.instrs.push((Nop {}.into(), InstrLocId::default()));
+        seq.push_preamble(Const {
+            value: Value::I32(1),
+        });
+        seq.push_preamble(Drop {});
+        assert_eq!(seq.preamble_len(), 2);
+        assert!(seq.preamble_intact());
+
+        // Adding to the end of the sequence is fine.
+        seq.instrs.push((Nop {}.into(), InstrLocId::default()));
+        assert!(seq.preamble_intact());
+
+        // Putting something in front of the preamble isn't, unless the
+        // preamble is updated to include it.
+        seq.instrs.insert(0, (Nop {}.into(), InstrLocId::default()));
+        assert_eq!(seq.preamble_intact(), !cfg!(debug_assertions));
+        seq.set_preamble_len(3);
+        assert!(seq.preamble_intact());
+
+        seq.instrs.truncate(2);
+        assert!(!seq.preamble_intact());
+    }
+
+    #[test]
+    fn effective_range() {
+        let arg = MemArg {
+            align: 4,
+            offset: 8,
+        };
+        assert_eq!(arg.effective_range(None, 4), None);
+        assert_eq!(arg.effective_range(Some(16), 4), Some((24, 28)));
+
+        // An access ending exactly at the end of the 32-bit address space.
+        let end = 1 << 32;
+        assert_eq!(
+            arg.effective_range(Some(u32::MAX - 11), 4),
+            Some((end - 4, end))
+        );
+        // The effective address itself doesn't wrap around.
+        assert_eq!(
+            arg.effective_range(Some(u32::MAX), 4),
+            Some((end + 7, end + 11))
+        );
+    }
+}
@@ -20,6 +20,7 @@ macro_rules! maybe_parallel {
 mod arena_set;
 pub mod dot;
 mod emit;
+pub mod encoding;
 mod error;
 mod function_builder;
 mod init_expr;
@@ -28,6 +29,7 @@ mod map;
 mod module;
 mod parse;
 pub mod passes;
+pub mod testing;
 mod tombstone_arena;
 mod ty;
 
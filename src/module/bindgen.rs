@@ -0,0 +1,24 @@
+//! Recognizing modules produced by `wasm-bindgen`.
+//!
+//! `wasm-bindgen` describes the interface between JS and Rust in a custom
+//! section that its CLI reads back when generating bindings. Like every custom
+//! section walrus doesn't understand, it is kept as an opaque
+//! `RawCustomSection`, and emitted with exactly the bytes it was parsed with.
+
+use crate::Module;
+
+/// The name of the custom section that `wasm-bindgen` embeds in the modules
+/// it produces.
+pub const WASM_BINDGEN_SECTION: &str = "__wasm_bindgen_unstable";
+
+/// Was this module produced by `wasm-bindgen`, and not yet processed by its
+/// CLI?
+///
+/// This is the case if the module has a `__wasm_bindgen_unstable` custom
+/// section.
+pub fn is_wasm_bindgen_module(module: &Module) -> bool {
+    module
+        .customs
+        .iter()
+        .any(|(_, section)| section.name() == WASM_BINDGEN_SECTION)
+}
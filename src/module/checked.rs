@@ -0,0 +1,933 @@
+//! Checking a module for problems before handing its encoding to an engine.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::module::functions::{Epoch, Snapshot};
+use crate::{
+    ActiveDataLocation, DataId, DataKind, ElementId, ElementKind, ExportItem, Function, FunctionId,
+    GlobalId, GlobalKind, InitExpr, LocalFunction, MemoryId, Module, TableId, TypeId, ValType,
+};
+use std::fmt;
+use std::mem;
+use wasmparser::{FunctionBody, Parser, ValidPayload, Validator, WasmFeatures};
+
+/// A single problem found by `Module::emit_wasm_checked`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmitProblem {
+    /// The function the problem is in, or `None` if it is elsewhere in the
+    /// module.
+    pub function: Option<FunctionId>,
+    /// The name of the function the problem is in, if it has one.
+    pub function_name: Option<String>,
+    /// The offending instruction within the function, if the problem can be
+    /// pinned on one.
+    ///
+    /// This is the line that the instruction is printed on by the function's
+    /// `Display` implementation, counting the `func` line as line 0.
+    pub instruction: Option<usize>,
+    /// What is wrong.
+    pub message: String,
+    /// The lines of the function printed around the offending instruction,
+    /// if there is one.
+    ///
+    /// The function is printed with `LocalFunction::to_string_lossy`, so
+    /// this is there however broken the function is.
+    pub snippet: Option<String>,
+}
+
+/// Every problem found by `Module::emit_wasm_checked`.
+#[derive(Clone, Debug)]
+pub struct EmitError {
+    /// The problems, grouped by the function they are in, with problems
+    /// elsewhere in the module first.
+    pub problems: Vec<EmitProblem>,
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "found {} problem(s) while checking the module to emit",
+            self.problems.len()
+        )?;
+        let mut current = None;
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i == 0 || problem.function != current {
+                current = problem.function;
+                match (problem.function, &problem.function_name) {
+                    (None, _) => writeln!(f, "in the module:")?,
+                    (Some(_), Some(name)) => writeln!(f, "in function `{}`:", name)?,
+                    (Some(id), None) => writeln!(f, "in function {:?}:", id)?,
+                }
+            }
+            match problem.instruction {
+                Some(n) => writeln!(f, "  instruction {}: {}", n, problem.message)?,
+                None => writeln!(f, "  {}", problem.message)?,
+            }
+            if let Some(snippet) = &problem.snippet {
+                for line in snippet.lines() {
+                    writeln!(f, "    | {}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EmitError {}
+
+/// Remembers which functions `Module::validate_incremental` found to be valid,
+/// so that later calls only need to validate the functions that changed since.
+///
+/// A cache can safely be used with any module: functions that it has no
+/// record of, or that don't match its record, are always validated.
+#[derive(Debug)]
+pub struct ValidationCache {
+    features: WasmFeatures,
+    /// Everything outside of function bodies that the validity of a body can
+    /// depend on, as of the last validation.
+    module: Option<ModuleFingerprint>,
+    /// The functions that were valid as of the last validation.
+    funcs: IdHashMap<Function, FunctionFingerprint>,
+    /// When the last validation started. Functions that haven't been mutably
+    /// borrowed since don't need to be looked at again.
+    epoch: Option<Epoch>,
+}
+
+impl ValidationCache {
+    /// Create an empty cache, for validating against the given features.
+    pub fn new(features: WasmFeatures) -> ValidationCache {
+        ValidationCache {
+            features,
+            module: None,
+            funcs: Default::default(),
+            epoch: None,
+        }
+    }
+}
+
+/// The signatures of every item a function body can refer to.
+#[derive(Debug, PartialEq)]
+struct ModuleFingerprint {
+    types: Vec<(TypeId, Vec<ValType>, Vec<ValType>)>,
+    funcs: Vec<(FunctionId, TypeId)>,
+    globals: Vec<(GlobalId, ValType, bool)>,
+    tables: Vec<(TableId, ValType)>,
+    memories: Vec<(MemoryId, bool)>,
+    data: Vec<DataId>,
+    elements: Vec<(ElementId, ValType)>,
+    /// Functions that `ref.func` may refer to.
+    declared: Vec<FunctionId>,
+}
+
+impl ModuleFingerprint {
+    fn new(module: &Module) -> ModuleFingerprint {
+        let mut declared = module
+            .elements
+            .iter()
+            .flat_map(|e| e.members.iter().filter_map(|m| *m))
+            .chain(module.exports.iter().filter_map(|e| match e.item {
+                ExportItem::Function(id) => Some(id),
+                _ => None,
+            }))
+            .chain(module.globals.iter().filter_map(|g| match g.kind {
+                GlobalKind::Local(InitExpr::RefFunc(id)) => Some(id),
+                _ => None,
+            }))
+            .collect::<Vec<_>>();
+        declared.sort();
+        declared.dedup();
+
+        ModuleFingerprint {
+            types: module
+                .types
+                .iter()
+                .map(|t| (t.id(), t.params().to_vec(), t.results().to_vec()))
+                .collect(),
+            funcs: module.funcs.iter().map(|f| (f.id(), f.ty())).collect(),
+            globals: module
+                .globals
+                .iter()
+                .map(|g| (g.id(), g.ty, g.mutable))
+                .collect(),
+            tables: module
+                .tables
+                .iter()
+                .map(|t| (t.id(), t.element_ty))
+                .collect(),
+            memories: module.memories.iter().map(|m| (m.id(), m.shared)).collect(),
+            data: module.data.iter().map(|d| d.id()).collect(),
+            elements: module.elements.iter().map(|e| (e.id(), e.ty)).collect(),
+            declared,
+        }
+    }
+}
+
+/// A function's signature and instructions, and the types of its locals.
+#[derive(Debug, PartialEq)]
+struct FunctionFingerprint {
+    snapshot: Snapshot,
+    locals: Vec<(LocalId, ValType)>,
+}
+
+impl FunctionFingerprint {
+    fn new(module: &Module, func: &LocalFunction) -> FunctionFingerprint {
+        let mut locals = func
+            .used_locals()
+            .into_iter()
+            .chain(func.args.iter().cloned())
+            .map(|local| (local, module.locals.get(local).ty()))
+            .collect::<Vec<_>>();
+        locals.sort();
+        locals.dedup();
+        FunctionFingerprint {
+            snapshot: Snapshot::new(func),
+            locals,
+        }
+    }
+
+    /// Do the function's locals still have the types they had?
+    fn locals_unchanged(&self, module: &Module) -> bool {
+        self.locals
+            .iter()
+            .all(|&(local, ty)| module.locals.get(local).ty() == ty)
+    }
+}
+
+impl Module {
+    /// Emit this module into an in-memory wasm buffer, after checking that
+    /// the result is valid for an engine supporting the given features.
+    ///
+    /// Unlike `emit_wasm`, which leaves finding problems to whatever engine
+    /// eventually loads the module, this reports every problem it finds at
+    /// once, grouped by function:
+    ///
+    /// * references to deleted functions, tables, globals, memories, data or
+    ///   element segments,
+    /// * `return`s with the wrong number or types of values,
+    /// * instructions that are given operands of the wrong type, and
+    /// * anything that relies on a feature that isn't enabled.
+    ///
+    /// Functions that refer to deleted items or return the wrong values can't
+    /// be encoded, so their other problems aren't reported. The same goes for
+    /// the whole module if an export, the start function, an element or data
+    /// segment, or a global's initializer refers to a deleted item.
+    ///
+    /// This is the recommended way to emit a module that has been transformed,
+    /// since the resulting errors point at what went wrong far more precisely
+    /// than an engine's would.
+    pub fn emit_wasm_checked(&mut self, features: WasmFeatures) -> Result<Vec<u8>, EmitError> {
+        let (wasm, problems) = self.check(features, |_| true);
+        if problems.is_empty() {
+            Ok(wasm)
+        } else {
+            Err(self.emit_error(problems))
+        }
+    }
+
+    /// Check the module for the same problems as `emit_wasm_checked`, but
+    /// skip the function bodies that `cache` knows were valid when this was
+    /// last called, and haven't changed since.
+    ///
+    /// Function bodies are validated again regardless if anything they could
+    /// depend on outside of function bodies has changed, such as the
+    /// signature of a function, global, or table, or the set of functions
+    /// that `ref.func` may refer to. The rest of the module is always checked
+    /// in full, since doing so is cheap compared to validating code.
+    ///
+    /// Only the functions that were mutably borrowed since the last call, with
+    /// `ModuleFunctions::get_mut` or one of its iterators, are looked at; the
+    /// bodies of the rest are left out of the encoding that gets checked. So
+    /// the cost depends on how much of the module changed, rather than on its
+    /// size.
+    ///
+    /// This is meant for checking the module after each pass of a long
+    /// pipeline, where most passes only touch a few functions.
+    pub fn validate_incremental(&mut self, cache: &mut ValidationCache) -> Result<(), EmitError> {
+        let module = ModuleFingerprint::new(self);
+        if cache.module.take().as_ref() != Some(&module) {
+            cache.funcs.clear();
+        }
+        cache.funcs.retain(|id, _| self.funcs.contains(*id));
+        let since = cache.epoch.take();
+        let epoch = self.funcs.next_epoch();
+
+        let mut changed = IdHashMap::default();
+        for (id, func) in self.funcs.iter_local() {
+            // Only functions that have been mutably borrowed since the last
+            // validation can have changed, but even those usually haven't.
+            let untouched = since.map_or(false, |since| !self.funcs.modified_since(id, since));
+            if let Some(cached) = cache.funcs.get(&id) {
+                if untouched && cached.locals_unchanged(self) {
+                    continue;
+                }
+            }
+            let fingerprint = FunctionFingerprint::new(self, func);
+            if cache.funcs.get(&id) != Some(&fingerprint) {
+                changed.insert(id, fingerprint);
+            }
+        }
+
+        let (_, problems) = self.check(cache.features, |id| changed.contains_key(&id));
+        if problems.iter().any(|p| p.function.is_none()) {
+            // Function bodies may not have been validated at all.
+            cache.funcs.clear();
+        } else {
+            for problem in problems.iter() {
+                let id = problem.function.unwrap();
+                changed.remove(&id);
+                cache.funcs.remove(&id);
+            }
+            cache.funcs.extend(changed);
+            cache.module = Some(module);
+        }
+        cache.epoch = Some(epoch);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(self.emit_error(problems))
+        }
+    }
+
+    /// Emit the module and check it for problems, only looking into the
+    /// bodies of the functions for which `needs_check` returns `true`.
+    fn check(
+        &mut self,
+        features: WasmFeatures,
+        needs_check: impl Fn(FunctionId) -> bool,
+    ) -> (Vec<u8>, Vec<EmitProblem>) {
+        let mut problems = self.dangling_references(&needs_check);
+        if problems.iter().any(|p| p.function.is_none()) {
+            return (Vec::new(), problems);
+        }
+        problems.extend(self.return_type_mismatches(&problems, &needs_check));
+
+        // Stub out functions that can't be encoded while the rest of the
+        // module is, so that their problems don't hide everyone else's. The
+        // bodies that don't need checking are stubbed out too, since encoding
+        // them would only be wasted work; pinned ones are left alone, since
+        // they are emitted as they are anyway.
+        let mut stub = problems
+            .iter()
+            .filter_map(|p| p.function)
+            .collect::<Vec<_>>();
+        stub.dedup();
+        stub.extend(
+            self.funcs
+                .iter_local()
+                .map(|(id, _)| id)
+                .filter(|&id| !needs_check(id) && self.funcs.pinned(id).is_none()),
+        );
+        let mut stubbed = Vec::new();
+        for id in stub {
+            let func = self.funcs.get_mut_untracked(id).kind.unwrap_local_mut();
+            let entry = func.entry_block();
+            let stub = vec![(Unreachable {}.into(), Default::default())];
+            let entry = func.block_mut(entry);
+            let preamble = entry.preamble_len();
+            entry.set_preamble_len(0);
+            stubbed.push((id, mem::replace(&mut entry.instrs, stub), preamble));
+        }
+        let emitted = self.emit_wasm_with_code_transform();
+        for (id, instrs, preamble) in stubbed {
+            let func = self.funcs.get_mut_untracked(id).kind.unwrap_local_mut();
+            let entry = func.entry_block();
+            func.block_mut(entry).instrs = instrs;
+            func.block_mut(entry).set_preamble_len(preamble);
+        }
+        let (wasm, code_transform) = match emitted {
+            Ok(emitted) => emitted,
+            Err(e) => {
+                problems.push(EmitProblem {
+                    function: None,
+                    function_name: None,
+                    instruction: None,
+                    message: e.to_string(),
+                    snippet: None,
+                });
+                return (Vec::new(), problems);
+            }
+        };
+
+        let mut funcs = code_transform.function_ranges;
+        funcs.sort_by_key(|(_, range)| range.start);
+        let mut funcs = funcs.into_iter().map(|(id, _)| id);
+
+        let mut validator = Validator::new();
+        validator.wasm_features(features);
+        for payload in Parser::new(0).parse_all(&wasm) {
+            let result = payload.and_then(|payload| validator.payload(&payload));
+            match result {
+                Ok(ValidPayload::Func(mut func_validator, body)) => {
+                    let id = funcs.next().expect("every code entry was emitted");
+                    if !needs_check(id) {
+                        continue;
+                    }
+                    if let Err(e) = func_validator.validate(&body) {
+                        problems.push(EmitProblem {
+                            function: Some(id),
+                            function_name: None,
+                            instruction: operator_at(&body, e.offset()),
+                            message: e.message().to_string(),
+                            snippet: None,
+                        });
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // The validator can't carry on after a problem outside of
+                    // a function body.
+                    problems.push(EmitProblem {
+                        function: None,
+                        function_name: None,
+                        instruction: None,
+                        message: e.message().to_string(),
+                        snippet: None,
+                    });
+                    break;
+                }
+            }
+        }
+
+        (wasm, problems)
+    }
+
+    /// Find every reference to an item that has been deleted, in the module
+    /// and in the functions for which `needs_check` returns `true`.
+    fn dangling_references(&self, needs_check: impl Fn(FunctionId) -> bool) -> Vec<EmitProblem> {
+        let mut problems = Vec::new();
+
+        for export in self.exports.iter() {
+            let dangling = match export.item {
+                ExportItem::Function(id) => !self.funcs.contains(id),
+                ExportItem::Table(id) => !self.tables.contains(id),
+                ExportItem::Memory(id) => !self.memories.contains(id),
+                ExportItem::Global(id) => !self.globals.contains(id),
+            };
+            if dangling {
+                problems.push(EmitProblem {
+                    function: None,
+                    function_name: None,
+                    instruction: None,
+                    message: format!("export `{}` refers to a deleted item", export.name),
+                    snippet: None,
+                });
+            }
+        }
+        if let Some(start) = self.start {
+            if !self.funcs.contains(start) {
+                problems.push(EmitProblem {
+                    function: None,
+                    function_name: None,
+                    instruction: None,
+                    message: "the start function has been deleted".to_string(),
+                    snippet: None,
+                });
+            }
+        }
+
+        let mut item_refers_to = |item: String, deleted: Option<&str>| {
+            if let Some(deleted) = deleted {
+                problems.push(EmitProblem {
+                    function: None,
+                    function_name: None,
+                    instruction: None,
+                    message: format!("{} refers to a deleted {}", item, deleted),
+                    snippet: None,
+                });
+            }
+        };
+        for elem in self.elements.iter() {
+            let item = || format!("element segment {:?}", elem.id());
+            if elem
+                .members
+                .iter()
+                .flatten()
+                .any(|&f| !self.funcs.contains(f))
+            {
+                item_refers_to(item(), Some("function"));
+            }
+            if let ElementKind::Active { table, offset } = &elem.kind {
+                if !self.tables.contains(*table) {
+                    item_refers_to(item(), Some("table"));
+                }
+                item_refers_to(item(), self.deleted_in_init_expr(offset));
+            }
+        }
+        for data in self.data.iter() {
+            if let DataKind::Active(active) = &data.kind {
+                let item = || format!("data segment {:?}", data.id());
+                if !self.memories.contains(active.memory) {
+                    item_refers_to(item(), Some("memory"));
+                }
+                if let ActiveDataLocation::Relative(global) = active.location {
+                    if !self.globals.contains(global) {
+                        item_refers_to(item(), Some("global"));
+                    }
+                }
+            }
+        }
+        for global in self.globals.iter() {
+            if let GlobalKind::Local(init) = &global.kind {
+                let item = format!("the initializer of global {:?}", global.id());
+                item_refers_to(item, self.deleted_in_init_expr(init));
+            }
+        }
+
+        for (id, func) in self.funcs.iter_local() {
+            if !needs_check(id) {
+                continue;
+            }
+            for (n, instr) in func.numbered_instrs() {
+                let mut v = Dangling {
+                    module: self,
+                    deleted: None,
+                };
+                instr.visit(&mut v);
+                if let Some(deleted) = v.deleted {
+                    problems.push(EmitProblem {
+                        function: Some(id),
+                        function_name: None,
+                        instruction: Some(n),
+                        message: format!("refers to a deleted {}", deleted),
+                        snippet: None,
+                    });
+                }
+            }
+        }
+
+        return problems;
+
+        struct Dangling<'a> {
+            module: &'a Module,
+            deleted: Option<&'static str>,
+        }
+
+        impl<'instr> Visitor<'instr> for Dangling<'_> {
+            fn visit_function_id(&mut self, &id: &FunctionId) {
+                if !self.module.funcs.contains(id) {
+                    self.deleted = Some("function");
+                }
+            }
+
+            fn visit_table_id(&mut self, &id: &crate::TableId) {
+                if !self.module.tables.contains(id) {
+                    self.deleted = Some("table");
+                }
+            }
+
+            fn visit_global_id(&mut self, &id: &crate::GlobalId) {
+                if !self.module.globals.contains(id) {
+                    self.deleted = Some("global");
+                }
+            }
+
+            fn visit_memory_id(&mut self, &id: &crate::MemoryId) {
+                if !self.module.memories.contains(id) {
+                    self.deleted = Some("memory");
+                }
+            }
+
+            fn visit_data_id(&mut self, &id: &crate::DataId) {
+                if !self.module.data.contains(id) {
+                    self.deleted = Some("data segment");
+                }
+            }
+
+            fn visit_element_id(&mut self, &id: &crate::ElementId) {
+                if !self.module.elements.contains(id) {
+                    self.deleted = Some("element segment");
+                }
+            }
+        }
+    }
+
+    /// The kind of deleted item the given constant expression refers to, if
+    /// it refers to one.
+    fn deleted_in_init_expr(&self, init: &InitExpr) -> Option<&'static str> {
+        match *init {
+            InitExpr::Global(id) if !self.globals.contains(id) => Some("global"),
+            InitExpr::RefFunc(id) if !self.funcs.contains(id) => Some("function"),
+            _ => None,
+        }
+    }
+
+    /// Find every `return` with the wrong values, and every call with the
+    /// wrong arguments, in functions for which `needs_check` returns `true`,
+    /// and that don't already have problems.
+    fn return_type_mismatches(
+        &self,
+        problems: &[EmitProblem],
+        needs_check: impl Fn(FunctionId) -> bool,
+    ) -> Vec<EmitProblem> {
+        let mut mismatches = Vec::new();
+        for (id, func) in self.funcs.iter_local() {
+            if !needs_check(id) || problems.iter().any(|p| p.function == Some(id)) {
+                continue;
+            }
+            let returns = func
+                .return_type_mismatches(self)
+                .into_iter()
+                .map(|m| (m.seq, m.index, m.to_string()));
+            let calls = func
+                .call_argument_mismatches(self)
+                .into_iter()
+                .map(|m| (m.seq, m.index, m.to_string()));
+            for (seq, index, message) in returns.chain(calls) {
+                let instr = &func.block(seq).instrs[index].0;
+                let instruction = func
+                    .numbered_instrs()
+                    .into_iter()
+                    .find(|(_, i)| std::ptr::eq(*i, instr))
+                    .map(|(n, _)| n);
+                mismatches.push(EmitProblem {
+                    function: Some(id),
+                    function_name: None,
+                    instruction,
+                    message,
+                    snippet: None,
+                });
+            }
+        }
+        mismatches
+    }
+
+    /// Name the functions the given problems are in, and group the problems
+    /// by function.
+    fn emit_error(&self, mut problems: Vec<EmitProblem>) -> EmitError {
+        for problem in problems.iter_mut() {
+            if let Some(id) = problem.function {
+                problem.function_name = self.funcs.get(id).name.clone();
+                if let Some(n) = problem.instruction {
+                    problem.snippet = Some(snippet(&self.funcs.dump_lossy(id), n));
+                }
+            }
+        }
+        // Stable, so each function's problems stay in order.
+        problems.sort_by_key(|p| (p.function.is_some(), p.function));
+        EmitError { problems }
+    }
+}
+
+/// The lines of a printed function from two before line `n` to two after it,
+/// with line `n` marked.
+fn snippet(dump: &str, n: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in dump.lines().enumerate() {
+        if i + 2 >= n && i <= n + 2 {
+            out.push_str(if i == n { "> " } else { "  " });
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Find the position, counting from 1, of the operator at the given offset in
+/// the function's body.
+///
+/// Problems found once all the operators have been read, such as values left
+/// over on the stack, are pinned on the final `end`.
+fn operator_at(body: &FunctionBody, offset: usize) -> Option<usize> {
+    let mut reader = body.get_operators_reader().ok()?;
+    let mut n = 0;
+    while !reader.eof() {
+        let (_, position) = reader.read_with_offset().ok()?;
+        if position > offset {
+            return None;
+        }
+        n += 1;
+        if position == offset {
+            return Some(n);
+        }
+    }
+    Some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let mut module = Module::default();
+
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let deleted = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .name("dangling".to_string())
+            .func_body()
+            .call(deleted);
+        builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .name("bad_select".to_string())
+            .func_body()
+            .i32_const(1)
+            .f32_const(2.0)
+            .i32_const(0)
+            .select(None);
+        builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .name("simd".to_string())
+            .func_body()
+            .v128_const(0)
+            .drop();
+        builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .name("bad_return".to_string())
+            .func_body()
+            .i64_const(1)
+            .return_();
+        builder.finish(vec![], &mut module.funcs);
+
+        module.funcs.delete(deleted);
+
+        let features = WasmFeatures {
+            simd: false,
+            ..WasmFeatures::default()
+        };
+        let err = module.emit_wasm_checked(features).unwrap_err();
+        let problems = err
+            .problems
+            .iter()
+            .map(|p| (p.function_name.as_deref(), p.instruction))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                (Some("dangling"), Some(1)),
+                (Some("bad_select"), Some(4)),
+                (Some("simd"), Some(1)),
+                (Some("bad_return"), Some(2)),
+            ]
+        );
+        assert!(err.problems[2].message.contains("SIMD"));
+
+        let message = err.to_string();
+        assert!(message.contains("in function `dangling`:"));
+        assert!(message.contains("instruction 1: refers to a deleted function"));
+        assert!(message.contains("in function `bad_select`:"));
+
+        // Once the problems are fixed, the module emits.
+        for id in module.funcs.iter().map(|f| f.id()).collect::<Vec<_>>() {
+            module.funcs.delete(id);
+        }
+        let features = WasmFeatures::default();
+        assert!(module.emit_wasm_checked(features).is_ok());
+    }
+
+    #[test]
+    fn reports_calls_with_too_few_arguments() {
+        let mut module = Module::default();
+        let callee = FunctionBuilder::new(&mut module.types, &[ValType::I32, ValType::I32], &[])
+            .finish(vec![], &mut module.funcs);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .name("bad_call".to_string())
+            .func_body()
+            .i32_const(1)
+            .call(callee);
+        builder.finish(vec![], &mut module.funcs);
+
+        let err = module
+            .emit_wasm_checked(WasmFeatures::default())
+            .unwrap_err();
+        assert_eq!(err.problems.len(), 1);
+        assert_eq!(err.problems[0].function_name.as_deref(), Some("bad_call"));
+        assert_eq!(err.problems[0].instruction, Some(2));
+        assert!(err.problems[0].message.contains("arity mismatch"));
+        let snippet = err.problems[0].snippet.as_deref().unwrap();
+        assert!(snippet.contains(">   call"));
+        assert!(err.to_string().contains("    | >   call"));
+    }
+
+    #[test]
+    fn validate_incremental() {
+        let mut module = Module::default();
+        let init = crate::InitExpr::Value(Value::I32(0));
+        let global = module.globals.add_local(ValType::I32, false, init);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.name("a".to_string()).func_body().global_get(global);
+        let a = builder.finish(vec![], &mut module.funcs);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.name("b".to_string()).func_body().call(a);
+        let b = builder.finish(vec![], &mut module.funcs);
+
+        let mut cache = ValidationCache::new(WasmFeatures::default());
+        module.validate_incremental(&mut cache).unwrap();
+        assert_eq!(cache.funcs.len(), 2);
+
+        // Break `b`: its new problem is caught, and it is forgotten.
+        let func = module.funcs.get_mut(b).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.block_mut(entry).instrs = vec![(
+            Const {
+                value: Value::F32(1.0),
+            }
+            .into(),
+            Default::default(),
+        )];
+        let err = module.validate_incremental(&mut cache).unwrap_err();
+        let problems = err
+            .problems
+            .iter()
+            .map(|p| (p.function_name.as_deref(), p.instruction))
+            .collect::<Vec<_>>();
+        assert_eq!(problems, [(Some("b"), Some(2))]);
+        assert!(cache.funcs.contains_key(&a));
+        assert!(!cache.funcs.contains_key(&b));
+
+        // It stays broken until it is fixed.
+        assert!(module.validate_incremental(&mut cache).is_err());
+        let func = module.funcs.get_mut(b).kind.unwrap_local_mut();
+        func.block_mut(entry).instrs = vec![(Call { func: a }.into(), Default::default())];
+        module.validate_incremental(&mut cache).unwrap();
+        assert_eq!(cache.funcs.len(), 2);
+
+        // Changing what a body depends on revalidates unchanged bodies.
+        let global = module.globals.get_mut(global);
+        global.ty = ValType::I64;
+        global.kind = crate::GlobalKind::Local(crate::InitExpr::Value(Value::I64(0)));
+        let err = module.validate_incremental(&mut cache).unwrap_err();
+        let names = err
+            .problems
+            .iter()
+            .map(|p| p.function_name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [Some("a")]);
+        assert_eq!(cache.funcs.len(), 1);
+    }
+
+    #[test]
+    fn validate_incremental_only_looks_at_borrowed_functions() {
+        let mut module = Module::default();
+        let mut funcs = Vec::new();
+        for _ in 0..2 {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            builder.func_body().nop();
+            funcs.push(builder.finish(vec![], &mut module.funcs));
+        }
+        let mut cache = ValidationCache::new(WasmFeatures::default());
+        module.validate_incremental(&mut cache).unwrap();
+
+        // Break the first function behind the cache's back, then borrow only
+        // the second: the first isn't looked at again.
+        let func = module.funcs.get_mut_untracked(funcs[0]);
+        let func = func.kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.builder_mut().instr_seq(entry).i32_const(0);
+        module.funcs.get_mut(funcs[1]);
+        module.validate_incremental(&mut cache).unwrap();
+
+        // Once it is borrowed, it is.
+        module.funcs.get_mut(funcs[0]);
+        assert!(module.validate_incremental(&mut cache).is_err());
+    }
+
+    fn module_problems(module: &mut Module) -> Vec<String> {
+        let err = module
+            .emit_wasm_checked(WasmFeatures::default())
+            .unwrap_err();
+        err.problems
+            .into_iter()
+            .inspect(|p| assert_eq!(p.function, None))
+            .map(|p| p.message)
+            .collect()
+    }
+
+    #[test]
+    fn reports_dangling_references_in_element_segments() {
+        let mut module = Module::default();
+        let table = module.tables.add_local(1, None, ValType::Funcref);
+        let global = module
+            .globals
+            .add_local(ValType::I32, false, InitExpr::i32_const(0));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let f = builder.finish(vec![], &mut module.funcs);
+        let offset = InitExpr::Global(global);
+        let kind = ElementKind::Active { table, offset };
+        let elem = module.elements.add(kind, ValType::Funcref, vec![Some(f)]);
+        module.funcs.delete(f);
+        module.tables.delete(table);
+        module.globals.delete(global);
+
+        let item = format!("element segment {:?}", elem);
+        assert_eq!(
+            module_problems(&mut module),
+            [
+                format!("{} refers to a deleted function", item),
+                format!("{} refers to a deleted table", item),
+                format!("{} refers to a deleted global", item),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_dangling_references_in_data_segments() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let global = module
+            .globals
+            .add_local(ValType::I32, false, InitExpr::i32_const(0));
+        let location = ActiveDataLocation::Relative(global);
+        let data = module.data.add(
+            DataKind::Active(crate::ActiveData { memory, location }),
+            vec![1],
+        );
+        module.memories.delete(memory);
+        module.globals.delete(global);
+
+        let item = format!("data segment {:?}", data);
+        assert_eq!(
+            module_problems(&mut module),
+            [
+                format!("{} refers to a deleted memory", item),
+                format!("{} refers to a deleted global", item),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_dangling_references_in_global_initializers() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let f = builder.finish(vec![], &mut module.funcs);
+        let other = module
+            .globals
+            .add_local(ValType::I32, false, InitExpr::i32_const(0));
+        let a = module
+            .globals
+            .add_local(ValType::I32, false, InitExpr::Global(other));
+        let b = module
+            .globals
+            .add_local(ValType::Funcref, false, InitExpr::RefFunc(f));
+        module.globals.delete(other);
+        module.funcs.delete(f);
+
+        assert_eq!(
+            module_problems(&mut module),
+            [
+                format!(
+                    "the initializer of global {:?} refers to a deleted global",
+                    a
+                ),
+                format!(
+                    "the initializer of global {:?} refers to a deleted function",
+                    b
+                ),
+            ]
+        );
+    }
+}
@@ -4,6 +4,7 @@ use crate::module::Module;
 use crate::parse::IndicesToIds;
 use std::fmt;
 use std::path::Path;
+use wasmparser::WasmFeatures;
 
 /// Configuration for a `Module` which currently affects parsing.
 #[derive(Default)]
@@ -15,11 +16,36 @@ pub struct ModuleConfig {
     pub(crate) skip_producers_section: bool,
     pub(crate) skip_name_section: bool,
     pub(crate) preserve_code_transform: bool,
+    pub(crate) preserve_original_function_bodies: bool,
+    pub(crate) disable_mutable_globals: bool,
+    pub(crate) preserve_type_order: bool,
+    pub(crate) flatten_trivial_blocks: bool,
+    pub(crate) preserve_unreachable_code: bool,
+    pub(crate) duplicate_imports: DuplicateImports,
     pub(crate) on_parse:
         Option<Box<dyn Fn(&mut Module, &IndicesToIds) -> Result<()> + Sync + Send + 'static>>,
     pub(crate) on_instr_loc: Option<Box<dyn Fn(&usize) -> InstrLocId + Sync + Send + 'static>>,
 }
 
+/// What parsing does with an import that has the same module, name and kind
+/// as an earlier one; see `ModuleConfig::duplicate_imports`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateImports {
+    /// Keep both imports.
+    Preserve,
+    /// Fail, with `ErrorKind::DuplicateImport` as the root cause.
+    Error,
+    /// Use the earlier import wherever the later one is used, and drop the
+    /// later one, if both have the same type. Otherwise keep both.
+    Merge,
+}
+
+impl Default for DuplicateImports {
+    fn default() -> DuplicateImports {
+        DuplicateImports::Preserve
+    }
+}
+
 impl Clone for ModuleConfig {
     fn clone(&self) -> ModuleConfig {
         ModuleConfig {
@@ -32,6 +58,12 @@ impl Clone for ModuleConfig {
             skip_producers_section: self.skip_producers_section,
             skip_name_section: self.skip_name_section,
             preserve_code_transform: self.preserve_code_transform,
+            preserve_original_function_bodies: self.preserve_original_function_bodies,
+            disable_mutable_globals: self.disable_mutable_globals,
+            preserve_type_order: self.preserve_type_order,
+            flatten_trivial_blocks: self.flatten_trivial_blocks,
+            preserve_unreachable_code: self.preserve_unreachable_code,
+            duplicate_imports: self.duplicate_imports,
 
             // ... and this is left empty.
             on_parse: None,
@@ -52,6 +84,12 @@ impl fmt::Debug for ModuleConfig {
             ref skip_producers_section,
             ref skip_name_section,
             ref preserve_code_transform,
+            ref preserve_original_function_bodies,
+            ref disable_mutable_globals,
+            ref preserve_type_order,
+            ref flatten_trivial_blocks,
+            ref preserve_unreachable_code,
+            ref duplicate_imports,
             ref on_parse,
             ref on_instr_loc,
         } = self;
@@ -67,6 +105,15 @@ impl fmt::Debug for ModuleConfig {
             .field("skip_producers_section", skip_producers_section)
             .field("skip_name_section", skip_name_section)
             .field("preserve_code_transform", preserve_code_transform)
+            .field(
+                "preserve_original_function_bodies",
+                preserve_original_function_bodies,
+            )
+            .field("disable_mutable_globals", disable_mutable_globals)
+            .field("preserve_type_order", preserve_type_order)
+            .field("flatten_trivial_blocks", flatten_trivial_blocks)
+            .field("preserve_unreachable_code", preserve_unreachable_code)
+            .field("duplicate_imports", duplicate_imports)
             .field("on_parse", &on_parse.as_ref().map(|_| ".."))
             .field("on_instr_loc", &on_instr_loc.as_ref().map(|_| ".."))
             .finish()
@@ -200,6 +247,86 @@ impl ModuleConfig {
         self
     }
 
+    /// Sets a flag to whether the original encoding of each function body is
+    /// kept around after parsing, so that functions can later be emitted
+    /// verbatim with `ModuleFunctions::pin_original_encoding`.
+    ///
+    /// By default this flag is `false`, since it keeps a copy of the whole
+    /// code section in memory.
+    pub fn preserve_original_function_bodies(&mut self, preserve: bool) -> &mut ModuleConfig {
+        self.preserve_original_function_bodies = preserve;
+        self
+    }
+
+    /// Sets whether the mutable-globals feature is enabled, which allows a
+    /// module to import mutable globals.
+    ///
+    /// When it is disabled, parsing a module that imports a mutable global
+    /// fails.
+    ///
+    /// By default this flag is `true`.
+    pub fn mutable_globals(&mut self, enable: bool) -> &mut ModuleConfig {
+        self.disable_mutable_globals = !enable;
+        self
+    }
+
+    /// Sets whether types are emitted in the order they had in the type
+    /// section of the parsed module, followed by any types added since, in
+    /// the order they were added.
+    ///
+    /// Otherwise types are sorted by their parameters and results. Keeping
+    /// the original order keeps type indices stable, which makes for smaller
+    /// diffs between the input and output binaries. Duplicate types in the
+    /// type section are still merged into the first of them.
+    ///
+    /// By default this flag is `false`.
+    pub fn preserve_type_order(&mut self, preserve: bool) -> &mut ModuleConfig {
+        self.preserve_type_order = preserve;
+        self
+    }
+
+    /// Sets whether `block`s and `loop`s that hold a single instruction, and
+    /// that are never the target of a branch, are replaced by that
+    /// instruction while parsing function bodies.
+    ///
+    /// Such blocks don't change what the code does, so leaving them out makes
+    /// for smaller IR up front. Blocks whose instruction is an unconditional
+    /// branch, `return` or `unreachable` are kept.
+    ///
+    /// By default this flag is `false`, so that the parsed IR mirrors the
+    /// original binary.
+    pub fn flatten_trivial_blocks(&mut self, flatten: bool) -> &mut ModuleConfig {
+        self.flatten_trivial_blocks = flatten;
+        self
+    }
+
+    /// Sets whether instructions that follow an unconditional branch,
+    /// `return` or `unreachable` in the same block, and so can never run,
+    /// are kept in the IR while parsing function bodies.
+    ///
+    /// Such code is typed by the stack-polymorphic rules of validation, and
+    /// is otherwise dropped. Keeping it, along with the other preserving
+    /// options, lets a module round-trip byte for byte. The instructions
+    /// from `InstrSeq::reachable_len` on are the unreachable ones. Passes
+    /// see them like any other instructions, so they should leave them as
+    /// they are, or remove them all.
+    ///
+    /// By default this flag is `false`.
+    pub fn preserve_unreachable_code(&mut self, preserve: bool) -> &mut ModuleConfig {
+        self.preserve_unreachable_code = preserve;
+        self
+    }
+
+    /// Sets what parsing does with an import that has the same module, name
+    /// and kind as an earlier one, which malformed modules sometimes have.
+    ///
+    /// By default this is `DuplicateImports::Preserve`, so that such modules
+    /// round-trip faithfully.
+    pub fn duplicate_imports(&mut self, handling: DuplicateImports) -> &mut ModuleConfig {
+        self.duplicate_imports = handling;
+        self
+    }
+
     /// Parses an in-memory WebAssembly file into a `Module` using this
     /// configuration.
     pub fn parse(&self, wasm: &[u8]) -> Result<Module> {
@@ -213,4 +340,18 @@ impl ModuleConfig {
     {
         Module::from_file_with_config(path, self)
     }
+
+    /// The features that modules parsed with this configuration may use.
+    pub(crate) fn wasm_features(&self) -> WasmFeatures {
+        WasmFeatures {
+            reference_types: !self.only_stable_features,
+            multi_value: true,
+            bulk_memory: !self.only_stable_features,
+            simd: !self.only_stable_features,
+            threads: !self.only_stable_features,
+            multi_memory: !self.only_stable_features,
+            exceptions: !self.only_stable_features,
+            ..WasmFeatures::default()
+        }
+    }
 }
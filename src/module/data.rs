@@ -6,6 +6,9 @@ use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
 use crate::{GlobalId, InitExpr, MemoryId, Module, Result, ValType};
 use anyhow::{bail, Context};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::convert::TryFrom;
 
 /// A passive element segment identifier
 pub type DataId = Id<Data>;
@@ -64,6 +67,18 @@ pub enum ActiveDataLocation {
     Relative(GlobalId),
 }
 
+impl TryFrom<InitExpr> for ActiveDataLocation {
+    type Error = anyhow::Error;
+
+    fn try_from(offset: InitExpr) -> Result<ActiveDataLocation> {
+        match offset {
+            InitExpr::Value(Value::I32(n)) => Ok(ActiveDataLocation::Absolute(n as u32)),
+            InitExpr::Global(global) => Ok(ActiveDataLocation::Relative(global)),
+            _ => bail!("data segment offsets must be an `i32.const` or a `global.get`"),
+        }
+    }
+}
+
 impl Tombstone for Data {
     fn on_delete(&mut self) {
         self.value = Vec::new();
@@ -103,6 +118,11 @@ impl ModuleData {
         &mut self.arena[id]
     }
 
+    /// Is there a data segment with the given id that hasn't been deleted?
+    pub(crate) fn contains(&self, id: DataId) -> bool {
+        self.arena.contains(id)
+    }
+
     /// Delete a passive data segment from this module.
     ///
     /// It is up to you to ensure that all references to the deleted segment are
@@ -116,6 +136,14 @@ impl ModuleData {
         self.arena.iter().map(|(_, f)| f)
     }
 
+    /// Get a parallel iterator of this module's data segments.
+    ///
+    /// Requires the `parallel` feature of this crate to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = &Data> {
+        self.arena.par_iter().map(|(_, f)| f)
+    }
+
     /// Add a data segment
     pub fn add(&mut self, kind: DataKind, value: Vec<u8>) -> DataId {
         let id = self.arena.next_id();
@@ -132,19 +160,18 @@ impl ModuleData {
     // Note that this is inaccordance with the upstream bulk memory proposal to
     // WebAssembly and isn't currently part of the WebAssembly standard.
     pub(crate) fn emit_data_count(&self, cx: &mut EmitContext) {
-        #[cfg(feature = "parallel")]
-        use rayon::iter::ParallelIterator;
-
         if self.arena.len() == 0 {
             return;
         }
 
-        let mut count = 0;
+        let mut count = 0u32;
         let mut any_passive = false;
 
         for data in self.iter() {
-            cx.indices.set_data_index(data.id(), count as u32);
-            count += 1;
+            cx.indices.set_data_index(data.id(), count);
+            count = count
+                .checked_add(1)
+                .expect("too many data segments for 32-bit indices");
             any_passive |= data.is_passive();
         }
 
@@ -176,7 +203,7 @@ impl Module {
     /// considered valid, and it's only afterwards that we discover whether
     /// they're actually passive or not, and that property is checked during
     /// validation.
-    pub(crate) fn reserve_data(&mut self, count: u32, ids: &mut IndicesToIds) {
+    pub(crate) fn reserve_data(&mut self, count: u32, ids: &mut IndicesToIds) -> Result<()> {
         log::debug!("reserving space for {} data segments", count);
         for _ in 0..count {
             ids.push_data(self.data.arena.alloc_with_id(|id| Data {
@@ -186,8 +213,9 @@ impl Module {
                 value: Vec::new(),
                 kind: DataKind::Passive,
                 name: None,
-            }));
+            }))?;
         }
+        Ok(())
     }
 
     /// Parses a raw wasm section into a fully-formed `ModuleData` instance.
@@ -230,21 +258,13 @@ impl Module {
                     let memory = self.memories.get_mut(memory_id);
                     memory.data_segments.insert(data.id);
 
-                    let offset = InitExpr::eval(&init_expr, ids)
+                    let location = InitExpr::eval_typed(&init_expr, ids, self, ValType::I32)
+                        .and_then(ActiveDataLocation::try_from)
                         .with_context(|| format!("in segment {}", i))?;
+                    let data = self.data.get_mut(id);
                     data.kind = DataKind::Active(ActiveData {
                         memory: memory_id,
-                        location: match offset {
-                            InitExpr::Value(Value::I32(n)) => {
-                                ActiveDataLocation::Absolute(n as u32)
-                            }
-                            InitExpr::Global(global)
-                                if self.globals.get(global).ty == ValType::I32 =>
-                            {
-                                ActiveDataLocation::Relative(global)
-                            }
-                            _ => bail!("non-i32 constant in segment {}", i),
-                        },
+                        location,
                     });
                 }
             }
@@ -273,14 +293,7 @@ impl Emit for ModuleData {
                 DataKind::Active(ref a) => {
                     wasm_data_section.active(
                         cx.indices.get_memory_index(a.memory),
-                        &match a.location {
-                            ActiveDataLocation::Absolute(a) => {
-                                wasm_encoder::ConstExpr::i32_const(a as i32)
-                            }
-                            ActiveDataLocation::Relative(g) => {
-                                wasm_encoder::ConstExpr::global_get(cx.indices.get_global_index(g))
-                            }
-                        },
+                        &InitExpr::from(a.location).to_encoder(cx),
                         data.value.clone(),
                     );
                 }
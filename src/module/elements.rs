@@ -3,8 +3,10 @@
 use crate::emit::{Emit, EmitContext};
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
-use crate::{ir::Value, FunctionId, InitExpr, Module, Result, TableId, ValType};
+use crate::{FunctionId, InitExpr, Module, Result, TableId, ValType};
 use anyhow::{bail, Context};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 /// A passive element segment identifier
 pub type ElementId = Id<Element>;
@@ -63,6 +65,11 @@ impl ModuleElements {
         &mut self.arena[id]
     }
 
+    /// Is there an element segment with the given id that hasn't been deleted?
+    pub(crate) fn contains(&self, id: ElementId) -> bool {
+        self.arena.contains(id)
+    }
+
     /// Delete an elements entry from this module.
     ///
     /// It is up to you to ensure that all references to this deleted element
@@ -76,6 +83,14 @@ impl ModuleElements {
         self.arena.iter().map(|(_, f)| f)
     }
 
+    /// Get a parallel iterator of this module's elements.
+    ///
+    /// Requires the `parallel` feature of this crate to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = &Element> {
+        self.arena.par_iter().map(|(_, f)| f)
+    }
+
     /// Get a mutable reference to this module's elements.
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Element> {
         self.arena.iter_mut().map(|(_, f)| f)
@@ -111,7 +126,7 @@ impl Module {
         log::debug!("parse element section");
         for (i, segment) in section.into_iter().enumerate() {
             let segment = segment?;
-            let ty = ValType::parse(&segment.ty)?;
+            let ty = ValType::from_parser(&segment.ty)?;
             match ty {
                 ValType::Funcref => {}
                 _ => bail!("only funcref type allowed in element segments"),
@@ -139,14 +154,8 @@ impl Module {
                     let table = ids.get_table(table_index)?;
                     self.tables.get_mut(table).elem_segments.insert(id);
 
-                    let offset = InitExpr::eval(&init_expr, ids)
+                    let offset = InitExpr::eval_typed(&init_expr, ids, self, ValType::I32)
                         .with_context(|| format!("in segment {}", i))?;
-                    match offset {
-                        InitExpr::Value(Value::I32(_)) => {}
-                        InitExpr::Global(global) if self.globals.get(global).ty == ValType::I32 => {
-                        }
-                        _ => bail!("non-i32 constant in segment {}", i),
-                    }
                     ElementKind::Active { table, offset }
                 }
             };
@@ -157,7 +166,7 @@ impl Module {
                 members,
                 name: None,
             });
-            ids.push_element(id);
+            ids.push_element(id)?;
         }
         Ok(())
     }
@@ -211,7 +220,7 @@ impl Emit for ModuleElements {
                             Some(cx.indices.get_table_index(*table)).filter(|&index| index != 0);
                         wasm_element_section.active(
                             table_index,
-                            &offset.to_wasmencoder_type(&cx),
+                            &offset.to_encoder(&cx),
                             wasm_encoder::RefType::FUNCREF,
                             els,
                         );
@@ -1,11 +1,12 @@
 //! Exported items in a wasm module.
 
-use anyhow::{bail, Context};
+use anyhow::{bail, Context, Error};
+use std::collections::HashMap;
 
 use crate::emit::{Emit, EmitContext};
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
-use crate::{FunctionId, GlobalId, MemoryId, Module, Result, TableId};
+use crate::{ErrorKind, FunctionId, GlobalId, MemoryId, Module, Result, TableId};
 
 /// The id of an export.
 pub type ExportId = Id<Export>;
@@ -46,11 +47,29 @@ pub enum ExportItem {
     Global(GlobalId),
 }
 
+/// The number of items of each kind in a module's exports or imports, as
+/// given by `ModuleExports::count_by_kind` and `ModuleImports::count_by_kind`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ItemCounts {
+    /// The number of functions.
+    pub functions: usize,
+    /// The number of tables.
+    pub tables: usize,
+    /// The number of memories.
+    pub memories: usize,
+    /// The number of globals.
+    pub globals: usize,
+}
+
 /// The set of exports in a module.
 #[derive(Debug, Default)]
 pub struct ModuleExports {
     /// The arena containing this module's exports.
     arena: TombstoneArena<Export>,
+    /// An export with each name, for `try_add`. Exports can be renamed
+    /// through `get_mut` and `iter_mut`, so this is thrown away by them, and
+    /// rebuilt when it is next needed.
+    names: Option<HashMap<String, ExportId>>,
 }
 
 impl ModuleExports {
@@ -61,11 +80,19 @@ impl ModuleExports {
 
     /// Gets a reference to an export given its id
     pub fn get_mut(&mut self, id: ExportId) -> &mut Export {
+        self.names = None;
         &mut self.arena[id]
     }
 
     /// Delete an export entry from this module.
     pub fn delete(&mut self, id: ExportId) {
+        if let Some(names) = &self.names {
+            // Another export may have the same name, which the map has to be
+            // rebuilt to find.
+            if names.get(&self.arena[id].name) == Some(&id) {
+                self.names = None;
+            }
+        }
         self.arena.delete(id);
     }
 
@@ -76,16 +103,99 @@ impl ModuleExports {
 
     /// Get a mutable reference to this module's exports.
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Export> {
+        self.names = None;
         self.arena.iter_mut().map(|(_, f)| f)
     }
 
+    /// The exported functions with their export names, in the order they
+    /// were declared in the original wasm or added since.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, FunctionId)> {
+        self.iter().filter_map(|e| match e.item {
+            ExportItem::Function(id) => Some((e.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// The exported tables with their export names, in declaration order.
+    pub fn tables(&self) -> impl Iterator<Item = (&str, TableId)> {
+        self.iter().filter_map(|e| match e.item {
+            ExportItem::Table(id) => Some((e.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// The exported memories with their export names, in declaration order.
+    pub fn memories(&self) -> impl Iterator<Item = (&str, MemoryId)> {
+        self.iter().filter_map(|e| match e.item {
+            ExportItem::Memory(id) => Some((e.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// The exported globals with their export names, in declaration order.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, GlobalId)> {
+        self.iter().filter_map(|e| match e.item {
+            ExportItem::Global(id) => Some((e.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// Count the exports of each kind.
+    pub fn count_by_kind(&self) -> ItemCounts {
+        let mut counts = ItemCounts::default();
+        for export in self.iter() {
+            match export.item {
+                ExportItem::Function(_) => counts.functions += 1,
+                ExportItem::Table(_) => counts.tables += 1,
+                ExportItem::Memory(_) => counts.memories += 1,
+                ExportItem::Global(_) => counts.globals += 1,
+            }
+        }
+        counts
+    }
+
     /// Add a new export to this module
+    ///
+    /// This doesn't check whether there already is an export with the same
+    /// name, which makes for an invalid module. Use `try_add` for that.
     pub fn add(&mut self, name: &str, item: impl Into<ExportItem>) -> ExportId {
-        self.arena.alloc_with_id(|id| Export {
+        let id = self.arena.alloc_with_id(|id| Export {
             id,
             name: name.to_string(),
             item: item.into(),
-        })
+        });
+        if let Some(names) = &mut self.names {
+            names.entry(name.to_string()).or_insert(id);
+        }
+        id
+    }
+
+    /// Add a new export to this module, unless there already is an export
+    /// with the same name.
+    ///
+    /// The error's root cause is `ErrorKind::DuplicateExport` in that case.
+    pub fn try_add(&mut self, name: &str, item: impl Into<ExportItem>) -> Result<ExportId> {
+        let item = item.into();
+        if self.names.is_none() {
+            let mut names = HashMap::new();
+            for export in self.iter() {
+                names.entry(export.name.clone()).or_insert(export.id());
+            }
+            self.names = Some(names);
+        }
+        if let Some(&existing) = self.names.as_ref().unwrap().get(name) {
+            let existing = self.get(existing);
+            return Err(Error::new(ErrorKind::DuplicateExport)).with_context(|| {
+                format!(
+                    "cannot export {:?} as {:?}, export [{:?}] of {:?} has that name",
+                    item,
+                    name,
+                    existing.id(),
+                    existing.item
+                )
+            });
+        }
+        Ok(self.add(name, item))
     }
 
     #[doc(hidden)]
@@ -156,6 +266,25 @@ impl ModuleExports {
 }
 
 impl Module {
+    /// Check that no two exports in the section have the same name, before
+    /// the section is validated, so that duplicates are reported with
+    /// `ErrorKind::DuplicateExport`.
+    pub(crate) fn check_export_names(section: wasmparser::ExportSectionReader) -> Result<()> {
+        let mut seen = HashMap::new();
+        for (i, entry) in section.into_iter().enumerate() {
+            let entry = entry?;
+            if let Some((j, kind, index)) = seen.insert(entry.field, (i, entry.kind, entry.index)) {
+                return Err(Error::new(ErrorKind::DuplicateExport)).with_context(|| {
+                    format!(
+                        "exports {} ({:?} {}) and {} ({:?} {}) are both named {:?}",
+                        j, kind, index, i, entry.kind, entry.index, entry.field
+                    )
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Construct the export set for a wasm module.
     pub(crate) fn parse_exports(
         &mut self,
@@ -176,7 +305,7 @@ impl Module {
                     unimplemented!("module linking not supported");
                 }
                 Tag => {
-                    unimplemented!("exception handling not supported");
+                    bail!("exception handling is not supported");
                 }
             };
             self.exports.arena.alloc_with_id(|id| Export {
@@ -275,6 +404,79 @@ mod tests {
         arena.next_id()
     }
 
+    #[test]
+    fn duplicate_export_names_are_rejected() {
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+            0x03, 0x02, 0x01, 0x00, // function section
+            0x07, 0x0f, 0x02, // export section, with two exports
+            0x04, b'm', b'a', b'i', b'n', 0x00, 0x00, // "main", function 0
+            0x04, b'm', b'a', b'i', b'n', 0x00, 0x00, // "main", function 0
+            0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code section
+        ];
+        let err = Module::from_buffer(&wasm).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::DuplicateExport)
+        );
+
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let id = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("main", id);
+        let err = module.exports.try_add("main", id).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::DuplicateExport)
+        );
+        let other = module.exports.try_add("other", id).unwrap();
+
+        // Renaming and deleting exports frees up their names.
+        module.exports.get_mut(other).name = "renamed".to_string();
+        assert!(module.exports.try_add("other", id).is_ok());
+        let main = module
+            .exports
+            .iter()
+            .find(|e| e.name == "main")
+            .unwrap()
+            .id();
+        module.exports.delete(main);
+        assert!(module.exports.try_add("main", id).is_ok());
+    }
+
+    #[test]
+    fn exports_by_kind() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let mut funcs = Vec::new();
+        for _ in 0..2 {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            builder.func_body();
+            funcs.push(builder.finish(vec![], &mut module.funcs));
+        }
+        // Declared out of id order, with a memory in between.
+        module.exports.add("second", funcs[1]);
+        module.exports.add("memory", memory);
+        module.exports.add("first", funcs[0]);
+
+        let functions = module.exports.functions().collect::<Vec<_>>();
+        assert_eq!(functions, [("second", funcs[1]), ("first", funcs[0])]);
+        let memories = module.exports.memories().collect::<Vec<_>>();
+        assert_eq!(memories, [("memory", memory)]);
+        assert_eq!(module.exports.tables().count(), 0);
+        assert_eq!(module.exports.globals().count(), 0);
+        assert_eq!(
+            module.exports.count_by_kind(),
+            ItemCounts {
+                functions: 2,
+                memories: 1,
+                ..ItemCounts::default()
+            }
+        );
+    }
+
     #[test]
     fn get_exported_func() {
         let mut module = Module::default();
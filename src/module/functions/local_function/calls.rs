@@ -0,0 +1,133 @@
+//! Checking that calls pass the arguments their callee's type says they
+//! should.
+
+use super::returns::{types_match, write_types};
+use super::LocalFunction;
+use crate::ir::*;
+use crate::{FunctionId, Module, ValType};
+use std::fmt;
+
+/// A `call` or `call_indirect` whose operands don't match the parameters of
+/// the function it calls.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallArgumentMismatch {
+    /// The sequence containing the call.
+    pub seq: InstrSeqId,
+    /// The index of the call within `seq`.
+    pub index: usize,
+    /// The function called, or `None` for a `call_indirect`.
+    pub callee: Option<FunctionId>,
+    /// The callee's parameter types. For a `call_indirect`, these are
+    /// followed by the `i32` table index.
+    pub expected: Vec<ValType>,
+    /// The types of the values on top of the stack at the call, as many as
+    /// there are expected, or fewer if there aren't enough values.
+    ///
+    /// A type is `None` if it can't be determined without full type
+    /// inference. Such values are assumed to have the expected type.
+    pub got: Vec<Option<ValType>>,
+}
+
+impl fmt::Display for CallArgumentMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.callee {
+            Some(_) => write!(f, "`call` expects ")?,
+            None => write!(f, "`call_indirect` expects ")?,
+        }
+        let expected = self.expected.iter().map(|ty| Some(*ty)).collect::<Vec<_>>();
+        write_types(f, &expected)?;
+        write!(f, " but got ")?;
+        write_types(f, &self.got)?;
+        if self.got.len() < self.expected.len() {
+            write!(
+                f,
+                " (arity mismatch: {} arguments instead of {})",
+                self.got.len(),
+                self.expected.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CallArgumentMismatch {}
+
+impl LocalFunction {
+    /// Find every call that doesn't pass as many arguments as its callee has
+    /// parameters, or passes an argument of the wrong type.
+    ///
+    /// Like `return_type_mismatches`, calls in code that is unreachable
+    /// within its sequence aren't checked.
+    pub fn call_argument_mismatches(&self, module: &Module) -> Vec<CallArgumentMismatch> {
+        let mut mismatches = Vec::new();
+        self.for_each_operand_stack(module, |seq, index, instr, stack| {
+            let (callee, expected) = match instr {
+                Instr::Call(Call { func }) => {
+                    let params = module.types.params(module.funcs.get(*func).ty());
+                    (Some(*func), params.to_vec())
+                }
+                Instr::CallIndirect(CallIndirect { ty, .. }) => {
+                    let mut params = module.types.params(*ty).to_vec();
+                    params.push(ValType::I32);
+                    (None, params)
+                }
+                _ => return,
+            };
+            let got = stack[stack.len().saturating_sub(expected.len())..].to_vec();
+            if !types_match(&got, &expected) {
+                mismatches.push(CallArgumentMismatch {
+                    seq,
+                    index,
+                    callee,
+                    expected,
+                    got,
+                });
+            }
+        });
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FunctionBuilder, Module, ValType};
+
+    #[test]
+    fn call_with_wrong_arguments() {
+        let mut module = Module::default();
+        let params = [ValType::I32, ValType::I64];
+        let callee =
+            FunctionBuilder::new(&mut module.types, &params, &[]).finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(1).i64_const(2).call(callee);
+        let ok = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i64_const(2).call(callee);
+        let too_few = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().f32_const(1.0).i64_const(2).call(callee);
+        let wrong_type = builder.finish(vec![], &mut module.funcs);
+
+        let mismatches = |func| {
+            let func = module.funcs.get(func).kind.unwrap_local();
+            func.call_argument_mismatches(&module)
+        };
+        assert!(mismatches(ok).is_empty());
+
+        let too_few = mismatches(too_few);
+        assert_eq!(too_few.len(), 1);
+        assert_eq!(too_few[0].callee, Some(callee));
+        assert_eq!(too_few[0].got, [Some(ValType::I64)]);
+        assert_eq!(
+            too_few[0].to_string(),
+            "`call` expects [i32, i64] but got [i64] (arity mismatch: 1 arguments instead of 2)"
+        );
+
+        let wrong_type = mismatches(wrong_type);
+        assert_eq!(wrong_type.len(), 1);
+        assert_eq!(wrong_type[0].got, [Some(ValType::F32), Some(ValType::I64)]);
+    }
+}
@@ -28,6 +28,18 @@ pub(crate) struct ControlFrame {
     pub kind: BlockKind,
 }
 
+impl ControlFrame {
+    /// The types of the values a branch to this frame carries: a `loop`'s
+    /// parameters, since branching to it starts it over, and every other
+    /// block's results.
+    pub fn label_types(&self) -> &[ValType] {
+        match self.kind {
+            BlockKind::Loop => &self.start_types,
+            _ => &self.end_types,
+        }
+    }
+}
+
 /// The control frame stack.
 pub(crate) type ControlStack = Vec<ControlFrame>;
 
@@ -143,7 +155,7 @@ impl<'a> ValidationContext<'a> {
         loc: InstrLocId,
     ) -> Result<()> {
         let frame = self.control(control)?;
-        if frame.unreachable {
+        if frame.unreachable && !self.module.config.preserve_unreachable_code {
             return Ok(());
         }
         let block = frame.block;
@@ -218,3 +230,35 @@ fn impl_pop_control(controls: &mut ControlStack) -> Result<ControlFrame> {
     let frame = controls.pop().unwrap();
     Ok(frame)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    #[test]
+    fn label_types() {
+        let mut module = Module::default();
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let frame = |kind| ControlFrame {
+            start_types: vec![ValType::I32].into(),
+            end_types: vec![ValType::I64, ValType::F32].into(),
+            unreachable: false,
+            block: builder.func_body_id(),
+            kind,
+        };
+
+        // Branching to a loop starts it over with new parameters, while
+        // branching to any other block exits it with its results.
+        assert_eq!(frame(BlockKind::Loop).label_types(), [ValType::I32]);
+        let others = [
+            BlockKind::Block,
+            BlockKind::If,
+            BlockKind::Else,
+            BlockKind::FunctionEntry,
+        ];
+        for &kind in others.iter() {
+            assert_eq!(frame(kind).label_types(), [ValType::I64, ValType::F32]);
+        }
+    }
+}
@@ -0,0 +1,708 @@
+//! A textual dump of a local function's instructions, for debugging.
+
+use super::Nesting;
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::module::functions::LocalFunction;
+use crate::Module;
+use std::fmt;
+
+/// Prints the function's instructions one per line, indented by nesting.
+///
+/// Blocks, loops and `if/else`s that some branch targets are given a label,
+/// `$l0`, `$l1`, ..., numbered in the order they are opened; the function
+/// body itself is labeled on its `func` line if something branches to it.
+/// Sequences that nothing branches to are left unlabeled, which makes it easy
+/// to see which ones actually matter for control flow. As in the emitted
+/// code, the `else` of an `if` is left out when its alternative is empty.
+impl fmt::Display for LocalFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            func: self,
+            targets: self.branch_targets(),
+            labels: IdHashMap::default(),
+            lossy: false,
+            open: Vec::new(),
+        };
+        let entry = self.entry_block();
+        printer.open(f, 0, "func", &[entry])?;
+        printer.seq(f, 1, entry)?;
+        writeln!(f, "end")
+    }
+}
+
+/// How deeply `to_string_lossy` nests sequences before cutting them short.
+const LOSSY_MAX_DEPTH: usize = 64;
+
+struct Lossy<'a>(&'a LocalFunction);
+
+impl fmt::Display for Lossy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            func: self.0,
+            // Finding the branch targets means walking the sequences, which
+            // may not all be there, so labels are left out altogether.
+            targets: IdHashSet::default(),
+            labels: IdHashMap::default(),
+            lossy: true,
+            open: Vec::new(),
+        };
+        let entry = self.0.entry_block();
+        printer.open(f, 0, "func", &[entry])?;
+        printer.seq(f, 1, entry)?;
+        writeln!(f, "end")
+    }
+}
+
+impl LocalFunction {
+    /// Print this function like its `Display` implementation does, even if
+    /// it is broken in ways that would make that panic or never finish.
+    ///
+    /// Instead of a sequence, this prints `<dangling ...>` if it doesn't
+    /// exist, `<cycle to ...>` if it is already being printed further out,
+    /// and `<truncated>` if it is nested too deeply. Branch targets are
+    /// printed as ids rather than labels.
+    ///
+    /// A well-formed function prints one line per instruction just as with
+    /// `Display`, so instructions keep their line numbers.
+    pub fn to_string_lossy(&self) -> String {
+        Lossy(self).to_string()
+    }
+
+    /// Print the instruction at `index` of `seq` as folded WebAssembly text,
+    /// with the instructions computing its operands nested in it, like
+    /// `(i32.add (local.get 0) (i32.const 1))`.
+    ///
+    /// An operand is only folded in if the instruction right before it
+    /// pushes just that value; folding stops at the first one that doesn't,
+    /// and the remaining operands are left out, as they come from the stack.
+    /// Blocks are printed without their bodies.
+    ///
+    /// Locals are numbered the way they are when the function is emitted.
+    /// Other items are referred to by their name, like `$f`, if they have
+    /// one, and otherwise by the index of their id, which is their index in
+    /// the module they were parsed from. Instructions without a text form
+    /// here are printed the way `Display` prints them.
+    pub fn display_expr(&self, module: &Module, seq: InstrSeqId, index: usize) -> String {
+        let printer = Wat {
+            func: self,
+            module,
+            seq,
+            locals: self.emit_locals(module).2,
+            nesting: self.nesting(),
+        };
+        let instrs = &self.block(seq).instrs;
+        return fold(&printer, instrs, index).0;
+
+        /// The folded form of `instrs[end]`, and the index of the first
+        /// instruction in it.
+        fn fold(printer: &Wat, instrs: &[(Instr, InstrLocId)], end: usize) -> (String, usize) {
+            let (func, module) = (printer.func, printer.module);
+            let instr = &instrs[end].0;
+            let pops = func.stack_effect(module, instr).map_or(0, |(pops, _)| pops);
+            let mut operands = Vec::new();
+            let mut start = end;
+            while operands.len() < pops && start > 0 {
+                match func.stack_effect(module, &instrs[start - 1].0) {
+                    Some((_, 1)) => {}
+                    _ => break,
+                }
+                let (operand, operand_start) = fold(printer, instrs, start - 1);
+                operands.push(operand);
+                start = operand_start;
+            }
+            let mut folded = format!("({}", printer.instr(instr));
+            for operand in operands.iter().rev() {
+                folded.push(' ');
+                folded.push_str(operand);
+            }
+            folded.push(')');
+            (folded, start)
+        }
+    }
+
+    /// Number each of this function's instructions by the line it is printed
+    /// on by its `Display` implementation, counting the `func` line as line 0.
+    ///
+    /// Every other line of that output is exactly one operator in the
+    /// function's encoding, so these are also the instructions' positions
+    /// among the emitted operators, counting from 1.
+    pub(crate) fn numbered_instrs(&self) -> Vec<(usize, &Instr)> {
+        let mut numbered = Vec::new();
+        let mut line = 0;
+        number(self, self.entry_block(), &mut line, &mut numbered);
+        return numbered;
+
+        fn number<'a>(
+            func: &'a LocalFunction,
+            seq: InstrSeqId,
+            line: &mut usize,
+            numbered: &mut Vec<(usize, &'a Instr)>,
+        ) {
+            for (instr, _) in func.block(seq).instrs.iter() {
+                *line += 1;
+                numbered.push((*line, instr));
+                match instr {
+                    Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                        number(func, *seq, line, numbered);
+                        // The `end`.
+                        *line += 1;
+                    }
+                    Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    }) => {
+                        number(func, *consequent, line, numbered);
+                        if func.needs_else(*alternative) {
+                            // The `else`.
+                            *line += 1;
+                            number(func, *alternative, line, numbered);
+                        }
+                        // The `end`.
+                        *line += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+struct Printer<'a> {
+    func: &'a LocalFunction,
+    targets: IdHashSet<InstrSeq>,
+    labels: IdHashMap<InstrSeq, usize>,
+    /// Whether to print broken functions rather than panic on them.
+    lossy: bool,
+    /// The sequences being printed, from the outermost in.
+    open: Vec<InstrSeqId>,
+}
+
+impl Printer<'_> {
+    /// Print the line opening a construct made up of the given sequences,
+    /// labeling it if any of them is a branch target.
+    fn open(
+        &mut self,
+        f: &mut fmt::Formatter,
+        depth: usize,
+        name: &str,
+        seqs: &[InstrSeqId],
+    ) -> fmt::Result {
+        write!(f, "{:1$}{2}", "", depth * 2, name)?;
+        if seqs.iter().any(|seq| self.targets.contains(seq)) {
+            let label = self.labels.len();
+            for seq in seqs {
+                self.labels.insert(*seq, label);
+            }
+            write!(f, " $l{}", label)?;
+        }
+        writeln!(f)
+    }
+
+    fn label(&self, seq: InstrSeqId) -> String {
+        match self.labels.get(&seq) {
+            Some(label) => format!("$l{}", label),
+            None => format!("{:?}", seq),
+        }
+    }
+
+    fn seq(&mut self, f: &mut fmt::Formatter, depth: usize, seq: InstrSeqId) -> fmt::Result {
+        let indent = depth * 2;
+        let func = self.func;
+        if !self.lossy {
+            return self.instrs(f, depth, &func.block(seq).instrs);
+        }
+        let block = match func.builder.arena.get(seq) {
+            Some(block) => block,
+            None => return writeln!(f, "{:1$}<dangling {2:?}>", "", indent, seq),
+        };
+        if self.open.contains(&seq) {
+            return writeln!(f, "{:1$}<cycle to {2:?}>", "", indent, seq);
+        }
+        if depth > LOSSY_MAX_DEPTH {
+            return writeln!(f, "{:1$}<truncated>", "", indent);
+        }
+        self.open.push(seq);
+        self.instrs(f, depth, &block.instrs)?;
+        self.open.pop();
+        Ok(())
+    }
+
+    /// Whether the `else` of an `if` with the given alternative is printed.
+    fn needs_else(&self, alternative: InstrSeqId) -> bool {
+        if self.lossy && self.func.builder.arena.get(alternative).is_none() {
+            return true;
+        }
+        self.func.needs_else(alternative)
+    }
+
+    fn instrs(
+        &mut self,
+        f: &mut fmt::Formatter,
+        depth: usize,
+        instrs: &[(Instr, InstrLocId)],
+    ) -> fmt::Result {
+        let indent = depth * 2;
+        for (instr, _) in instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) => {
+                    self.open(f, depth, "block", &[*seq])?;
+                    self.seq(f, depth + 1, *seq)?;
+                }
+                Instr::Loop(Loop { seq }) => {
+                    self.open(f, depth, "loop", &[*seq])?;
+                    self.seq(f, depth + 1, *seq)?;
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    self.open(f, depth, "if", &[*consequent, *alternative])?;
+                    self.seq(f, depth + 1, *consequent)?;
+                    if self.needs_else(*alternative) {
+                        writeln!(f, "{:1$}else", "", indent)?;
+                        self.seq(f, depth + 1, *alternative)?;
+                    }
+                }
+                Instr::Br(Br { block }) => {
+                    writeln!(f, "{:1$}br {2}", "", indent, self.label(*block))?;
+                    continue;
+                }
+                Instr::BrIf(BrIf { block }) => {
+                    writeln!(f, "{:1$}br_if {2}", "", indent, self.label(*block))?;
+                    continue;
+                }
+                Instr::BrTable(BrTable { blocks, default }) => {
+                    write!(f, "{:1$}br_table", "", indent)?;
+                    for block in blocks.iter().chain(Some(default)) {
+                        write!(f, " {}", self.label(*block))?;
+                    }
+                    writeln!(f)?;
+                    continue;
+                }
+                other => {
+                    writeln!(f, "{:1$}{2}", "", indent, mnemonic(other))?;
+                    continue;
+                }
+            }
+            writeln!(f, "{:1$}end", "", indent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints instructions of `seq` as WebAssembly text, for `display_expr`.
+struct Wat<'a> {
+    func: &'a LocalFunction,
+    module: &'a Module,
+    seq: InstrSeqId,
+    locals: IdHashMap<Local, u32>,
+    nesting: Nesting,
+}
+
+impl Wat<'_> {
+    fn instr(&self, instr: &Instr) -> String {
+        let module = self.module;
+        match instr {
+            Instr::Const(Const { value }) => match value {
+                Value::I32(v) => format!("i32.const {}", v),
+                Value::I64(v) => format!("i64.const {}", v),
+                Value::F32(v) => format!("f32.const {}", v),
+                Value::F64(v) => format!("f64.const {}", v),
+                Value::V128(v) => format!("v128.const i64x2 {} {}", *v as u64, (v >> 64) as u64),
+            },
+            Instr::LocalGet(LocalGet { local }) => format!("local.get {}", self.local(*local)),
+            Instr::LocalSet(LocalSet { local }) => format!("local.set {}", self.local(*local)),
+            Instr::LocalTee(LocalTee { local }) => format!("local.tee {}", self.local(*local)),
+            Instr::GlobalGet(GlobalGet { global }) => {
+                format!(
+                    "global.get {}",
+                    item(*global, &module.globals.get(*global).name)
+                )
+            }
+            Instr::GlobalSet(GlobalSet { global }) => {
+                format!(
+                    "global.set {}",
+                    item(*global, &module.globals.get(*global).name)
+                )
+            }
+            Instr::Call(Call { func }) => {
+                format!("call {}", item(*func, &module.funcs.get(*func).name))
+            }
+            Instr::CallIndirect(CallIndirect { ty, table }) => format!(
+                "call_indirect {} (type {})",
+                item(*table, &module.tables.get(*table).name),
+                item(*ty, &module.types.get(*ty).name)
+            ),
+            Instr::Binop(Binop { op }) => operator(&format!("{:?}", op)),
+            Instr::Unop(Unop { op }) => operator(&format!("{:?}", op)),
+            Instr::Load(Load { kind, arg, .. }) => {
+                let (ty, bits, sign) = match kind {
+                    LoadKind::I32 { .. } => ("i32", "", None),
+                    LoadKind::I64 { .. } => ("i64", "", None),
+                    LoadKind::F32 => ("f32", "", None),
+                    LoadKind::F64 => ("f64", "", None),
+                    LoadKind::V128 => ("v128", "", None),
+                    LoadKind::I32_8 { kind } => ("i32", "8", Some(kind)),
+                    LoadKind::I32_16 { kind } => ("i32", "16", Some(kind)),
+                    LoadKind::I64_8 { kind } => ("i64", "8", Some(kind)),
+                    LoadKind::I64_16 { kind } => ("i64", "16", Some(kind)),
+                    LoadKind::I64_32 { kind } => ("i64", "32", Some(kind)),
+                };
+                let sign = match sign {
+                    Some(ExtendedLoad::SignExtend) => "_s",
+                    Some(_) => "_u",
+                    None => "",
+                };
+                let atomic = if kind.atomic() { ".atomic" } else { "" };
+                let op = format!("{}{}.load{}{}", ty, atomic, bits, sign);
+                mem_arg(op, arg, kind.width())
+            }
+            Instr::Store(Store { kind, arg, .. }) => {
+                let (ty, bits) = match kind {
+                    StoreKind::I32 { .. } => ("i32", ""),
+                    StoreKind::I64 { .. } => ("i64", ""),
+                    StoreKind::F32 => ("f32", ""),
+                    StoreKind::F64 => ("f64", ""),
+                    StoreKind::V128 => ("v128", ""),
+                    StoreKind::I32_8 { .. } => ("i32", "8"),
+                    StoreKind::I32_16 { .. } => ("i32", "16"),
+                    StoreKind::I64_8 { .. } => ("i64", "8"),
+                    StoreKind::I64_16 { .. } => ("i64", "16"),
+                    StoreKind::I64_32 { .. } => ("i64", "32"),
+                };
+                let atomic = if kind.atomic() { ".atomic" } else { "" };
+                let op = format!("{}{}.store{}", ty, atomic, bits);
+                mem_arg(op, arg, kind.width())
+            }
+            Instr::MemorySize(_) => "memory.size".to_string(),
+            Instr::MemoryGrow(_) => "memory.grow".to_string(),
+            Instr::Block(_) => "block".to_string(),
+            Instr::Loop(_) => "loop".to_string(),
+            Instr::IfElse(_) => "if".to_string(),
+            Instr::Br(Br { block }) => format!("br {}", self.label(*block)),
+            Instr::BrIf(BrIf { block }) => format!("br_if {}", self.label(*block)),
+            Instr::BrTable(BrTable { blocks, default }) => {
+                let mut out = "br_table".to_string();
+                for block in blocks.iter().chain(Some(default)) {
+                    out.push(' ');
+                    out.push_str(&self.label(*block));
+                }
+                out
+            }
+            Instr::Select(Select { ty: None }) => "select".to_string(),
+            Instr::Select(Select { ty: Some(ty) }) => format!("select (result {})", ty),
+            Instr::Drop(_) => "drop".to_string(),
+            Instr::Return(_) => "return".to_string(),
+            Instr::Unreachable(_) => "unreachable".to_string(),
+            Instr::Nop(_) => "nop".to_string(),
+            other => mnemonic(other),
+        }
+    }
+
+    fn local(&self, local: LocalId) -> String {
+        match self.locals.get(&local) {
+            Some(index) => index.to_string(),
+            None => format!("{:?}", local),
+        }
+    }
+
+    /// The label of a branch in `seq` to `target`, which is its depth.
+    fn label(&self, target: InstrSeqId) -> String {
+        match self.nesting.depth(target, self.seq) {
+            Some(depth) => depth.to_string(),
+            None => format!("{:?}", target),
+        }
+    }
+}
+
+/// `$name` for an item with a name, and the index of its id otherwise.
+fn item<T>(id: id_arena::Id<T>, name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("${}", name),
+        None => id.index().to_string(),
+    }
+}
+
+/// The text form of a `BinaryOp` or `UnaryOp`, given its `Debug` output, so
+/// that `I32Add` becomes `i32.add`, `I32TruncSF32` becomes `i32.trunc_f32_s`
+/// and `I8x16ExtractLaneS { idx: 1 }` becomes `i8x16.extract_lane_s 1`.
+fn operator(debug: &str) -> String {
+    let (name, idx) = match debug.find(" { idx: ") {
+        Some(i) => (&debug[..i], Some(debug[i + 8..].trim_end_matches(" }"))),
+        None => (debug, None),
+    };
+    // Split the name before each capital letter, so digits and `x`s stay
+    // with the word before them, as in `i8x16`.
+    let mut words = Vec::new();
+    for c in name.chars() {
+        if c.is_ascii_uppercase() || words.is_empty() {
+            words.push(String::new());
+        }
+        words.last_mut().unwrap().push(c.to_ascii_lowercase());
+    }
+    let mut out = words.remove(0);
+    out.push('.');
+    // Conversions name their signedness last in the text format.
+    if let Some(i) = words.iter().position(|w| w == "s" || w == "u") {
+        if i > 0 && ["trunc", "convert", "extend"].contains(&words[i - 1].as_str()) {
+            let sign = words.remove(i);
+            words.push(sign);
+        }
+    }
+    let mut op = words.join("_");
+    let renames = [
+        ("p_min", "pmin"),
+        ("p_max", "pmax"),
+        ("ext_mul", "extmul"),
+        ("rounding_average", "avgr"),
+    ];
+    for (from, to) in &renames {
+        op = op.replace(from, to);
+    }
+    out.push_str(&op);
+    if let Some(idx) = idx {
+        out.push(' ');
+        out.push_str(idx);
+    }
+    out
+}
+
+/// A memory access's operator followed by its `offset` and `align`, if they
+/// aren't the defaults.
+fn mem_arg(mut op: String, arg: &MemArg, width: u32) -> String {
+    if arg.offset != 0 {
+        op.push_str(&format!(" offset={}", arg.offset));
+    }
+    if arg.align != width {
+        op.push_str(&format!(" align={}", arg.align));
+    }
+    op
+}
+
+/// Render any other instruction as its name in `snake_case`, followed by its
+/// fields, if it has any.
+///
+/// For example `Instr::LocalGet(LocalGet { local })` becomes
+/// `local_get { local: Id { idx: 0 } }`.
+fn mnemonic(instr: &Instr) -> String {
+    let debug = format!("{:?}", instr);
+    let (name, fields) = match debug.find('(') {
+        // Strip the `Variant(` ... `)` wrapper and the inner struct's name.
+        Some(paren) => {
+            let inner = &debug[paren + 1..debug.len() - 1];
+            (&debug[..paren], inner.find(' ').map_or("", |i| &inner[i..]))
+        }
+        None => (&debug[..], ""),
+    };
+
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out.push_str(fields);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{Block, Instr};
+    use crate::{FunctionBuilder, Module};
+
+    #[test]
+    fn labels_only_branch_targets() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .block(None, |targeted| {
+                let id = targeted.id();
+                targeted.i32_const(1).br_if(id);
+            })
+            .block(None, |untargeted| {
+                untargeted.nop();
+            });
+        let func = builder.finish(vec![], &mut module.funcs);
+        let func = module.funcs.get(func).kind.unwrap_local();
+
+        assert_eq!(
+            func.to_string(),
+            "\
+func
+  block $l0
+    const { value: I32(1) }
+    br_if $l0
+  end
+  block
+    nop
+  end
+end
+"
+        );
+    }
+
+    #[test]
+    fn leaves_out_empty_else() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .if_else(
+                None,
+                |then| {
+                    then.nop();
+                },
+                |_| {},
+            )
+            .i32_const(0)
+            .if_else(
+                None,
+                |_| {},
+                |else_| {
+                    else_.nop();
+                },
+            );
+        let func = builder.finish(vec![], &mut module.funcs);
+        let func = module.funcs.get(func).kind.unwrap_local();
+
+        assert_eq!(
+            func.to_string(),
+            "\
+func
+  const { value: I32(1) }
+  if
+    nop
+  end
+  const { value: I32(0) }
+  if
+  else
+    nop
+  end
+end
+"
+        );
+        // The lines match the output above.
+        let lines = func
+            .numbered_instrs()
+            .into_iter()
+            .map(|(line, _)| line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [1, 2, 3, 5, 6, 8]);
+    }
+
+    #[test]
+    fn lossy_printing_of_broken_functions() {
+        let mut module = Module::default();
+        let mut other = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let elsewhere = other.func_body_id();
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let cycle = builder.dangling_instr_seq(None).id();
+        builder.instr_seq(cycle).instr(Block { seq: cycle });
+        let mut deep = builder.dangling_instr_seq(None).id();
+        for _ in 0..100 {
+            let outer = builder.dangling_instr_seq(None).id();
+            builder.instr_seq(outer).instr(Block { seq: deep });
+            deep = outer;
+        }
+        builder
+            .func_body()
+            .instr(Block { seq: elsewhere })
+            .instr(Block { seq: cycle })
+            .instr(Block { seq: deep });
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        let dump = module.funcs.dump_lossy(func);
+        assert!(dump.contains(&format!("<dangling {:?}>", elsewhere)));
+        assert!(dump.contains(&format!("<cycle to {:?}>", cycle)));
+        assert!(dump.contains("<truncated>"));
+
+        module.funcs.delete(func);
+        assert_eq!(
+            module.funcs.dump_lossy(func),
+            format!("<dangling {:?}>\n", func)
+        );
+    }
+
+    #[test]
+    fn display_expr_folds_operands() {
+        use crate::ir::BinaryOp;
+
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .i32_const(2)
+            .i32_const(3)
+            .binop(BinaryOp::I32Mul)
+            .binop(BinaryOp::I32Add)
+            .drop();
+        let func = builder.finish(vec![], &mut module.funcs);
+        let func = module.funcs.get(func).kind.unwrap_local();
+        let entry = func.entry_block();
+
+        assert_eq!(
+            func.display_expr(&module, entry, 4),
+            "(i32.add (i32.const 1) (i32.mul (i32.const 2) (i32.const 3)))"
+        );
+        // The constant before the product has nothing to fold.
+        assert_eq!(func.display_expr(&module, entry, 0), "(i32.const 1)");
+    }
+
+    #[test]
+    fn display_expr_prints_wat() {
+        use crate::ir::{BinaryOp, ExtendedLoad, LoadKind, MemArg, UnaryOp};
+        use crate::ValType;
+
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let x = module.locals.add(ValType::I32);
+        let y = module.locals.add(ValType::F32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder.func_body().block(None, |block| {
+            let id = block.id();
+            block
+                .local_get(x)
+                .load(
+                    memory,
+                    LoadKind::I32_8 {
+                        kind: ExtendedLoad::SignExtend,
+                    },
+                    MemArg {
+                        align: 1,
+                        offset: 8,
+                    },
+                )
+                .br_if(id)
+                .local_get(y)
+                .unop(UnaryOp::I32TruncSF32)
+                .i32_const(2)
+                .binop(BinaryOp::I32Shl)
+                .drop();
+        });
+        let func = builder.finish(vec![x], &mut module.funcs);
+        let func = module.funcs.get(func).kind.unwrap_local();
+        let block = match func.block(func.entry_block())[0].0 {
+            Instr::Block(Block { seq }) => seq,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            func.display_expr(&module, block, 2),
+            "(br_if 0 (i32.load8_s offset=8 (local.get 0)))"
+        );
+        assert_eq!(
+            func.display_expr(&module, block, 7),
+            "(drop (i32.shl (i32.trunc_f32_s (local.get 1)) (i32.const 2)))"
+        );
+    }
+}
@@ -13,9 +13,11 @@ pub(crate) fn run(
     map: Option<&mut Vec<(InstrLocId, usize)>>,
 ) {
     let v = &mut Emit {
+        func,
         indices,
         blocks: vec![],
         block_kinds: vec![BlockKind::FunctionEntry],
+        needs_else: vec![],
         encoder,
         local_indices,
         map,
@@ -24,9 +26,13 @@ pub(crate) fn run(
 
     debug_assert!(v.blocks.is_empty());
     debug_assert!(v.block_kinds.is_empty());
+    debug_assert!(v.needs_else.is_empty());
 }
 
 struct Emit<'a> {
+    // The function being emitted, to look at the alternatives of `if/else`s.
+    func: &'a LocalFunction,
+
     // Needed so we can map locals to their indices.
     indices: &'a IdsToIndices,
     local_indices: &'a IdHashMap<Local, u32>,
@@ -42,6 +48,11 @@ struct Emit<'a> {
     // kind.
     block_kinds: Vec<BlockKind>,
 
+    // For each `if` block on the stack, whether its alternative is emitted
+    // after an `else` opcode. Empty alternatives are left out, along with the
+    // `else`.
+    needs_else: Vec<bool>,
+
     // The instruction sequence we are building up to emit.
     encoder: &'a mut wasm_encoder::Function,
 
@@ -85,11 +96,12 @@ impl<'instr> Visitor<'instr> for Emit<'_> {
         debug_assert_eq!(self.blocks.len(), self.block_kinds.len());
 
         if let BlockKind::If = popped_kind.unwrap() {
-            // We're about to visit the `else` block, so push its kind.
-            //
-            // TODO: don't emit `else` for empty else blocks
+            // We're about to visit the `else` block, so push its kind. If it
+            // is left out, the `end` is emitted once it has been visited.
             self.block_kinds.push(BlockKind::Else);
-            self.encoder.instruction(&Instruction::Else);
+            if self.needs_else.pop().unwrap() {
+                self.encoder.instruction(&Instruction::Else);
+            }
         } else {
             self.encoder.instruction(&Instruction::End);
         }
@@ -118,8 +130,9 @@ impl<'instr> Visitor<'instr> for Emit<'_> {
             // `if` block, we'll pop the `if` kind and push the `else`
             // kind. This allows us to maintain the `self.blocks.len() ==
             // self.block_kinds.len()` invariant.
-            IfElse(_) => {
+            IfElse(e) => {
                 self.block_kinds.push(BlockKind::If);
+                self.needs_else.push(self.func.needs_else(e.alternative));
                 true
             }
             _ => false,
@@ -584,11 +597,12 @@ impl<'instr> Visitor<'instr> for Emit<'_> {
             }
 
             Select(e) => match e.ty {
-                Some(ty) => Instruction::TypedSelect(ty.to_wasmencoder_type()),
+                Some(ty) => Instruction::TypedSelect(ty.to_encoder()),
                 None => Instruction::Select,
             },
 
             Unreachable(_) => Instruction::Unreachable,
+            Nop(_) => Instruction::Nop,
 
             Br(e) => Instruction::Br(self.branch_target(e.block)),
 
@@ -765,11 +779,7 @@ impl<'instr> Visitor<'instr> for Emit<'_> {
             TableGrow(e) => Instruction::TableGrow(self.indices.get_table_index(e.table)),
             TableSize(e) => Instruction::TableSize(self.indices.get_table_index(e.table)),
             TableFill(e) => Instruction::TableFill(self.indices.get_table_index(e.table)),
-            RefNull(e) => Instruction::RefNull(match &e.ty {
-                crate::ValType::Externref => wasm_encoder::HeapType::Extern,
-                crate::ValType::Funcref => wasm_encoder::HeapType::Func,
-                _ => unreachable!(),
-            }),
+            RefNull(e) => Instruction::RefNull(e.ty.to_encoder_heap()),
             RefIsNull(_) => Instruction::RefIsNull,
             RefFunc(e) => Instruction::RefFunc(self.indices.get_func_index(e.func)),
 
@@ -845,9 +855,7 @@ impl Emit<'_> {
     fn block_type(&self, ty: InstrSeqType) -> wasm_encoder::BlockType {
         match ty {
             InstrSeqType::Simple(None) => wasm_encoder::BlockType::Empty,
-            InstrSeqType::Simple(Some(ty)) => {
-                wasm_encoder::BlockType::Result(ty.to_wasmencoder_type())
-            }
+            InstrSeqType::Simple(Some(ty)) => wasm_encoder::BlockType::Result(ty.to_encoder()),
             InstrSeqType::MultiValue(ty) => {
                 wasm_encoder::BlockType::FunctionType(self.indices.get_type_index(ty))
             }
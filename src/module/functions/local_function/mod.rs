@@ -1,15 +1,26 @@
 //! Functions defined locally within a wasm module.
 
+mod calls;
 mod context;
+mod display;
 mod emit;
+mod reorder;
+mod returns;
+mod simplify;
+mod value_numbers;
 
+pub use self::calls::CallArgumentMismatch;
 use self::context::ValidationContext;
+pub use self::reorder::{ConflictReason, EffectLocation, ReorderConflict};
+pub use self::returns::ReturnTypeMismatch;
 use crate::emit::IdsToIndices;
 use crate::ir::*;
 use crate::map::{IdHashMap, IdHashSet};
 use crate::parse::IndicesToIds;
-use crate::{Data, DataId, FunctionBuilder, FunctionId, MemoryId, Module, Result, TypeId, ValType};
-use std::collections::BTreeMap;
+use crate::{Data, DataId, FunctionBuilder, FunctionId, MemoryId, Module, ModuleLocals, Result};
+use crate::{TypeId, ValType};
+use anyhow::bail;
+use std::collections::{BTreeMap, HashMap};
 use wasmparser::{FuncValidator, Operator, Range, ValidatorResources};
 
 /// A function defined locally within the wasm module.
@@ -28,6 +39,34 @@ pub struct LocalFunction {
     pub original_range: Option<Range>,
 }
 
+/// How the sequences of a `LocalFunction` are nested in one another.
+#[derive(Debug, Default)]
+pub(crate) struct Nesting {
+    /// The sequences that are the bodies of `loop`s.
+    pub(crate) loops: IdHashSet<InstrSeq>,
+    /// The sequence that each nested sequence is nested in.
+    parents: IdHashMap<InstrSeq, InstrSeqId>,
+}
+
+impl Nesting {
+    /// Whether `outer` is `inner`, or contains it however deeply nested.
+    pub(crate) fn encloses(&self, outer: InstrSeqId, inner: InstrSeqId) -> bool {
+        self.depth(outer, inner).is_some()
+    }
+
+    /// How many sequences out from `inner` `outer` is, which is the label a
+    /// branch in `inner` uses for it, or `None` if it doesn't enclose `inner`.
+    pub(crate) fn depth(&self, outer: InstrSeqId, inner: InstrSeqId) -> Option<usize> {
+        let mut seq = inner;
+        let mut depth = 0;
+        while seq != outer {
+            seq = *self.parents.get(&seq)?;
+            depth += 1;
+        }
+        Some(depth)
+    }
+}
+
 impl LocalFunction {
     /// Creates a new definition of a local function from its components.
     pub(crate) fn new(args: Vec<LocalId>, builder: FunctionBuilder) -> LocalFunction {
@@ -90,7 +129,7 @@ impl LocalFunction {
                 InstrLocId::new(pos as u32)
             };
             validator.op(pos, &inst)?;
-            append_instruction(&mut ctx, inst, loc);
+            append_instruction(&mut ctx, inst, loc)?;
             instruction_mapping.insert(pos - code_address_offset, loc);
         }
         ctx.func.instruction_mapping = instruction_mapping.into_iter().collect();
@@ -129,6 +168,17 @@ impl LocalFunction {
         &mut self.builder.arena[id]
     }
 
+    /// Whether an `if/else` with the given alternative is written with an
+    /// `else`, when emitted or displayed.
+    ///
+    /// An `if` without an `else` behaves like one with an empty alternative,
+    /// as long as the `if` has no results, so the `else` is only written for
+    /// alternatives that have instructions or results.
+    pub(crate) fn needs_else(&self, alternative: InstrSeqId) -> bool {
+        let alternative = self.block(alternative);
+        !alternative.instrs.is_empty() || alternative.ty != InstrSeqType::Simple(None)
+    }
+
     /// Get access to a `FunctionBuilder` to continue adding instructions to
     /// this function.
     pub fn builder(&self) -> &FunctionBuilder {
@@ -168,6 +218,728 @@ impl LocalFunction {
             .all(|(e, _)| e.is_const())
     }
 
+    /// Enumerate every exit that supplies the results of the given instruction
+    /// sequence.
+    ///
+    /// This includes the sequence's fallthrough, if its end is reachable, and
+    /// every `br`, `br_if`, and `br_table` that targets the sequence. Branches
+    /// to a `loop` jump to its start rather than supplying its results, so a
+    /// loop's only supplier is its fallthrough.
+    ///
+    /// An `if/else`'s results are supplied by the exits of both its
+    /// consequent and alternative sequences.
+    pub fn result_suppliers(&self, seq: InstrSeqId) -> Vec<ResultSupplier> {
+        let mut suppliers = Vec::new();
+
+        let fallthrough_reachable = !self
+            .block(seq)
+            .instrs
+            .iter()
+            .any(|(instr, _)| instr.following_instructions_are_unreachable());
+        if fallthrough_reachable {
+            suppliers.push(ResultSupplier::Fallthrough { seq });
+        }
+
+        let mut v = BranchesTo {
+            target: seq,
+            is_loop: false,
+            branches: Vec::new(),
+        };
+        dfs_in_order(&mut v, self, self.entry_block());
+        if !v.is_loop {
+            suppliers.extend(v.branches);
+        }
+        return suppliers;
+
+        struct BranchesTo {
+            target: InstrSeqId,
+            is_loop: bool,
+            branches: Vec<ResultSupplier>,
+        }
+
+        impl<'instr> Visitor<'instr> for BranchesTo {
+            fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+                for (index, (instr, _)) in seq.instrs.iter().enumerate() {
+                    let targets = match instr {
+                        Instr::Loop(Loop { seq }) => {
+                            self.is_loop |= *seq == self.target;
+                            false
+                        }
+                        Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => {
+                            *block == self.target
+                        }
+                        Instr::BrTable(BrTable { blocks, default }) => {
+                            *default == self.target || blocks.contains(&self.target)
+                        }
+                        _ => false,
+                    };
+                    if targets {
+                        self.branches.push(ResultSupplier::Branch {
+                            seq: seq.id(),
+                            index,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check that every result of every instruction sequence in this function
+    /// has a supplier.
+    ///
+    /// Each exit enumerated by `result_suppliers` must leave at least as many
+    /// values on the stack as the sequence has results. A sequence without
+    /// any suppliers never completes, so it has no results to supply. Exits
+    /// in dead code, after an instruction whose stack effect is polymorphic,
+    /// aren't checked.
+    pub fn check_result_suppliers(&self, module: &Module) -> Result<()> {
+        for seq in self.instr_seqs() {
+            let (_, results) = self.seq_arity(module, self.block(seq).ty);
+            if results == 0 {
+                continue;
+            }
+            'suppliers: for supplier in self.result_suppliers(seq) {
+                let (site, end, needed) = match supplier {
+                    ResultSupplier::Fallthrough { seq } => (seq, self.block(seq).len(), results),
+                    ResultSupplier::Branch { seq, index } => match self.block(seq)[index].0 {
+                        // The branch condition or table index sits on top of
+                        // the supplied values.
+                        Instr::BrIf(_) | Instr::BrTable(_) => (seq, index, results + 1),
+                        _ => (seq, index, results),
+                    },
+                };
+                let (mut height, _) = self.seq_arity(module, self.block(site).ty);
+                for (instr, _) in &self.block(site).instrs[..end] {
+                    match self.stack_effect(module, instr) {
+                        Some((pops, pushes)) => height = height.saturating_sub(pops) + pushes,
+                        None => continue 'suppliers,
+                    }
+                }
+                if height < needed {
+                    bail!(
+                        "{:?} supplies {} of the {} results of {:?}",
+                        supplier,
+                        height.saturating_sub(needed - results),
+                        results,
+                        seq
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the instruction that produced the `position`th result of `seq`
+    /// (counting from the first result) at the given supplier.
+    ///
+    /// This walks backwards from the supplier over instructions with a known
+    /// stack effect. Returns the instruction's sequence and index within that
+    /// sequence, or `None` if the value's origin can't be determined locally,
+    /// for example because it flows in from a block parameter or is produced
+    /// by an instruction whose stack effect is polymorphic.
+    pub fn supplied_value(
+        &self,
+        module: &Module,
+        seq: InstrSeqId,
+        supplier: ResultSupplier,
+        position: usize,
+    ) -> Option<(InstrSeqId, usize)> {
+        let (_, results) = self.seq_arity(module, self.block(seq).ty);
+        if position >= results {
+            return None;
+        }
+        let mut depth = results - position - 1;
+
+        let (site, end) = match supplier {
+            ResultSupplier::Fallthrough { seq } => (seq, self.block(seq).len()),
+            ResultSupplier::Branch { seq, index } => {
+                match self.block(seq).instrs.get(index)?.0 {
+                    // The branch condition or table index sits on top of the
+                    // supplied values.
+                    Instr::BrIf(_) | Instr::BrTable(_) => depth += 1,
+                    _ => {}
+                }
+                (seq, index)
+            }
+        };
+
+        let index = self.producer(module, site, end, depth)?;
+        Some((site, index))
+    }
+
+    /// Find the instruction in `seq` that pushed the value `depth` slots below
+    /// the top of the stack just before the instruction at `end`.
+    pub(crate) fn producer(
+        &self,
+        module: &Module,
+        seq: InstrSeqId,
+        end: usize,
+        mut depth: usize,
+    ) -> Option<usize> {
+        let instrs = &self.block(seq).instrs;
+        for i in (0..end).rev() {
+            let (pops, pushes) = self.stack_effect(module, &instrs[i].0)?;
+            if depth < pushes {
+                return Some(i);
+            }
+            depth = depth - pushes + pops;
+        }
+        None
+    }
+
+    /// Are the values pushed by the instructions at `a` and `b` in `seq`
+    /// computed by structurally identical expressions?
+    ///
+    /// Each instruction's operands are followed back to the instructions in
+    /// `seq` that produced them, which are compared in turn. Expressions
+    /// containing control flow, instructions that don't push exactly one
+    /// value, or operands flowing in from outside `seq` are never equal.
+    ///
+    /// With `commutative` set, the operands of commutative binary operators
+    /// may match in either order, so that `a + b` equals `b + a`.
+    ///
+    /// Note that equal expressions don't necessarily evaluate to the same
+    /// value, since state they read may change in between them.
+    pub fn exprs_equal(
+        &self,
+        module: &Module,
+        seq: InstrSeqId,
+        a: usize,
+        b: usize,
+        commutative: bool,
+    ) -> bool {
+        let instrs = &self.block(seq).instrs;
+        let instr = &instrs[a].0;
+        if *instr != instrs[b].0 {
+            return false;
+        }
+        match instr {
+            Instr::Block(_) | Instr::Loop(_) | Instr::IfElse(_) => return false,
+            _ => {}
+        }
+        let pops = match self.stack_effect(module, instr) {
+            Some((pops, 1)) => pops,
+            _ => return false,
+        };
+
+        let operands = |end| {
+            (0..pops)
+                .map(|depth| self.producer(module, seq, end, depth))
+                .collect::<Option<Vec<_>>>()
+        };
+        let (a, b) = match (operands(a), operands(b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return false,
+        };
+        let all_equal = |b: &[usize]| {
+            a.iter()
+                .zip(b)
+                .all(|(a, b)| self.exprs_equal(module, seq, *a, *b, commutative))
+        };
+
+        if all_equal(&b) {
+            return true;
+        }
+        match instr {
+            Instr::Binop(Binop { op }) if commutative && op.is_commutative() => {
+                all_equal(&[b[1], b[0]])
+            }
+            _ => false,
+        }
+    }
+
+    /// The number of parameters and results of an instruction sequence type.
+    fn seq_arity(&self, module: &Module, ty: InstrSeqType) -> (usize, usize) {
+        match ty {
+            InstrSeqType::Simple(None) => (0, 0),
+            InstrSeqType::Simple(Some(_)) => (0, 1),
+            InstrSeqType::MultiValue(ty) => {
+                let (params, results) = module.types.params_results(ty);
+                (params.len(), results.len())
+            }
+        }
+    }
+
+    /// The number of values an instruction pops from and pushes onto the
+    /// stack, or `None` if its effect is stack-polymorphic.
+    pub(crate) fn stack_effect(&self, module: &Module, instr: &Instr) -> Option<(usize, usize)> {
+        Some(match instr {
+            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                self.seq_arity(module, self.block(*seq).ty)
+            }
+            Instr::IfElse(IfElse { consequent, .. }) => {
+                let (params, results) = self.seq_arity(module, self.block(*consequent).ty);
+                (params + 1, results)
+            }
+            Instr::Call(Call { func }) => {
+                let (params, results) = module.types.params_results(module.funcs.get(*func).ty());
+                (params.len(), results.len())
+            }
+            Instr::CallIndirect(CallIndirect { ty, .. }) => {
+                let (params, results) = module.types.params_results(*ty);
+                (params.len() + 1, results.len())
+            }
+            Instr::Br(_)
+            | Instr::BrIf(_)
+            | Instr::BrTable(_)
+            | Instr::Return(_)
+            | Instr::Unreachable(_) => return None,
+
+            Instr::Const(_)
+            | Instr::LocalGet(_)
+            | Instr::GlobalGet(_)
+            | Instr::MemorySize(_)
+            | Instr::TableSize(_)
+            | Instr::RefNull(_)
+            | Instr::RefFunc(_) => (0, 1),
+            Instr::LocalSet(_) | Instr::GlobalSet(_) | Instr::Drop(_) => (1, 0),
+            Instr::LocalTee(_)
+            | Instr::Unop(_)
+            | Instr::Load(_)
+            | Instr::MemoryGrow(_)
+            | Instr::TableGet(_)
+            | Instr::RefIsNull(_) => (1, 1),
+            Instr::Binop(_)
+            | Instr::AtomicRmw(_)
+            | Instr::AtomicNotify(_)
+            | Instr::TableGrow(_)
+            | Instr::I8x16Swizzle(_)
+            | Instr::I8x16Shuffle(_) => (2, 1),
+            Instr::Store(_) | Instr::TableSet(_) => (2, 0),
+            Instr::Select(_)
+            | Instr::Cmpxchg(_)
+            | Instr::AtomicWait(_)
+            | Instr::V128Bitselect(_) => (3, 1),
+            Instr::MemoryInit(_)
+            | Instr::MemoryCopy(_)
+            | Instr::MemoryFill(_)
+            | Instr::TableFill(_)
+            | Instr::TableInit(_)
+            | Instr::TableCopy(_) => (3, 0),
+            Instr::DataDrop(_) | Instr::ElemDrop(_) | Instr::AtomicFence(_) | Instr::Nop(_) => {
+                (0, 0)
+            }
+            Instr::LoadSimd(LoadSimd { kind, .. }) => match kind {
+                LoadSimdKind::V128Load8Lane(_)
+                | LoadSimdKind::V128Load16Lane(_)
+                | LoadSimdKind::V128Load32Lane(_)
+                | LoadSimdKind::V128Load64Lane(_) => (2, 1),
+                LoadSimdKind::V128Store8Lane(_)
+                | LoadSimdKind::V128Store16Lane(_)
+                | LoadSimdKind::V128Store32Lane(_)
+                | LoadSimdKind::V128Store64Lane(_) => (2, 0),
+                _ => (1, 1),
+            },
+        })
+    }
+
+    /// Replace the instruction at `index` in `seq` with `new`, and return the
+    /// instruction that was there.
+    ///
+    /// This is for rewrites that change what an instruction does without
+    /// changing its place in the function, such as turning a `global.get` of
+    /// a constant global into an `*.const`. The instruction keeps its
+    /// location in the original binary.
+    ///
+    /// The replacement must pop as many values, and push as many values of
+    /// the same types, as the instruction it replaces, and accept the types
+    /// of the operands on the stack there, so that the code around it stays
+    /// well-typed. Types that can't be determined without full type
+    /// inference, such as the results of atomic operations, are assumed to
+    /// match. Branches, `return` and `unreachable` can only be replaced with
+    /// an instruction of the same kind, since they change control flow. If
+    /// the replacement isn't compatible, the function is left untouched and
+    /// an error is returned.
+    ///
+    /// Panics if `index` is out of bounds for `seq`.
+    pub fn replace_in_place(
+        &mut self,
+        module: &Module,
+        seq: InstrSeqId,
+        index: usize,
+        new: impl Into<Instr>,
+    ) -> Result<Instr> {
+        let new = new.into();
+        let old = &self.block(seq).instrs[index].0;
+        match (
+            self.stack_effect(module, old),
+            self.stack_effect(module, &new),
+        ) {
+            (None, None) if std::mem::discriminant(old) == std::mem::discriminant(&new) => {}
+            (None, None) => bail!(
+                "cannot replace instruction {} of {:?}: it changes control flow differently \
+                 than its replacement",
+                index,
+                seq
+            ),
+            (Some((old_pops, old_pushes)), Some((new_pops, new_pushes)))
+                if old_pops == new_pops && old_pushes == new_pushes =>
+            {
+                // The operands the instruction pops, as far as they can be
+                // told without full type inference.
+                let popped = match self.operand_types(module, seq, index) {
+                    Ok(mut stack) if stack.len() >= old_pops => {
+                        stack.split_off(stack.len() - old_pops)
+                    }
+                    _ => vec![None; old_pops],
+                };
+                // Unknown types are assumed to agree with anything.
+                let agree = |a: &[Option<ValType>], b: &[Option<ValType>]| {
+                    a.iter().zip(b).all(|pair| match pair {
+                        (Some(a), Some(b)) => a == b,
+                        _ => true,
+                    })
+                };
+                let accepted = self.popped_types(module, &new);
+                if !agree(&popped, &accepted) {
+                    bail!(
+                        "cannot replace instruction {} of {:?}: its operands are {:?}, but its \
+                         replacement pops {:?}",
+                        index,
+                        seq,
+                        popped,
+                        accepted
+                    );
+                }
+
+                let old_tys = self.pushed_types(module, old, &popped);
+                let new_tys = self.pushed_types(module, &new, &popped);
+                if !agree(&old_tys, &new_tys) {
+                    bail!(
+                        "cannot replace instruction {} of {:?}: it pushes {:?}, but its \
+                         replacement pushes {:?}",
+                        index,
+                        seq,
+                        old_tys,
+                        new_tys
+                    );
+                }
+            }
+            (old_effect, new_effect) => bail!(
+                "cannot replace instruction {} of {:?}: its stack effect is {:?}, but its \
+                 replacement's is {:?}",
+                index,
+                seq,
+                old_effect,
+                new_effect
+            ),
+        }
+        Ok(std::mem::replace(
+            &mut self.block_mut(seq).instrs[index].0,
+            new,
+        ))
+    }
+
+    /// The types of the values that `seq`'s instructions leave on the stack,
+    /// starting from the sequence's parameters.
+    ///
+    /// Nested sequences are assumed to have the types they are declared
+    /// with. An error is returned if an instruction pops more values than
+    /// there are on the stack, if the sequence ends in stack-polymorphic code
+    /// such as `br` or `unreachable`, or if the type of a remaining value
+    /// can't be determined without full type inference, as is the case for
+    /// the results of atomic operations.
+    pub fn inferred_results(&self, module: &Module, seq: InstrSeqId) -> Result<Vec<ValType>> {
+        let len = self.block(seq).instrs.len();
+        self.operand_types(module, seq, len)?
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| match ty {
+                Some(ty) => Ok(*ty),
+                None => bail!(
+                    "cannot infer the results of {:?}: the type of result {} is unknown",
+                    seq,
+                    i
+                ),
+            })
+            .collect()
+    }
+
+    /// The types of the values on the stack right before instruction `end`
+    /// of `seq`, starting from the sequence's parameters, with `None` for
+    /// those that can't be determined without full type inference.
+    fn operand_types(
+        &self,
+        module: &Module,
+        seq: InstrSeqId,
+        end: usize,
+    ) -> Result<Vec<Option<ValType>>> {
+        let mut stack = match self.block(seq).ty {
+            InstrSeqType::Simple(_) => Vec::new(),
+            InstrSeqType::MultiValue(ty) => {
+                module.types.params(ty).iter().map(|ty| Some(*ty)).collect()
+            }
+        };
+        for (index, (instr, _)) in self.block(seq).instrs[..end].iter().enumerate() {
+            let pops = match self.stack_effect(module, instr) {
+                Some((pops, _)) => pops,
+                None => bail!(
+                    "cannot infer the stack of {:?}: instruction {} is stack-polymorphic",
+                    seq,
+                    index
+                ),
+            };
+            if pops > stack.len() {
+                bail!(
+                    "cannot infer the stack of {:?}: instruction {} pops {} values, but \
+                     only {} are on the stack",
+                    seq,
+                    index,
+                    pops,
+                    stack.len()
+                );
+            }
+            let popped = stack.split_off(stack.len() - pops);
+            stack.extend(self.pushed_types(module, instr, &popped));
+        }
+        Ok(stack)
+    }
+
+    /// The types of the values that a branch to `seq` carries: a `loop`'s
+    /// parameters, since branching to it starts it over, and the results of
+    /// every other sequence.
+    pub fn branch_target_types<'a>(&'a self, module: &'a Module, seq: InstrSeqId) -> &'a [ValType] {
+        self.label_types(module, seq, &self.nesting().loops)
+    }
+
+    /// `branch_target_types`, with the function's loops already collected, so
+    /// that asking about many branches only walks the function once.
+    pub(crate) fn label_types<'a>(
+        &'a self,
+        module: &'a Module,
+        seq: InstrSeqId,
+        loops: &IdHashSet<InstrSeq>,
+    ) -> &'a [ValType] {
+        let is_loop = loops.contains(&seq);
+        match &self.block(seq).ty {
+            InstrSeqType::Simple(_) if is_loop => &[],
+            InstrSeqType::Simple(Some(ty)) => std::slice::from_ref(ty),
+            InstrSeqType::Simple(None) => &[],
+            InstrSeqType::MultiValue(ty) if is_loop => module.types.params(*ty),
+            InstrSeqType::MultiValue(ty) => module.types.results(*ty),
+        }
+    }
+
+    /// The sequences that are the bodies of `loop`s, and the sequence that
+    /// each nested sequence is nested in, from one walk over the function.
+    pub(crate) fn nesting(&self) -> Nesting {
+        let mut nesting = Nesting::default();
+        for (id, block) in self.builder.arena.iter() {
+            for (instr, _) in &block.instrs {
+                match instr {
+                    Instr::Block(Block { seq }) => {
+                        nesting.parents.insert(*seq, id);
+                    }
+                    Instr::Loop(Loop { seq }) => {
+                        nesting.loops.insert(*seq);
+                        nesting.parents.insert(*seq, id);
+                    }
+                    Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    }) => {
+                        nesting.parents.insert(*consequent, id);
+                        nesting.parents.insert(*alternative, id);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        nesting
+    }
+
+    /// Make the `br` or `br_if` at `index` in `seq` branch to `target`
+    /// instead.
+    ///
+    /// Returns an error, without changing anything, if the instruction isn't
+    /// a `br` or `br_if`, if `target` isn't `seq` or a sequence enclosing it,
+    /// or if the values on the stack before the branch don't have the types
+    /// in `branch_target_types` for `target`.
+    pub fn retarget_branch(
+        &mut self,
+        module: &Module,
+        seq: InstrSeqId,
+        index: usize,
+        target: InstrSeqId,
+    ) -> Result<()> {
+        let is_br_if = match self.block(seq).instrs.get(index) {
+            Some((Instr::Br(_), _)) => false,
+            Some((Instr::BrIf(_), _)) => true,
+            _ => bail!(
+                "instruction {} of {:?} is not a `br` or `br_if`",
+                index,
+                seq
+            ),
+        };
+        let nesting = self.nesting();
+        if !nesting.encloses(target, seq) {
+            bail!(
+                "cannot branch to {:?} from {:?}, which it doesn't enclose",
+                target,
+                seq
+            );
+        }
+        let mut stack = self.operand_types(module, seq, index)?;
+        if is_br_if {
+            // The condition.
+            stack.pop();
+        }
+        let expected = self.label_types(module, target, &nesting.loops);
+        let got = &stack[stack.len().saturating_sub(expected.len())..];
+        if !returns::types_match(got, expected) {
+            bail!(
+                "cannot branch to {:?} with {:?} on the stack, it expects {:?}",
+                target,
+                got,
+                expected
+            );
+        }
+        match &mut self.block_mut(seq).instrs[index].0 {
+            Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => *block = target,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Replace all of the instructions of `seq` with `instrs`, and return the
+    /// old ones. The sequence's preamble is dropped along with them.
+    ///
+    /// In debug builds, the sequences nested in the old instructions that
+    /// aren't reachable anymore are deleted, so that using them by mistake
+    /// panics rather than quietly emitting stale code. Include them in
+    /// `instrs` to keep them.
+    ///
+    /// Panics if `instrs` are invalid; see `try_replace_block_instrs`.
+    pub fn replace_block_instrs(
+        &mut self,
+        seq: InstrSeqId,
+        instrs: Vec<(Instr, InstrLocId)>,
+    ) -> Vec<(Instr, InstrLocId)> {
+        self.try_replace_block_instrs(seq, instrs)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `replace_block_instrs`, but returns an error, and leaves the
+    /// function untouched, if `instrs` refer to a sequence that isn't part of
+    /// this function, or nest a sequence that is already nested elsewhere in
+    /// the function, or `seq` itself.
+    pub fn try_replace_block_instrs(
+        &mut self,
+        seq: InstrSeqId,
+        instrs: Vec<(Instr, InstrLocId)>,
+    ) -> Result<Vec<(Instr, InstrLocId)>> {
+        let nested_before = self.reachable_seqs(seq);
+
+        // With `seq` emptied, every sequence that is still reachable is
+        // nested somewhere else.
+        let preamble_len = self.block(seq).preamble_len();
+        self.block_mut(seq).set_preamble_len(0);
+        let old = std::mem::take(&mut self.block_mut(seq).instrs);
+        let elsewhere = self.reachable_seqs(self.entry_block());
+        let mut refs = SeqRefs::default();
+        for (instr, _) in instrs.iter() {
+            instr.visit(&mut refs);
+        }
+        let invalid = refs
+            .all
+            .iter()
+            .find(|id| !self.builder.arena.contains(**id))
+            .map(|id| format!("{:?} is not a sequence of this function", id))
+            .or_else(|| {
+                refs.nested
+                    .iter()
+                    .find(|id| **id == seq || elsewhere.contains(id))
+                    .map(|id| format!("{:?} is already nested elsewhere", id))
+            });
+        if let Some(invalid) = invalid {
+            self.block_mut(seq).instrs = old;
+            self.block_mut(seq).set_preamble_len(preamble_len);
+            bail!("cannot replace the instructions of {:?}: {}", seq, invalid);
+        }
+        self.block_mut(seq).instrs = instrs;
+
+        if cfg!(debug_assertions) {
+            let mut reachable = self.reachable_seqs(self.entry_block());
+            reachable.extend(self.reachable_seqs(seq));
+            for id in nested_before {
+                if !reachable.contains(&id) {
+                    self.builder.arena.delete(id);
+                }
+            }
+        }
+        return Ok(old);
+
+        #[derive(Default)]
+        struct SeqRefs {
+            all: Vec<InstrSeqId>,
+            nested: Vec<InstrSeqId>,
+        }
+
+        impl<'instr> Visitor<'instr> for SeqRefs {
+            fn visit_instr_seq_id(&mut self, id: &InstrSeqId) {
+                self.all.push(*id);
+            }
+
+            fn visit_block(&mut self, instr: &Block) {
+                self.nested.push(instr.seq);
+            }
+
+            fn visit_loop(&mut self, instr: &Loop) {
+                self.nested.push(instr.seq);
+            }
+
+            fn visit_if_else(&mut self, instr: &IfElse) {
+                self.nested.push(instr.consequent);
+                self.nested.push(instr.alternative);
+            }
+        }
+    }
+
+    /// Take each sequence's preamble as it is now, after a pass rewrote
+    /// instructions within preambles in place.
+    pub(crate) fn keep_rewritten_preambles(&mut self) {
+        for seq in self.instr_seqs() {
+            let block = self.block_mut(seq);
+            block.set_preamble_len(block.preamble_len());
+        }
+    }
+
+    /// Every sequence of this function, depth first from the entry block.
+    pub(crate) fn instr_seqs(&self) -> Vec<InstrSeqId> {
+        let mut v = Seqs::default();
+        dfs_in_order(&mut v, self, self.entry_block());
+        return v.seqs;
+
+        #[derive(Default)]
+        struct Seqs {
+            seqs: Vec<InstrSeqId>,
+        }
+
+        impl<'instr> Visitor<'instr> for Seqs {
+            fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+                self.seqs.push(seq.id());
+            }
+        }
+    }
+
+    /// The sequences reachable from `seq`, including itself.
+    fn reachable_seqs(&self, seq: InstrSeqId) -> IdHashSet<InstrSeq> {
+        let mut v = Seqs::default();
+        dfs_in_order(&mut v, self, seq);
+        return v.seqs;
+
+        #[derive(Default)]
+        struct Seqs {
+            seqs: IdHashSet<InstrSeq>,
+        }
+
+        impl<'instr> Visitor<'instr> for Seqs {
+            fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+                self.seqs.insert(seq.id());
+            }
+        }
+    }
+
     /// Collect the set of data segments that are used in this function via
     /// `memory.init` or `data.drop` instructions.
     pub fn used_data_segments(&self) -> IdHashSet<Data> {
@@ -187,7 +959,137 @@ impl LocalFunction {
         }
     }
 
-    fn used_locals(&self) -> IdHashSet<Local> {
+    /// Collect the set of instruction sequences that are the target of some
+    /// `br`, `br_if` or `br_table` in this function.
+    pub fn branch_targets(&self) -> IdHashSet<InstrSeq> {
+        let mut visitor = BranchTargets::default();
+        dfs_in_order(&mut visitor, self, self.entry_block());
+        return visitor.targets;
+
+        #[derive(Default)]
+        struct BranchTargets {
+            targets: IdHashSet<InstrSeq>,
+        }
+
+        impl<'instr> Visitor<'instr> for BranchTargets {
+            fn visit_br(&mut self, instr: &Br) {
+                self.targets.insert(instr.block);
+            }
+
+            fn visit_br_if(&mut self, instr: &BrIf) {
+                self.targets.insert(instr.block);
+            }
+
+            fn visit_br_table(&mut self, instr: &BrTable) {
+                self.targets.extend(instr.blocks.iter().cloned());
+                self.targets.insert(instr.default);
+            }
+        }
+    }
+
+    /// Does this function never return to its caller?
+    ///
+    /// That is the case when no path through the function reaches a
+    /// `return`, a branch to the function's body, or the end of the body: every
+    /// path either traps or loops forever. Calls are assumed to return, even
+    /// if the called function diverges itself.
+    pub fn diverges(&self) -> bool {
+        let mut exits = Exits::default();
+        let falls_through = exits.seq(self, self.entry_block());
+        return !(falls_through || exits.returns || exits.targets.contains(&self.entry_block()));
+
+        #[derive(Default)]
+        struct Exits {
+            /// The sequences targeted by reachable branches.
+            targets: IdHashSet<InstrSeq>,
+            /// Is a `return` reachable?
+            returns: bool,
+        }
+
+        impl Exits {
+            /// Follow the reachable code in `seq`, returning whether control
+            /// can reach its end.
+            fn seq(&mut self, func: &LocalFunction, seq: InstrSeqId) -> bool {
+                for (instr, _) in func.block(seq).instrs.iter() {
+                    let completes = match instr {
+                        Instr::Block(Block { seq }) => {
+                            self.seq(func, *seq) || self.targets.contains(seq)
+                        }
+                        // Branches to a loop start it over.
+                        Instr::Loop(Loop { seq }) => self.seq(func, *seq),
+                        Instr::IfElse(IfElse {
+                            consequent,
+                            alternative,
+                        }) => {
+                            let consequent_completes = self.seq(func, *consequent);
+                            let alternative_completes = self.seq(func, *alternative);
+                            consequent_completes
+                                || alternative_completes
+                                || self.targets.contains(consequent)
+                                || self.targets.contains(alternative)
+                        }
+                        Instr::Br(Br { block }) => {
+                            self.targets.insert(*block);
+                            false
+                        }
+                        Instr::BrIf(BrIf { block }) => {
+                            self.targets.insert(*block);
+                            true
+                        }
+                        Instr::BrTable(BrTable { blocks, default }) => {
+                            self.targets.extend(blocks.iter().cloned());
+                            self.targets.insert(*default);
+                            false
+                        }
+                        Instr::Return(_) => {
+                            self.returns = true;
+                            false
+                        }
+                        Instr::Unreachable(_) => false,
+                        _ => true,
+                    };
+                    if !completes {
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Get the number of locals in this function, including its parameters.
+    ///
+    /// Locals that are declared but never used aren't counted, as they
+    /// aren't emitted either.
+    pub fn local_count(&self) -> usize {
+        self.all_locals().len()
+    }
+
+    /// Count this function's locals, including its parameters, by type.
+    pub fn local_type_histogram(&self, locals: &ModuleLocals) -> HashMap<ValType, usize> {
+        let mut histogram = HashMap::new();
+        for local in self.all_locals() {
+            *histogram.entry(locals.get(local).ty()).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Does this function have any locals, including its parameters, of the
+    /// given type?
+    pub fn has_locals_of_type(&self, locals: &ModuleLocals, ty: ValType) -> bool {
+        self.all_locals()
+            .into_iter()
+            .any(|local| locals.get(local).ty() == ty)
+    }
+
+    /// The function's parameters along with every other local it uses.
+    fn all_locals(&self) -> IdHashSet<Local> {
+        let mut locals = self.used_locals();
+        locals.extend(self.args.iter().cloned());
+        locals
+    }
+
+    pub(crate) fn used_locals(&self) -> IdHashSet<Local> {
         let mut locals = Used::default();
         dfs_in_order(&mut locals, self, self.entry_block());
         return locals.locals;
@@ -257,7 +1159,7 @@ impl LocalFunction {
         (
             ty_to_locals
                 .iter()
-                .map(|(ty, locals)| (locals.len() as u32, ty.to_wasmencoder_type()))
+                .map(|(ty, locals)| (locals.len() as u32, ty.to_encoder()))
                 .collect(),
             used_set,
             local_map,
@@ -302,11 +1204,61 @@ fn block_param_tys(
     }
 }
 
+/// Replace the `block` or `loop` that just ended with its only instruction,
+/// if it has exactly one and isn't the target of a branch.
+fn flatten_trivial_block(ctx: &mut ValidationContext, seq: InstrSeqId) {
+    // The block's instruction is only in its parent if the parent is
+    // reachable, and it is the last instruction there.
+    let parent = match ctx.controls.last() {
+        Some(frame) if !frame.unreachable => frame.block,
+        _ => return,
+    };
+    match ctx.func.block(parent).instrs.last() {
+        Some((Instr::Block(Block { seq: s }), _)) | Some((Instr::Loop(Loop { seq: s }), _))
+            if *s == seq => {}
+        _ => return,
+    }
+    match &ctx.func.block(seq).instrs[..] {
+        [(instr, _)] if !instr.following_instructions_are_unreachable() => {}
+        _ => return,
+    }
+    let mut v = BranchesTo {
+        target: seq,
+        found: false,
+    };
+    dfs_in_order(&mut v, ctx.func, seq);
+    if v.found {
+        return;
+    }
+
+    let instr = ctx.func.block_mut(seq).instrs.pop().unwrap();
+    *ctx.func.block_mut(parent).instrs.last_mut().unwrap() = instr;
+
+    struct BranchesTo {
+        target: InstrSeqId,
+        found: bool,
+    }
+
+    impl<'instr> Visitor<'instr> for BranchesTo {
+        fn visit_br(&mut self, instr: &Br) {
+            self.found |= instr.block == self.target;
+        }
+
+        fn visit_br_if(&mut self, instr: &BrIf) {
+            self.found |= instr.block == self.target;
+        }
+
+        fn visit_br_table(&mut self, instr: &BrTable) {
+            self.found |= instr.default == self.target || instr.blocks.contains(&self.target);
+        }
+    }
+}
+
 fn append_instruction<'context>(
     ctx: &'context mut ValidationContext,
     inst: Operator,
     loc: InstrLocId,
-) {
+) -> Result<()> {
     // NB. there's a lot of `unwrap()` here in this function, and that's because
     // the `Operator` was validated above to already be valid, so everything
     // should succeed.
@@ -551,7 +1503,7 @@ fn append_instruction<'context>(
         Operator::Drop => ctx.alloc_instr(Drop {}, loc),
         Operator::Select => ctx.alloc_instr(Select { ty: None }, loc),
         Operator::TypedSelect { ty } => {
-            let ty = ValType::parse(&ty).unwrap();
+            let ty = ValType::from_parser(&ty).unwrap();
             ctx.alloc_instr(Select { ty: Some(ty) }, loc);
         }
         Operator::Return => {
@@ -631,6 +1583,9 @@ fn append_instruction<'context>(
                         loc,
                     );
                 }
+                BlockKind::Block | BlockKind::Loop if ctx.module.config.flatten_trivial_blocks => {
+                    flatten_trivial_block(ctx, frame.block);
+                }
                 _ => {}
             }
         }
@@ -667,11 +1622,22 @@ fn append_instruction<'context>(
         }
 
         Operator::BrTable { table } => {
+            // The number of targets is untrusted, but `wasmparser` has already
+            // checked that it is within its limit on `br_table` sizes, and
+            // that the body holds that many targets, so it is safe to
+            // allocate for them up front.
             let mut blocks = Vec::with_capacity(table.len());
             let mut default = None;
+            let mut arity = None;
             for pair in table.targets() {
                 let (target, is_default) = pair.unwrap();
                 let control = ctx.control(target as usize).unwrap();
+                let label_arity = control.label_types().len();
+                debug_assert!(
+                    arity.map_or(true, |arity| arity == label_arity),
+                    "`br_table` targets carry different numbers of values"
+                );
+                arity = Some(label_arity);
                 if is_default {
                     default = Some(control.block);
                 } else {
@@ -997,7 +1963,7 @@ fn append_instruction<'context>(
             ctx.alloc_instr(TableFill { table }, loc);
         }
         Operator::RefNull { ty } => {
-            let ty = ValType::parse(&ty).unwrap();
+            let ty = ValType::from_parser(&ty).unwrap();
             ctx.alloc_instr(RefNull { ty }, loc);
         }
         Operator::RefIsNull => {
@@ -1310,15 +2276,489 @@ fn append_instruction<'context>(
             ctx.alloc_instr(ElemDrop { elem }, loc);
         }
 
-        Operator::ReturnCall { .. }
-        | Operator::ReturnCallIndirect { .. }
-        | Operator::Try { ty: _ }
+        Operator::ReturnCall { .. } | Operator::ReturnCallIndirect { .. } => {
+            bail!("tail calls are not supported")
+        }
+
+        Operator::Try { ty: _ }
         | Operator::Catch { index: _ }
         | Operator::Throw { index: _ }
         | Operator::Rethrow { relative_depth: _ }
         | Operator::Delegate { relative_depth: _ }
         | Operator::CatchAll => {
-            unimplemented!("not supported")
+            bail!("exception handling is not supported")
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    /// A module with a single function whose body is `block`, `i32.const 0`,
+    /// then a `br_table` declaring `count` targets but holding only two, and
+    /// the `end`s.
+    fn br_table_module(count: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x00, 0x02, 0x40, 0x41, 0x00, 0x0e];
+        body.extend_from_slice(count);
+        body.extend_from_slice(&[0x00, 0x00, 0x0b, 0x0b]);
+
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        wasm.extend_from_slice(&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+        wasm.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+        wasm.extend_from_slice(&[0x0a, body.len() as u8 + 2, 0x01, body.len() as u8]);
+        wasm.extend_from_slice(&body);
+        wasm
+    }
+
+    #[test]
+    fn rejects_oversized_br_table() {
+        // One target and the default.
+        Module::from_buffer(&br_table_module(&[0x01])).unwrap();
+
+        // More targets than the body has bytes for.
+        assert!(Module::from_buffer(&br_table_module(&[0x64])).is_err());
+
+        // A billion targets.
+        let billion = [0x80, 0x94, 0xeb, 0xdc, 0x03];
+        assert!(Module::from_buffer(&br_table_module(&billion)).is_err());
+    }
+
+    #[test]
+    fn replace_block_instrs() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut inner = None;
+        let mut kept = None;
+        builder
+            .func_body()
+            .block(None, |block| {
+                inner = Some(block.id());
+                block.nop();
+            })
+            .block(None, |block| {
+                kept = Some(block.id());
+                block.nop();
+            });
+        let mut func = builder.local_func(vec![]);
+        let (inner, kept) = (inner.unwrap(), kept.unwrap());
+        let entry = func.entry_block();
+
+        // Sequences of other functions, and sequences that are already nested
+        // elsewhere, can't be used.
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let other = builder.dangling_instr_seq(None).id();
+        let nested = |seq| vec![(Instr::Block(Block { seq }), InstrLocId::default())];
+        assert!(func.try_replace_block_instrs(entry, nested(other)).is_err());
+        assert!(func.try_replace_block_instrs(inner, nested(kept)).is_err());
+        assert!(func.try_replace_block_instrs(inner, nested(inner)).is_err());
+        assert_eq!(func.block(entry).len(), 2);
+
+        let old = func.replace_block_instrs(entry, nested(kept));
+        assert_eq!(old.len(), 2);
+        assert_eq!(func.block(entry).instrs, nested(kept));
+        assert_eq!(func.block(kept).len(), 1);
+        assert_eq!(func.builder.arena.contains(inner), !cfg!(debug_assertions));
+    }
+
+    #[test]
+    fn replace_in_place() {
+        let mut module = Module::default();
+        let x = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder
+            .func_body()
+            .local_get(x)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add);
+        let mut func = builder.local_func(vec![x]);
+        let entry = func.entry_block();
+
+        // A value of the same type can take the `local.get`'s place.
+        let five = Const {
+            value: Value::I32(5),
+        };
+        let old = func
+            .replace_in_place(&module, entry, 0, five.clone())
+            .unwrap();
+        assert_eq!(old, Instr::LocalGet(LocalGet { local: x }));
+        assert_eq!(func.block(entry)[0].0, Instr::Const(five.clone()));
+
+        // So can another operator with the same result type.
+        let sub = Binop {
+            op: BinaryOp::I32Sub,
+        };
+        func.replace_in_place(&module, entry, 2, sub.clone())
+            .unwrap();
+
+        // But a value of another type, or an instruction with another stack
+        // effect, can't.
+        let float = Const {
+            value: Value::F32(5.0),
+        };
+        assert!(func.replace_in_place(&module, entry, 0, float).is_err());
+        assert!(func.replace_in_place(&module, entry, 0, Drop {}).is_err());
+        assert!(func
+            .replace_in_place(&module, entry, 2, Unreachable {})
+            .is_err());
+        // Nor can one that takes other operands, even if it pushes the same.
+        let eq = Binop {
+            op: BinaryOp::F32Eq,
+        };
+        assert!(func.replace_in_place(&module, entry, 2, eq).is_err());
+        assert_eq!(func.block(entry)[0].0, Instr::Const(five));
+        assert_eq!(func.block(entry)[2].0, Instr::Binop(sub));
+    }
+
+    #[test]
+    fn replace_in_place_keeps_control_flow() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let outer = builder.func_body_id();
+        let mut inner = None;
+        builder.func_body().block(None, |block| {
+            inner = Some(block.id());
+            block.br(outer).unreachable();
+        });
+        let mut func = builder.local_func(vec![]);
+        let inner = inner.unwrap();
+
+        // A branch can be retargeted, but not turned into a conditional one,
+        // and a trap can't become a return.
+        func.replace_in_place(&module, inner, 0, Br { block: inner })
+            .unwrap();
+        assert!(func
+            .replace_in_place(&module, inner, 0, BrIf { block: inner })
+            .is_err());
+        assert!(func.replace_in_place(&module, inner, 1, Return {}).is_err());
+        assert_eq!(func.block(inner)[0].0, Instr::Br(Br { block: inner }));
+        assert_eq!(func.block(inner)[1].0, Instr::Unreachable(Unreachable {}));
+    }
+
+    #[test]
+    fn if_else_result_suppliers() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(0).if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(1);
+            },
+            |else_| {
+                else_.i32_const(2);
+            },
+        );
+        let func = builder.local_func(vec![]);
+        let (consequent, alternative) = match func.block(func.entry_block()).last() {
+            Some((Instr::IfElse(e), _)) => (e.consequent, e.alternative),
+            _ => panic!("expected an if/else"),
+        };
+
+        for &arm in [consequent, alternative].iter() {
+            let suppliers = func.result_suppliers(arm);
+            assert_eq!(suppliers, vec![ResultSupplier::Fallthrough { seq: arm }]);
+            assert_eq!(
+                func.supplied_value(&module, arm, suppliers[0], 0),
+                Some((arm, 0))
+            );
+        }
+        match func.block(alternative)[0].0 {
+            Instr::Const(Const {
+                value: Value::I32(2),
+            }) => {}
+            ref other => panic!("unexpected instruction: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn br_table_result_suppliers() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let index = module.locals.add(ValType::I32);
+        let mut ids = None;
+        builder.func_body().block(ValType::I32, |outer| {
+            let outer_id = outer.id();
+            outer
+                .block(ValType::I32, |middle| {
+                    let middle_id = middle.id();
+                    middle
+                        .block(ValType::I32, |inner| {
+                            let inner_id = inner.id();
+                            inner
+                                .i32_const(7)
+                                .local_get(index)
+                                .br_table(vec![inner_id, middle_id].into(), outer_id);
+                            ids = Some((outer_id, middle_id, inner_id));
+                        })
+                        .i32_const(1)
+                        .binop(BinaryOp::I32Add);
+                })
+                .i32_const(2)
+                .binop(BinaryOp::I32Add);
+        });
+        let func = builder.local_func(vec![index]);
+        let (outer, middle, inner) = ids.unwrap();
+        let br_table = ResultSupplier::Branch {
+            seq: inner,
+            index: 2,
+        };
+
+        // The inner block never falls through, so the `br_table` is its only
+        // supplier.
+        assert_eq!(func.result_suppliers(inner), vec![br_table]);
+        for &seq in [outer, middle].iter() {
+            let suppliers = func.result_suppliers(seq);
+            assert_eq!(
+                suppliers,
+                vec![ResultSupplier::Fallthrough { seq }, br_table]
+            );
+            assert_eq!(
+                func.supplied_value(&module, seq, suppliers[0], 0),
+                Some((seq, 2))
+            );
+        }
+
+        // Every arm of the `br_table` is supplied by the `i32.const 7`.
+        for &seq in [outer, middle, inner].iter() {
+            assert_eq!(
+                func.supplied_value(&module, seq, br_table, 0),
+                Some((inner, 0))
+            );
+        }
+    }
+
+    #[test]
+    fn commutative_exprs_equal() {
+        let mut module = Module::default();
+        let x = module.locals.add(ValType::I32);
+        let y = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[]);
+        builder
+            .func_body()
+            .local_get(x)
+            .local_get(y)
+            .binop(BinaryOp::I32Add)
+            .local_get(y)
+            .local_get(x)
+            .binop(BinaryOp::I32Add)
+            .local_get(x)
+            .local_get(y)
+            .binop(BinaryOp::I32Sub)
+            .local_get(y)
+            .local_get(x)
+            .binop(BinaryOp::I32Sub);
+        let func = builder.local_func(vec![x, y]);
+        let entry = func.entry_block();
+
+        // `x + y` and `y + x`
+        assert!(func.exprs_equal(&module, entry, 2, 5, true));
+        assert!(!func.exprs_equal(&module, entry, 2, 5, false));
+        // `x - y` and `y - x`
+        assert!(!func.exprs_equal(&module, entry, 8, 11, true));
+        // `x + y` and itself
+        assert!(func.exprs_equal(&module, entry, 2, 2, false));
+    }
+
+    #[test]
+    fn diverges() {
+        let mut module = Module::default();
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().unreachable();
+        let traps = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().loop_(None, |body| {
+            let id = body.id();
+            body.br(id);
+        });
+        let loops = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(1);
+        let normal = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .block(None, |block| {
+                let id = block.id();
+                block.i32_const(0).br_if(id).unreachable();
+            })
+            .unreachable();
+        let escapes_block = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(0).if_else(
+            None,
+            |then| {
+                then.return_();
+            },
+            |else_| {
+                else_.unreachable();
+            },
+        );
+        let returns_early = builder.finish(vec![], &mut module.funcs);
+
+        let diverges = |func| module.funcs.get(func).kind.unwrap_local().diverges();
+        assert!(diverges(traps));
+        assert!(diverges(loops));
+        assert!(!diverges(normal));
+        assert!(diverges(escapes_block));
+        assert!(!diverges(returns_early));
+    }
+
+    #[test]
+    fn local_stats() {
+        let mut module = Module::default();
+        let arg = module.locals.add(ValType::I32);
+        let a = module.locals.add(ValType::I64);
+        let b = module.locals.add(ValType::I64);
+        // Declared, but never used by the function.
+        module.locals.add(ValType::F32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder
+            .func_body()
+            .local_get(a)
+            .local_set(b)
+            .local_get(a)
+            .local_set(a);
+        let func = builder.finish(vec![arg], &mut module.funcs);
+        let func = module.funcs.get(func).kind.unwrap_local();
+
+        assert_eq!(func.local_count(), 3);
+        let histogram = func.local_type_histogram(&module.locals);
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram[&ValType::I32], 1);
+        assert_eq!(histogram[&ValType::I64], 2);
+        assert!(func.has_locals_of_type(&module.locals, ValType::I64));
+        assert!(!func.has_locals_of_type(&module.locals, ValType::F32));
+    }
+
+    #[test]
+    fn v128_const_is_little_endian() {
+        let alternating = 0x00000000_ffffffff_00000000_ffffffff;
+        for &value in [
+            u128::MAX,
+            alternating,
+            0x0f0e0d0c_0b0a0908_07060504_03020100,
+        ]
+        .iter()
+        {
+            let mut module = Module::default();
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::V128]);
+            builder.func_body().v128_const(value);
+            builder.finish(vec![], &mut module.funcs);
+
+            // `v128.const` is `0xfd 0x0c` followed by its 16 immediate bytes,
+            // least significant first.
+            let wasm = module.emit_wasm();
+            let mut expected = vec![0xfd, 0x0c];
+            expected.extend_from_slice(&value.to_le_bytes());
+            assert!(wasm.windows(18).any(|w| w == &expected[..]));
+
+            let module = Module::from_buffer(&wasm).unwrap();
+            let (_, func) = module.funcs.iter_local().next().unwrap();
+            match &func.block(func.entry_block()).instrs[..] {
+                [(Instr::Const(Const { value: v }), _)] => assert_eq!(*v, Value::V128(value)),
+                other => panic!("unexpected instructions: {:?}", other),
+            }
         }
+
+        assert_eq!(
+            Value::V128(alternating).to_string(),
+            "0x00000000ffffffff00000000ffffffff"
+        );
+        assert_eq!(Value::V128(1).to_string(), format!("0x{}1", "0".repeat(31)));
+    }
+
+    #[test]
+    fn retarget_branch_checks_target_types() {
+        // block $outer (result i32)
+        //   i64.const 5
+        //   loop $loop (param i64)
+        //     block $inner (result i64)
+        //       i64.const 1
+        //       br $inner
+        //     end
+        //     br $loop
+        //   end
+        //   i32.const 0
+        // end
+        let mut module = Module::default();
+        let loop_ty = InstrSeqType::new(&mut module.types, &[ValType::I64], &[]);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        let outer = builder.dangling_instr_seq(ValType::I32).id();
+        let loop_ = builder.dangling_instr_seq(loop_ty).id();
+        let inner = builder.dangling_instr_seq(ValType::I64).id();
+        builder.instr_seq(inner).i64_const(1).br(inner);
+        builder
+            .instr_seq(loop_)
+            .instr(Block { seq: inner })
+            .br(loop_);
+        builder
+            .instr_seq(outer)
+            .i64_const(5)
+            .instr(Loop { seq: loop_ })
+            .i32_const(0);
+        builder.func_body().instr(Block { seq: outer });
+        let mut func = builder.local_func(vec![]);
+
+        assert_eq!(func.branch_target_types(&module, outer), [ValType::I32]);
+        // Branching to a loop starts it over, so it expects the parameters.
+        assert_eq!(func.branch_target_types(&module, loop_), [ValType::I64]);
+        assert_eq!(func.branch_target_types(&module, inner), [ValType::I64]);
+
+        let target = |func: &LocalFunction| match func.block(inner).instrs[1].0 {
+            Instr::Br(Br { block }) => block,
+            _ => unreachable!(),
+        };
+        func.retarget_branch(&module, inner, 1, loop_).unwrap();
+        assert_eq!(target(&func), loop_);
+
+        // `$outer` expects an `i32`, not the `i64` on the stack.
+        let err = func.retarget_branch(&module, inner, 1, outer).unwrap_err();
+        assert!(err.to_string().contains("expects [I32]"));
+        assert_eq!(target(&func), loop_);
+
+        // Not a branch.
+        assert!(func.retarget_branch(&module, inner, 0, outer).is_err());
+        // `$inner` doesn't enclose the `br $loop`.
+        assert!(func.retarget_branch(&module, loop_, 1, inner).is_err());
+
+        func.retarget_branch(&module, inner, 1, inner).unwrap();
+        module.funcs.add_local(func);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn check_result_suppliers() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        let mut ids = None;
+        builder.func_body().block(ValType::I32, |outer| {
+            let outer_id = outer.id();
+            outer.block(ValType::I32, |inner| {
+                let inner_id = inner.id();
+                inner.i32_const(1).br(outer_id);
+                ids = Some((outer_id, inner_id));
+            });
+        });
+        let mut func = builder.local_func(vec![]);
+        let (outer, inner) = ids.unwrap();
+
+        // The inner block never completes, so it has nothing to supply.
+        assert!(func.result_suppliers(inner).is_empty());
+        func.check_result_suppliers(&module).unwrap();
+
+        // Dropping the branch's value leaves one of the outer block's exits
+        // without a result.
+        func.block_mut(inner)
+            .instrs
+            .insert(1, (Drop {}.into(), Default::default()));
+        let err = func.check_result_suppliers(&module).unwrap_err();
+        assert!(err.to_string().contains(&format!("{:?}", outer)));
     }
 }
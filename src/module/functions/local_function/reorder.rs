@@ -0,0 +1,742 @@
+//! Moving instructions within a function without changing its behavior.
+//!
+//! Instruction sequences are plain `Vec`s, so nothing stops a pass from
+//! shuffling a store past a load of the same address. The methods here only
+//! perform a move after checking that it doesn't cross a conflicting side
+//! effect, and otherwise report which instruction is in the way and why.
+
+use super::LocalFunction;
+use crate::ir::*;
+use crate::{DataId, ElementId, GlobalId, MemoryId, Module, TableId};
+use std::fmt;
+use std::ops::Range;
+
+/// A piece of state that an instruction may read or write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EffectLocation {
+    /// A local of the function.
+    Local(LocalId),
+    /// A global.
+    Global(GlobalId),
+    /// A linear memory.
+    Memory(MemoryId),
+    /// A table.
+    Table(TableId),
+    /// A passive data segment.
+    Data(DataId),
+    /// A passive element segment.
+    Element(ElementId),
+}
+
+impl EffectLocation {
+    /// Can this location still be observed after the function traps?
+    fn outlives_trap(&self) -> bool {
+        match self {
+            EffectLocation::Local(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Why moving instructions past another instruction would change behavior.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictReason {
+    /// One side calls another function, or has effects that aren't modeled
+    /// precisely (such as atomic waits and fences), and the other side
+    /// touches state outside of the function.
+    Call,
+    /// One side writes a location that the other side reads or writes.
+    Location(EffectLocation),
+    /// One side may trap and the other side writes state that outlives the
+    /// trap.
+    Trap,
+    /// One side may transfer control elsewhere, changing whether the other
+    /// side runs at all.
+    Control,
+    /// The instructions being moved don't form a complete statement that
+    /// consumes and leaves nothing on the stack.
+    Stack,
+    /// The move would take instructions into, out of, or across a sequence's
+    /// preamble.
+    Preamble,
+}
+
+/// An explanation of why a move was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReorderConflict {
+    /// The sequence containing the conflicting instruction.
+    pub seq: InstrSeqId,
+    /// The index of the conflicting instruction within `seq`.
+    pub index: usize,
+    /// What the conflict is.
+    pub reason: ConflictReason,
+}
+
+impl fmt::Display for ReorderConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot move past instruction {}: ", self.index)?;
+        match self.reason {
+            ConflictReason::Call => "it has unknown side effects".fmt(f),
+            ConflictReason::Location(l) => write!(f, "conflicting access to {:?}", l),
+            ConflictReason::Trap => "it may trap or observe a trap".fmt(f),
+            ConflictReason::Control => "it may branch elsewhere".fmt(f),
+            ConflictReason::Stack => "it is not a complete statement".fmt(f),
+            ConflictReason::Preamble => "it is part of a preamble".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ReorderConflict {}
+
+/// A single read or write of some location.
+struct Access {
+    location: EffectLocation,
+    write: bool,
+    /// For memory accesses at a statically known address, the `[lo, hi)`
+    /// range of bytes accessed.
+    range: Option<(u64, u64)>,
+}
+
+impl Access {
+    fn overlaps(&self, other: &Access) -> bool {
+        if self.location != other.location {
+            return false;
+        }
+        match (self.range, other.range) {
+            (Some((a_lo, a_hi)), Some((b_lo, b_hi))) => a_lo < b_hi && b_lo < a_hi,
+            _ => true,
+        }
+    }
+}
+
+/// The side effects of some run of instructions.
+#[derive(Default)]
+struct Effects {
+    accesses: Vec<Access>,
+    call: bool,
+    may_trap: bool,
+    control: bool,
+}
+
+impl Effects {
+    fn read(&mut self, location: EffectLocation) {
+        self.access(location, false, None);
+    }
+
+    fn write(&mut self, location: EffectLocation) {
+        self.access(location, true, None);
+    }
+
+    fn access(&mut self, location: EffectLocation, write: bool, range: Option<(u64, u64)>) {
+        self.accesses.push(Access {
+            location,
+            write,
+            range,
+        });
+    }
+
+    /// Does anything here depend on or affect state outside the function?
+    fn touches_outside(&self) -> bool {
+        self.call || self.may_trap || self.accesses.iter().any(|a| a.location.outlives_trap())
+    }
+
+    /// Does anything here write state that outlives a trap?
+    fn writes_outside(&self) -> bool {
+        self.call
+            || self
+                .accesses
+                .iter()
+                .any(|a| a.write && a.location.outlives_trap())
+    }
+
+    /// Do any of our writes clobber a location that `other` accesses?
+    fn clobbers(&self, other: &Effects) -> Option<ConflictReason> {
+        if self.call && other.touches_outside() {
+            return Some(ConflictReason::Call);
+        }
+        for a in self.accesses.iter().filter(|a| a.write) {
+            if other.accesses.iter().any(|b| a.overlaps(b)) {
+                return Some(ConflictReason::Location(a.location));
+            }
+        }
+        None
+    }
+
+    /// Do any of our writes feed into our own reads the next time around a
+    /// loop?
+    fn depends_on_itself(&self) -> Option<ConflictReason> {
+        if self.call {
+            return Some(ConflictReason::Call);
+        }
+        for a in self.accesses.iter().filter(|a| a.write) {
+            if self.accesses.iter().any(|b| !b.write && a.overlaps(b)) {
+                return Some(ConflictReason::Location(a.location));
+            }
+        }
+        None
+    }
+
+    /// Can these effects and `other`'s not be swapped with each other?
+    fn conflict(&self, other: &Effects) -> Option<ConflictReason> {
+        if self.control || other.control {
+            return Some(ConflictReason::Control);
+        }
+        if let Some(reason) = self.clobbers(other).or_else(|| other.clobbers(self)) {
+            return Some(reason);
+        }
+        if (self.may_trap && other.writes_outside()) || (other.may_trap && self.writes_outside()) {
+            return Some(ConflictReason::Trap);
+        }
+        None
+    }
+}
+
+impl LocalFunction {
+    /// Move the statement ending at `from` in `seq` so that it starts just
+    /// before the instruction that is currently at `to`.
+    ///
+    /// A statement is an instruction that leaves nothing on the stack, such
+    /// as a `local.set`, `global.set`, store, or `drop`, together with the
+    /// instructions that compute its operands. The move is only performed if
+    /// the statement's side effects don't conflict with any instruction it
+    /// would be moved past. Otherwise, the function is left untouched and the
+    /// first conflicting instruction is reported.
+    ///
+    /// Panics if `from` or `to` are out of bounds for `seq`.
+    pub fn try_move(
+        &mut self,
+        module: &Module,
+        seq: InstrSeqId,
+        from: usize,
+        to: usize,
+    ) -> Result<(), ReorderConflict> {
+        assert!(to <= self.block(seq).len());
+        let start = self.statement_start(module, seq, from)?;
+        let preamble = self.block(seq).preamble_len();
+        if start < preamble {
+            return Err(conflict(seq, start, ConflictReason::Preamble));
+        }
+        if to < preamble {
+            return Err(conflict(seq, preamble - 1, ConflictReason::Preamble));
+        }
+        let moved = self.effects(module, seq, start..from + 1);
+        if moved.control {
+            return Err(conflict(seq, from, ConflictReason::Control));
+        }
+
+        let crossed = if to < start {
+            to..start
+        } else if to > from + 1 {
+            from + 1..to
+        } else {
+            return Ok(());
+        };
+        for index in crossed {
+            let effects = self.effects(module, seq, index..index + 1);
+            if let Some(reason) = moved.conflict(&effects) {
+                return Err(conflict(seq, index, reason));
+            }
+        }
+
+        let instrs = &mut self.block_mut(seq).instrs;
+        let statement = instrs.drain(start..from + 1).collect::<Vec<_>>();
+        let at = if to < start { to } else { to - statement.len() };
+        instrs.splice(at..at, statement);
+        Ok(())
+    }
+
+    /// Hoist the statement ending at `from` in the `loop` body `body` out of
+    /// the loop, placing it just before the `loop` instruction.
+    ///
+    /// The statement must be loop-invariant: it may not read anything that
+    /// it or the rest of the loop writes, and nothing in the loop may clobber
+    /// what it writes. It must also run unconditionally on the loop's first
+    /// iteration, so nothing before it in the body may branch or trap in a
+    /// way that would be observable.
+    ///
+    /// Panics if `body` isn't the body of a reachable `loop` in this function.
+    pub fn try_hoist_out_of_loop(
+        &mut self,
+        module: &Module,
+        body: InstrSeqId,
+        from: usize,
+    ) -> Result<(), ReorderConflict> {
+        let (parent, loop_index) = self
+            .find_loop(body)
+            .expect("not the body of a `loop` in this function");
+        let start = self.statement_start(module, body, from)?;
+        if start < self.block(body).preamble_len() {
+            return Err(conflict(body, start, ConflictReason::Preamble));
+        }
+        if loop_index < self.block(parent).preamble_len() {
+            return Err(conflict(parent, loop_index, ConflictReason::Preamble));
+        }
+        let hoisted = self.effects(module, body, start..from + 1);
+        if hoisted.control {
+            return Err(conflict(body, from, ConflictReason::Control));
+        }
+        // Running the statement once instead of on every iteration is only
+        // equivalent if it doesn't depend on its own previous execution, as
+        // incrementing a counter would.
+        if let Some(reason) = hoisted.depends_on_itself() {
+            return Err(conflict(body, from, reason));
+        }
+
+        for index in (0..start).chain(from + 1..self.block(body).len()) {
+            let effects = self.effects(module, body, index..index + 1);
+            let reason = if index < start {
+                hoisted.conflict(&effects)
+            } else {
+                // The statement has already run by the time control reaches
+                // these instructions, so they only conflict if they change
+                // something the statement would see on the next iteration.
+                effects.clobbers(&hoisted)
+            };
+            if let Some(reason) = reason {
+                return Err(conflict(body, index, reason));
+            }
+        }
+
+        let statement = self
+            .block_mut(body)
+            .instrs
+            .drain(start..from + 1)
+            .collect::<Vec<_>>();
+        self.block_mut(parent)
+            .instrs
+            .splice(loop_index..loop_index, statement);
+        Ok(())
+    }
+
+    /// Find the index of the first instruction of the statement whose last
+    /// instruction is at `root`.
+    fn statement_start(
+        &self,
+        module: &Module,
+        seq: InstrSeqId,
+        root: usize,
+    ) -> Result<usize, ReorderConflict> {
+        let instrs = &self.block(seq).instrs;
+        let stack = |index| conflict(seq, index, ConflictReason::Stack);
+        let (mut needed, pushes) = self
+            .stack_effect(module, &instrs[root].0)
+            .ok_or_else(|| stack(root))?;
+        if pushes != 0 {
+            return Err(stack(root));
+        }
+        let mut start = root;
+        while needed > 0 {
+            if start == 0 {
+                // The operands flow in from the sequence's parameters.
+                return Err(stack(root));
+            }
+            start -= 1;
+            let (pops, pushes) = self
+                .stack_effect(module, &instrs[start].0)
+                .ok_or_else(|| stack(start))?;
+            if pushes > needed {
+                return Err(stack(start));
+            }
+            needed = needed - pushes + pops;
+        }
+        Ok(start)
+    }
+
+    /// Find the sequence and index of the `loop` instruction whose body is
+    /// `body`.
+    fn find_loop(&self, body: InstrSeqId) -> Option<(InstrSeqId, usize)> {
+        let mut v = FindLoop { body, found: None };
+        dfs_in_order(&mut v, self, self.entry_block());
+        return v.found;
+
+        struct FindLoop {
+            body: InstrSeqId,
+            found: Option<(InstrSeqId, usize)>,
+        }
+
+        impl<'instr> Visitor<'instr> for FindLoop {
+            fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+                for (index, (instr, _)) in seq.instrs.iter().enumerate() {
+                    if let Instr::Loop(Loop { seq: body }) = instr {
+                        if *body == self.body {
+                            self.found = Some((seq.id(), index));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Compute the side effects of the given instructions in `seq`, including
+    /// the contents of any nested blocks.
+    fn effects(&self, module: &Module, seq: InstrSeqId, range: Range<usize>) -> Effects {
+        let mut effects = Effects::default();
+        let mut nested = Vec::new();
+        let mut targets = Vec::new();
+        for index in range {
+            self.add_effects(module, seq, index, &mut effects, &mut nested, &mut targets);
+        }
+        // Branching to a block within the instructions stays within them, but
+        // any other branch leaves.
+        effects.control |= targets.iter().any(|t| !nested.contains(t));
+        effects
+    }
+
+    fn add_effects(
+        &self,
+        module: &Module,
+        seq: InstrSeqId,
+        index: usize,
+        effects: &mut Effects,
+        nested: &mut Vec<InstrSeqId>,
+        targets: &mut Vec<InstrSeqId>,
+    ) {
+        use self::EffectLocation as L;
+
+        let mut block = |seq: InstrSeqId, effects: &mut Effects, targets: &mut Vec<_>| {
+            nested.push(seq);
+            for i in 0..self.block(seq).len() {
+                self.add_effects(module, seq, i, effects, nested, targets);
+            }
+        };
+        // The address operand of a memory access is `depth` slots below the
+        // top of the stack.
+        let address = |depth| match self.producer(module, seq, index, depth) {
+            Some(i) => match self.block(seq).instrs[i].0 {
+                Instr::Const(Const {
+                    value: Value::I32(address),
+                }) => Some(address as u32),
+                _ => None,
+            },
+            None => None,
+        };
+        // Memories never shrink, so an access that fits within a memory's
+        // initial size can't trap.
+        let may_trap = |memory: MemoryId, range: Option<(u64, u64)>| match range {
+            Some((_, hi)) => hi > module.memories.initial_size_bytes(memory),
+            None => true,
+        };
+
+        match &self.block(seq).instrs[index].0 {
+            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                block(*seq, effects, targets);
+            }
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                block(*consequent, effects, targets);
+                block(*alternative, effects, targets);
+            }
+
+            Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => targets.push(*block),
+            Instr::BrTable(BrTable { blocks, default }) => {
+                targets.extend(blocks.iter().chain(Some(default)).cloned());
+            }
+            Instr::Return(_) | Instr::Unreachable(_) => effects.control = true,
+
+            Instr::Call(_)
+            | Instr::CallIndirect(_)
+            | Instr::AtomicNotify(_)
+            | Instr::AtomicWait(_)
+            | Instr::AtomicFence(_) => effects.call = true,
+
+            Instr::LocalGet(LocalGet { local }) => effects.read(L::Local(*local)),
+            Instr::LocalSet(LocalSet { local }) | Instr::LocalTee(LocalTee { local }) => {
+                effects.write(L::Local(*local))
+            }
+            Instr::GlobalGet(GlobalGet { global }) => effects.read(L::Global(*global)),
+            Instr::GlobalSet(GlobalSet { global }) => effects.write(L::Global(*global)),
+
+            Instr::Load(Load {
+                memory, kind, arg, ..
+            }) => {
+                let range = arg.effective_range(address(0), kind.width());
+                effects.access(L::Memory(*memory), false, range);
+                effects.may_trap |= may_trap(*memory, range);
+            }
+            Instr::Store(Store {
+                memory, kind, arg, ..
+            }) => {
+                let range = arg.effective_range(address(1), kind.width());
+                effects.access(L::Memory(*memory), true, range);
+                effects.may_trap |= may_trap(*memory, range);
+            }
+            Instr::LoadSimd(LoadSimd { memory, kind, .. }) => {
+                match kind {
+                    LoadSimdKind::V128Store8Lane(_)
+                    | LoadSimdKind::V128Store16Lane(_)
+                    | LoadSimdKind::V128Store32Lane(_)
+                    | LoadSimdKind::V128Store64Lane(_) => effects.write(L::Memory(*memory)),
+                    _ => effects.read(L::Memory(*memory)),
+                }
+                effects.may_trap = true;
+            }
+            Instr::AtomicRmw(AtomicRmw { memory, .. }) | Instr::Cmpxchg(Cmpxchg { memory, .. }) => {
+                effects.read(L::Memory(*memory));
+                effects.write(L::Memory(*memory));
+                effects.may_trap = true;
+            }
+            Instr::MemorySize(MemorySize { memory }) => effects.read(L::Memory(*memory)),
+            Instr::MemoryGrow(MemoryGrow { memory }) => {
+                effects.read(L::Memory(*memory));
+                effects.write(L::Memory(*memory));
+            }
+            Instr::MemoryInit(MemoryInit { memory, data }) => {
+                effects.read(L::Data(*data));
+                effects.write(L::Memory(*memory));
+                effects.may_trap = true;
+            }
+            Instr::DataDrop(DataDrop { data }) => effects.write(L::Data(*data)),
+            Instr::MemoryCopy(MemoryCopy { src, dst }) => {
+                effects.read(L::Memory(*src));
+                effects.write(L::Memory(*dst));
+                effects.may_trap = true;
+            }
+            Instr::MemoryFill(MemoryFill { memory }) => {
+                effects.write(L::Memory(*memory));
+                effects.may_trap = true;
+            }
+
+            Instr::TableGet(TableGet { table }) => {
+                effects.read(L::Table(*table));
+                effects.may_trap = true;
+            }
+            Instr::TableSet(TableSet { table }) | Instr::TableFill(TableFill { table }) => {
+                effects.write(L::Table(*table));
+                effects.may_trap = true;
+            }
+            Instr::TableSize(TableSize { table }) => effects.read(L::Table(*table)),
+            Instr::TableGrow(TableGrow { table }) => {
+                effects.read(L::Table(*table));
+                effects.write(L::Table(*table));
+            }
+            Instr::TableInit(TableInit { table, elem }) => {
+                effects.read(L::Element(*elem));
+                effects.write(L::Table(*table));
+                effects.may_trap = true;
+            }
+            Instr::ElemDrop(ElemDrop { elem }) => effects.write(L::Element(*elem)),
+            Instr::TableCopy(TableCopy { src, dst }) => {
+                effects.read(L::Table(*src));
+                effects.write(L::Table(*dst));
+                effects.may_trap = true;
+            }
+
+            Instr::Binop(Binop { op }) => match op {
+                BinaryOp::I32DivS
+                | BinaryOp::I32DivU
+                | BinaryOp::I32RemS
+                | BinaryOp::I32RemU
+                | BinaryOp::I64DivS
+                | BinaryOp::I64DivU
+                | BinaryOp::I64RemS
+                | BinaryOp::I64RemU => effects.may_trap = true,
+                _ => {}
+            },
+            Instr::Unop(Unop { op }) => match op {
+                UnaryOp::I32TruncSF32
+                | UnaryOp::I32TruncUF32
+                | UnaryOp::I32TruncSF64
+                | UnaryOp::I32TruncUF64
+                | UnaryOp::I64TruncSF32
+                | UnaryOp::I64TruncUF32
+                | UnaryOp::I64TruncSF64
+                | UnaryOp::I64TruncUF64 => effects.may_trap = true,
+                _ => {}
+            },
+
+            Instr::Const(_)
+            | Instr::Nop(_)
+            | Instr::Select(_)
+            | Instr::Drop(_)
+            | Instr::RefNull(_)
+            | Instr::RefIsNull(_)
+            | Instr::RefFunc(_)
+            | Instr::V128Bitselect(_)
+            | Instr::I8x16Swizzle(_)
+            | Instr::I8x16Shuffle(_) => {}
+        }
+    }
+}
+
+fn conflict(seq: InstrSeqId, index: usize, reason: ConflictReason) -> ReorderConflict {
+    ReorderConflict { seq, index, reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, InitExpr, ValType};
+
+    fn global(module: &mut Module) -> GlobalId {
+        module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)))
+    }
+
+    #[test]
+    fn move_past_unrelated_global() {
+        let mut module = Module::default();
+        let a = global(&mut module);
+        let b = global(&mut module);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .global_set(a)
+            .i32_const(2)
+            .global_set(b);
+        let mut func = builder.local_func(vec![]);
+
+        let entry = func.entry_block();
+        func.try_move(&module, entry, 3, 0).unwrap();
+        match func.block(entry)[1].0 {
+            Instr::GlobalSet(GlobalSet { global }) => assert_eq!(global, b),
+            ref other => panic!("unexpected instruction: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn move_past_read_of_same_global_is_rejected() {
+        let mut module = Module::default();
+        let a = global(&mut module);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .global_get(a)
+            .drop()
+            .i32_const(1)
+            .global_set(a);
+        let mut func = builder.local_func(vec![]);
+
+        let entry = func.entry_block();
+        let err = func.try_move(&module, entry, 3, 0).unwrap_err();
+        assert_eq!(err.index, 0);
+        assert_eq!(
+            err.reason,
+            ConflictReason::Location(EffectLocation::Global(a))
+        );
+        assert_eq!(func.block(entry).len(), 4);
+    }
+
+    #[test]
+    fn move_across_preamble_is_rejected() {
+        let mut module = Module::default();
+        let a = global(&mut module);
+        let b = global(&mut module);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(2).global_set(b);
+        let mut func = builder.local_func(vec![]);
+
+        let entry = func.entry_block();
+        func.block_mut(entry).push_preamble(Const {
+            value: Value::I32(1),
+        });
+        func.block_mut(entry).push_preamble(GlobalSet { global: a });
+
+        let err = func.try_move(&module, entry, 3, 0).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(err.reason, ConflictReason::Preamble);
+        let err = func.try_move(&module, entry, 1, 4).unwrap_err();
+        assert_eq!(err.index, 0);
+        assert_eq!(err.reason, ConflictReason::Preamble);
+        func.try_move(&module, entry, 3, 2).unwrap();
+    }
+
+    #[test]
+    fn move_store_past_load() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let arg = MemArg {
+            align: 4,
+            offset: 0,
+        };
+        let build = |module: &mut Module, load_address| {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            builder
+                .func_body()
+                .i32_const(load_address)
+                .load(memory, LoadKind::I32 { atomic: false }, arg)
+                .drop()
+                .i32_const(0)
+                .i32_const(1)
+                .store(memory, StoreKind::I32 { atomic: false }, arg);
+            builder.local_func(vec![])
+        };
+
+        let mut disjoint = build(&mut module, 8);
+        let entry = disjoint.entry_block();
+        disjoint.try_move(&module, entry, 5, 0).unwrap();
+
+        let mut overlapping = build(&mut module, 2);
+        let entry = overlapping.entry_block();
+        let err = overlapping.try_move(&module, entry, 5, 0).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(
+            err.reason,
+            ConflictReason::Location(EffectLocation::Memory(memory))
+        );
+    }
+
+    #[test]
+    fn hoist_invariant_global_set() {
+        let mut module = Module::default();
+        let a = global(&mut module);
+        let b = global(&mut module);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut body = None;
+        builder.func_body().loop_(None, |l| {
+            let id = l.id();
+            body = Some(id);
+            l.i32_const(7).global_set(a).global_get(b).br_if(id);
+        });
+        let mut func = builder.local_func(vec![]);
+        let body = body.unwrap();
+
+        func.try_hoist_out_of_loop(&module, body, 1).unwrap();
+        assert_eq!(func.block(func.entry_block()).len(), 3);
+        assert_eq!(func.block(body).len(), 2);
+    }
+
+    #[test]
+    fn hoist_rejected_when_loop_reads_first() {
+        let mut module = Module::default();
+        let a = global(&mut module);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut body = None;
+        builder.func_body().loop_(None, |l| {
+            body = Some(l.id());
+            l.global_get(a).drop().i32_const(7).global_set(a);
+        });
+        let mut func = builder.local_func(vec![]);
+        let body = body.unwrap();
+
+        let err = func.try_hoist_out_of_loop(&module, body, 3).unwrap_err();
+        assert_eq!(err.index, 0);
+        assert_eq!(
+            err.reason,
+            ConflictReason::Location(EffectLocation::Global(a))
+        );
+    }
+
+    #[test]
+    fn hoist_rejected_for_counter() {
+        let mut module = Module::default();
+        let a = global(&mut module);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut body = None;
+        builder.func_body().loop_(None, |l| {
+            body = Some(l.id());
+            l.global_get(a)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .global_set(a);
+        });
+        let mut func = builder.local_func(vec![]);
+        let body = body.unwrap();
+
+        let err = func.try_hoist_out_of_loop(&module, body, 3).unwrap_err();
+        assert_eq!(err.index, 3);
+    }
+}
@@ -0,0 +1,359 @@
+//! Checking that `return` instructions return what their function's type
+//! says they should.
+
+use super::LocalFunction;
+use crate::ir::*;
+use crate::{Module, ValType};
+use std::fmt;
+
+/// A `return` whose operands don't match its function's result types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReturnTypeMismatch {
+    /// The sequence containing the `return`.
+    pub seq: InstrSeqId,
+    /// The index of the `return` within `seq`.
+    pub index: usize,
+    /// The function's result types.
+    pub expected: Vec<ValType>,
+    /// The types of the values on top of the stack at the `return`, as many
+    /// as there are results, or fewer if there aren't enough values.
+    ///
+    /// A type is `None` if it can't be determined without full type
+    /// inference, as is the case for the results of atomic operations. Such
+    /// values are assumed to have the expected type.
+    pub got: Vec<Option<ValType>>,
+}
+
+impl fmt::Display for ReturnTypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`return` expects ")?;
+        let expected = self.expected.iter().map(|ty| Some(*ty)).collect::<Vec<_>>();
+        write_types(f, &expected)?;
+        write!(f, " but got ")?;
+        write_types(f, &self.got)
+    }
+}
+
+impl std::error::Error for ReturnTypeMismatch {}
+
+/// Write a list of types, with `_` for the unknown ones.
+pub(crate) fn write_types(f: &mut fmt::Formatter, tys: &[Option<ValType>]) -> fmt::Result {
+    write!(f, "[")?;
+    for (i, ty) in tys.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match ty {
+            Some(ty) => write!(f, "{}", ty)?,
+            None => write!(f, "_")?,
+        }
+    }
+    write!(f, "]")
+}
+
+impl LocalFunction {
+    /// Find every `return` that doesn't return as many values as the
+    /// function has results, or returns a value of the wrong type.
+    ///
+    /// Each sequence's operand stack is followed from the sequence's start,
+    /// so `return`s in code that is unreachable within its sequence aren't
+    /// checked.
+    pub fn return_type_mismatches(&self, module: &Module) -> Vec<ReturnTypeMismatch> {
+        let expected = module.types.results(self.ty()).to_vec();
+        let mut mismatches = Vec::new();
+        self.for_each_operand_stack(module, |seq, index, instr, stack| {
+            if let Instr::Return(_) = instr {
+                let got = stack[stack.len().saturating_sub(expected.len())..].to_vec();
+                if !types_match(&got, &expected) {
+                    mismatches.push(ReturnTypeMismatch {
+                        seq,
+                        index,
+                        expected: expected.clone(),
+                        got,
+                    });
+                }
+            }
+        });
+        mismatches
+    }
+
+    /// Follow the operand stack of each of this function's instruction
+    /// sequences from the sequence's start, calling `f` with the types on
+    /// the stack right before each instruction.
+    ///
+    /// A sequence is followed until an instruction whose stack effect isn't
+    /// known, such as a branch, which makes the rest of the sequence
+    /// unreachable, or until an instruction pops more values than there are.
+    pub(crate) fn for_each_operand_stack(
+        &self,
+        module: &Module,
+        mut f: impl FnMut(InstrSeqId, usize, &Instr, &[Option<ValType>]),
+    ) {
+        let mut v = Seqs::default();
+        dfs_in_order(&mut v, self, self.entry_block());
+
+        for seq in v.seqs {
+            let mut stack = match self.block(seq).ty {
+                InstrSeqType::Simple(_) => Vec::new(),
+                InstrSeqType::MultiValue(ty) => {
+                    module.types.params(ty).iter().map(|ty| Some(*ty)).collect()
+                }
+            };
+            for (index, (instr, _)) in self.block(seq).instrs.iter().enumerate() {
+                f(seq, index, instr, &stack);
+                let pops = match self.stack_effect(module, instr) {
+                    Some((pops, _)) if pops <= stack.len() => pops,
+                    // Either the rest of the sequence is unreachable, or the
+                    // sequence is malformed in a way we don't check here.
+                    _ => break,
+                };
+                let popped = stack.split_off(stack.len() - pops);
+                stack.extend(self.pushed_types(module, instr, &popped));
+            }
+        }
+
+        #[derive(Default)]
+        struct Seqs {
+            seqs: Vec<InstrSeqId>,
+        }
+
+        impl<'instr> Visitor<'instr> for Seqs {
+            fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+                self.seqs.push(seq.id());
+            }
+        }
+    }
+
+    /// The types of the values an instruction with a known stack effect
+    /// pushes, given the types of the values it pops.
+    pub(crate) fn pushed_types(
+        &self,
+        module: &Module,
+        instr: &Instr,
+        popped: &[Option<ValType>],
+    ) -> Vec<Option<ValType>> {
+        let known =
+            |tys: &[ValType]| -> Vec<Option<ValType>> { tys.iter().map(|ty| Some(*ty)).collect() };
+        let ty = match instr {
+            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                return self.seq_results(module, *seq)
+            }
+            Instr::IfElse(IfElse { consequent, .. }) => {
+                return self.seq_results(module, *consequent)
+            }
+            Instr::Call(Call { func }) => {
+                return known(module.types.results(module.funcs.get(*func).ty()))
+            }
+            Instr::CallIndirect(CallIndirect { ty, .. }) => {
+                return known(module.types.results(*ty))
+            }
+
+            Instr::Const(Const { value }) => Some(match value {
+                Value::I32(_) => ValType::I32,
+                Value::I64(_) => ValType::I64,
+                Value::F32(_) => ValType::F32,
+                Value::F64(_) => ValType::F64,
+                Value::V128(_) => ValType::V128,
+            }),
+            Instr::LocalGet(LocalGet { local }) | Instr::LocalTee(LocalTee { local }) => {
+                Some(module.locals.get(*local).ty())
+            }
+            Instr::GlobalGet(GlobalGet { global }) => Some(module.globals.get(*global).ty),
+            Instr::TableGet(TableGet { table }) => Some(module.tables.get(*table).element_ty),
+            Instr::RefNull(RefNull { ty }) => Some(*ty),
+            Instr::RefFunc(_) => Some(ValType::Funcref),
+            Instr::Binop(Binop { op }) => Some(op.result_ty()),
+            Instr::Unop(Unop { op }) => Some(op.result_ty()),
+            Instr::Select(Select { ty }) => ty.or(popped[0]),
+            Instr::Load(Load { kind, .. }) => Some(match kind {
+                LoadKind::I32 { .. } | LoadKind::I32_8 { .. } | LoadKind::I32_16 { .. } => {
+                    ValType::I32
+                }
+                LoadKind::I64 { .. }
+                | LoadKind::I64_8 { .. }
+                | LoadKind::I64_16 { .. }
+                | LoadKind::I64_32 { .. } => ValType::I64,
+                LoadKind::F32 => ValType::F32,
+                LoadKind::F64 => ValType::F64,
+                LoadKind::V128 => ValType::V128,
+            }),
+            Instr::MemorySize(_)
+            | Instr::MemoryGrow(_)
+            | Instr::TableSize(_)
+            | Instr::TableGrow(_)
+            | Instr::RefIsNull(_)
+            | Instr::AtomicNotify(_)
+            | Instr::AtomicWait(_) => Some(ValType::I32),
+            Instr::LoadSimd(_)
+            | Instr::I8x16Swizzle(_)
+            | Instr::I8x16Shuffle(_)
+            | Instr::V128Bitselect(_) => Some(ValType::V128),
+            _ => None,
+        };
+        let pushes = self
+            .stack_effect(module, instr)
+            .map_or(0, |(_, pushes)| pushes);
+        vec![ty; pushes]
+    }
+
+    /// The types of the values an instruction with a known stack effect
+    /// pops, with `None` for operands that can be of several types, or
+    /// whose types aren't tracked here, such as those of SIMD operators.
+    pub(crate) fn popped_types(&self, module: &Module, instr: &Instr) -> Vec<Option<ValType>> {
+        use crate::ir::BinaryOp::*;
+        use crate::ir::UnaryOp::*;
+
+        let known =
+            |tys: &[ValType]| -> Vec<Option<ValType>> { tys.iter().map(|ty| Some(*ty)).collect() };
+        let pops = self.stack_effect(module, instr).map_or(0, |(pops, _)| pops);
+        let ty =
+            match instr {
+                Instr::Call(Call { func }) => {
+                    return known(module.types.params(module.funcs.get(*func).ty()))
+                }
+                Instr::CallIndirect(CallIndirect { ty, .. }) => {
+                    let mut tys = known(module.types.params(*ty));
+                    tys.push(Some(ValType::I32));
+                    return tys;
+                }
+                Instr::Store(Store { kind, .. }) => {
+                    let value = match kind {
+                        StoreKind::I32 { .. }
+                        | StoreKind::I32_8 { .. }
+                        | StoreKind::I32_16 { .. } => ValType::I32,
+                        StoreKind::I64 { .. }
+                        | StoreKind::I64_8 { .. }
+                        | StoreKind::I64_16 { .. }
+                        | StoreKind::I64_32 { .. } => ValType::I64,
+                        StoreKind::F32 => ValType::F32,
+                        StoreKind::F64 => ValType::F64,
+                        StoreKind::V128 => ValType::V128,
+                    };
+                    return vec![Some(ValType::I32), Some(value)];
+                }
+
+                Instr::LocalSet(LocalSet { local }) | Instr::LocalTee(LocalTee { local }) => {
+                    Some(module.locals.get(*local).ty())
+                }
+                Instr::GlobalSet(GlobalSet { global }) => Some(module.globals.get(*global).ty),
+                Instr::Load(_) | Instr::MemoryGrow(_) | Instr::BrIf(_) | Instr::BrTable(_) => {
+                    Some(ValType::I32)
+                }
+                Instr::Binop(Binop { op }) => match op {
+                    I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU
+                    | I32GeS | I32GeU | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS
+                    | I32RemU | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl
+                    | I32Rotr => Some(ValType::I32),
+                    I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU
+                    | I64GeS | I64GeU | I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS
+                    | I64RemU | I64And | I64Or | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl
+                    | I64Rotr => Some(ValType::I64),
+                    F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F32Add | F32Sub | F32Mul
+                    | F32Div | F32Min | F32Max | F32Copysign => Some(ValType::F32),
+                    F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge | F64Add | F64Sub | F64Mul
+                    | F64Div | F64Min | F64Max | F64Copysign => Some(ValType::F64),
+                    _ => None,
+                },
+                Instr::Unop(Unop { op }) => match op {
+                    I32Eqz | I32Clz | I32Ctz | I32Popcnt | I32Extend8S | I32Extend16S
+                    | I64ExtendSI32 | I64ExtendUI32 | F32ConvertSI32 | F32ConvertUI32
+                    | F64ConvertSI32 | F64ConvertUI32 | F32ReinterpretI32 => Some(ValType::I32),
+                    I64Eqz | I64Clz | I64Ctz | I64Popcnt | I64Extend8S | I64Extend16S
+                    | I64Extend32S | I32WrapI64 | F32ConvertSI64 | F32ConvertUI64
+                    | F64ConvertSI64 | F64ConvertUI64 | F64ReinterpretI64 => Some(ValType::I64),
+                    F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt
+                    | I32TruncSF32 | I32TruncUF32 | I64TruncSF32 | I64TruncUF32
+                    | I32TruncSSatF32 | I32TruncUSatF32 | I64TruncSSatF32 | I64TruncUSatF32
+                    | F64PromoteF32 | I32ReinterpretF32 => Some(ValType::F32),
+                    F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt
+                    | I32TruncSF64 | I32TruncUF64 | I64TruncSF64 | I64TruncUF64
+                    | I32TruncSSatF64 | I32TruncUSatF64 | I64TruncSSatF64 | I64TruncUSatF64
+                    | F32DemoteF64 | I64ReinterpretF64 => Some(ValType::F64),
+                    _ => None,
+                },
+                _ => None,
+            };
+        vec![ty; pops]
+    }
+
+    /// The result types of an instruction sequence.
+    fn seq_results(&self, module: &Module, seq: InstrSeqId) -> Vec<Option<ValType>> {
+        match self.block(seq).ty {
+            InstrSeqType::Simple(ty) => ty.into_iter().map(Some).collect(),
+            InstrSeqType::MultiValue(ty) => module
+                .types
+                .results(ty)
+                .iter()
+                .map(|ty| Some(*ty))
+                .collect(),
+        }
+    }
+}
+
+/// Whether the types of some operands match the expected types, treating
+/// unknown types as matching.
+pub(crate) fn types_match(got: &[Option<ValType>], expected: &[ValType]) -> bool {
+    got.len() == expected.len()
+        && got
+            .iter()
+            .zip(expected)
+            .all(|(got, expected)| got.map_or(true, |got| got == *expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FunctionBuilder, Module, ValType};
+
+    #[test]
+    fn return_with_wrong_values() {
+        let mut module = Module::default();
+        let results = [ValType::I32, ValType::I64];
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &results);
+        builder.func_body().i32_const(1).i64_const(2).return_();
+        let ok = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &results);
+        builder.func_body().i64_const(2).return_();
+        let too_few = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &results);
+        builder.func_body().i32_const(1).f64_const(2.0).return_();
+        let wrong_type = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &results);
+        builder
+            .func_body()
+            .i32_const(1)
+            .i64_const(2)
+            .block(None, |block| {
+                block.return_();
+            })
+            .unreachable();
+        let in_block = builder.finish(vec![], &mut module.funcs);
+
+        let mismatches = |func| {
+            let func = module.funcs.get(func).kind.unwrap_local();
+            func.return_type_mismatches(&module)
+        };
+        assert!(mismatches(ok).is_empty());
+
+        let too_few = mismatches(too_few);
+        assert_eq!(too_few.len(), 1);
+        assert_eq!(too_few[0].expected, results);
+        assert_eq!(too_few[0].got, [Some(ValType::I64)]);
+        assert_eq!(
+            too_few[0].to_string(),
+            "`return` expects [i32, i64] but got [i64]"
+        );
+
+        let wrong_type = mismatches(wrong_type);
+        assert_eq!(wrong_type.len(), 1);
+        assert_eq!(wrong_type[0].got, [Some(ValType::I32), Some(ValType::F64)]);
+
+        // The `return` inside the block can't see the values outside of it.
+        let in_block = mismatches(in_block);
+        assert_eq!(in_block.len(), 1);
+        assert_eq!(in_block[0].got, []);
+    }
+}
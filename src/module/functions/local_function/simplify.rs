@@ -0,0 +1,138 @@
+//! Running the peephole passes on a function until none of them finds more
+//! to do.
+
+use super::LocalFunction;
+use crate::ir::*;
+use crate::passes::{fold_const_if, local_cleanup, narrow_block_results, remove_nop};
+
+/// How many times `simplify` runs the passes at most. Each round usually
+/// only exposes a little more work for the next one, so this is rarely hit.
+const MAX_SIMPLIFY_ROUNDS: usize = 16;
+
+impl LocalFunction {
+    /// Clean this function up with every peephole pass, repeating them until
+    /// none of them changes anything, or a fixed number of rounds have run.
+    ///
+    /// A round folds constant `if/else` conditions, cleans up writes to
+    /// locals, narrows blocks whose result is dropped, removes the code after
+    /// unconditional branches and traps, removes pure values that are
+    /// immediately dropped, and finally removes `nop`s. Like the passes
+    /// themselves, this leaves preambles alone.
+    ///
+    /// Returns the total number of changes made.
+    pub fn simplify(&mut self) -> usize {
+        let mut total = 0;
+        for _ in 0..MAX_SIMPLIFY_ROUNDS {
+            let changes = fold_const_if::run_func(self)
+                + local_cleanup::run_func(self).total()
+                + narrow_block_results::run_func(self)
+                + self.remove_dead_code()
+                + self.remove_dropped_values()
+                + remove_nop::remove_nop_expressions(self);
+            if changes == 0 {
+                break;
+            }
+            total += changes;
+        }
+        total
+    }
+
+    /// Remove the instructions after the first one in each sequence that
+    /// never falls through.
+    ///
+    /// Returns the number of instructions removed, not counting those nested
+    /// in removed blocks.
+    fn remove_dead_code(&mut self) -> usize {
+        let mut removed = 0;
+        for seq in self.instr_seqs() {
+            let block = self.block_mut(seq);
+            let end = block
+                .instrs
+                .iter()
+                .position(|(instr, _)| instr.following_instructions_are_unreachable());
+            if let Some(end) = end {
+                let keep = (end + 1).max(block.preamble_len());
+                removed += block.instrs.len().saturating_sub(keep);
+                block.instrs.truncate(keep);
+            }
+        }
+        removed
+    }
+
+    /// Remove the pairs of an instruction pushing a value without any other
+    /// effect, and a `drop` of that value.
+    ///
+    /// Returns the number of instructions removed.
+    fn remove_dropped_values(&mut self) -> usize {
+        let mut removed = 0;
+        for seq in self.instr_seqs() {
+            let block = self.block_mut(seq);
+            let mut i = block.preamble_len() + 1;
+            while i < block.instrs.len() {
+                let pure = match block.instrs[i - 1].0 {
+                    Instr::Const(_)
+                    | Instr::LocalGet(_)
+                    | Instr::GlobalGet(_)
+                    | Instr::RefNull(_)
+                    | Instr::RefFunc(_) => true,
+                    _ => false,
+                };
+                if pure && block.instrs[i].0.is_drop() {
+                    block.instrs.drain(i - 1..=i);
+                    removed += 2;
+                    i = i.saturating_sub(1).max(block.preamble_len() + 1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::*;
+    use crate::{FunctionBuilder, Module, ValType};
+
+    #[test]
+    fn reaches_a_minimal_form() {
+        let mut module = Module::default();
+        let x = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .if_else(
+                None,
+                |then| {
+                    // A write that is never read.
+                    then.i32_const(2).local_set(x).nop();
+                },
+                |else_| {
+                    else_.unreachable();
+                },
+            )
+            .i32_const(5)
+            .return_()
+            .i32_const(6)
+            .drop()
+            .unreachable();
+        let mut func = builder.local_func(vec![]);
+
+        assert!(func.simplify() > 0);
+        let entry = func.block(func.entry_block());
+        let instrs = entry.iter().map(|(i, _)| i.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            instrs,
+            [
+                Instr::Const(Const {
+                    value: Value::I32(5)
+                }),
+                Instr::Return(Return {}),
+            ]
+        );
+        // Nothing is left to do.
+        assert_eq!(func.simplify(), 0);
+    }
+}
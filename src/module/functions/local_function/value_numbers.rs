@@ -0,0 +1,289 @@
+//! Value numbering of the expressions in a function.
+//!
+//! Every instruction pushing a single value is given a number, such that two
+//! instructions with the same number are known to push the same value. Locals
+//! are followed through `local.set` and `local.tee` the way an SSA form would,
+//! so a `local.get` shares the number of the value last stored in the local.
+//! Values that merge at the end of a block, or flow around a loop, aren't
+//! tracked, and locals written there get a fresh number afterwards.
+
+use super::LocalFunction;
+use crate::ir::*;
+use crate::{FunctionId, GlobalId, Module, ValType};
+use std::collections::HashMap;
+
+/// The structure of a pure expression, in terms of the numbers of its
+/// operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Const(ValType, u128),
+    Global(GlobalId),
+    Unop(UnaryOp, u32),
+    Binop(BinaryOp, u32, u32),
+    Select(u32, u32, u32),
+    RefNull(ValType),
+    RefIsNull(u32),
+    RefFunc(FunctionId),
+}
+
+impl LocalFunction {
+    /// Number the values pushed by the instructions of this function.
+    ///
+    /// The result maps each instruction pushing exactly one value, identified
+    /// by its sequence and index, to its value number. Pure instructions with
+    /// the same immediates and operand numbers share a number, with the
+    /// operands of commutative binary operators compared in either order.
+    /// Everything else, such as calls, loads, and reads of mutable globals,
+    /// gets a number of its own.
+    ///
+    /// Trapping instructions like `i32.div_s` still count as pure, since the
+    /// second of two equal ones is only reached if the first didn't trap.
+    pub fn value_numbers(&self, module: &Module) -> HashMap<(InstrSeqId, usize), u32> {
+        let mut numbering = Numbering {
+            func: self,
+            module,
+            numbers: HashMap::new(),
+            exprs: HashMap::new(),
+            locals: HashMap::new(),
+            next: 0,
+        };
+        numbering.seq(self.entry_block(), Vec::new());
+        numbering.numbers
+    }
+}
+
+struct Numbering<'a> {
+    func: &'a LocalFunction,
+    module: &'a Module,
+    numbers: HashMap<(InstrSeqId, usize), u32>,
+    exprs: HashMap<Key, u32>,
+    /// The number of the value currently held by each local.
+    locals: HashMap<LocalId, u32>,
+    next: u32,
+}
+
+impl Numbering<'_> {
+    fn fresh(&mut self) -> u32 {
+        self.next += 1;
+        self.next - 1
+    }
+
+    fn local(&mut self, local: LocalId) -> u32 {
+        if let Some(number) = self.locals.get(&local) {
+            return *number;
+        }
+        let number = self.fresh();
+        self.locals.insert(local, number);
+        number
+    }
+
+    /// Give a fresh number to every local written within `seq`.
+    fn clobber(&mut self, seq: InstrSeqId) {
+        let mut written = Vec::new();
+        locals_written(self.func, seq, &mut written);
+        for local in written {
+            let number = self.fresh();
+            self.locals.insert(local, number);
+        }
+    }
+
+    fn seq(&mut self, seq: InstrSeqId, params: Vec<u32>) {
+        let func = self.func;
+        let mut stack = params;
+        for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+            let (pops, pushes) = match func.stack_effect(self.module, instr) {
+                Some(effect) => effect,
+                None => {
+                    // Code after a stack-polymorphic instruction is
+                    // unreachable, and its operands get fresh numbers.
+                    if let Instr::BrIf(_) = instr {
+                        stack.pop();
+                    } else {
+                        stack.clear();
+                    }
+                    continue;
+                }
+            };
+            let split = stack.len().saturating_sub(pops);
+            let mut operands = stack.split_off(split);
+            while operands.len() < pops {
+                let number = self.fresh();
+                operands.insert(0, number);
+            }
+
+            let pushed = match instr {
+                Instr::Block(Block { seq: inner }) => {
+                    self.seq(*inner, operands);
+                    self.clobber(*inner);
+                    None
+                }
+                Instr::Loop(Loop { seq: inner }) => {
+                    self.clobber(*inner);
+                    self.seq(*inner, operands);
+                    self.clobber(*inner);
+                    None
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    operands.pop();
+                    let before = self.locals.clone();
+                    self.seq(*consequent, operands.clone());
+                    self.locals = before;
+                    self.seq(*alternative, operands);
+                    self.clobber(*consequent);
+                    self.clobber(*alternative);
+                    None
+                }
+                Instr::LocalGet(LocalGet { local }) => Some(self.local(*local)),
+                Instr::LocalSet(LocalSet { local }) => {
+                    self.locals.insert(*local, operands[0]);
+                    None
+                }
+                Instr::LocalTee(LocalTee { local }) => {
+                    self.locals.insert(*local, operands[0]);
+                    Some(operands[0])
+                }
+                _ => match key(self.module, instr, &operands) {
+                    Some(key) => match self.exprs.get(&key) {
+                        Some(number) => Some(*number),
+                        None => {
+                            let number = self.fresh();
+                            self.exprs.insert(key, number);
+                            Some(number)
+                        }
+                    },
+                    None => None,
+                },
+            };
+
+            match pushed {
+                Some(number) => {
+                    self.numbers.insert((seq, index), number);
+                    stack.push(number);
+                }
+                None => {
+                    for _ in 0..pushes {
+                        let number = self.fresh();
+                        stack.push(number);
+                    }
+                    if pushes == 1 {
+                        self.numbers.insert((seq, index), *stack.last().unwrap());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The structure of `instr` if it's a pure expression.
+fn key(module: &Module, instr: &Instr, operands: &[u32]) -> Option<Key> {
+    Some(match instr {
+        Instr::Const(Const { value }) => match *value {
+            Value::I32(n) => Key::Const(ValType::I32, n as u32 as u128),
+            Value::I64(n) => Key::Const(ValType::I64, n as u64 as u128),
+            Value::F32(n) => Key::Const(ValType::F32, n.to_bits() as u128),
+            Value::F64(n) => Key::Const(ValType::F64, n.to_bits() as u128),
+            Value::V128(n) => Key::Const(ValType::V128, n),
+        },
+        Instr::GlobalGet(GlobalGet { global }) if !module.globals.get(*global).mutable => {
+            Key::Global(*global)
+        }
+        Instr::Unop(Unop { op }) => Key::Unop(*op, operands[0]),
+        Instr::Binop(Binop { op }) => {
+            let (a, b) = (operands[0], operands[1]);
+            if op.is_commutative() && b < a {
+                Key::Binop(*op, b, a)
+            } else {
+                Key::Binop(*op, a, b)
+            }
+        }
+        Instr::Select(_) => Key::Select(operands[0], operands[1], operands[2]),
+        Instr::RefNull(RefNull { ty }) => Key::RefNull(*ty),
+        Instr::RefIsNull(_) => Key::RefIsNull(operands[0]),
+        Instr::RefFunc(RefFunc { func }) => Key::RefFunc(*func),
+        _ => return None,
+    })
+}
+
+/// Collect the locals written by `local.set` or `local.tee` anywhere within
+/// `seq`.
+fn locals_written(func: &LocalFunction, seq: InstrSeqId, written: &mut Vec<LocalId>) {
+    for (instr, _) in func.block(seq).instrs.iter() {
+        match instr {
+            Instr::LocalSet(LocalSet { local }) | Instr::LocalTee(LocalTee { local }) => {
+                written.push(*local)
+            }
+            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                locals_written(func, *seq, written)
+            }
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                locals_written(func, *consequent, written);
+                locals_written(func, *alternative, written);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    #[test]
+    fn equal_expressions_share_numbers() {
+        let mut module = Module::default();
+        let a = module.locals.add(ValType::I32);
+        let b = module.locals.add(ValType::I32);
+        let callee = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32])
+            .finish(vec![], &mut module.funcs);
+        let mut builder = FunctionBuilder::new(
+            &mut module.types,
+            &[ValType::I32, ValType::I32],
+            &[ValType::I32],
+        );
+        builder
+            .func_body()
+            // 0..3: a + b
+            .local_get(a)
+            .local_get(b)
+            .binop(BinaryOp::I32Add)
+            // 3..6: a + b again
+            .local_get(a)
+            .local_get(b)
+            .binop(BinaryOp::I32Add)
+            // 6..9: b + a
+            .local_get(b)
+            .local_get(a)
+            .binop(BinaryOp::I32Add)
+            // 9..11: two calls
+            .call(callee)
+            .call(callee)
+            // 11..15: a + b after writing a
+            .local_set(a)
+            .local_get(a)
+            .local_get(b)
+            .binop(BinaryOp::I32Add)
+            .drop()
+            .drop()
+            .drop()
+            .drop();
+        let func = builder.local_func(vec![a, b]);
+
+        let entry = func.entry_block();
+        let numbers = func.value_numbers(&module);
+        let number = |index| numbers[&(entry, index)];
+        assert_eq!(number(2), number(5));
+        assert_eq!(number(2), number(8));
+        assert_ne!(number(9), number(10));
+        // `a` now holds the second call's result.
+        assert_eq!(number(12), number(10));
+        assert_ne!(number(14), number(2));
+        assert!(!numbers.contains_key(&(entry, 11)));
+    }
+}
@@ -1,9 +1,10 @@
 //! Functions within a wasm module.
 
 use std::cmp;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::{bail, Context};
+use anyhow::{bail, Context, Error};
 use wasm_encoder::Encode;
 use wasmparser::{FuncValidator, FunctionBody, Range, ValidatorResources};
 
@@ -11,19 +12,27 @@ use wasmparser::{FuncValidator, FunctionBody, Range, ValidatorResources};
 use rayon::prelude::*;
 
 mod local_function;
+mod original;
 
-use crate::emit::{Emit, EmitContext};
-use crate::error::Result;
-use crate::ir::InstrLocId;
+use crate::emit::{Emit, EmitContext, IdsToIndices};
+use crate::encoding;
+use crate::error::{ErrorKind, Result};
+use crate::ir::{Instr, InstrLocId, InstrSeqId, InstrSeqType};
+use crate::map::IdHashMap;
 use crate::module::imports::ImportId;
+use crate::module::types::ModuleTypes;
 use crate::module::Module;
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
-use crate::ty::TypeId;
-use crate::ty::ValType;
+use crate::ty::{Type, TypeId, ValType};
 use crate::{ExportItem, FunctionBuilder, InstrSeqBuilder, LocalId, Memory, MemoryId};
 
-pub use self::local_function::LocalFunction;
+pub use self::local_function::{
+    CallArgumentMismatch, ConflictReason, EffectLocation, LocalFunction, ReorderConflict,
+    ReturnTypeMismatch,
+};
+use self::original::OriginalBody;
+pub(crate) use self::original::Snapshot;
 
 /// A function identifier.
 pub type FunctionId = Id<Function>;
@@ -48,6 +57,24 @@ pub struct Function {
 
     /// An optional name associated with this function
     pub name: Option<String>,
+
+    /// How passes must treat this function.
+    pub flags: FunctionFlags,
+}
+
+/// Flags protecting a function from passes, set with
+/// `ModuleFunctions::set_flags`.
+///
+/// The passes in this crate honor them, and other passes should too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FunctionFlags {
+    /// Calls to this function must not be inlined.
+    pub no_inline: bool,
+    /// This function's body must not be changed, either by optimizations or
+    /// by instrumentation.
+    pub no_modify: bool,
+    /// This function must not be removed, even if nothing uses it.
+    pub keep_alive: bool,
 }
 
 impl Tombstone for Function {
@@ -55,6 +82,7 @@ impl Tombstone for Function {
         let ty = self.ty();
         self.kind = FunctionKind::Uninitialized(ty);
         self.name = None;
+        self.flags = FunctionFlags::default();
     }
 }
 
@@ -64,6 +92,7 @@ impl Function {
             id,
             kind: FunctionKind::Uninitialized(ty),
             name: None,
+            flags: FunctionFlags::default(),
         }
     }
 
@@ -153,6 +182,38 @@ pub struct ModuleFunctions {
 
     /// Original code section offset.
     pub(crate) code_section_offset: usize,
+
+    /// The original encoding of each local function's body, if the module
+    /// was parsed with `ModuleConfig::preserve_original_function_bodies`.
+    original: IdHashMap<Function, OriginalBody>,
+
+    /// Tells this set of functions apart from every other.
+    instance: Instance,
+    /// The current epoch, which `next_epoch` starts a new one of.
+    epoch: u64,
+    /// The epoch in which each function was last mutably borrowed, if it has
+    /// been.
+    modified: IdHashMap<Function, u64>,
+}
+
+/// A unique identifier for a `ModuleFunctions`, so that an `Epoch` from one
+/// set of functions is never mistaken for one from another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Instance(u64);
+
+impl Default for Instance {
+    fn default() -> Instance {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Instance(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A point in the history of a `ModuleFunctions`, for finding out which
+/// functions might have been modified since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Epoch {
+    instance: Instance,
+    epoch: u64,
 }
 
 impl ModuleFunctions {
@@ -167,6 +228,7 @@ impl ModuleFunctions {
             id,
             kind: FunctionKind::Import(ImportedFunction { import, ty }),
             name: None,
+            flags: FunctionFlags::default(),
         })
     }
 
@@ -177,6 +239,7 @@ impl ModuleFunctions {
             id,
             kind: FunctionKind::Local(func),
             name: func_name,
+            flags: FunctionFlags::default(),
         })
     }
 
@@ -187,9 +250,57 @@ impl ModuleFunctions {
 
     /// Gets a reference to a function given its id
     pub fn get_mut(&mut self, id: FunctionId) -> &mut Function {
+        self.modified.insert(id, self.epoch);
+        &mut self.arena[id]
+    }
+
+    /// Like `get_mut`, but without counting the function as modified, for
+    /// changes that are undone before anything can observe them.
+    pub(crate) fn get_mut_untracked(&mut self, id: FunctionId) -> &mut Function {
         &mut self.arena[id]
     }
 
+    /// Start a new epoch, and return it. Functions that are mutably borrowed
+    /// from now on count as `modified_since` it.
+    pub(crate) fn next_epoch(&mut self) -> Epoch {
+        self.epoch += 1;
+        Epoch {
+            instance: self.instance,
+            epoch: self.epoch,
+        }
+    }
+
+    /// Might the given function have been modified since the given epoch
+    /// started?
+    ///
+    /// Always true for an epoch of a different set of functions.
+    pub(crate) fn modified_since(&self, id: FunctionId, since: Epoch) -> bool {
+        since.instance != self.instance
+            || self
+                .modified
+                .get(&id)
+                .map_or(false, |&epoch| epoch >= since.epoch)
+    }
+
+    /// Print a function for debugging, without panicking however broken it
+    /// is, using `LocalFunction::to_string_lossy`.
+    ///
+    /// Functions that can't be printed, because they were deleted or are
+    /// imported, are printed as a single `<...>` line saying so.
+    pub fn dump_lossy(&self, id: FunctionId) -> String {
+        match self.arena.get(id).map(|f| &f.kind) {
+            Some(FunctionKind::Local(func)) => func.to_string_lossy(),
+            Some(FunctionKind::Import(_)) => format!("<imported {:?}>\n", id),
+            Some(FunctionKind::Uninitialized(_)) => format!("<unparsed {:?}>\n", id),
+            None => format!("<dangling {:?}>\n", id),
+        }
+    }
+
+    /// Is there a function with the given id that hasn't been deleted?
+    pub(crate) fn contains(&self, id: FunctionId) -> bool {
+        self.arena.contains(id)
+    }
+
     /// Get a function ID by its name.
     ///
     /// The name used is the "name" custom section name and *not* the export
@@ -207,6 +318,24 @@ impl ModuleFunctions {
         })
     }
 
+    /// Set the flags that passes must honor for the given function.
+    pub fn set_flags(&mut self, func: FunctionId, flags: FunctionFlags) {
+        self.get_mut(func).flags = flags;
+    }
+
+    /// Set the flags of the function with the given name, as found by
+    /// `by_name`, and return its id.
+    ///
+    /// Returns an error if there is no function with that name.
+    pub fn set_flags_by_name(&mut self, name: &str, flags: FunctionFlags) -> Result<FunctionId> {
+        let func = match self.by_name(name) {
+            Some(func) => func,
+            None => bail!("cannot set the flags of function {:?}, there is none", name),
+        };
+        self.set_flags(func, flags);
+        Ok(func)
+    }
+
     /// Removes a function from this module.
     ///
     /// It is up to you to ensure that any potential references to the deleted
@@ -214,6 +343,53 @@ impl ModuleFunctions {
     /// elements, etc.
     pub fn delete(&mut self, id: FunctionId) {
         self.arena.delete(id);
+        self.modified.remove(&id);
+    }
+
+    /// Pin the given function to its encoding in the original wasm binary,
+    /// so that it is emitted byte-for-byte as it was parsed.
+    ///
+    /// Returns an error if the original encoding of the function wasn't kept,
+    /// which is the case unless the module was parsed with
+    /// `ModuleConfig::preserve_original_function_bodies`.
+    ///
+    /// Types are reordered when the module is emitted, so the type indices in
+    /// the body are rewritten to match. It is an error to emit the module with
+    /// `Module::try_emit_wasm` if the function is modified after it is pinned,
+    /// or if any table, function, global, memory, element or data segment its
+    /// body refers to ends up at a different index than it had in the original
+    /// binary, and `Module::emit_wasm` panics instead.
+    pub fn pin_original_encoding(&mut self, func: FunctionId) -> Result<()> {
+        let original = match self.original.get_mut(&func) {
+            Some(original) => original,
+            None => bail!("the original encoding of function {:?} wasn't kept", func),
+        };
+        let local = match &self.arena[func].kind {
+            FunctionKind::Local(local) => local,
+            _ => bail!("function {:?} is not a local function", func),
+        };
+        original.pinned = Some(Snapshot::new(local));
+        Ok(())
+    }
+
+    /// Get the original encoding of the given function, if it is pinned.
+    pub(crate) fn pinned(&self, func: FunctionId) -> Option<&OriginalBody> {
+        self.original
+            .get(&func)
+            .filter(|original| original.pinned.is_some())
+    }
+
+    /// Check that every pinned function can still be emitted with its original
+    /// encoding.
+    pub(crate) fn check_pinned(&self, indices: &IdsToIndices) -> Result<()> {
+        for func in self.iter() {
+            if let (Some(original), FunctionKind::Local(local)) =
+                (self.pinned(func.id()), &func.kind)
+            {
+                original.check(func.id(), local, indices)?;
+            }
+        }
+        Ok(())
     }
 
     /// Get a shared reference to this module's functions.
@@ -221,6 +397,20 @@ impl ModuleFunctions {
         self.arena.iter().map(|(_, f)| f)
     }
 
+    /// Group this module's functions by their signatures.
+    ///
+    /// Each group is keyed by the first type seen with its signature, so
+    /// functions with call-compatible types always end up in the same group.
+    pub fn group_by_type(&self, types: &ModuleTypes) -> IdHashMap<Type, Vec<FunctionId>> {
+        let mut keys = HashMap::new();
+        let mut groups = IdHashMap::default();
+        for f in self.iter() {
+            let key = *keys.entry(types.params_results(f.ty())).or_insert(f.ty());
+            groups.entry(key).or_insert_with(Vec::new).push(f.id());
+        }
+        groups
+    }
+
     /// Get a shared reference to this module's functions.
     ///
     /// Requires the `parallel` feature of this crate to be enabled.
@@ -250,7 +440,11 @@ impl ModuleFunctions {
 
     /// Get a mutable reference to this module's functions.
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Function> {
-        self.arena.iter_mut().map(|(_, f)| f)
+        let (modified, epoch) = (&mut self.modified, self.epoch);
+        self.arena.iter_mut().map(move |(id, f)| {
+            modified.insert(id, epoch);
+            f
+        })
     }
 
     /// Get a mutable reference to this module's functions.
@@ -258,6 +452,9 @@ impl ModuleFunctions {
     /// Requires the `parallel` feature of this crate to be enabled.
     #[cfg(feature = "parallel")]
     pub fn par_iter_mut(&mut self) -> impl ParallelIterator<Item = &mut Function> {
+        for (id, _) in self.arena.iter() {
+            self.modified.insert(id, self.epoch);
+        }
         self.arena.par_iter_mut().map(|(_, f)| f)
     }
 
@@ -272,6 +469,21 @@ impl ModuleFunctions {
         })
     }
 
+    /// Get an iterator of this module's local functions that passes may
+    /// modify, leaving out those flagged `no_modify`.
+    pub fn iter_modifiable_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (FunctionId, &mut LocalFunction)> {
+        self.iter_mut().filter_map(|f| {
+            let id = f.id();
+            let modifiable = !f.flags.no_modify;
+            match &mut f.kind {
+                FunctionKind::Local(local) if modifiable => Some((id, local)),
+                _ => None,
+            }
+        })
+    }
+
     /// Get a parallel iterator of this module's local functions
     ///
     /// Requires the `parallel` feature of this crate to be enabled.
@@ -311,6 +523,85 @@ impl ModuleFunctions {
 }
 
 impl Module {
+    /// Take the `walrus.flags` custom section out of this module, if it has
+    /// one, and set the function flags it lists. Returns the number of lines
+    /// it has.
+    ///
+    /// This lets build systems bake the flags into their output. The section
+    /// is UTF-8 text with one function per line: a comma-separated list of
+    /// flags out of `no_inline`, `no_modify` and `keep_alive`, a space, and
+    /// the function's name as found by `ModuleFunctions::by_name`. The flags
+    /// are added to those the function already has. Blank lines are ignored.
+    ///
+    /// Returns an error if a line lists an unknown flag or names a function
+    /// that doesn't exist, in which case no flags are set.
+    pub fn apply_function_flags_section(&mut self) -> Result<usize> {
+        let section = match self.customs.remove_raw("walrus.flags") {
+            Some(section) => section,
+            None => return Ok(0),
+        };
+        let text = std::str::from_utf8(&section.data)
+            .context("the `walrus.flags` section isn't valid UTF-8")?;
+
+        let mut updates = Vec::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let (names, func) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => bail!("no function name in `walrus.flags` line {:?}", line),
+            };
+            let id = match self.funcs.by_name(func) {
+                Some(id) => id,
+                None => bail!("cannot set the flags of function {:?}, there is none", func),
+            };
+            let mut flags = FunctionFlags::default();
+            for name in names.split(',') {
+                match name {
+                    "no_inline" => flags.no_inline = true,
+                    "no_modify" => flags.no_modify = true,
+                    "keep_alive" => flags.keep_alive = true,
+                    _ => bail!("unknown function flag {:?} in `walrus.flags`", name),
+                }
+            }
+            updates.push((id, flags));
+        }
+        for (id, added) in updates.iter() {
+            let flags = &mut self.funcs.get_mut(*id).flags;
+            flags.no_inline |= added.no_inline;
+            flags.no_modify |= added.no_modify;
+            flags.keep_alive |= added.keep_alive;
+        }
+        Ok(updates.len())
+    }
+
+    /// Call `f` with each instruction of each local function, along with the
+    /// function, the instruction sequence it is in, and its index there.
+    ///
+    /// A function's sequences are visited depth first, starting with its
+    /// entry block, and the instructions of each sequence in order.
+    pub fn walk_instrs(&self, mut f: impl FnMut(FunctionId, InstrSeqId, usize, &Instr)) {
+        for (id, func) in self.funcs.iter_local() {
+            for seq in func.instr_seqs() {
+                for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+                    f(id, seq, index, instr);
+                }
+            }
+        }
+    }
+
+    /// Check that the code section has as many bodies as the function section
+    /// declares functions, which a truncated or corrupt module may not.
+    pub(crate) fn check_code_section_count(declared: u32, bodies: u32) -> Result<()> {
+        if declared == bodies {
+            return Ok(());
+        }
+        Err(Error::new(ErrorKind::InvalidWasm)).with_context(|| {
+            format!(
+                "the function section declares {} functions, but the code section has {} bodies",
+                declared, bodies
+            )
+        })
+    }
+
     /// Declare local functions after seeing the `function` section of a wasm
     /// executable.
     pub(crate) fn declare_local_functions(
@@ -325,7 +616,7 @@ impl Module {
                 .funcs
                 .arena
                 .alloc_with_id(|id| Function::new_uninitialized(id, ty));
-            let idx = ids.push_func(id);
+            let idx = ids.push_func(id)?;
             if self.config.generate_synthetic_names_for_anonymous_items {
                 self.funcs.get_mut(id).name = Some(format!("f{}", idx));
             }
@@ -363,7 +654,7 @@ impl Module {
             let type_ = self.types.get(ty);
             for ty in type_.params().iter() {
                 let local_id = self.locals.add(*ty);
-                let idx = indices.push_local(id, local_id);
+                let idx = indices.push_local(id, local_id)?;
                 args.push(local_id);
                 if self.config.generate_synthetic_names_for_anonymous_items {
                     let name = format!("arg{}", idx);
@@ -378,6 +669,13 @@ impl Module {
             let results = type_.results().to_vec();
             self.types.add_entry_ty(&results);
 
+            let mut original = None;
+            if self.config.preserve_original_function_bodies {
+                let mut reader = body.get_binary_reader();
+                let bytes = reader.read_bytes(reader.bytes_remaining())?;
+                original = Some(bytes.to_vec());
+            }
+
             // Next up comes all the locals of the function.
             let mut reader = body.get_binary_reader();
             for _ in 0..reader.read_var_u32()? {
@@ -385,10 +683,10 @@ impl Module {
                 let count = reader.read_var_u32()?;
                 let ty = reader.read_type()?;
                 validator.define_locals(pos, count, ty)?;
-                let ty = ValType::parse(&ty)?;
+                let ty = ValType::from_parser(&ty)?;
                 for _ in 0..count {
                     let local_id = self.locals.add(ty);
-                    let idx = indices.push_local(id, local_id);
+                    let idx = indices.push_local(id, local_id)?;
                     if self.config.generate_synthetic_names_for_anonymous_items {
                         let name = format!("l{}", idx);
                         self.locals.get_mut(local_id).name = Some(name);
@@ -396,6 +694,17 @@ impl Module {
                 }
             }
 
+            if let Some(bytes) = original {
+                let original = OriginalBody {
+                    types: original::type_immediates(&bytes, indices)?,
+                    bytes,
+                    locals: indices.locals(id).to_vec(),
+                    references: Vec::new(),
+                    pinned: None,
+                };
+                self.funcs.original.insert(id, original);
+            }
+
             bodies.push((id, reader, args, ty, validator));
         }
 
@@ -426,6 +735,20 @@ impl Module {
             self.funcs.arena[id].kind = FunctionKind::Local(func);
         }
 
+        // Now that the bodies are parsed, record the original index of every
+        // item they refer to, so that emitting a pinned function can check
+        // that its encoding is still valid.
+        if !self.funcs.original.is_empty() {
+            let original_indices = indices.original_indices();
+            for (id, body) in self.funcs.original.iter_mut() {
+                let func = self.funcs.arena[*id].kind.unwrap_local();
+                body.references = original::references(func)
+                    .into_iter()
+                    .filter_map(|item| Some((item, *original_indices.get(&item)?)))
+                    .collect();
+            }
+        }
+
         Ok(())
     }
 
@@ -561,6 +884,110 @@ impl Module {
             bail!("cannot replace function [{fid:?}], it is not an imported function");
         }
     }
+
+    /// Wrap a function in a new function with the same signature, which calls
+    /// it.
+    ///
+    /// The `wrapper` closure is given the wrapped function's ID, a builder for
+    /// the body of the new function, and the new function's argument locals.
+    /// It may emit any code that should run before the call, and must leave
+    /// the arguments to pass to the wrapped function on the stack. The call is
+    /// then appended to the body, and its results are returned as is.
+    ///
+    /// For example, to wrap a function, passing its arguments through
+    /// unchanged,
+    ///
+    /// ```ignore
+    /// module.with_trampoline(fid, true, |_, body, args| {
+    ///     for arg in args {
+    ///         body.local_get(*arg);
+    ///     }
+    /// })?;
+    /// ```
+    ///
+    /// If `reexport` is `true`, every export of the wrapped function is
+    /// changed to export the new function instead, and the wrapped function is
+    /// exported under the hidden name `__original_{name}` for each of them.
+    /// When that name is already taken, `_1`, `_2` and so on are appended
+    /// until it isn't.
+    /// It is an error to ask to reexport a function that isn't exported.
+    ///
+    /// This function returns the function ID of the new function.
+    pub fn with_trampoline(
+        &mut self,
+        target: FunctionId,
+        reexport: bool,
+        wrapper: impl FnOnce(FunctionId, &mut InstrSeqBuilder, &[LocalId]),
+    ) -> Result<FunctionId> {
+        let exports = self
+            .exports
+            .iter()
+            .filter(|e| matches!(e.item, ExportItem::Function(f) if f == target))
+            .map(|e| e.id())
+            .collect::<Vec<_>>();
+        if reexport && exports.is_empty() {
+            bail!("cannot reexport function [{target:?}], it is not exported");
+        }
+
+        let ty = self.funcs.get(target).ty();
+        let (params, results) = self.types.params_results(ty);
+        let (params, results) = (params.to_vec(), results.to_vec());
+        let args = params
+            .iter()
+            .map(|ty| self.locals.add(*ty))
+            .collect::<Vec<_>>();
+
+        let mut builder = FunctionBuilder::new(&mut self.types, &params, &results);
+        if let Some(name) = &self.funcs.get(target).name {
+            builder.name(format!("{}.trampoline", name));
+        }
+        let mut body = builder.func_body();
+        wrapper(target, &mut body, &args);
+        body.call(target);
+        let trampoline = builder.finish(args, &mut self.funcs);
+
+        if reexport {
+            for id in exports {
+                let export = self.exports.get_mut(id);
+                export.item = ExportItem::Function(trampoline);
+                let base = format!("__original_{}", export.name);
+                let mut hidden = base.clone();
+                let mut suffix = 0;
+                while self.exports.iter().any(|e| e.name == hidden) {
+                    suffix += 1;
+                    hidden = format!("{}_{}", base, suffix);
+                }
+                self.exports.add(&hidden, target);
+            }
+        }
+        Ok(trampoline)
+    }
+
+    /// Set the type of the sequence `seq` in the local function `func` to
+    /// the results its instructions leave on the stack, keeping its
+    /// parameters, and return the new type.
+    ///
+    /// This saves spelling out result types when building blocks by hand:
+    /// build the block with no type, then infer it once its body is done.
+    /// See `LocalFunction::inferred_results` for when the results can't be
+    /// inferred; the sequence is left untouched in that case.
+    pub fn infer_block_type(&mut self, func: FunctionId, seq: InstrSeqId) -> Result<InstrSeqType> {
+        let local = match &self.funcs.get(func).kind {
+            FunctionKind::Local(local) => local,
+            _ => bail!("cannot infer block types in function [{func:?}], it is not local"),
+        };
+        let results = local.inferred_results(self, seq)?;
+        let params = match local.block(seq).ty {
+            InstrSeqType::Simple(_) => Vec::new(),
+            InstrSeqType::MultiValue(ty) => self.types.params(ty).to_vec(),
+        };
+        let ty = InstrSeqType::new(&mut self.types, &params, &results);
+        match &mut self.funcs.get_mut(func).kind {
+            FunctionKind::Local(local) => local.block_mut(seq).ty = ty,
+            _ => unreachable!(),
+        }
+        Ok(ty)
+    }
 }
 
 fn used_local_functions<'a>(cx: &mut EmitContext<'a>) -> Vec<(FunctionId, &'a LocalFunction, u64)> {
@@ -608,7 +1035,6 @@ impl Emit for ModuleFunctions {
             return;
         }
 
-        let mut wasm_code_section = wasm_encoder::CodeSection::new();
         let code_section_start_offset = cx.wasm_module.as_slice().len() + 1;
 
         let generate_map = cx.module.config.preserve_code_transform;
@@ -619,6 +1045,30 @@ impl Emit for ModuleFunctions {
         let bytes = maybe_parallel!(functions.(into_iter | into_par_iter))
             .map(|(id, func, _size)| {
                 log::debug!("emit function {:?} {:?}", id, cx.module.funcs.get(id).name);
+                if let Some(original) = cx.module.funcs.pinned(id) {
+                    let (wasm, byte_len, used_locals, local_indices) = original.emit(cx.indices);
+                    return (wasm, byte_len, id, used_locals, local_indices, None);
+                }
+                debug_assert!(
+                    func.builder()
+                        .arena
+                        .iter()
+                        .all(|(_, seq)| seq.preamble_intact()),
+                    "function {:?} has a preamble that a pass changed",
+                    id
+                );
+                debug_assert!(
+                    func.return_type_mismatches(cx.module).is_empty(),
+                    "function {:?} returns the wrong values: {}",
+                    id,
+                    func.return_type_mismatches(cx.module)[0]
+                );
+                debug_assert!(
+                    func.call_argument_mismatches(cx.module).is_empty(),
+                    "function {:?} calls with the wrong arguments: {}",
+                    id,
+                    func.call_argument_mismatches(cx.module)[0]
+                );
                 let mut wasm = Vec::new();
                 let mut map = if generate_map { Some(Vec::new()) } else { None };
 
@@ -645,21 +1095,28 @@ impl Emit for ModuleFunctions {
         let mut instruction_map = BTreeMap::new();
         cx.indices.locals.reserve(bytes.len());
 
+        // Each body is already prefixed with its size, so the section is
+        // their count followed by the bodies as they are.
+        let mut section = Vec::new();
+        encoding::write_u32(&mut section, bytes.len() as u32);
+        let bodies_start = section.len();
         let mut offset_data = Vec::new();
         for (wasm, byte_len, id, used_locals, local_indices, map) in bytes {
             let leb_len = wasm.len() - byte_len;
-            wasm_code_section.raw(&wasm[leb_len..]);
+            section.extend_from_slice(&wasm);
             cx.indices.locals.insert(id, local_indices);
             cx.locals.insert(id, used_locals);
             offset_data.push((byte_len, id, map, leb_len));
         }
-        cx.wasm_module.section(&wasm_code_section);
+        cx.wasm_module.section(&wasm_encoder::RawSection {
+            id: wasm_encoder::SectionId::Code as u8,
+            data: &section,
+        });
 
-        let mut cur_offset = cx.wasm_module.as_slice().len() - wasm_code_section.byte_len();
+        let mut cur_offset = cx.wasm_module.as_slice().len() - (section.len() - bodies_start);
 
         // update the map afterwards based on final offset differences
         for (byte_len, id, map, leb_len) in offset_data {
-            // (this assumes the leb encodes the same)
             let code_start_offset = cur_offset + leb_len;
             cur_offset += leb_len + byte_len;
             if let Some(map) = map {
@@ -682,7 +1139,7 @@ impl Emit for ModuleFunctions {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Export, FunctionBuilder, Module};
+    use crate::{Export, FunctionBuilder, Module, ModuleConfig};
 
     #[test]
     fn get_memory_id() {
@@ -775,6 +1232,139 @@ mod tests {
         }
     }
 
+    /// Running `with_trampoline` should wrap the function in one calling it,
+    /// and move its export over to the wrapper
+    #[test]
+    fn with_trampoline() {
+        let mut module = Module::default();
+
+        // Create the original function, which doubles its argument
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let x = module.locals.add(ValType::I32);
+        builder
+            .name("double".to_string())
+            .func_body()
+            .local_get(x)
+            .local_get(x)
+            .binop(crate::ir::BinaryOp::I32Add);
+        let original_fn_id = builder.finish(vec![x], &mut module.funcs);
+        module.exports.add("double", original_fn_id);
+
+        // Wrap it in a function that increments the argument first
+        let trampoline = module
+            .with_trampoline(original_fn_id, true, |target, body, args| {
+                assert_eq!(target, original_fn_id);
+                body.local_get(args[0])
+                    .i32_const(1)
+                    .binop(crate::ir::BinaryOp::I32Add);
+            })
+            .expect("wrapping worked");
+
+        assert_eq!(
+            module.funcs.get(trampoline).ty(),
+            module.funcs.get(original_fn_id).ty()
+        );
+        assert_eq!(
+            module.exports.get_func_by_name("double").unwrap(),
+            trampoline
+        );
+        assert_eq!(
+            module
+                .exports
+                .get_func_by_name("__original_double")
+                .unwrap(),
+            original_fn_id
+        );
+        assert_eq!(module.funcs.by_name("double.trampoline"), Some(trampoline));
+
+        // Wrapping again doesn't clash with the first hidden export.
+        let second = module
+            .with_trampoline(trampoline, true, |_, body, args| {
+                body.local_get(args[0]);
+            })
+            .expect("wrapping again worked");
+        assert_eq!(module.exports.get_func_by_name("double").unwrap(), second);
+        assert_eq!(
+            module
+                .exports
+                .get_func_by_name("__original_double_1")
+                .unwrap(),
+            trampoline
+        );
+
+        let body = module.funcs.get(trampoline).kind.unwrap_local();
+        let instrs = &body.block(body.entry_block()).instrs;
+        match instrs.last() {
+            Some((crate::ir::Instr::Call(call), _)) => assert_eq!(call.func, original_fn_id),
+            other => panic!("expected a call, got {:?}", other),
+        }
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+
+        // Functions that aren't exported can only be wrapped without reexporting
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let internal = builder.finish(vec![], &mut module.funcs);
+        assert!(module
+            .with_trampoline(internal, true, |_, _, _| {})
+            .is_err());
+        assert!(module
+            .with_trampoline(internal, false, |_, _, _| {})
+            .is_ok());
+    }
+
+    #[test]
+    fn infer_block_type() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut pushes_i32 = None;
+        let mut pushes_two = None;
+        builder
+            .func_body()
+            .block(None, |block| {
+                pushes_i32 = Some(block.id());
+                block.i32_const(42);
+            })
+            .drop()
+            .block(None, |block| {
+                pushes_two = Some(block.id());
+                block.i64_const(1).f32_const(2.0);
+            })
+            .drop()
+            .drop();
+        let func = builder.finish(vec![], &mut module.funcs);
+        let (pushes_i32, pushes_two) = (pushes_i32.unwrap(), pushes_two.unwrap());
+
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(
+            local.inferred_results(&module, pushes_i32).unwrap(),
+            [ValType::I32]
+        );
+        let ty = module.infer_block_type(func, pushes_i32).unwrap();
+        assert_eq!(ty, InstrSeqType::Simple(Some(ValType::I32)));
+        let ty = module.infer_block_type(func, pushes_two).unwrap();
+        match ty {
+            InstrSeqType::MultiValue(ty) => {
+                assert_eq!(module.types.results(ty), [ValType::I64, ValType::F32])
+            }
+            other => panic!("expected a multi-value type, got {:?}", other),
+        }
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+
+        // Popping a value that isn't there is an error, and leaves the block
+        // as it was.
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut ill_typed = None;
+        builder.func_body().block(None, |block| {
+            ill_typed = Some(block.id());
+            block.drop();
+        });
+        let func = builder.finish(vec![], &mut module.funcs);
+        let ill_typed = ill_typed.unwrap();
+        assert!(module.infer_block_type(func, ill_typed).is_err());
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(local.block(ill_typed).ty, InstrSeqType::Simple(None));
+    }
+
     /// Running `replace_imported_func` with a closure that builds
     /// a function should replace the existing function with the new one
     #[test]
@@ -850,4 +1440,343 @@ mod tests {
             "new local function has the right kind"
         );
     }
+
+    #[test]
+    fn group_by_type() {
+        let mut module = Module::default();
+        let unary = module.types.add(&[ValType::I32], &[ValType::I32]);
+        let nullary = module.types.add(&[], &[]);
+        let (import, _) = module.add_import_func("env", "f", unary);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let arg = module.locals.add(ValType::I32);
+        builder.func_body().local_get(arg);
+        let local = builder.finish(vec![arg], &mut module.funcs);
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let other = builder.finish(vec![], &mut module.funcs);
+
+        let groups = module.funcs.group_by_type(&module.types);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&unary], [import, local]);
+        assert_eq!(groups[&nullary], [other]);
+
+        assert_eq!(
+            module.types.functions_with_type(&module.funcs, unary),
+            [import, local]
+        );
+    }
+
+    /// Emit a module whose exported function `f` calls another function and
+    /// contains a `nop`, which walrus drops when parsing, then parse it back
+    /// keeping the original function bodies around.
+    fn module_with_original_bodies() -> (Vec<u8>, Module, FunctionId) {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().nop();
+        let g = builder.finish(vec![], &mut module.funcs);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().nop().call(g);
+        let f = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", f);
+        let wasm = module.emit_wasm();
+
+        let module = ModuleConfig::new()
+            .preserve_original_function_bodies(true)
+            .parse(&wasm)
+            .unwrap();
+        let f = module.exports.get_func_by_name("f").unwrap();
+        (wasm, module, f)
+    }
+
+    fn code_bodies(wasm: &[u8]) -> Vec<Vec<u8>> {
+        wasmparser::Parser::new(0)
+            .parse_all(wasm)
+            .filter_map(|payload| match payload.unwrap() {
+                wasmparser::Payload::CodeSectionEntry(body) => {
+                    Some(wasm[body.range().start..body.range().end].to_vec())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pin_original_encoding() {
+        let (wasm, mut module, f) = module_with_original_bodies();
+        let original = code_bodies(&wasm);
+
+        // `f` keeps its `nop`, while the function it calls, which isn't
+        // pinned, is re-encoded without one.
+        module.funcs.pin_original_encoding(f).unwrap();
+        let emitted = code_bodies(&module.emit_wasm());
+        assert_eq!(emitted[0], original[0]);
+        assert_ne!(emitted[1], original[1]);
+    }
+
+    #[test]
+    fn pin_requires_original_bodies() {
+        let (wasm, _, _) = module_with_original_bodies();
+        let mut module = Module::from_buffer(&wasm).unwrap();
+        let f = module.exports.get_func_by_name("f").unwrap();
+        assert!(module.funcs.pin_original_encoding(f).is_err());
+    }
+
+    #[test]
+    fn pin_original_encoding_then_mutate() {
+        let (_, mut module, f) = module_with_original_bodies();
+        module.funcs.pin_original_encoding(f).unwrap();
+
+        let func = module.funcs.get_mut(f).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.builder_mut().instr_seq(entry).nop();
+        let err = module.try_emit_wasm().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("modified after its original encoding was pinned"));
+    }
+
+    #[test]
+    fn pin_original_encoding_then_shift_indices() {
+        let (_, mut module, f) = module_with_original_bodies();
+        module.funcs.pin_original_encoding(f).unwrap();
+
+        // Imported functions come first in the function index space, so this
+        // moves the function that `f` calls.
+        let ty = module.types.add(&[], &[]);
+        module.add_import_func("env", "h", ty);
+        let err = module.try_emit_wasm().unwrap_err();
+        assert!(err.to_string().contains("has moved to index"));
+    }
+
+    #[test]
+    fn pin_original_encoding_then_shift_types() {
+        let mut module = Module::default();
+        let table = module.tables.add_local(1, None, ValType::Funcref);
+        let unary = module.types.add(&[ValType::I32], &[]);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .nop()
+            .i32_const(0)
+            .block(InstrSeqType::MultiValue(unary), |block| {
+                block.drop();
+            })
+            .i32_const(0)
+            .i32_const(0)
+            .call_indirect(unary, table);
+        let f = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", f);
+        let wasm = module.emit_wasm();
+
+        let mut module = ModuleConfig::new()
+            .preserve_original_function_bodies(true)
+            .parse(&wasm)
+            .unwrap();
+        let f = module.exports.get_func_by_name("f").unwrap();
+        module.funcs.pin_original_encoding(f).unwrap();
+
+        // Types are sorted when emitted, so this moves `(param i32)` from
+        // index 1 to index 2.
+        module.types.add(&[], &[ValType::I32]);
+        let emitted = module.try_emit_wasm().unwrap();
+        wasmparser::validate(&emitted).unwrap();
+        let (original, emitted) = (code_bodies(&wasm), code_bodies(&emitted));
+        assert_eq!(original[0].len(), emitted[0].len());
+        assert_ne!(original[0], emitted[0]);
+    }
+
+    fn named_funcs(module: &mut Module, names: &[&str]) -> Vec<FunctionId> {
+        names
+            .iter()
+            .map(|name| {
+                let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+                builder.name(name.to_string()).func_body();
+                builder.finish(vec![], &mut module.funcs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn set_flags() {
+        let mut module = Module::default();
+        let funcs = named_funcs(&mut module, &["a", "b"]);
+        let flags = FunctionFlags {
+            no_modify: true,
+            ..FunctionFlags::default()
+        };
+        assert_eq!(
+            module.funcs.set_flags_by_name("b", flags).unwrap(),
+            funcs[1]
+        );
+        assert!(module.funcs.set_flags_by_name("c", flags).is_err());
+        assert_eq!(module.funcs.get(funcs[1]).flags, flags);
+
+        let modifiable = module
+            .funcs
+            .iter_modifiable_mut()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(modifiable, [funcs[0]]);
+    }
+
+    #[test]
+    fn apply_function_flags_section() {
+        let mut module = Module::default();
+        let funcs = named_funcs(&mut module, &["a", "hot loop"]);
+        module.customs.add(crate::RawCustomSection {
+            name: "walrus.flags".to_string(),
+            data: b"no_inline,keep_alive a\n\nno_modify hot loop\n".to_vec(),
+        });
+        assert_eq!(module.apply_function_flags_section().unwrap(), 2);
+        assert!(module.customs.remove_raw("walrus.flags").is_none());
+
+        let a = module.funcs.get(funcs[0]).flags;
+        assert!(a.no_inline && a.keep_alive && !a.no_modify);
+        let hot = module.funcs.get(funcs[1]).flags;
+        assert!(hot.no_modify && !hot.no_inline && !hot.keep_alive);
+
+        module.customs.add(crate::RawCustomSection {
+            name: "walrus.flags".to_string(),
+            data: b"no_optimize a".to_vec(),
+        });
+        assert!(module.apply_function_flags_section().is_err());
+    }
+
+    #[test]
+    fn round_trip_more_items_than_fit_in_16_bits() {
+        use crate::ir::{GlobalGet, Instr, Value};
+        use crate::{GlobalKind, InitExpr};
+
+        const COUNT: usize = (1 << 16) + 1;
+        let mut module = Module::default();
+        let globals = (0..COUNT)
+            .map(|i| {
+                let init = InitExpr::Value(Value::I32(i as i32));
+                module.globals.add_local(ValType::I32, false, init)
+            })
+            .collect::<Vec<_>>();
+        // Each function has a type of its own, spelling out its index in base
+        // 4, and reads the global at the other end.
+        let tys = [ValType::I32, ValType::I64, ValType::F32, ValType::F64];
+        let mut last = None;
+        for i in 0..COUNT {
+            let params = (0..9)
+                .map(|digit| tys[(i >> (2 * digit)) & 3])
+                .collect::<Vec<_>>();
+            let mut builder = FunctionBuilder::new(&mut module.types, &params, &[ValType::I32]);
+            builder.func_body().global_get(globals[COUNT - 1 - i]);
+            let args = params.iter().map(|ty| module.locals.add(*ty)).collect();
+            last = Some(builder.finish(args, &mut module.funcs));
+        }
+        module.exports.add("last", last.unwrap());
+
+        let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+        assert_eq!(module.funcs.iter().count(), COUNT);
+        assert_eq!(module.globals.iter().count(), COUNT);
+        // One more for the type of the functions' entry blocks.
+        assert_eq!(module.types.iter().count(), COUNT + 1);
+
+        let last = module.exports.get_func_by_name("last").unwrap();
+        let func = module.funcs.get(last).kind.unwrap_local();
+        assert_eq!(module.types.params(func.ty()).len(), 9);
+        let global = match func.block(func.entry_block())[0].0 {
+            Instr::GlobalGet(GlobalGet { global }) => global,
+            ref other => panic!("unexpected {:?}", other),
+        };
+        match module.globals.get(global).kind {
+            GlobalKind::Local(InitExpr::Value(Value::I32(0))) => {}
+            ref other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn code_section_count_must_match() {
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+            0x03, 0x04, 0x03, 0x00, 0x00, 0x00, // function section, three functions
+            0x0a, 0x07, 0x02, // code section, with two bodies
+            0x02, 0x00, 0x0b, //
+            0x02, 0x00, 0x0b, //
+        ];
+        let err = Module::from_buffer(&wasm).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::InvalidWasm)
+        );
+        assert!(format!("{:#}", err).contains("declares 3 functions"));
+        assert!(format!("{:#}", err).contains("has 2 bodies"));
+
+        // No code section at all.
+        let err = Module::from_buffer(&wasm[..20]).unwrap_err();
+        assert!(format!("{:#}", err).contains("has 0 bodies"));
+    }
+
+    #[test]
+    fn preserve_unreachable_code() {
+        use crate::ir::{BinaryOp, Block, Instr};
+
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().block(ValType::I32, |block| {
+            let id = block.id();
+            // Everything after the `br` is typed stack-polymorphically: the
+            // `i32.add` pops values that were never pushed.
+            block
+                .i32_const(1)
+                .br(id)
+                .binop(BinaryOp::I32Add)
+                .unreachable()
+                .i32_const(2);
+        });
+        let f = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", f);
+        let wasm = module.emit_wasm();
+        wasmparser::validate(&wasm).unwrap();
+
+        let block_len = |module: &Module| {
+            let f = module.exports.get_func_by_name("f").unwrap();
+            let func = module.funcs.get(f).kind.unwrap_local();
+            let seq = match func.block(func.entry_block()).instrs[..] {
+                [(Instr::Block(Block { seq }), _)] => seq,
+                _ => panic!("expected a single block"),
+            };
+            let block = func.block(seq);
+            (block.len(), block.reachable_len())
+        };
+
+        let module = Module::from_buffer(&wasm).unwrap();
+        assert_eq!(block_len(&module), (2, 2));
+
+        let module = ModuleConfig::new()
+            .preserve_unreachable_code(true)
+            .parse(&wasm)
+            .unwrap();
+        assert_eq!(block_len(&module), (5, 2));
+        let emitted = module.emit_wasm();
+        wasmparser::validate(&emitted).unwrap();
+        assert_eq!(code_bodies(&emitted), code_bodies(&wasm));
+    }
+
+    #[test]
+    fn walk_instrs() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(1).drop();
+        let f = builder.finish(vec![], &mut module.funcs);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().block(None, |block| {
+            block.i32_const(2).drop();
+        });
+        let g = builder.finish(vec![], &mut module.funcs);
+
+        let mut counts = BTreeMap::new();
+        module.walk_instrs(|func, seq, index, instr| {
+            let local = module.funcs.get(func).kind.unwrap_local();
+            assert!(std::ptr::eq(&local.block(seq).instrs[index].0, instr));
+            *counts.entry(func).or_insert(0) += 1;
+        });
+        assert_eq!(counts[&f], 2);
+        assert_eq!(counts[&g], 3);
+        assert_eq!(counts.values().sum::<usize>(), 5);
+    }
 }
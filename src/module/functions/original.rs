@@ -0,0 +1,230 @@
+//! Keeping the original encoding of function bodies around, so that pinned
+//! functions can be emitted verbatim.
+
+use crate::emit::IdsToIndices;
+use crate::encoding::{self, Cursor};
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::parse::{IndexedItem, IndicesToIds};
+use crate::{FunctionId, LocalFunction, LocalId, Result, TypeId};
+use anyhow::bail;
+use std::collections::HashSet;
+use std::ops::Range;
+use wasmparser::{FunctionBody, Operator, TypeOrFuncType};
+
+/// A function body's encoding in the original wasm binary.
+#[derive(Debug)]
+pub(crate) struct OriginalBody {
+    /// The body's bytes, starting with its local declarations.
+    pub(crate) bytes: Vec<u8>,
+    /// The function's locals, in the order of their original indices.
+    pub(crate) locals: Vec<LocalId>,
+    /// Every item other than a type that the body refers to, along with its
+    /// original index.
+    pub(crate) references: Vec<(IndexedItem, u32)>,
+    /// The type indices in the body, which are rewritten when it is emitted.
+    pub(crate) types: Vec<TypeImmediate>,
+    /// The function as it was when it was pinned, if it has been.
+    pub(crate) pinned: Option<Snapshot>,
+}
+
+/// A copy of a function's signature and instructions, used to tell whether it
+/// has been modified.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Snapshot {
+    ty: TypeId,
+    args: Vec<LocalId>,
+    seqs: Vec<(InstrSeqType, Vec<Instr>)>,
+}
+
+/// A type index encoded in a function body, as the type of a block or of a
+/// `call_indirect`.
+#[derive(Debug)]
+pub(crate) struct TypeImmediate {
+    /// Where the index is in the body's bytes.
+    range: Range<usize>,
+    /// The type it refers to.
+    ty: TypeId,
+    /// Whether it is encoded as a block type, which is a signed LEB128.
+    signed: bool,
+}
+
+/// Find every type index in the given function body's encoding.
+pub(crate) fn type_immediates(bytes: &[u8], indices: &IndicesToIds) -> Result<Vec<TypeImmediate>> {
+    let mut immediates = Vec::new();
+    let mut operators = FunctionBody::new(0, bytes).get_operators_reader()?;
+    while !operators.eof() {
+        let (op, offset) = operators.read_with_offset()?;
+        let (index, signed) = match op {
+            Operator::Block {
+                ty: TypeOrFuncType::FuncType(index),
+            }
+            | Operator::Loop {
+                ty: TypeOrFuncType::FuncType(index),
+            }
+            | Operator::If {
+                ty: TypeOrFuncType::FuncType(index),
+            } => (index, true),
+            Operator::CallIndirect { index, .. } => (index, false),
+            _ => continue,
+        };
+
+        // Both kinds of index directly follow a single-byte opcode.
+        let start = offset + 1;
+        let mut cursor = Cursor::new(&bytes[start..]);
+        if signed {
+            cursor.read_i64()?;
+        } else {
+            cursor.read_u32()?;
+        }
+        immediates.push(TypeImmediate {
+            range: start..start + cursor.position(),
+            ty: indices.get_type(index)?,
+            signed,
+        });
+    }
+    Ok(immediates)
+}
+
+impl Snapshot {
+    pub(crate) fn new(func: &LocalFunction) -> Snapshot {
+        let mut v = Seqs::default();
+        dfs_in_order(&mut v, func, func.entry_block());
+        return Snapshot {
+            ty: func.ty(),
+            args: func.args.clone(),
+            seqs: v.seqs,
+        };
+
+        #[derive(Default)]
+        struct Seqs {
+            seqs: Vec<(InstrSeqType, Vec<Instr>)>,
+        }
+
+        impl<'instr> Visitor<'instr> for Seqs {
+            fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+                let instrs = seq.instrs.iter().map(|(instr, _)| instr.clone());
+                self.seqs.push((seq.ty, instrs.collect()));
+            }
+        }
+    }
+}
+
+/// Every item in an index space other than the types that the given
+/// function's body refers to.
+pub(crate) fn references(func: &LocalFunction) -> Vec<IndexedItem> {
+    let mut v = References {
+        items: HashSet::new(),
+    };
+    dfs_in_order(&mut v, func, func.entry_block());
+    return v.items.into_iter().collect();
+
+    struct References {
+        items: HashSet<IndexedItem>,
+    }
+
+    impl<'instr> Visitor<'instr> for References {
+        fn visit_table_id(&mut self, &table: &crate::TableId) {
+            self.items.insert(IndexedItem::Table(table));
+        }
+
+        fn visit_function_id(&mut self, &func: &FunctionId) {
+            self.items.insert(IndexedItem::Function(func));
+        }
+
+        fn visit_global_id(&mut self, &global: &crate::GlobalId) {
+            self.items.insert(IndexedItem::Global(global));
+        }
+
+        fn visit_memory_id(&mut self, &memory: &crate::MemoryId) {
+            self.items.insert(IndexedItem::Memory(memory));
+        }
+
+        fn visit_element_id(&mut self, &elem: &crate::ElementId) {
+            self.items.insert(IndexedItem::Element(elem));
+        }
+
+        fn visit_data_id(&mut self, &data: &crate::DataId) {
+            self.items.insert(IndexedItem::Data(data));
+        }
+    }
+}
+
+impl OriginalBody {
+    /// Check that the given pinned function can still be emitted with its
+    /// original encoding: that it wasn't modified since it was pinned, and
+    /// that every item other than a type that it refers to is still at its
+    /// original index.
+    pub(crate) fn check(
+        &self,
+        id: FunctionId,
+        func: &LocalFunction,
+        indices: &IdsToIndices,
+    ) -> Result<()> {
+        if self.pinned.as_ref() != Some(&Snapshot::new(func)) {
+            bail!(
+                "function {:?} was modified after its original encoding was pinned",
+                id
+            );
+        }
+
+        for &(item, original) in self.references.iter() {
+            let index = match item {
+                IndexedItem::Table(id) => indices.get_table_index(id),
+                IndexedItem::Type(id) => indices.get_type_index(id),
+                IndexedItem::Function(id) => indices.get_func_index(id),
+                IndexedItem::Global(id) => indices.get_global_index(id),
+                IndexedItem::Memory(id) => indices.get_memory_index(id),
+                IndexedItem::Element(id) => indices.get_element_index(id),
+                IndexedItem::Data(id) => indices.get_data_index(id),
+            };
+            if index != original {
+                bail!(
+                    "the pinned encoding of function {:?} refers to {:?} by its original \
+                     index {}, but it has moved to index {}",
+                    id,
+                    item,
+                    original,
+                    index
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the original encoding of a pinned function, which must have been
+    /// checked with `check`, with its type indices rewritten to their new
+    /// values.
+    ///
+    /// Returns the size-prefixed body and the size of the body itself, along
+    /// with the function's locals and their indices.
+    pub(crate) fn emit(
+        &self,
+        indices: &IdsToIndices,
+    ) -> (Vec<u8>, usize, IdHashSet<Local>, IdHashMap<Local, u32>) {
+        let mut body = Vec::with_capacity(self.bytes.len());
+        let mut copied = 0;
+        for immediate in self.types.iter() {
+            body.extend_from_slice(&self.bytes[copied..immediate.range.start]);
+            let index = indices.get_type_index(immediate.ty);
+            if immediate.signed {
+                encoding::write_i64(&mut body, index.into());
+            } else {
+                encoding::write_u32(&mut body, index);
+            }
+            copied = immediate.range.end;
+        }
+        body.extend_from_slice(&self.bytes[copied..]);
+
+        let mut wasm = Vec::new();
+        encoding::write_bytes(&mut wasm, &body);
+        let used_locals = self.locals.iter().cloned().collect();
+        let local_indices = self
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(index, local)| (*local, index as u32))
+            .collect();
+        (wasm, body.len(), used_locals, local_indices)
+    }
+}
@@ -1,8 +1,11 @@
 //! Globals within a wasm module.
 use crate::emit::{Emit, EmitContext};
+use crate::ir::Value;
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
 use crate::{ImportId, InitExpr, Module, Result, ValType};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 /// The id of a global.
 pub type GlobalId = Id<Global>;
@@ -83,6 +86,11 @@ impl ModuleGlobals {
         &mut self.arena[id]
     }
 
+    /// Is there a global with the given id that hasn't been deleted?
+    pub(crate) fn contains(&self, id: GlobalId) -> bool {
+        self.arena.contains(id)
+    }
+
     /// Removes a global from this module.
     ///
     /// It is up to you to ensure that any potential references to the deleted
@@ -95,6 +103,34 @@ impl ModuleGlobals {
     pub fn iter(&self) -> impl Iterator<Item = &Global> {
         self.arena.iter().map(|(_, f)| f)
     }
+
+    /// Get a parallel iterator of this module's globals.
+    ///
+    /// Requires the `parallel` feature of this crate to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = &Global> {
+        self.arena.par_iter().map(|(_, f)| f)
+    }
+
+    /// Get the constant value that the given global is initialized to.
+    ///
+    /// Returns `Some` only if the global is locally defined and its
+    /// initializer is a single constant value. Imported globals, and globals
+    /// initialized from another global or a reference, return `None`.
+    pub fn initial_value(&self, id: GlobalId) -> Option<Value> {
+        match self.get(id).kind {
+            GlobalKind::Local(InitExpr::Value(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Is the given global declared without the `mutable` flag?
+    ///
+    /// Together with `initial_value`, this tells whether reads of the global
+    /// can be treated as a compile-time constant.
+    pub fn is_immutable(&self, id: GlobalId) -> bool {
+        !self.get(id).mutable
+    }
 }
 
 impl Module {
@@ -107,12 +143,10 @@ impl Module {
         log::debug!("parse global section");
         for g in section {
             let g = g?;
-            let id = self.globals.add_local(
-                ValType::parse(&g.ty.content_type)?,
-                g.ty.mutable,
-                InitExpr::eval(&g.init_expr, ids)?,
-            );
-            ids.push_global(id);
+            let ty = ValType::from_parser(&g.ty.content_type)?;
+            let init = InitExpr::eval_typed(&g.init_expr, ids, self, ty)?;
+            let id = self.globals.add_local(ty, g.ty.mutable, init);
+            ids.push_global(id)?;
         }
         Ok(())
     }
@@ -142,13 +176,76 @@ impl Emit for ModuleGlobals {
 
             wasm_global_section.global(
                 wasm_encoder::GlobalType {
-                    val_type: global.ty.to_wasmencoder_type(),
+                    val_type: global.ty.to_encoder(),
                     mutable: global.mutable,
                 },
-                &local.to_wasmencoder_type(cx),
+                &local.to_encoder(cx),
             );
         }
 
         cx.wasm_module.section(&wasm_global_section);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::Cursor;
+    use crate::ir::Value;
+    use crate::{InitExpr, Module, ValType};
+
+    #[test]
+    fn initial_value() {
+        let mut module = Module::default();
+        let constant =
+            module
+                .globals
+                .add_local(ValType::I32, false, InitExpr::Value(Value::I32(42)));
+        let derived = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Global(constant));
+        let (imported, _) = module.add_import_global("env", "g", ValType::I32, false);
+
+        match module.globals.initial_value(constant) {
+            Some(Value::I32(42)) => {}
+            other => panic!("unexpected initial value: {:?}", other),
+        }
+        assert!(module.globals.initial_value(derived).is_none());
+        assert!(module.globals.initial_value(imported).is_none());
+
+        assert!(module.globals.is_immutable(constant));
+        assert!(!module.globals.is_immutable(derived));
+        assert!(module.globals.is_immutable(imported));
+    }
+
+    #[test]
+    fn emits_mutability_flag() {
+        let mut module = Module::default();
+        let global = module
+            .globals
+            .add_local(ValType::I64, false, InitExpr::Value(Value::I64(7)));
+
+        // Find the `globaltype` of the only global, and return its flag byte.
+        fn flag(wasm: &[u8]) -> u8 {
+            let mut pos = 8;
+            loop {
+                let id = wasm[pos];
+                let mut cursor = Cursor::new(&wasm[pos + 1..]);
+                let size = cursor.read_u32().unwrap() as usize;
+                pos += 1 + cursor.position();
+                if id == 6 {
+                    let mut cursor = Cursor::new(&wasm[pos..]);
+                    assert_eq!(cursor.read_u32().unwrap(), 1);
+                    assert_eq!(cursor.read_val_type().unwrap(), ValType::I64);
+                    return cursor.read_u8().unwrap();
+                }
+                pos += size;
+            }
+        }
+
+        assert_eq!(flag(&module.emit_wasm()), 0x00);
+        module.globals.get_mut(global).mutable = true;
+        assert_eq!(flag(&module.emit_wasm()), 0x01);
+        module.globals.get_mut(global).mutable = false;
+        assert_eq!(flag(&module.emit_wasm()), 0x00);
+    }
+}
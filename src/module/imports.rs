@@ -1,12 +1,14 @@
 //! A wasm module's imports.
 
-use anyhow::{bail, Context};
+use anyhow::{bail, Context, Error};
+use std::collections::HashMap;
 
 use crate::emit::{Emit, EmitContext};
+use crate::error::ErrorKind;
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
-use crate::{FunctionId, GlobalId, MemoryId, Result, TableId};
-use crate::{Module, TypeId, ValType};
+use crate::{DuplicateImports, Module, TypeId, ValType};
+use crate::{FunctionId, GlobalId, ItemCounts, MemoryId, Result, TableId};
 
 /// The id of an import.
 pub type ImportId = Id<Import>;
@@ -95,6 +97,57 @@ impl ModuleImports {
         })
     }
 
+    /// The imported functions with the module and name they are imported
+    /// from, in the order they were declared in the original wasm or added
+    /// since.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &str, FunctionId)> {
+        self.iter().filter_map(|i| match i.kind {
+            ImportKind::Function(id) => Some((i.module.as_str(), i.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// The imported tables with the module and name they are imported from,
+    /// in declaration order.
+    pub fn tables(&self) -> impl Iterator<Item = (&str, &str, TableId)> {
+        self.iter().filter_map(|i| match i.kind {
+            ImportKind::Table(id) => Some((i.module.as_str(), i.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// The imported memories with the module and name they are imported
+    /// from, in declaration order.
+    pub fn memories(&self) -> impl Iterator<Item = (&str, &str, MemoryId)> {
+        self.iter().filter_map(|i| match i.kind {
+            ImportKind::Memory(id) => Some((i.module.as_str(), i.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// The imported globals with the module and name they are imported from,
+    /// in declaration order.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &str, GlobalId)> {
+        self.iter().filter_map(|i| match i.kind {
+            ImportKind::Global(id) => Some((i.module.as_str(), i.name.as_str(), id)),
+            _ => None,
+        })
+    }
+
+    /// Count the imports of each kind.
+    pub fn count_by_kind(&self) -> ItemCounts {
+        let mut counts = ItemCounts::default();
+        for import in self.iter() {
+            match import.kind {
+                ImportKind::Function(_) => counts.functions += 1,
+                ImportKind::Table(_) => counts.tables += 1,
+                ImportKind::Memory(_) => counts.memories += 1,
+                ImportKind::Global(_) => counts.globals += 1,
+            }
+        }
+        counts
+    }
+
     /// Get the import with the given module and name
     pub fn find(&self, module: &str, name: &str) -> Option<ImportId> {
         let import = self
@@ -166,28 +219,54 @@ impl Module {
         ids: &mut IndicesToIds,
     ) -> Result<()> {
         log::debug!("parse import section");
+        let mut seen: HashMap<(&str, &str), Vec<ImportId>> = HashMap::new();
         for entry in section {
             let entry = entry?;
+            let field = entry.field.expect("module linking not supported");
+            let earlier = seen.get(&(entry.module, field)).map_or(&[][..], |v| &v[..]);
+            match self.config.duplicate_imports {
+                DuplicateImports::Preserve => {}
+                DuplicateImports::Error => {
+                    for import in earlier {
+                        if self.same_import_type(*import, &entry.ty, ids)?.is_some() {
+                            return Err(Error::new(ErrorKind::DuplicateImport)).with_context(
+                                || format!("`{}.{}` is imported twice", entry.module, field),
+                            );
+                        }
+                    }
+                }
+                DuplicateImports::Merge => {
+                    let mut merged = None;
+                    for import in earlier {
+                        if self.same_import_type(*import, &entry.ty, ids)? == Some(true) {
+                            merged = Some(self.imports.get(*import).kind.clone());
+                            break;
+                        }
+                    }
+                    if let Some(kind) = merged {
+                        match kind {
+                            ImportKind::Function(id) => ids.push_func(id)?,
+                            ImportKind::Table(id) => ids.push_table(id)?,
+                            ImportKind::Memory(id) => ids.push_memory(id)?,
+                            ImportKind::Global(id) => ids.push_global(id)?,
+                        };
+                        continue;
+                    }
+                }
+            }
+
+            let import = self.imports.arena.next_id();
+            seen.entry((entry.module, field)).or_default().push(import);
             match entry.ty {
                 wasmparser::ImportSectionEntryType::Function(idx) => {
                     let ty = ids.get_type(idx)?;
-                    let id = self.add_import_func(
-                        entry.module,
-                        entry.field.expect("module linking not supported"),
-                        ty,
-                    );
-                    ids.push_func(id.0);
+                    let id = self.add_import_func(entry.module, field, ty);
+                    ids.push_func(id.0)?;
                 }
                 wasmparser::ImportSectionEntryType::Table(t) => {
-                    let ty = ValType::parse(&t.element_type)?;
-                    let id = self.add_import_table(
-                        entry.module,
-                        entry.field.expect("module linking not supported"),
-                        t.initial,
-                        t.maximum,
-                        ty,
-                    );
-                    ids.push_table(id.0);
+                    let ty = ValType::from_parser(&t.element_type)?;
+                    let id = self.add_import_table(entry.module, field, t.initial, t.maximum, ty);
+                    ids.push_table(id.0)?;
                 }
                 wasmparser::ImportSectionEntryType::Memory(m) => {
                     if m.memory64 {
@@ -195,28 +274,34 @@ impl Module {
                     };
                     let id = self.add_import_memory(
                         entry.module,
-                        entry.field.expect("module linking not supported"),
+                        field,
                         m.shared,
                         m.initial as u32,
                         m.maximum.map(|m| m as u32),
                     );
-                    ids.push_memory(id.0);
+                    ids.push_memory(id.0)?;
                 }
                 wasmparser::ImportSectionEntryType::Global(g) => {
+                    if g.mutable && self.config.disable_mutable_globals {
+                        bail!(
+                            "importing mutable global `{}` requires the mutable-globals feature",
+                            field
+                        );
+                    }
                     let id = self.add_import_global(
                         entry.module,
-                        entry.field.expect("module linking not supported"),
-                        ValType::parse(&g.content_type)?,
+                        field,
+                        ValType::from_parser(&g.content_type)?,
                         g.mutable,
                     );
-                    ids.push_global(id.0);
+                    ids.push_global(id.0)?;
                 }
                 wasmparser::ImportSectionEntryType::Module(_)
                 | wasmparser::ImportSectionEntryType::Instance(_) => {
                     unimplemented!("component model not implemented");
                 }
                 wasmparser::ImportSectionEntryType::Tag(_) => {
-                    unimplemented!("exception handling not implemented");
+                    bail!("exception handling is not supported");
                 }
             }
         }
@@ -224,6 +309,43 @@ impl Module {
         Ok(())
     }
 
+    /// Whether the import `id` is of the same kind as `ty`, and if so,
+    /// whether it has the same type too.
+    fn same_import_type(
+        &self,
+        id: ImportId,
+        ty: &wasmparser::ImportSectionEntryType,
+        ids: &IndicesToIds,
+    ) -> Result<Option<bool>> {
+        use wasmparser::ImportSectionEntryType as Entry;
+        Ok(match (self.imports.get(id).kind.clone(), ty) {
+            (ImportKind::Function(f), Entry::Function(idx)) => {
+                Some(self.funcs.get(f).ty() == ids.get_type(*idx)?)
+            }
+            (ImportKind::Table(t), Entry::Table(ty)) => {
+                let t = self.tables.get(t);
+                Some(
+                    t.initial == ty.initial
+                        && t.maximum == ty.maximum
+                        && t.element_ty == ValType::from_parser(&ty.element_type)?,
+                )
+            }
+            (ImportKind::Memory(m), Entry::Memory(ty)) => {
+                let m = self.memories.get(m);
+                Some(
+                    m.shared == ty.shared
+                        && u64::from(m.initial) == ty.initial
+                        && m.maximum.map(u64::from) == ty.maximum,
+                )
+            }
+            (ImportKind::Global(g), Entry::Global(ty)) => {
+                let g = self.globals.get(g);
+                Some(g.mutable == ty.mutable && g.ty == ValType::from_parser(&ty.content_type)?)
+            }
+            _ => None,
+        })
+    }
+
     /// Add an imported function to this module
     pub fn add_import_func(
         &mut self,
@@ -308,11 +430,7 @@ impl Emit for ModuleImports {
                         cx.indices.push_table(id);
                         let table = cx.module.tables.get(id);
                         wasm_encoder::EntityType::Table(wasm_encoder::TableType {
-                            element_type: match table.element_ty {
-                                ValType::Externref => wasm_encoder::RefType::EXTERNREF,
-                                ValType::Funcref => wasm_encoder::RefType::FUNCREF,
-                                _ => panic!("Unexpected table type"),
-                            },
+                            element_type: table.element_ty.to_encoder_ref(),
                             minimum: table.initial,
                             maximum: table.maximum,
                         })
@@ -331,7 +449,7 @@ impl Emit for ModuleImports {
                         cx.indices.push_global(id);
                         let g = cx.module.globals.get(id);
                         wasm_encoder::EntityType::Global(wasm_encoder::GlobalType {
-                            val_type: g.ty.to_wasmencoder_type(),
+                            val_type: g.ty.to_encoder(),
                             mutable: g.mutable,
                         })
                     }
@@ -398,4 +516,110 @@ mod tests {
             .get_func_by_name("mod", "dummy")
             .is_ok_and(|fid| fid == new_fn_id));
     }
+
+    #[test]
+    fn module_imports() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[], &[]);
+        let (func, _) = module.add_import_func("env", "f", ty);
+        let (memory, _) = module.add_import_memory("env", "memory", false, 1, None);
+
+        let imports = module
+            .imports()
+            .map(|i| (i.module.as_str(), i.name.as_str(), i.kind.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            imports,
+            [
+                ("env", "f", ImportKind::Function(func)),
+                ("env", "memory", ImportKind::Memory(memory)),
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_by_kind() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[], &[]);
+        let (g, _) = module.add_import_func("env", "g", ty);
+        let (memory, _) = module.add_import_memory("env", "memory", false, 1, None);
+        let (f, _) = module.add_import_func("host", "f", ty);
+
+        let functions = module.imports.functions().collect::<Vec<_>>();
+        assert_eq!(functions, [("env", "g", g), ("host", "f", f)]);
+        let memories = module.imports.memories().collect::<Vec<_>>();
+        assert_eq!(memories, [("env", "memory", memory)]);
+        assert_eq!(module.imports.tables().count(), 0);
+        assert_eq!(module.imports.globals().count(), 0);
+        assert_eq!(
+            module.imports.count_by_kind(),
+            ItemCounts {
+                functions: 2,
+                memories: 1,
+                ..ItemCounts::default()
+            }
+        );
+    }
+
+    #[test]
+    fn round_trip_shared_memory_import() {
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x02, 0x0d, 0x01, // import section, one import
+            0x03, b'e', b'n', b'v', 0x03, b'm', b'e', b'm', // "env" "mem"
+            0x02, 0x03, 0x01, 0x01, // (memory 1 1 shared)
+        ];
+        let mut module = Module::from_buffer(&wasm).unwrap();
+        let memory = module.get_memory_id().unwrap();
+        assert!(module.memories.get(memory).import.is_some());
+        assert!(module.memories.is_shared(memory));
+
+        let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+        let memory = module.get_memory_id().unwrap();
+        assert!(module.memories.get(memory).import.is_some());
+        assert!(module.memories.is_shared(memory));
+        assert_eq!(module.memories.get(memory).initial, 1);
+        assert_eq!(module.memories.get(memory).maximum, Some(1));
+    }
+
+    #[test]
+    fn duplicate_imports() {
+        use crate::{DuplicateImports, ErrorKind, ModuleConfig};
+
+        let mut module = Module::default();
+        let ty = module.types.add(&[], &[]);
+        let (first, _) = module.add_import_func("env", "f", ty);
+        let (second, _) = module.add_import_func("env", "f", ty);
+        // Of a different kind, so not a duplicate.
+        module.add_import_global("env", "f", ValType::I32, false);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().call(first).call(second);
+        let caller = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("caller", caller);
+        let wasm = module.emit_wasm();
+
+        let parse = |handling| ModuleConfig::new().duplicate_imports(handling).parse(&wasm);
+        let module = parse(DuplicateImports::Preserve).unwrap();
+        assert_eq!(module.imports.iter().count(), 3);
+
+        let err = parse(DuplicateImports::Error).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::DuplicateImport)
+        );
+        assert!(format!("{:#}", err).contains("`env.f` is imported twice"));
+
+        let module = parse(DuplicateImports::Merge).unwrap();
+        assert_eq!(module.imports.iter().count(), 2);
+        let (_, _, f) = module.imports.functions().next().unwrap();
+        let caller = module.exports.get_func_by_name("caller").unwrap();
+        let func = module.funcs.get(caller).kind.unwrap_local();
+        let callees = func
+            .block(func.entry_block())
+            .iter()
+            .map(|(instr, _)| instr.unwrap_call().func)
+            .collect::<Vec<_>>();
+        assert_eq!(callees, [f, f]);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
 }
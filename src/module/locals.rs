@@ -2,6 +2,7 @@
 
 use crate::ir::{Local, LocalId};
 use crate::ty::ValType;
+use crate::Module;
 use id_arena::Arena;
 
 /// The set of locals in each function in this module.
@@ -35,3 +36,98 @@ impl ModuleLocals {
         self.arena.iter().map(|(_, f)| f)
     }
 }
+
+/// How `Module::auto_name_locals` names locals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalNaming {
+    /// Number every local of a function, parameters first: `l0`, `l1`, ...
+    Sequential,
+    /// Number parameters and other locals separately: `param0`, `param1`,
+    /// ..., and `var0`, `var1`, ...
+    ParamsAndVars,
+}
+
+impl Module {
+    /// Give a synthetic name to every local of every local function, which
+    /// then shows up in the `name` section.
+    ///
+    /// This is mostly useful for making dumps of stripped modules readable.
+    /// Parameters are numbered in order. Other locals are numbered in the
+    /// order they were created, which for a parsed function is the order
+    /// they were declared in.
+    ///
+    /// Locals that already have a name keep it, unless `overwrite` is set.
+    pub fn auto_name_locals(&mut self, naming: LocalNaming, overwrite: bool) {
+        for (_, func) in self.funcs.iter_local() {
+            let mut vars = func
+                .used_locals()
+                .into_iter()
+                .filter(|local| !func.args.contains(local))
+                .collect::<Vec<_>>();
+            vars.sort();
+
+            let names = func.args.iter().enumerate().map(|(i, arg)| {
+                let name = match naming {
+                    LocalNaming::Sequential => format!("l{}", i),
+                    LocalNaming::ParamsAndVars => format!("param{}", i),
+                };
+                (*arg, name)
+            });
+            let vars = vars.iter().enumerate().map(|(i, var)| {
+                let name = match naming {
+                    LocalNaming::Sequential => format!("l{}", func.args.len() + i),
+                    LocalNaming::ParamsAndVars => format!("var{}", i),
+                };
+                (*var, name)
+            });
+            for (local, name) in names.chain(vars) {
+                let local = self.locals.get_mut(local);
+                if overwrite || local.name.is_none() {
+                    local.name = Some(name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    fn local_names(wasm: &[u8]) -> Vec<String> {
+        let module = Module::from_buffer(wasm).unwrap();
+        let mut names = module
+            .locals
+            .iter()
+            .filter_map(|local| local.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn auto_name_locals() {
+        let mut module = Module::default();
+        let arg = module.locals.add(ValType::I32);
+        let named = module.locals.add(ValType::I32);
+        let unnamed = module.locals.add(ValType::I64);
+        module.locals.get_mut(named).name = Some("kept".to_string());
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder
+            .func_body()
+            .local_get(arg)
+            .local_set(named)
+            .i64_const(0)
+            .local_set(unnamed);
+        let func = builder.finish(vec![arg], &mut module.funcs);
+        module.exports.add("f", func);
+
+        module.auto_name_locals(LocalNaming::Sequential, false);
+        assert_eq!(local_names(&module.emit_wasm()), ["kept", "l0", "l2"]);
+
+        module.auto_name_locals(LocalNaming::ParamsAndVars, true);
+        assert_eq!(local_names(&module.emit_wasm()), ["param0", "var0", "var1"]);
+    }
+}
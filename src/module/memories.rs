@@ -6,6 +6,14 @@ use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
 use crate::{Data, ImportId, Module, Result};
 use anyhow::bail;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// The size of a wasm page, in bytes.
+pub(crate) const PAGE_SIZE: u64 = 64 * 1024;
+
+/// The largest number of pages a 32-bit memory can have.
+pub(crate) const MAX_PAGES: u32 = 65536;
 
 /// The id of a memory.
 pub type MemoryId = Id<Memory>;
@@ -98,6 +106,11 @@ impl ModuleMemories {
         &mut self.arena[id]
     }
 
+    /// Is there a memory with the given id that hasn't been deleted?
+    pub(crate) fn contains(&self, id: MemoryId) -> bool {
+        self.arena.contains(id)
+    }
+
     /// Removes a memory from this module.
     ///
     /// It is up to you to ensure that any potential references to the deleted
@@ -111,6 +124,14 @@ impl ModuleMemories {
         self.arena.iter().map(|(_, f)| f)
     }
 
+    /// Get a parallel iterator of this module's memories.
+    ///
+    /// Requires the `parallel` feature of this crate to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = &Memory> {
+        self.arena.par_iter().map(|(_, f)| f)
+    }
+
     /// Get a mutable reference to this module's memories.
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Memory> {
         self.arena.iter_mut().map(|(_, f)| f)
@@ -120,6 +141,24 @@ impl ModuleMemories {
     pub fn len(&self) -> usize {
         self.arena.len()
     }
+
+    /// Get the initial size of the given memory, in bytes.
+    pub fn initial_size_bytes(&self, id: MemoryId) -> u64 {
+        u64::from(self.get(id).initial) * PAGE_SIZE
+    }
+
+    /// Get the maximum size of the given memory, in bytes, or `None` if it
+    /// is unbounded.
+    pub fn maximum_size_bytes(&self, id: MemoryId) -> Option<u64> {
+        self.get(id)
+            .maximum
+            .map(|pages| u64::from(pages) * PAGE_SIZE)
+    }
+
+    /// Is the given memory shared between threads?
+    pub fn is_shared(&self, id: MemoryId) -> bool {
+        self.get(id).shared
+    }
 }
 
 impl Module {
@@ -138,7 +177,7 @@ impl Module {
             let id =
                 self.memories
                     .add_local(m.shared, m.initial as u32, m.maximum.map(|m| m as u32));
-            ids.push_memory(id);
+            ids.push_memory(id)?;
         }
         Ok(())
     }
@@ -186,4 +225,19 @@ mod tests {
         module.memories.add_local(true, 1024, Some(2048));
         assert_eq!(module.memories.len(), 2);
     }
+
+    #[test]
+    fn sizes_in_bytes() {
+        let mut module = Module::default();
+        let bounded = module.memories.add_local(true, 1, Some(2));
+        let unbounded = module.memories.add_local(false, 0, None);
+
+        assert_eq!(module.memories.initial_size_bytes(bounded), 65536);
+        assert_eq!(module.memories.maximum_size_bytes(bounded), Some(131072));
+        assert!(module.memories.is_shared(bounded));
+
+        assert_eq!(module.memories.initial_size_bytes(unbounded), 0);
+        assert_eq!(module.memories.maximum_size_bytes(unbounded), None);
+        assert!(!module.memories.is_shared(unbounded));
+    }
 }
@@ -1,5 +1,8 @@
 //! A high-level API for manipulating wasm modules.
 
+#[cfg(feature = "wasm-bindgen")]
+pub mod bindgen;
+mod checked;
 mod config;
 mod custom;
 mod data;
@@ -11,13 +14,18 @@ mod globals;
 mod imports;
 mod locals;
 mod memories;
+mod optimize;
 mod producers;
+mod stats;
+mod table_allocator;
 mod tables;
+mod tags;
 mod types;
 
 use crate::emit::{Emit, EmitContext, IdsToIndices};
 use crate::error::Result;
 pub use crate::ir::InstrLocId;
+pub use crate::module::checked::{EmitError, EmitProblem, ValidationCache};
 pub use crate::module::custom::{
     CustomSection, CustomSectionId, ModuleCustomSections, RawCustomSection, TypedCustomSectionId,
     UntypedCustomSectionId,
@@ -26,27 +34,36 @@ pub use crate::module::data::{ActiveData, ActiveDataLocation, Data, DataId, Data
 pub use crate::module::debug::ModuleDebugData;
 pub use crate::module::elements::ElementKind;
 pub use crate::module::elements::{Element, ElementId, ModuleElements};
-pub use crate::module::exports::{Export, ExportId, ExportItem, ModuleExports};
+pub use crate::module::exports::{Export, ExportId, ExportItem, ItemCounts, ModuleExports};
+pub use crate::module::functions::{
+    CallArgumentMismatch, ConflictReason, EffectLocation, ReorderConflict, ReturnTypeMismatch,
+};
 pub use crate::module::functions::{FuncParams, FuncResults};
 pub use crate::module::functions::{Function, FunctionId, ModuleFunctions};
-pub use crate::module::functions::{FunctionKind, ImportedFunction, LocalFunction};
+pub use crate::module::functions::{FunctionFlags, FunctionKind, ImportedFunction, LocalFunction};
 pub use crate::module::globals::{Global, GlobalId, GlobalKind, ModuleGlobals};
 pub use crate::module::imports::{Import, ImportId, ImportKind, ModuleImports};
-pub use crate::module::locals::ModuleLocals;
+pub use crate::module::locals::{LocalNaming, ModuleLocals};
 pub use crate::module::memories::{Memory, MemoryId, ModuleMemories};
+pub(crate) use crate::module::memories::{MAX_PAGES, PAGE_SIZE};
+pub use crate::module::optimize::OptLevel;
 pub use crate::module::producers::ModuleProducers;
+pub use crate::module::stats::ModuleStats;
+pub use crate::module::table_allocator::TableAllocator;
 pub use crate::module::tables::{ModuleTables, Table, TableId};
+pub use crate::module::tags::{ModuleTags, Tag, TagId};
 pub use crate::module::types::ModuleTypes;
 use crate::parse::IndicesToIds;
 use anyhow::{bail, Context};
 use id_arena::Id;
 use log::warn;
+use std::convert::TryFrom;
 use std::fs;
 use std::mem;
 use std::path::Path;
-use wasmparser::{Parser, Payload, Validator, WasmFeatures};
+use wasmparser::{Parser, Payload, Validator};
 
-pub use self::config::ModuleConfig;
+pub use self::config::{DuplicateImports, ModuleConfig};
 
 /// A wasm module.
 #[derive(Debug, Default)]
@@ -60,6 +77,8 @@ pub struct Module {
     pub locals: ModuleLocals,
     pub exports: ModuleExports,
     pub memories: ModuleMemories,
+    /// Tags declared for the exception handling proposal.
+    pub tags: ModuleTags,
     /// Registration of passive data segments, if any
     pub data: ModuleData,
     /// Registration of passive element segments, if any
@@ -75,6 +94,12 @@ pub struct Module {
     /// The name of this module, used for debugging purposes in the `name`
     /// custom section.
     pub name: Option<String>,
+    /// Subsections of the `name` custom section that walrus doesn't
+    /// understand, as `(id, contents)` pairs.
+    ///
+    /// These are re-emitted verbatim, so any indices within them are not
+    /// updated to account for transformations of the module.
+    pub unknown_name_subsections: Vec<(u8, Vec<u8>)>,
     pub(crate) config: ModuleConfig,
 }
 
@@ -133,18 +158,12 @@ impl Module {
         ret.config = config.clone();
         let mut indices = IndicesToIds::default();
         let mut validator = Validator::new();
-        validator.wasm_features(WasmFeatures {
-            reference_types: !config.only_stable_features,
-            multi_value: true,
-            bulk_memory: !config.only_stable_features,
-            simd: !config.only_stable_features,
-            threads: !config.only_stable_features,
-            multi_memory: !config.only_stable_features,
-            ..WasmFeatures::default()
-        });
+        validator.wasm_features(config.wasm_features());
 
         let mut local_functions = Vec::new();
         let mut debug_sections = Vec::new();
+        let mut declared_functions = 0;
+        let mut code_section_seen = false;
 
         for payload in Parser::new(0).parse_all(wasm) {
             match payload? {
@@ -181,6 +200,12 @@ impl Module {
                         .context("failed to parse memory section")?;
                     ret.parse_memories(s, &mut indices)?;
                 }
+                Payload::TagSection(s) => {
+                    validator
+                        .tag_section(&s)
+                        .context("failed to parse tag section")?;
+                    ret.parse_tags(s, &mut indices)?;
+                }
                 Payload::GlobalSection(s) => {
                     validator
                         .global_section(&s)
@@ -188,6 +213,7 @@ impl Module {
                     ret.parse_globals(s, &mut indices)?;
                 }
                 Payload::ExportSection(s) => {
+                    Module::check_export_names(s.clone())?;
                     validator
                         .export_section(&s)
                         .context("failed to parse export section")?;
@@ -207,13 +233,16 @@ impl Module {
                     validator
                         .function_section(&s)
                         .context("failed to parse function section")?;
+                    declared_functions = s.get_count();
                     ret.declare_local_functions(s, &mut indices)?;
                 }
                 Payload::DataCountSection { count, range } => {
                     validator.data_count_section(count, &range)?;
-                    ret.reserve_data(count, &mut indices);
+                    ret.reserve_data(count, &mut indices)?;
                 }
                 Payload::CodeSectionStart { count, range, .. } => {
+                    Module::check_code_section_count(declared_functions, count)?;
+                    code_section_seen = true;
                     validator.code_section_start(count, &range)?;
                     ret.funcs.code_section_offset = range.start;
                 }
@@ -259,7 +288,12 @@ impl Module {
                     unreachable!()
                 }
 
-                Payload::End => validator.end()?,
+                Payload::End => {
+                    if !code_section_seen {
+                        Module::check_code_section_count(declared_functions, 0)?;
+                    }
+                    validator.end()?
+                }
 
                 // the module linking proposal is not implemented yet.
                 Payload::AliasSection(s) => {
@@ -285,11 +319,6 @@ impl Module {
                     validator.module_section_start(count, &range)?;
                     bail!("not supported yet");
                 }
-                // exception handling is not implemented yet.
-                Payload::TagSection(s) => {
-                    validator.tag_section(&s)?;
-                    bail!("not supported yet");
-                }
             }
         }
 
@@ -319,13 +348,39 @@ impl Module {
     where
         P: AsRef<Path>,
     {
-        let buffer = self.emit_wasm();
+        let buffer = self.try_emit_wasm()?;
         fs::write(path, buffer).context("failed to write wasm module")?;
         Ok(())
     }
 
     /// Emit this module into an in-memory wasm buffer.
+    ///
+    /// Every index, count and size is encoded as a minimal-length LEB128, even
+    /// if it was padded in the module that was parsed. Custom sections and
+    /// functions pinned with `ModuleFunctions::pin_original_encoding` are
+    /// emitted verbatim, though.
+    ///
+    /// Panics if a pinned function can't be emitted with its original
+    /// encoding; use `try_emit_wasm` to get an error instead.
     pub fn emit_wasm(&mut self) -> Vec<u8> {
+        match self.try_emit_wasm() {
+            Ok(wasm) => wasm,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    /// Emit this module into an in-memory wasm buffer, like `emit_wasm`.
+    ///
+    /// Returns an error if a function pinned with
+    /// `ModuleFunctions::pin_original_encoding` can't be emitted with its
+    /// original encoding.
+    pub fn try_emit_wasm(&mut self) -> Result<Vec<u8>> {
+        Ok(self.emit_wasm_with_code_transform()?.0)
+    }
+
+    /// Emit this module into an in-memory wasm buffer, along with the record of
+    /// where each function's code was placed in it.
+    pub(crate) fn emit_wasm_with_code_transform(&mut self) -> Result<(Vec<u8>, CodeTransform)> {
         log::debug!("start emit");
 
         let indices = &mut IdsToIndices::default();
@@ -344,7 +399,7 @@ impl Module {
         self.funcs.emit_func_section(&mut cx);
         self.tables.emit(&mut cx);
         self.memories.emit(&mut cx);
-        // TODO: tag section
+        self.tags.emit(&mut cx);
         self.globals.emit(&mut cx);
         self.exports.emit(&mut cx);
         if let Some(start) = self.start {
@@ -355,6 +410,10 @@ impl Module {
         }
         self.elements.emit(&mut cx);
         self.data.emit_data_count(&mut cx);
+        if let Err(e) = self.funcs.check_pinned(cx.indices) {
+            self.customs = customs;
+            return Err(e);
+        }
         self.funcs.emit(&mut cx);
         self.data.emit(&mut cx);
 
@@ -390,6 +449,7 @@ impl Module {
             });
         }
 
+        let code_transform = mem::take(&mut cx.code_transform);
         let out = cx.wasm_module.finish();
         log::debug!("emission finished");
 
@@ -399,7 +459,7 @@ impl Module {
         //     panic!("Unable to validate serialized output");
         // }
 
-        out
+        Ok((out, code_transform))
     }
 
     /// Returns an iterator over all functions in this module
@@ -407,6 +467,12 @@ impl Module {
         self.funcs.iter()
     }
 
+    /// Returns an iterator over all imports in this module, along with the
+    /// kind of item and the id it was imported as
+    pub fn imports(&self) -> impl Iterator<Item = &Import> {
+        self.imports.iter()
+    }
+
     fn parse_name_section(
         &mut self,
         names: wasmparser::NameSectionReader,
@@ -524,7 +590,15 @@ impl Module {
                         }
                     }
                 }
-                wasmparser::Name::Unknown { ty, .. } => warn!("unknown name subsection {}", ty),
+                wasmparser::Name::Unknown { ty, data, .. } => match u8::try_from(ty) {
+                    Ok(id) => {
+                        warn!("preserving unknown name subsection {} as-is", ty);
+                        self.unknown_name_subsections.push((id, data.to_vec()));
+                    }
+                    // Subsection ids are encoded as a single byte, so this
+                    // one can't be re-emitted faithfully.
+                    Err(_) => warn!("dropping name subsection with out-of-range id {}", ty),
+                },
                 wasmparser::Name::Label(_) => warn!("labels name subsection ignored"),
             }
         }
@@ -569,7 +643,55 @@ fn emit_name_section(cx: &mut EmitContext) {
         .collect::<Vec<_>>();
     locals.sort_by_key(|p| p.0); // sort by index
 
-    if cx.module.name.is_none() && funcs.len() == 0 && locals.len() == 0 {
+    let types = cx
+        .module
+        .types
+        .iter()
+        .filter(|ty| !ty.is_for_function_entry())
+        .filter_map(|ty| Some((cx.indices.get_type_index(ty.id()), ty.name.as_ref()?)));
+    let types = name_map(types);
+    let tables = cx
+        .module
+        .tables
+        .iter()
+        .filter_map(|t| Some((cx.indices.get_table_index(t.id()), t.name.as_ref()?)));
+    let tables = name_map(tables);
+    let memories = cx
+        .module
+        .memories
+        .iter()
+        .filter_map(|m| Some((cx.indices.get_memory_index(m.id()), m.name.as_ref()?)));
+    let memories = name_map(memories);
+    let globals = cx
+        .module
+        .globals
+        .iter()
+        .filter_map(|g| Some((cx.indices.get_global_index(g.id()), g.name.as_ref()?)));
+    let globals = name_map(globals);
+    let elements = cx
+        .module
+        .elements
+        .iter()
+        .filter_map(|e| Some((cx.indices.get_element_index(e.id()), e.name.as_ref()?)));
+    let elements = name_map(elements);
+    let data = cx
+        .module
+        .data
+        .iter()
+        .filter_map(|d| Some((cx.indices.get_data_index(d.id()), d.name.as_ref()?)));
+    let data = name_map(data);
+
+    if cx.module.name.is_none()
+        && funcs.len() == 0
+        && locals.len() == 0
+        && types.is_none()
+        && tables.is_none()
+        && memories.is_none()
+        && globals.is_none()
+        && elements.is_none()
+        && data.is_none()
+        && cx.module.unknown_name_subsections.is_empty()
+    {
         return;
     }
 
@@ -598,5 +720,42 @@ fn emit_name_section(cx: &mut EmitContext) {
         wasm_name_section.locals(&indirect_name_map);
     }
 
+    if let Some(types) = types {
+        wasm_name_section.types(&types);
+    }
+    if let Some(tables) = tables {
+        wasm_name_section.tables(&tables);
+    }
+    if let Some(memories) = memories {
+        wasm_name_section.memories(&memories);
+    }
+    if let Some(globals) = globals {
+        wasm_name_section.globals(&globals);
+    }
+    if let Some(elements) = elements {
+        wasm_name_section.elements(&elements);
+    }
+    if let Some(data) = data {
+        wasm_name_section.data(&data);
+    }
+
+    for (id, contents) in cx.module.unknown_name_subsections.iter() {
+        wasm_name_section.raw(*id, contents);
+    }
+
     cx.wasm_module.section(&wasm_name_section);
 }
+
+/// Build a name map subsection out of `(index, name)` pairs, if there are any.
+fn name_map<'a>(names: impl Iterator<Item = (u32, &'a String)>) -> Option<wasm_encoder::NameMap> {
+    let mut names = names.collect::<Vec<_>>();
+    if names.is_empty() {
+        return None;
+    }
+    names.sort_by_key(|p| p.0); // sort by index
+    let mut name_map = wasm_encoder::NameMap::new();
+    for (index, name) in names {
+        name_map.append(index, name);
+    }
+    Some(name_map)
+}
@@ -0,0 +1,115 @@
+//! Running the whole-module optimization pipeline.
+
+use crate::passes::{demote_globals, devirtualize, gc};
+use crate::Module;
+
+/// How hard `Module::optimize` works, and towards what.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OptLevel {
+    /// Leave the module as it is.
+    None,
+    /// Only run passes that aim to make the module smaller.
+    ///
+    /// This usually shrinks a module, but isn't guaranteed to: replacing a
+    /// global with its value makes every read of a constant that takes more
+    /// bytes to encode than the `global.get` larger.
+    Size,
+    /// Also run passes that may grow the module to make it faster, such as
+    /// replacing indirect calls with direct ones.
+    Speed,
+}
+
+/// How many functions of the right type a table may hold for `OptLevel::Speed`
+/// to replace indirect calls through it with direct calls.
+const MAX_DEVIRTUALIZED_TARGETS: usize = 4;
+
+impl Module {
+    /// Optimize this whole module.
+    ///
+    /// At `OptLevel::Size` and above, globals that are never written, or
+    /// only written once while initializing, are replaced with their values,
+    /// every function is simplified with `LocalFunction::simplify`, and
+    /// finally the items that are no longer referenced are removed. Types are
+    /// always deduplicated, so there are no duplicates left to merge.
+    /// `OptLevel::Speed` first also devirtualizes indirect calls.
+    ///
+    /// Functions flagged `no_modify` are left alone, and functions flagged
+    /// `keep_alive` are kept.
+    pub fn optimize(&mut self, level: OptLevel) {
+        if level == OptLevel::None {
+            return;
+        }
+        if level == OptLevel::Speed {
+            devirtualize::devirtualize(self, MAX_DEVIRTUALIZED_TARGETS);
+        }
+        demote_globals::propagate_read_only_globals(self);
+        demote_globals::demote_single_write_globals(self);
+        for (_, func) in self.funcs.iter_modifiable_mut() {
+            func.simplify();
+        }
+        gc::run(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn size_removes_dead_functions_and_folds_constants() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().unreachable();
+        let dead = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(1).if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(2);
+            },
+            |else_| {
+                else_.i32_const(3);
+            },
+        );
+        let live = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("live", live);
+
+        let unoptimized = module.emit_wasm();
+        module.optimize(OptLevel::None);
+        assert_eq!(module.emit_wasm(), unoptimized);
+
+        module.optimize(OptLevel::Size);
+        assert!(module.funcs.iter().all(|f| f.id() != dead));
+        let func = module.funcs.get(live).kind.unwrap_local();
+        assert!(func
+            .block(func.entry_block())
+            .iter()
+            .all(|(instr, _)| !instr.is_if_else()));
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(1).if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(2);
+            },
+            |else_| {
+                else_.i32_const(3);
+            },
+        );
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", func);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        module.optimize(OptLevel::Speed);
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(local.block(local.entry_block()).len(), 2);
+    }
+}
@@ -0,0 +1,263 @@
+//! A quick summary of a module's contents, for debugging passes.
+
+use crate::{ExportItem, FunctionId, FunctionKind, GlobalId, ImportKind, Module, TableId, ValType};
+use std::env;
+use std::fmt;
+
+/// Counts of the items in a module, as returned by `Module::stats`.
+///
+/// Its `Display` implementation prints a short human-readable summary.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleStats {
+    /// The number of functions defined in the module.
+    pub local_functions: usize,
+    /// The number of imported functions.
+    pub imported_functions: usize,
+    /// The number of globals, imported or not.
+    pub globals: usize,
+    /// The number of memories, imported or not.
+    pub memories: usize,
+    /// The number of tables, imported or not.
+    pub tables: usize,
+    /// The number of function types.
+    pub types: usize,
+    /// The number of instructions in all local functions.
+    pub instructions: usize,
+    /// A rough estimate of the size of the emitted module, in bytes: the size
+    /// of the data segments, plus two bytes per instruction.
+    pub estimated_size: usize,
+    /// The names of the exports, each with the kind of item it exports.
+    pub exports: Vec<(String, &'static str)>,
+}
+
+impl fmt::Display for ModuleStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "functions:    {} ({} local, {} imported)",
+            self.local_functions + self.imported_functions,
+            self.local_functions,
+            self.imported_functions
+        )?;
+        writeln!(f, "globals:      {}", self.globals)?;
+        writeln!(f, "memories:     {}", self.memories)?;
+        writeln!(f, "tables:       {}", self.tables)?;
+        writeln!(f, "types:        {}", self.types)?;
+        writeln!(f, "instructions: {}", self.instructions)?;
+        writeln!(f, "size:         ~{} bytes", self.estimated_size)?;
+        write!(f, "exports:      {}", self.exports.len())?;
+        for (name, kind) in self.exports.iter() {
+            write!(f, "\n  {} {:?}", kind, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Count the items in this module.
+    ///
+    /// This only walks the module's items and function bodies; nothing is
+    /// emitted.
+    pub fn stats(&self) -> ModuleStats {
+        let mut stats = ModuleStats::default();
+        for func in self.funcs.iter() {
+            match &func.kind {
+                FunctionKind::Local(local) => {
+                    stats.local_functions += 1;
+                    stats.instructions += local.size() as usize;
+                }
+                FunctionKind::Import(_) => stats.imported_functions += 1,
+                FunctionKind::Uninitialized(_) => {}
+            }
+        }
+        stats.globals = self.globals.iter().count();
+        stats.memories = self.memories.len();
+        stats.tables = self.tables.iter().count();
+        stats.types = self
+            .types
+            .iter()
+            .filter(|ty| !ty.is_for_function_entry())
+            .count();
+        stats.estimated_size =
+            self.data.iter().map(|d| d.value.len()).sum::<usize>() + 2 * stats.instructions;
+        stats.exports = self
+            .exports
+            .iter()
+            .map(|export| {
+                let kind = match export.item {
+                    ExportItem::Function(_) => "func",
+                    ExportItem::Table(_) => "table",
+                    ExportItem::Memory(_) => "memory",
+                    ExportItem::Global(_) => "global",
+                };
+                (export.name.clone(), kind)
+            })
+            .collect();
+        stats
+    }
+
+    /// Print a summary of this module to stderr, as given by `Module::stats`.
+    ///
+    /// This is meant for checkpoints in a pipeline of passes while debugging
+    /// it. It only prints something in debug builds, or when the
+    /// `WALRUS_PRINT_STATS` environment variable is set to `1`, so calls to it
+    /// can be left in place.
+    pub fn print_stats(&self) {
+        if !cfg!(debug_assertions) && env::var("WALRUS_PRINT_STATS").as_deref() != Ok("1") {
+            return;
+        }
+        match &self.name {
+            Some(name) => eprintln!("module {:?}:", name),
+            None => eprintln!("module:"),
+        }
+        eprintln!("{}", self.stats());
+    }
+
+    /// Print this module's exports to stderr, with the type of each, like
+    /// `exports: { "memory": Memory, "malloc": Function(i32) -> i32 }`.
+    pub fn print_exports(&self) {
+        eprintln!("{}", self.format_exports());
+    }
+
+    /// Print this module's imports to stderr, with the type of each, like
+    /// `imports: { ("env", "abort"): Function(i32) }`.
+    pub fn print_imports(&self) {
+        eprintln!("{}", self.format_imports());
+    }
+
+    fn format_exports(&self) -> String {
+        let items = self.exports.iter().map(|export| {
+            let ty = match export.item {
+                ExportItem::Function(id) => self.format_function(id),
+                ExportItem::Table(id) => self.format_table(id),
+                ExportItem::Memory(_) => "Memory".to_string(),
+                ExportItem::Global(id) => self.format_global(id),
+            };
+            format!("{:?}: {}", export.name, ty)
+        });
+        format_items("exports", items)
+    }
+
+    fn format_imports(&self) -> String {
+        let items = self.imports.iter().map(|import| {
+            let ty = match import.kind {
+                ImportKind::Function(id) => self.format_function(id),
+                ImportKind::Table(id) => self.format_table(id),
+                ImportKind::Memory(_) => "Memory".to_string(),
+                ImportKind::Global(id) => self.format_global(id),
+            };
+            format!("({:?}, {:?}): {}", import.module, import.name, ty)
+        });
+        format_items("imports", items)
+    }
+
+    fn format_function(&self, id: FunctionId) -> String {
+        let (params, results) = self.types.params_results(self.funcs.get(id).ty());
+        let mut s = format!("Function({})", comma_separated(params));
+        match results {
+            [] => {}
+            [result] => s.push_str(&format!(" -> {}", result)),
+            _ => s.push_str(&format!(" -> ({})", comma_separated(results))),
+        }
+        s
+    }
+
+    fn format_table(&self, id: TableId) -> String {
+        format!("Table({})", self.tables.get(id).element_ty)
+    }
+
+    fn format_global(&self, id: GlobalId) -> String {
+        let global = self.globals.get(id);
+        if global.mutable {
+            format!("Global(mut {})", global.ty)
+        } else {
+            format!("Global({})", global.ty)
+        }
+    }
+}
+
+fn format_items(what: &str, items: impl Iterator<Item = String>) -> String {
+    let items = items.collect::<Vec<_>>();
+    if items.is_empty() {
+        format!("{}: {{}}", what)
+    } else {
+        format!("{}: {{ {} }}", what, items.join(", "))
+    }
+}
+
+fn comma_separated(tys: &[ValType]) -> String {
+    tys.iter()
+        .map(|ty| ty.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, InitExpr};
+
+    #[test]
+    fn counts_items() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[], &[]);
+        module.add_import_func("env", "f", ty);
+        module.memories.add_local(false, 1, None);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .i32_const(2)
+            .binop(crate::ir::BinaryOp::I32Add);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("g", func);
+
+        let stats = module.stats();
+        assert_eq!(stats.local_functions, 1);
+        assert_eq!(stats.imported_functions, 1);
+        assert_eq!(stats.memories, 1);
+        assert_eq!(stats.tables, 0);
+        assert_eq!(stats.types, 2);
+        assert_eq!(stats.instructions, 3);
+        assert_eq!(stats.exports, [("g".to_string(), "func")]);
+        assert!(stats
+            .to_string()
+            .contains("functions:    2 (1 local, 1 imported)"));
+    }
+
+    #[test]
+    fn formats_exports_and_imports() {
+        let mut module = Module::default();
+        assert_eq!(module.format_exports(), "exports: {}");
+
+        let ty = module.types.add(&[ValType::I32, ValType::I32], &[]);
+        module.add_import_func("wasi_snapshot_preview1", "fd_write", ty);
+        let memory = module.memories.add_local(false, 1, None);
+        module.exports.add("memory", memory);
+        let mut builder = FunctionBuilder::new(
+            &mut module.types,
+            &[ValType::I32],
+            &[ValType::I32, ValType::I64],
+        );
+        builder.func_body().i32_const(0).i64_const(0);
+        let arg = module.locals.add(ValType::I32);
+        let func = builder.finish(vec![arg], &mut module.funcs);
+        module.exports.add("malloc", func);
+        let global = module.globals.add_local(
+            ValType::I64,
+            true,
+            InitExpr::Value(crate::ir::Value::I64(0)),
+        );
+        module.exports.add("counter", global);
+
+        assert_eq!(
+            module.format_exports(),
+            "exports: { \"memory\": Memory, \"malloc\": Function(i32) -> (i32, i64), \
+             \"counter\": Global(mut i64) }"
+        );
+        assert_eq!(
+            module.format_imports(),
+            "imports: { (\"wasi_snapshot_preview1\", \"fd_write\"): Function(i32, i32) }"
+        );
+    }
+}
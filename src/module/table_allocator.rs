@@ -0,0 +1,423 @@
+//! Allocating slots of a function table, reusing the slots that are freed.
+
+use crate::ir::Value;
+use crate::{ElementId, ElementKind, FunctionId, InitExpr, Module, Result, TableId, ValType};
+use anyhow::bail;
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+/// Hands out slots of a function table, for code that adds functions to a
+/// table and removes them again, such as plugin systems patching a dispatch
+/// table.
+///
+/// Slots are filled by the table's active element segments. Freed slots are
+/// handed out again before the table is grown. A slot is free if no element
+/// segment puts a function in it, or if it holds the trap stub.
+///
+/// A freed slot is set to the trap stub, if there is one. Otherwise it is set
+/// to `ref.null func`, if reference types are enabled, and else the slot is
+/// cut out of the segments that fill it, splitting them in two, so that it is
+/// left null.
+///
+/// The allocator keeps track of which slots are free, so the table's element
+/// segments shouldn't be changed by other means while it is in use.
+#[derive(Debug)]
+pub struct TableAllocator {
+    table: TableId,
+    free: BTreeSet<u32>,
+    trap_stub: Option<FunctionId>,
+}
+
+impl TableAllocator {
+    /// Create an allocator for the given table, given the slots its active
+    /// element segments fill.
+    ///
+    /// Returns an error if the table doesn't hold functions, or if one of its
+    /// active element segments has an offset that isn't a constant.
+    pub fn new(module: &Module, table: TableId) -> Result<TableAllocator> {
+        let mut allocator = TableAllocator {
+            table,
+            free: BTreeSet::new(),
+            trap_stub: None,
+        };
+        if module.tables.get(table).element_ty != ValType::Funcref {
+            bail!("cannot allocate slots of table [{table:?}], it doesn't hold functions");
+        }
+        let mut slots = vec![None; module.tables.get(table).initial as usize];
+        for (id, offset) in allocator.segments(module)? {
+            for (i, member) in module.elements.get(id).members.iter().enumerate() {
+                if let Some(slot) = slots.get_mut(offset as usize + i) {
+                    *slot = *member;
+                }
+            }
+        }
+        allocator.free = (0..)
+            .zip(slots)
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index)
+            .collect();
+        Ok(allocator)
+    }
+
+    /// Fill freed slots with `stub`, rather than leaving them null, and treat
+    /// the slots that hold it as free.
+    ///
+    /// The stub should trap when called, so that stale function pointers are
+    /// caught.
+    pub fn set_trap_stub(&mut self, module: &Module, stub: FunctionId) -> Result<()> {
+        self.trap_stub = Some(stub);
+        for (id, offset) in self.segments(module)? {
+            for (i, member) in module.elements.get(id).members.iter().enumerate() {
+                if *member == Some(stub) {
+                    self.free.insert(offset + i as u32);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The table slots are allocated from.
+    pub fn table(&self) -> TableId {
+        self.table
+    }
+
+    /// Is the given slot free?
+    pub fn is_free(&self, index: u32) -> bool {
+        self.free.contains(&index)
+    }
+
+    /// Put `func` in a free slot of the table, growing the table if there is
+    /// no free slot, and return the slot's index.
+    pub fn allocate(&mut self, module: &mut Module, func: FunctionId) -> Result<u32> {
+        let index = match self.free.iter().next() {
+            Some(index) => *index,
+            None => {
+                let index = module.tables.get(self.table).initial;
+                self.grow(module, index + 1)?;
+                index
+            }
+        };
+        self.set(module, index, func)?;
+        Ok(index)
+    }
+
+    /// Reserve `len` consecutive slots, for a dispatch array, and return
+    /// their indices. The table is grown if there aren't enough consecutive
+    /// free slots.
+    ///
+    /// The reserved slots aren't free anymore, but are left as they are until
+    /// they are filled with `set`.
+    pub fn reserve_range(&mut self, module: &mut Module, len: u32) -> Result<Range<u32>> {
+        let size = module.tables.get(self.table).initial;
+        let mut start = size;
+        let mut run = 0;
+        for index in self.free.iter() {
+            if run > 0 && *index == start + run {
+                run += 1;
+            } else {
+                start = *index;
+                run = 1;
+            }
+            if run >= len {
+                break;
+            }
+        }
+        if run < len {
+            // Only a run of free slots at the end of the table can be grown.
+            if start + run != size {
+                start = size;
+            }
+            self.grow(module, start + len)?;
+        }
+        for index in start..start + len {
+            self.free.remove(&index);
+        }
+        Ok(start..start + len)
+    }
+
+    /// Put `func` in the given slot, whether or not it is free.
+    pub fn set(&mut self, module: &mut Module, index: u32, func: FunctionId) -> Result<()> {
+        if index >= module.tables.get(self.table).initial {
+            bail!(
+                "slot {} is out of bounds of table [{:?}]",
+                index,
+                self.table
+            );
+        }
+        match self.filling(module, index)?.last() {
+            Some((id, i)) => module.elements.get_mut(*id).members[*i] = Some(func),
+            None => {
+                // Extend a segment that ends right before the slot, or start
+                // a new one.
+                let before = self.segments(module)?.into_iter().find(|(id, offset)| {
+                    offset + module.elements.get(*id).members.len() as u32 == index
+                });
+                match before {
+                    Some((id, _)) => module.elements.get_mut(id).members.push(Some(func)),
+                    None => {
+                        let kind = ElementKind::Active {
+                            table: self.table,
+                            offset: InitExpr::Value(Value::I32(index as i32)),
+                        };
+                        let id = module
+                            .elements
+                            .add(kind, ValType::Funcref, vec![Some(func)]);
+                        module.tables.get_mut(self.table).elem_segments.insert(id);
+                    }
+                }
+            }
+        }
+        self.free.remove(&index);
+        Ok(())
+    }
+
+    /// Free the given slot, so that it can be allocated again.
+    ///
+    /// Returns an error if the slot is already free.
+    pub fn free(&mut self, module: &mut Module, index: u32) -> Result<()> {
+        if self.free.contains(&index) || index >= module.tables.get(self.table).initial {
+            bail!("slot {} of table [{:?}] is not in use", index, self.table);
+        }
+        let filling = self.filling(module, index)?;
+        if let Some(stub) = self.trap_stub {
+            self.set(module, index, stub)?;
+        } else if !module.config.only_stable_features {
+            if let Some((id, i)) = filling.last() {
+                module.elements.get_mut(*id).members[*i] = None;
+            }
+        } else {
+            for (id, i) in filling {
+                self.split(module, id, i);
+            }
+        }
+        self.free.insert(index);
+        Ok(())
+    }
+
+    /// The active segments of the table, with their offsets.
+    fn segments(&self, module: &Module) -> Result<Vec<(ElementId, u32)>> {
+        let mut segments = Vec::new();
+        for element in module.elements.iter() {
+            match element.kind {
+                ElementKind::Active { table, offset } if table == self.table => match offset {
+                    InitExpr::Value(Value::I32(offset)) => {
+                        segments.push((element.id(), offset as u32))
+                    }
+                    _ => bail!(
+                        "cannot allocate slots of table [{:?}], element segment [{:?}] \
+                         doesn't have a constant offset",
+                        self.table,
+                        element.id()
+                    ),
+                },
+                _ => {}
+            }
+        }
+        Ok(segments)
+    }
+
+    /// The segments that fill the given slot, and the slot's index within
+    /// each of them. The last one determines what the slot holds.
+    fn filling(&self, module: &Module, index: u32) -> Result<Vec<(ElementId, usize)>> {
+        Ok(self
+            .segments(module)?
+            .into_iter()
+            .filter_map(|(id, offset)| {
+                let i = index.checked_sub(offset)? as usize;
+                if i < module.elements.get(id).members.len() {
+                    Some((id, i))
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Cut the member at index `i` out of the given segment, moving the
+    /// members after it into a new segment.
+    fn split(&self, module: &mut Module, id: ElementId, i: usize) {
+        let offset = match module.elements.get(id).kind {
+            ElementKind::Active {
+                offset: InitExpr::Value(Value::I32(offset)),
+                ..
+            } => offset,
+            _ => unreachable!(),
+        };
+        let element = module.elements.get_mut(id);
+        let after = element.members.split_off(i + 1);
+        element.members.pop();
+        if element.members.is_empty() {
+            module.elements.delete(id);
+            module.tables.get_mut(self.table).elem_segments.remove(&id);
+        }
+        if !after.is_empty() {
+            let kind = ElementKind::Active {
+                table: self.table,
+                offset: InitExpr::Value(Value::I32(offset + i as i32 + 1)),
+            };
+            let id = module.elements.add(kind, ValType::Funcref, after);
+            module.tables.get_mut(self.table).elem_segments.insert(id);
+        }
+    }
+
+    /// Grow the table to `size` slots, which are free.
+    fn grow(&mut self, module: &mut Module, size: u32) -> Result<()> {
+        let table = module.tables.get_mut(self.table);
+        if let Some(maximum) = table.maximum {
+            if size > maximum {
+                bail!(
+                    "cannot grow table [{:?}] to {} slots, its maximum is {}",
+                    self.table,
+                    size,
+                    maximum
+                );
+            }
+        }
+        self.free.extend(table.initial..size);
+        table.initial = table.initial.max(size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ModuleConfig};
+
+    fn add_funcs(module: &mut Module, n: usize) -> Vec<FunctionId> {
+        (0..n)
+            .map(|_| {
+                let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+                builder.func_body();
+                builder.finish(vec![], &mut module.funcs)
+            })
+            .collect()
+    }
+
+    /// The functions in each active segment of `table`, by offset.
+    fn segments(module: &Module, table: TableId) -> Vec<(i32, Vec<Option<FunctionId>>)> {
+        let mut segments = module
+            .elements
+            .iter()
+            .filter_map(|e| match e.kind {
+                ElementKind::Active {
+                    table: t,
+                    offset: InitExpr::Value(Value::I32(offset)),
+                } if t == table => Some((offset, e.members.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        segments.sort_by_key(|(offset, _)| *offset);
+        segments
+    }
+
+    #[test]
+    fn allocate_free_and_reuse() {
+        let mut module = Module::default();
+        let f = add_funcs(&mut module, 4);
+        let table = module.tables.add_local(0, None, ValType::Funcref);
+        let mut alloc = TableAllocator::new(&module, table).unwrap();
+
+        assert_eq!(alloc.allocate(&mut module, f[0]).unwrap(), 0);
+        assert_eq!(alloc.allocate(&mut module, f[1]).unwrap(), 1);
+        assert_eq!(alloc.allocate(&mut module, f[2]).unwrap(), 2);
+        assert_eq!(module.tables.get(table).initial, 3);
+        assert_eq!(
+            segments(&module, table),
+            [(0, vec![Some(f[0]), Some(f[1]), Some(f[2])])]
+        );
+
+        // Reference types are enabled by default, so the slot is nulled.
+        alloc.free(&mut module, 1).unwrap();
+        assert!(alloc.free(&mut module, 1).is_err());
+        assert_eq!(
+            segments(&module, table),
+            [(0, vec![Some(f[0]), None, Some(f[2])])]
+        );
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+
+        // The freed slot is reused before the table grows.
+        assert_eq!(alloc.allocate(&mut module, f[3]).unwrap(), 1);
+        assert_eq!(alloc.allocate(&mut module, f[1]).unwrap(), 3);
+        assert_eq!(module.tables.get(table).initial, 4);
+
+        // A new allocator sees the same slots in use.
+        let alloc = TableAllocator::new(&module, table).unwrap();
+        assert!((0..4).all(|i| !alloc.is_free(i)));
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn free_splits_segments_without_reference_types() {
+        let mut config = ModuleConfig::new();
+        config.only_stable_features(true);
+        let mut module = Module::with_config(config);
+        let f = add_funcs(&mut module, 3);
+        let table = module.tables.add_local(0, None, ValType::Funcref);
+        let mut alloc = TableAllocator::new(&module, table).unwrap();
+        for func in f.iter() {
+            alloc.allocate(&mut module, *func).unwrap();
+        }
+
+        alloc.free(&mut module, 1).unwrap();
+        assert_eq!(
+            segments(&module, table),
+            [(0, vec![Some(f[0])]), (2, vec![Some(f[2])])]
+        );
+        // Without `ref.null`, the segments use the MVP encoding.
+        let features = wasmparser::WasmFeatures {
+            reference_types: false,
+            bulk_memory: false,
+            ..Default::default()
+        };
+        let mut validator = wasmparser::Validator::new();
+        validator.wasm_features(features);
+        validator.validate_all(&module.emit_wasm()).unwrap();
+
+        alloc.free(&mut module, 0).unwrap();
+        assert_eq!(segments(&module, table), [(2, vec![Some(f[2])])]);
+        assert_eq!(alloc.allocate(&mut module, f[1]).unwrap(), 0);
+        assert_eq!(alloc.allocate(&mut module, f[0]).unwrap(), 1);
+        assert_eq!(
+            segments(&module, table),
+            [(0, vec![Some(f[1]), Some(f[0])]), (2, vec![Some(f[2])])]
+        );
+        validator = wasmparser::Validator::new();
+        validator.wasm_features(features);
+        validator.validate_all(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn trap_stubs_and_ranges() {
+        let mut module = Module::default();
+        let f = add_funcs(&mut module, 3);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().unreachable();
+        let stub = builder.finish(vec![], &mut module.funcs);
+        let table = module.tables.add_local(0, Some(8), ValType::Funcref);
+        let mut alloc = TableAllocator::new(&module, table).unwrap();
+        alloc.set_trap_stub(&module, stub).unwrap();
+
+        assert_eq!(alloc.allocate(&mut module, f[0]).unwrap(), 0);
+        assert_eq!(alloc.allocate(&mut module, f[1]).unwrap(), 1);
+        alloc.free(&mut module, 0).unwrap();
+        assert_eq!(
+            segments(&module, table),
+            [(0, vec![Some(stub), Some(f[1])])]
+        );
+
+        // The single free slot is too small for the range.
+        let range = alloc.reserve_range(&mut module, 3).unwrap();
+        assert_eq!(range, 2..5);
+        for index in range {
+            assert!(!alloc.is_free(index));
+            alloc.set(&mut module, index, f[2]).unwrap();
+        }
+        assert_eq!(alloc.allocate(&mut module, f[0]).unwrap(), 0);
+
+        // The table can't grow past its maximum.
+        assert!(alloc.reserve_range(&mut module, 4).is_err());
+        assert_eq!(alloc.reserve_range(&mut module, 3).unwrap(), 5..8);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+}
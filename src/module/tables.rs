@@ -1,11 +1,15 @@
 //! Tables within a wasm module.
 
 use crate::emit::{Emit, EmitContext};
+use crate::ir::Value;
 use crate::map::IdHashSet;
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
-use crate::{Element, ImportId, Module, Result, ValType};
+use crate::{Element, ElementKind, FunctionId, ImportId, InitExpr, Module, Result};
+use crate::{TableAllocator, ValType};
 use anyhow::bail;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 /// The id of a table.
 pub type TableId = Id<Table>;
@@ -93,6 +97,11 @@ impl ModuleTables {
         &mut self.arena[table]
     }
 
+    /// Is there a table with the given id that hasn't been deleted?
+    pub(crate) fn contains(&self, id: TableId) -> bool {
+        self.arena.contains(id)
+    }
+
     /// Removes a table from this module.
     ///
     /// It is up to you to ensure that any potential references to the deleted
@@ -106,6 +115,14 @@ impl ModuleTables {
         self.arena.iter().map(|p| p.1)
     }
 
+    /// Get a parallel iterator of this module's tables.
+    ///
+    /// Requires the `parallel` feature of this crate to be enabled.
+    #[cfg(feature = "parallel")]
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = &Table> {
+        self.arena.par_iter().map(|p| p.1)
+    }
+
     /// Finds a unique function table in a module.
     ///
     /// Modules produced by compilers like LLVM typically have one function
@@ -144,13 +161,62 @@ impl Module {
         log::debug!("parse table section");
         for t in section {
             let t = t?;
-            let id = self
-                .tables
-                .add_local(t.initial, t.maximum, ValType::parse(&t.element_type)?);
-            ids.push_table(id);
+            let id =
+                self.tables
+                    .add_local(t.initial, t.maximum, ValType::from_parser(&t.element_type)?);
+            ids.push_table(id)?;
         }
         Ok(())
     }
+
+    /// The functions the given table holds right after instantiation, as put
+    /// there by its active element segments, with `None` for null slots.
+    ///
+    /// Later segments overwrite the slots filled by earlier ones, and members
+    /// past the table's initial size are left out.
+    ///
+    /// Returns an error if the table doesn't hold functions, or if one of its
+    /// active element segments has an offset that isn't a constant.
+    pub fn table_entries(&self, table: TableId) -> Result<Vec<Option<FunctionId>>> {
+        let table = self.tables.get(table);
+        if table.element_ty != ValType::Funcref {
+            bail!(
+                "cannot get the entries of table [{:?}], it doesn't hold functions",
+                table.id()
+            );
+        }
+        let mut entries = vec![None; table.initial as usize];
+        for element in self.elements.iter() {
+            let offset = match element.kind {
+                ElementKind::Active { table: t, offset } if t == table.id() => offset,
+                _ => continue,
+            };
+            let offset = match offset {
+                InitExpr::Value(Value::I32(offset)) => offset as u32 as usize,
+                _ => bail!(
+                    "cannot get the entries of table [{:?}], element segment [{:?}] \
+                     doesn't have a constant offset",
+                    table.id(),
+                    element.id()
+                ),
+            };
+            for (i, member) in element.members.iter().enumerate() {
+                if let Some(entry) = entries.get_mut(offset + i) {
+                    *entry = *member;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Put `func` in the given slot of the table, changing or adding an
+    /// active element segment to do so.
+    ///
+    /// Returns an error if the slot is out of the table's bounds, or for the
+    /// same reasons as `table_entries`.
+    pub fn set_table_entry(&mut self, table: TableId, index: u32, func: FunctionId) -> Result<()> {
+        TableAllocator::new(self, table)?.set(self, index, func)
+    }
 }
 
 impl Emit for ModuleTables {
@@ -171,14 +237,44 @@ impl Emit for ModuleTables {
             wasm_table_section.table(wasm_encoder::TableType {
                 minimum: table.initial,
                 maximum: table.maximum,
-                element_type: match table.element_ty {
-                    ValType::Externref => wasm_encoder::RefType::EXTERNREF,
-                    ValType::Funcref => wasm_encoder::RefType::FUNCREF,
-                    _ => unreachable!(),
-                },
+                element_type: table.element_ty.to_encoder_ref(),
             });
         }
 
         cx.wasm_module.section(&wasm_table_section);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    #[test]
+    fn set_and_read_back_entries() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let f = builder.finish(vec![], &mut module.funcs);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().nop();
+        let g = builder.finish(vec![], &mut module.funcs);
+        let table = module.tables.add_local(5, None, ValType::Funcref);
+        let kind = ElementKind::Active {
+            table,
+            offset: InitExpr::Value(Value::I32(0)),
+        };
+        let segment = module.elements.add(kind, ValType::Funcref, vec![Some(f)]);
+        module.tables.get_mut(table).elem_segments.insert(segment);
+
+        module.set_table_entry(table, 3, g).unwrap();
+        assert_eq!(
+            module.table_entries(table).unwrap(),
+            [Some(f), None, None, Some(g), None]
+        );
+        module.set_table_entry(table, 0, g).unwrap();
+        assert_eq!(module.table_entries(table).unwrap()[0], Some(g));
+        assert!(module.set_table_entry(table, 5, f).is_err());
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+}
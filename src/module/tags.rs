@@ -0,0 +1,164 @@
+//! Tags declared by a wasm module, for the exception handling proposal.
+
+use crate::emit::{Emit, EmitContext};
+use crate::parse::IndicesToIds;
+use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
+use crate::{Module, Result, TypeId};
+
+/// The id of a tag.
+pub type TagId = Id<Tag>;
+
+/// A tag, declaring the type of the values an exception carries.
+#[derive(Debug)]
+pub struct Tag {
+    id: TagId,
+    /// The function type whose parameters are the exception's values. It has
+    /// no results.
+    pub ty: TypeId,
+    /// The name of this tag, used for debugging purposes.
+    pub name: Option<String>,
+}
+
+impl Tombstone for Tag {}
+
+impl Tag {
+    /// Return the id of this tag
+    pub fn id(&self) -> TagId {
+        self.id
+    }
+}
+
+/// The set of tags in this module.
+#[derive(Debug, Default)]
+pub struct ModuleTags {
+    arena: TombstoneArena<Tag>,
+}
+
+impl ModuleTags {
+    /// Add a new tag of the given type to this module.
+    pub fn add(&mut self, ty: TypeId) -> TagId {
+        self.arena.alloc_with_id(|id| Tag { id, ty, name: None })
+    }
+
+    /// Gets a reference to a tag given its id
+    pub fn get(&self, id: TagId) -> &Tag {
+        &self.arena[id]
+    }
+
+    /// Gets a reference to a tag given its id
+    pub fn get_mut(&mut self, id: TagId) -> &mut Tag {
+        &mut self.arena[id]
+    }
+
+    /// Removes a tag from this module.
+    pub fn delete(&mut self, id: TagId) {
+        self.arena.delete(id);
+    }
+
+    /// Get a shared reference to this module's tags.
+    pub fn iter(&self) -> impl Iterator<Item = &Tag> {
+        self.arena.iter().map(|(_, t)| t)
+    }
+
+    /// Get a mutable reference to this module's tags.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Tag> {
+        self.arena.iter_mut().map(|(_, t)| t)
+    }
+
+    /// Get the number of tags in this module
+    pub fn len(&self) -> usize {
+        self.arena.len()
+    }
+}
+
+impl Module {
+    /// Construct the set of tags declared by the tag section.
+    pub(crate) fn parse_tags(
+        &mut self,
+        section: wasmparser::TagSectionReader,
+        ids: &mut IndicesToIds,
+    ) -> Result<()> {
+        log::debug!("parse tag section");
+        for tag in section {
+            let tag = tag?;
+            let id = self.tags.add(ids.get_type(tag.type_index)?);
+            ids.push_tag(id)?;
+        }
+        Ok(())
+    }
+}
+
+impl Emit for ModuleTags {
+    fn emit(&self, cx: &mut EmitContext) {
+        log::debug!("emit tag section");
+
+        if self.iter().next().is_none() {
+            return;
+        }
+
+        let mut wasm_tag_section = wasm_encoder::TagSection::new();
+        for tag in self.iter() {
+            cx.indices.push_tag(tag.id());
+            wasm_tag_section.tag(wasm_encoder::TagType {
+                kind: wasm_encoder::TagKind::Exception,
+                func_type_idx: cx.indices.get_type_index(tag.ty),
+            });
+        }
+
+        cx.wasm_module.section(&wasm_tag_section);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Module, ValType};
+
+    #[test]
+    fn round_trip_tag_section() {
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x01, 0x05, 0x01, 0x60, 0x01, 0x7f, 0x00, // type section, (param i32)
+            0x0d, 0x03, 0x01, 0x00, 0x00, // tag section, one exception of type 0
+        ];
+        let mut module = Module::from_buffer(&wasm).unwrap();
+        let tags = module.tags.iter().map(|t| t.ty).collect::<Vec<_>>();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(
+            module.types.params_results(tags[0]),
+            (&[ValType::I32][..], &[][..])
+        );
+
+        let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+        let tags = module.tags.iter().map(|t| t.ty).collect::<Vec<_>>();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(
+            module.types.params_results(tags[0]),
+            (&[ValType::I32][..], &[][..])
+        );
+    }
+
+    #[test]
+    fn exception_handling_is_an_error() {
+        let header = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let ty = [0x01, 0x04, 0x01, 0x60, 0x00, 0x00]; // type section, () -> ()
+
+        // A function whose body is `try catch_all end`.
+        let body = [
+            0x03, 0x02, 0x01, 0x00, // function section
+            0x0a, 0x08, 0x01, 0x06, 0x00, 0x06, 0x40, 0x19, 0x0b, 0x0b, // code section
+        ];
+        // An import of a tag `a.b`.
+        let import = [0x02, 0x08, 0x01, 0x01, 0x61, 0x01, 0x62, 0x04, 0x00, 0x00];
+        // A tag, and an export of it as `e`.
+        let export = [
+            0x0d, 0x03, 0x01, 0x00, 0x00, // tag section
+            0x07, 0x05, 0x01, 0x01, 0x65, 0x04, 0x00, // export section
+        ];
+
+        for section in [&body[..], &import[..], &export[..]].iter() {
+            let wasm = [&header[..], &ty[..], section].concat();
+            let err = Module::from_buffer(&wasm).unwrap_err();
+            assert!(format!("{:?}", err).contains("exception handling is not supported"));
+        }
+    }
+}
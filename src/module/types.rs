@@ -3,9 +3,11 @@
 use crate::arena_set::ArenaSet;
 use crate::emit::{Emit, EmitContext};
 use crate::error::Result;
-use crate::module::Module;
+use crate::map::IdHashMap;
+use crate::module::{FunctionId, Module, ModuleFunctions};
 use crate::parse::IndicesToIds;
 use crate::ty::{Type, TypeId, ValType};
+use anyhow::bail;
 
 /// The set of de-duplicated types within a module.
 #[derive(Debug, Default)]
@@ -40,6 +42,16 @@ impl ModuleTypes {
         self.get(id).results()
     }
 
+    /// Get the ids of all functions whose type is call-compatible with `ty`.
+    pub fn functions_with_type(&self, funcs: &ModuleFunctions, ty: TypeId) -> Vec<FunctionId> {
+        let ty = self.get(ty);
+        funcs
+            .iter()
+            .filter(|f| self.get(f.ty()).is_call_compatible(ty))
+            .map(|f| f.id())
+            .collect()
+    }
+
     /// Get a type ID by its name.
     ///
     /// This is currently only intended for in-memory modifications, and by
@@ -90,27 +102,72 @@ impl ModuleTypes {
 
     /// Find the existing type for the given parameters and results.
     pub fn find(&self, params: &[ValType], results: &[ValType]) -> Option<TypeId> {
-        self.arena.iter().find_map(|(id, ty)| {
-            if !ty.is_for_function_entry() && ty.params() == params && ty.results() == results {
-                Some(id)
-            } else {
-                None
-            }
-        })
+        self.arena.find(&Type::new(
+            self.arena.next_id(),
+            params.to_vec().into_boxed_slice(),
+            results.to_vec().into_boxed_slice(),
+        ))
     }
 
     pub(crate) fn find_for_function_entry(&self, results: &[ValType]) -> Option<TypeId> {
-        self.arena.iter().find_map(|(id, ty)| {
-            if ty.is_for_function_entry() && ty.params().is_empty() && ty.results() == results {
-                Some(id)
-            } else {
-                None
-            }
-        })
+        self.arena.find(&Type::for_function_entry(
+            self.arena.next_id(),
+            results.to_vec().into_boxed_slice(),
+        ))
     }
 }
 
 impl Module {
+    /// Get the type of the function `f`.
+    pub fn type_of_function(&self, f: FunctionId) -> &Type {
+        self.types.get(self.funcs.get(f).ty())
+    }
+
+    /// Get the ids of all functions whose type is `ty`.
+    ///
+    /// Unlike `ModuleTypes::functions_with_type`, this only matches `ty`
+    /// itself, not other types the functions could be called through.
+    pub fn functions_of_type(&self, ty: TypeId) -> impl Iterator<Item = FunctionId> + '_ {
+        self.funcs
+            .iter()
+            .filter(move |f| f.ty() == ty)
+            .map(|f| f.id())
+    }
+
+    /// Get the type a `call_indirect` with the type index `ty` calls
+    /// functions through.
+    ///
+    /// This is the same as `self.types.get(ty)`, named for readability where
+    /// indirect calls are handled.
+    pub fn call_indirect_type(&self, ty: TypeId) -> &Type {
+        self.types.get(ty)
+    }
+
+    /// Add every type of `src` to this module, reusing the equal types this
+    /// module already has, and map each of `src`'s type ids to the id of the
+    /// same type here.
+    ///
+    /// This is the first step of merging `src` into this module: the map is
+    /// then used to give `src`'s functions, `call_indirect`s and blocks their
+    /// types in this module. Names of types are carried over to the types
+    /// that don't have one yet.
+    pub fn merge_types_from(&mut self, src: &Module) -> IdHashMap<Type, TypeId> {
+        let mut map = IdHashMap::default();
+        for ty in src.types.iter() {
+            let id = if ty.is_for_function_entry() {
+                self.types.add_entry_ty(ty.results())
+            } else {
+                self.types.add(ty.params(), ty.results())
+            };
+            let merged = self.types.get_mut(id);
+            if merged.name.is_none() {
+                merged.name = ty.name.clone();
+            }
+            map.insert(ty.id(), id);
+        }
+        map
+    }
+
     /// Construct the set of types within a module.
     pub(crate) fn parse_types(
         &mut self,
@@ -121,7 +178,7 @@ impl Module {
         for ty in section {
             let fun_ty = match ty? {
                 wasmparser::TypeDef::Func(ty) => ty,
-                _ => unimplemented!("module linking not supported"),
+                _ => bail!("module linking is not supported"),
             };
             let id = self.types.arena.next_id();
             let params = fun_ty
@@ -137,7 +194,7 @@ impl Module {
                 .collect::<Result<Vec<_>>>()?
                 .into_boxed_slice();
             let id = self.types.arena.insert(Type::new(id, params, results));
-            ids.push_type(id);
+            ids.push_type(id)?;
         }
 
         Ok(())
@@ -160,17 +217,132 @@ impl Emit for ModuleTypes {
             return;
         }
 
-        // Sort for deterministic ordering.
-        tys.sort_by_key(|&(_, ty)| ty);
+        if cx.module.config.preserve_type_order {
+            // Types are allocated in the order they are parsed or added.
+            tys.sort_by_key(|&(id, _)| id);
+        } else {
+            // Sort for deterministic ordering.
+            tys.sort_by_key(|&(_, ty)| ty);
+        }
 
         for (id, ty) in tys {
             cx.indices.push_type(id);
             wasm_type_section.function(
-                ty.params().iter().map(ValType::to_wasmencoder_type),
-                ty.results().iter().map(ValType::to_wasmencoder_type),
+                ty.params().iter().map(ValType::to_encoder),
+                ty.results().iter().map(ValType::to_encoder),
             );
         }
 
         cx.wasm_module.section(&wasm_type_section);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Module, ModuleConfig, ValType};
+
+    #[test]
+    fn self_referential_type_is_an_error() {
+        #[rustfmt::skip]
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            // Type section with a single instance type whose only export is
+            // an instance of type 0, i.e. itself.
+            0x01, 0x07, 0x01,
+            0x62, 0x01, 0x01, b'a', 0x06, 0x00,
+        ];
+        assert!(Module::from_buffer(&wasm).is_err());
+    }
+
+    #[test]
+    fn preserve_type_order() {
+        #[rustfmt::skip]
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            // Type section with `(func (param f64))` followed by `(func)`.
+            0x01, 0x08, 0x02,
+            0x60, 0x01, 0x7c, 0x00,
+            0x60, 0x00, 0x00,
+        ];
+
+        fn emitted_types(
+            module: &mut Module,
+        ) -> Vec<(Vec<wasmparser::Type>, Vec<wasmparser::Type>)> {
+            let wasm = module.emit_wasm();
+            let mut types = Vec::new();
+            for payload in wasmparser::Parser::new(0).parse_all(&wasm) {
+                if let wasmparser::Payload::TypeSection(reader) = payload.unwrap() {
+                    for ty in reader {
+                        match ty.unwrap() {
+                            wasmparser::TypeDef::Func(ty) => {
+                                types.push((ty.params.to_vec(), ty.returns.to_vec()))
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+                }
+            }
+            types
+        }
+
+        use wasmparser::Type::{F64, I32};
+        let mut module = ModuleConfig::new()
+            .preserve_type_order(true)
+            .parse(&wasm)
+            .unwrap();
+        module.types.add(&[ValType::I32], &[]);
+        assert_eq!(
+            emitted_types(&mut module),
+            [(vec![F64], vec![]), (vec![], vec![]), (vec![I32], vec![])]
+        );
+
+        // By default, types are sorted.
+        let mut module = Module::from_buffer(&wasm).unwrap();
+        module.types.add(&[ValType::I32], &[]);
+        assert_eq!(
+            emitted_types(&mut module),
+            [(vec![], vec![]), (vec![I32], vec![]), (vec![F64], vec![])]
+        );
+    }
+
+    #[test]
+    fn merge_types_from() {
+        let mut dest = Module::default();
+        let shared = dest.types.add(&[ValType::I32], &[]);
+        dest.types.add(&[], &[ValType::F64]);
+
+        let mut src = Module::default();
+        let src_shared = src.types.add(&[ValType::I32], &[]);
+        let src_new = src.types.add(&[ValType::F32], &[ValType::I64]);
+        src.types.get_mut(src_new).name = Some("new".to_string());
+
+        let map = dest.merge_types_from(&src);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&src_shared], shared);
+        let new = map[&src_new];
+        assert_eq!(
+            dest.types.params_results(new),
+            (&[ValType::F32][..], &[ValType::I64][..])
+        );
+        assert_eq!(dest.types.by_name("new"), Some(new));
+        assert_eq!(dest.types.iter().count(), 3);
+
+        // Merging again adds nothing.
+        assert_eq!(dest.merge_types_from(&src), map);
+        assert_eq!(dest.types.iter().count(), 3);
+    }
+
+    #[test]
+    fn types_of_functions() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[ValType::I32], &[]);
+        let other = module.types.add(&[], &[]);
+        let a = module.add_import_func("env", "a", ty).0;
+        module.add_import_func("env", "b", other);
+        let c = module.add_import_func("env", "c", ty).0;
+
+        assert_eq!(module.type_of_function(a).params(), [ValType::I32]);
+        assert_eq!(module.functions_of_type(ty).collect::<Vec<_>>(), [a, c]);
+        assert!(module.call_indirect_type(other).params().is_empty());
+    }
+}
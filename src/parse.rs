@@ -1,7 +1,21 @@
 use crate::map::IdHashMap;
-use crate::{DataId, ElementId, Function, FunctionId, GlobalId, Result};
-use crate::{LocalId, MemoryId, TableId, TypeId};
-use anyhow::bail;
+use crate::{DataId, ElementId, ErrorKind, Function, FunctionId, GlobalId, Result};
+use crate::{LocalId, MemoryId, TableId, TagId, TypeId};
+use anyhow::{bail, Context, Error};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// An item in one of a module's index spaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum IndexedItem {
+    Table(TableId),
+    Type(TypeId),
+    Function(FunctionId),
+    Global(GlobalId),
+    Memory(MemoryId),
+    Element(ElementId),
+    Data(DataId),
+}
 
 /// Maps from old indices in the original Wasm binary to `walrus` IDs.
 ///
@@ -23,6 +37,7 @@ pub struct IndicesToIds {
     funcs: Vec<FunctionId>,
     globals: Vec<GlobalId>,
     memories: Vec<MemoryId>,
+    tags: Vec<TagId>,
     elements: Vec<ElementId>,
     data: Vec<DataId>,
     locals: IdHashMap<Function, Vec<LocalId>>,
@@ -32,9 +47,12 @@ macro_rules! define_push_get {
     ( $push:ident, $get:ident, $id_ty:ty, $member:ident ) => {
         impl IndicesToIds {
             /// Pushes a new local ID to map it to the next index internally
-            pub(crate) fn $push(&mut self, id: $id_ty) -> u32 {
+            ///
+            /// Returns an error if that index doesn't fit in 32 bits.
+            pub(crate) fn $push(&mut self, id: $id_ty) -> Result<u32> {
+                let index = next_index(self.$member.len(), stringify!($member))?;
                 self.$member.push(id);
-                (self.$member.len() - 1) as u32
+                Ok(index)
             }
 
             /// Gets the ID for a particular index.
@@ -55,20 +73,66 @@ macro_rules! define_push_get {
     };
 }
 
+/// The index of the next item of an index space that has `len` items.
+///
+/// Indices are 32 bits wide, so a binary that adds up to more items, say
+/// through both its imports and its own definitions, is invalid.
+fn next_index(len: usize, space: &str) -> Result<u32> {
+    u32::try_from(len)
+        .map_err(|_| Error::new(ErrorKind::InvalidWasm))
+        .with_context(|| format!("too many {} for 32-bit indices", space))
+}
+
 define_push_get!(push_table, get_table, TableId, tables);
 define_push_get!(push_type, get_type, TypeId, types);
 define_push_get!(push_func, get_func, FunctionId, funcs);
 define_push_get!(push_global, get_global, GlobalId, globals);
 define_push_get!(push_memory, get_memory, MemoryId, memories);
+define_push_get!(push_tag, get_tag, TagId, tags);
 define_push_get!(push_element, get_element, ElementId, elements);
 define_push_get!(push_data, get_data, DataId, data);
 
 impl IndicesToIds {
     /// Pushes a new local ID to map it to the next index internally
-    pub(crate) fn push_local(&mut self, function: FunctionId, id: LocalId) -> u32 {
+    ///
+    /// Returns an error if that index doesn't fit in 32 bits.
+    pub(crate) fn push_local(&mut self, function: FunctionId, id: LocalId) -> Result<u32> {
         let list = self.locals.entry(function).or_insert(Vec::new());
+        let index = next_index(list.len(), "locals")?;
         list.push(id);
-        (list.len() as u32) - 1
+        Ok(index)
+    }
+
+    /// Map every item back to its index in the original wasm binary.
+    ///
+    /// Types are deduplicated when parsing, so a type that appeared several
+    /// times maps to the first of its indices.
+    pub(crate) fn original_indices(&self) -> HashMap<IndexedItem, u32> {
+        fn add<T: Copy>(
+            indices: &mut HashMap<IndexedItem, u32>,
+            ids: &[T],
+            item: impl Fn(T) -> IndexedItem,
+        ) {
+            for (index, id) in ids.iter().enumerate() {
+                indices.entry(item(*id)).or_insert(index as u32);
+            }
+        }
+
+        let mut indices = HashMap::new();
+        add(&mut indices, &self.tables, IndexedItem::Table);
+        add(&mut indices, &self.types, IndexedItem::Type);
+        add(&mut indices, &self.funcs, IndexedItem::Function);
+        add(&mut indices, &self.globals, IndexedItem::Global);
+        add(&mut indices, &self.memories, IndexedItem::Memory);
+        add(&mut indices, &self.elements, IndexedItem::Element);
+        add(&mut indices, &self.data, IndexedItem::Data);
+        indices
+    }
+
+    /// Get the locals of the given function, in the order of their original
+    /// indices.
+    pub(crate) fn locals(&self, function: FunctionId) -> &[LocalId] {
+        self.locals.get(&function).map_or(&[], |locals| &locals[..])
     }
 
     /// Gets the ID for a particular index
@@ -0,0 +1,108 @@
+//! Find memory accesses whose alignment hint is larger than the access.
+//!
+//! A memory access's alignment may not be larger than the number of bytes it
+//! accesses. The validator rejects modules that break this rule, so parsed
+//! modules never do, but code built or rewritten in memory can. This is a
+//! correctness lint, not an optimization.
+
+use crate::ir::*;
+use crate::LocalFunction;
+
+/// A memory access whose stated alignment is larger than its natural
+/// alignment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlignmentWarning {
+    /// The instruction sequence containing the access.
+    pub seq: InstrSeqId,
+    /// The index of the access within `seq`.
+    pub index: usize,
+    /// The alignment the access claims, in bytes.
+    pub stated_align: u32,
+    /// The largest alignment the access may claim: the number of bytes it
+    /// accesses.
+    pub natural_align: u32,
+}
+
+/// Check every memory access in `func` for an over-large alignment.
+pub fn check_alignments(func: &LocalFunction) -> Vec<AlignmentWarning> {
+    let mut v = CheckAlignments::default();
+    dfs_in_order(&mut v, func, func.entry_block());
+    return v.warnings;
+
+    #[derive(Default)]
+    struct CheckAlignments {
+        warnings: Vec<AlignmentWarning>,
+    }
+
+    impl<'instr> Visitor<'instr> for CheckAlignments {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            for (index, (instr, _)) in seq.instrs.iter().enumerate() {
+                let (arg, natural_align) = match instr {
+                    Instr::Load(Load { kind, arg, .. }) => (arg, kind.width()),
+                    Instr::Store(Store { kind, arg, .. }) => (arg, kind.width()),
+                    Instr::LoadSimd(LoadSimd { kind, arg, .. }) => (arg, kind.width()),
+                    Instr::AtomicRmw(AtomicRmw { width, arg, .. }) => (arg, width.bytes()),
+                    Instr::Cmpxchg(Cmpxchg { width, arg, .. }) => (arg, width.bytes()),
+                    Instr::AtomicNotify(AtomicNotify { arg, .. }) => (arg, 4),
+                    Instr::AtomicWait(AtomicWait {
+                        arg, sixty_four, ..
+                    }) => (arg, if *sixty_four { 8 } else { 4 }),
+                    _ => continue,
+                };
+                if arg.align > natural_align {
+                    self.warnings.push(AlignmentWarning {
+                        seq: seq.id(),
+                        index,
+                        stated_align: arg.align,
+                        natural_align,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, Module};
+
+    #[test]
+    fn over_aligned_load() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(0)
+            .i32_const(0)
+            .load(
+                memory,
+                LoadKind::I32 { atomic: false },
+                MemArg {
+                    align: 8,
+                    offset: 0,
+                },
+            )
+            .store(
+                memory,
+                StoreKind::I32 { atomic: false },
+                MemArg {
+                    align: 4,
+                    offset: 0,
+                },
+            );
+        let func = builder.local_func(vec![]);
+
+        let warnings = check_alignments(&func);
+        assert_eq!(
+            warnings,
+            [AlignmentWarning {
+                seq: func.entry_block(),
+                index: 2,
+                stated_align: 8,
+                natural_align: 4,
+            }]
+        );
+    }
+}
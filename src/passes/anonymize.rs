@@ -0,0 +1,320 @@
+//! Strip a module of everything identifying it, while keeping its code.
+//!
+//! Reporting a bug in an engine usually means sharing the module that
+//! triggers it, which isn't always possible when the module is proprietary.
+//! Since such bugs almost always depend on the code alone, `anonymize`
+//! replaces everything else that could give the module away:
+//!
+//! * exports, imports, and the names of functions, locals, globals, tables,
+//!   memories, tags, types, data and element segments, and of the module
+//!   itself, become identifiers like `func_3f0c9e2a41d7b865`, derived from a
+//!   hash of the real name and a seed;
+//! * the contents of data segments become pseudorandom bytes of the same
+//!   length;
+//! * custom sections, DWARF, and the `producers` section are removed.
+//!
+//! The same name always gets the same identifier for the same seed, so a
+//! module can be anonymized again after a change and still match the
+//! returned `Anonymization`, which translates messages from the engine back
+//! to the real names.
+
+use crate::{Module, ModuleDebugData};
+use std::collections::HashMap;
+
+/// Configuration for `anonymize`.
+#[derive(Clone, Debug)]
+pub struct AnonymizeConfig {
+    /// The import modules whose imports keep their real module and item
+    /// names, since the module can't be instantiated without them. Imports
+    /// from modules whose name starts with `wasi` always keep their names.
+    pub keep_imports: Vec<String>,
+    /// Whether zero bytes of data segments stay zero, and only the others are
+    /// replaced, with random non-zero bytes. This keeps the module's data
+    /// about as compressible as before, and its zero-initialized memory
+    /// zeroed.
+    pub preserve_zero_runs: bool,
+}
+
+impl Default for AnonymizeConfig {
+    fn default() -> AnonymizeConfig {
+        AnonymizeConfig {
+            keep_imports: Vec::new(),
+            preserve_zero_runs: true,
+        }
+    }
+}
+
+/// The names replaced by `anonymize`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Anonymization {
+    /// Maps each identifier to the real name it replaced.
+    pub names: HashMap<String, String>,
+}
+
+impl Anonymization {
+    /// The real name that `anonymized` replaced, if any.
+    pub fn real_name(&self, anonymized: &str) -> Option<&str> {
+        self.names.get(anonymized).map(|s| s.as_str())
+    }
+
+    /// Replace every identifier in `text`, such as an engine's error message,
+    /// with the real name it stands for.
+    pub fn deanonymize(&self, text: &str) -> String {
+        let mut names = self.names.iter().collect::<Vec<_>>();
+        // Identifiers are all the same length for a given kind, but a longer
+        // one might still contain a shorter one.
+        names.sort_by_key(|(anonymized, _)| std::cmp::Reverse(anonymized.len()));
+        let mut text = text.to_string();
+        for (anonymized, real) in names {
+            text = text.replace(anonymized.as_str(), real);
+        }
+        text
+    }
+}
+
+/// Anonymize `module`, deriving identifiers and data from `seed`, and return
+/// the mapping back to the real names.
+///
+/// Instructions, types, and the structure of the module are left untouched.
+pub fn anonymize(module: &mut Module, seed: u64, config: &AnonymizeConfig) -> Anonymization {
+    let mut names = Names {
+        seed,
+        anonymization: Anonymization::default(),
+        anonymized: HashMap::new(),
+    };
+
+    for import in module.imports.iter_mut() {
+        let keep = import.module.starts_with("wasi")
+            || config.keep_imports.iter().any(|m| *m == import.module);
+        if !keep {
+            import.module = names.anonymize("module", &import.module);
+            import.name = names.anonymize("import", &import.name);
+        }
+    }
+    for export in module.exports.iter_mut() {
+        export.name = names.anonymize("export", &export.name);
+    }
+
+    if let Some(name) = &mut module.name {
+        *name = names.anonymize("module", name);
+    }
+    for func in module.funcs.iter_mut() {
+        names.anonymize_opt("func", &mut func.name);
+    }
+    let locals = module.locals.iter().map(|l| l.id()).collect::<Vec<_>>();
+    for local in locals {
+        names.anonymize_opt("local", &mut module.locals.get_mut(local).name);
+    }
+    let globals = module.globals.iter().map(|g| g.id()).collect::<Vec<_>>();
+    for global in globals {
+        names.anonymize_opt("global", &mut module.globals.get_mut(global).name);
+    }
+    for table in module.tables.iter_mut() {
+        names.anonymize_opt("table", &mut table.name);
+    }
+    for memory in module.memories.iter_mut() {
+        names.anonymize_opt("memory", &mut memory.name);
+    }
+    for tag in module.tags.iter_mut() {
+        names.anonymize_opt("tag", &mut tag.name);
+    }
+    let types = module.types.iter().map(|t| t.id()).collect::<Vec<_>>();
+    for ty in types {
+        names.anonymize_opt("type", &mut module.types.get_mut(ty).name);
+    }
+    for elem in module.elements.iter_mut() {
+        names.anonymize_opt("elem", &mut elem.name);
+    }
+
+    let data = module.data.iter().map(|d| d.id()).collect::<Vec<_>>();
+    for (index, data) in data.into_iter().enumerate() {
+        let data = module.data.get_mut(data);
+        names.anonymize_opt("data", &mut data.name);
+        let mut rng = SplitMix64(seed ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        for byte in data.value.iter_mut() {
+            if *byte == 0 && config.preserve_zero_runs {
+                continue;
+            }
+            *byte = loop {
+                let random = rng.next() as u8;
+                if random != 0 || !config.preserve_zero_runs {
+                    break random;
+                }
+            };
+        }
+    }
+
+    let customs = module.customs.iter().map(|(id, _)| id).collect::<Vec<_>>();
+    for id in customs {
+        module.customs.delete(id);
+    }
+    module.unknown_name_subsections.clear();
+    module.producers.clear();
+    module.debug = ModuleDebugData::default();
+
+    names.anonymization
+}
+
+struct Names {
+    seed: u64,
+    anonymization: Anonymization,
+    /// The identifier given to each kind of name and real name.
+    anonymized: HashMap<(&'static str, String), String>,
+}
+
+impl Names {
+    fn anonymize(&mut self, kind: &'static str, real: &str) -> String {
+        if let Some(anonymized) = self.anonymized.get(&(kind, real.to_string())) {
+            return anonymized.clone();
+        }
+        let mut hash = hash(self.seed, kind, real);
+        let mut anonymized = format!("{}_{:016x}", kind, hash);
+        // Two names hashing the same is unlikely, but identifiers must map
+        // back to a single name.
+        while self.anonymization.names.contains_key(&anonymized) {
+            hash = hash.wrapping_add(1);
+            anonymized = format!("{}_{:016x}", kind, hash);
+        }
+        self.anonymization
+            .names
+            .insert(anonymized.clone(), real.to_string());
+        self.anonymized
+            .insert((kind, real.to_string()), anonymized.clone());
+        anonymized
+    }
+
+    fn anonymize_opt(&mut self, kind: &'static str, name: &mut Option<String>) {
+        if let Some(name) = name {
+            *name = self.anonymize(kind, name);
+        }
+    }
+}
+
+/// A 64-bit FNV-1a hash of a name, its kind, and the seed, which unlike
+/// std's hashers is stable across Rust versions and platforms.
+fn hash(seed: u64, kind: &str, name: &str) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let bytes = seed
+        .to_le_bytes()
+        .iter()
+        .chain(kind.as_bytes())
+        .chain(&[0])
+        .chain(name.as_bytes())
+        .copied()
+        .collect::<Vec<_>>();
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    SplitMix64(hash).next()
+}
+
+/// The SplitMix64 generator, used for its simplicity and stability.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ActiveData, ActiveDataLocation, DataKind, FunctionBuilder, RawCustomSection, ValType,
+    };
+
+    fn module() -> Module {
+        let mut module = Module::default();
+        module.name = Some("secret".to_string());
+        let memory = module.memories.add_local(false, 1, None);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .name("compute_secret".to_string())
+            .func_body()
+            .i32_const(7);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("compute_secret", func);
+        let ty = module.types.add(&[], &[]);
+        module.add_import_func("vendor", "phone_home", ty);
+        module.add_import_func("wasi_snapshot_preview1", "proc_exit", ty);
+        module.data.add(
+            DataKind::Active(ActiveData {
+                memory,
+                location: ActiveDataLocation::Absolute(0),
+            }),
+            b"key\0\0\0value".to_vec(),
+        );
+        module.customs.add(RawCustomSection {
+            name: "internal".to_string(),
+            data: vec![1, 2, 3],
+        });
+        module
+    }
+
+    #[test]
+    fn replaces_names_and_data() {
+        let mut module = module();
+        let anonymization = anonymize(&mut module, 42, &AnonymizeConfig::default());
+
+        let export = module.exports.iter().next().unwrap();
+        assert!(export.name.starts_with("export_"));
+        assert_eq!(
+            anonymization.real_name(&export.name),
+            Some("compute_secret")
+        );
+        let func = module.funcs.iter().find(|f| f.name.is_some()).unwrap();
+        let name = func.name.clone().unwrap();
+        assert!(name.starts_with("func_"));
+        assert_eq!(
+            anonymization.deanonymize(&format!("trap in {}", name)),
+            "trap in compute_secret"
+        );
+
+        let imports = module
+            .imports
+            .iter()
+            .map(|i| (i.module.as_str(), i.name.as_str()))
+            .collect::<Vec<_>>();
+        assert!(imports[0].0.starts_with("module_"));
+        assert!(imports[0].1.starts_with("import_"));
+        assert_eq!(imports[1], ("wasi_snapshot_preview1", "proc_exit"));
+
+        let data = &module.data.iter().next().unwrap().value;
+        assert_eq!(data.len(), 11);
+        assert_ne!(&data[..3], b"key");
+        assert_eq!(&data[3..6], &[0, 0, 0]);
+        assert!(data
+            .iter()
+            .enumerate()
+            .all(|(i, b)| (*b == 0) == (3..6).contains(&i)));
+
+        assert_eq!(module.customs.iter().count(), 0);
+        assert!(module.name.as_ref().unwrap().starts_with("module_"));
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let mut a = module();
+        let mut b = module();
+        let config = AnonymizeConfig {
+            keep_imports: vec!["vendor".to_string()],
+            preserve_zero_runs: false,
+        };
+        assert_eq!(anonymize(&mut a, 7, &config), anonymize(&mut b, 7, &config));
+        assert_eq!(a.emit_wasm(), b.emit_wasm());
+        assert_eq!(a.imports.iter().next().unwrap().module, "vendor");
+
+        let mut c = module();
+        let other = anonymize(&mut c, 8, &config);
+        assert!(other
+            .real_name(&a.exports.iter().next().unwrap().name)
+            .is_none());
+    }
+}
@@ -0,0 +1,137 @@
+//! Express `br_if` as an `if` whose consequent branches.
+//!
+//! Some analyses only want to deal with one kind of conditional control flow.
+//! This pass rewrites each
+//!
+//! ```wat
+//! br_if $label
+//! ```
+//!
+//! as the equivalent
+//!
+//! ```wat
+//! if (param t*) (result t*)
+//!   br $label
+//! else
+//! end
+//! ```
+//!
+//! where `t*` are the types of the values that the branch carries, which the
+//! `if` passes through unchanged when the condition is zero. This is a
+//! normalization for analyses, not an optimization: the result is larger.
+
+use crate::ir::*;
+use crate::Module;
+use wasmparser::WasmFeatures;
+
+/// Rewrite every `br_if` in `module` as an `if` whose consequent branches to
+/// the same label, and whose alternative is empty.
+///
+/// A branch that carries values needs an `if` with parameters, so it is only
+/// rewritten with `multi_value` in `features`.
+///
+/// Functions flagged `no_modify` are left alone, and so are preambles.
+///
+/// Returns the number of `br_if`s that were rewritten.
+pub fn run(module: &mut Module, features: &WasmFeatures) -> usize {
+    let mut sites = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        let loops = func.nesting().loops;
+        for (seq, block) in func.builder().arena.iter() {
+            for (index, (instr, _)) in block.instrs.iter().enumerate() {
+                if let Instr::BrIf(BrIf { block: target }) = instr {
+                    let label = func.label_types(module, *target, &loops);
+                    let supported = label.is_empty() || features.multi_value;
+                    if supported && index >= block.preamble_len() {
+                        sites.push((id, seq, index, *target, label.to_vec()));
+                    }
+                }
+            }
+        }
+    }
+
+    let rewritten = sites.len();
+    for (id, seq, index, target, label) in sites {
+        let ty = InstrSeqType::new(&mut module.types, &label, &label);
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let mut consequent = func.builder_mut().dangling_instr_seq(ty);
+        consequent.br(target);
+        let consequent = consequent.id();
+        let alternative = func.builder_mut().dangling_instr_seq(ty).id();
+        func.block_mut(seq).instrs[index].0 = Instr::IfElse(IfElse {
+            consequent,
+            alternative,
+        });
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::manager::mvp;
+    use crate::testing::{self, HostImports};
+    use crate::{FunctionBuilder, ValType};
+
+    /// A module exporting `f`, which returns 7 if its argument is non-zero,
+    /// and 9 otherwise, by branching out of a block with 7 if it is.
+    fn module() -> Module {
+        let mut module = Module::default();
+        let cond = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder.func_body().block(ValType::I32, |block| {
+            let id = block.id();
+            block
+                .i32_const(7)
+                .local_get(cond)
+                .br_if(id)
+                .drop()
+                .i32_const(9);
+        });
+        let f = builder.finish(vec![cond], &mut module.funcs);
+        module.exports.add("f", f);
+        module
+    }
+
+    fn call(module: &Module, arg: i32) -> String {
+        let results = testing::run(module, "f", &[Value::I32(arg)], &mut HostImports::new());
+        format!("{:?}", results.unwrap())
+    }
+
+    fn br_ifs(module: &Module) -> usize {
+        let mut count = 0;
+        module.walk_instrs(|_, _, _, instr| count += instr.is_br_if() as usize);
+        count
+    }
+
+    #[test]
+    fn rewrites_br_if_as_if() {
+        let mut module = module();
+        assert_eq!(run(&mut module, &mvp()), 0);
+
+        assert_eq!(run(&mut module, &WasmFeatures::default()), 1);
+        assert_eq!(br_ifs(&module), 0);
+        assert_eq!(call(&module, 1), "[I32(7)]");
+        assert_eq!(call(&module, 0), "[I32(9)]");
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn rewrites_br_if_without_values_for_mvp() {
+        let mut module = Module::default();
+        let cond = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder.func_body().block(None, |block| {
+            let id = block.id();
+            block.local_get(cond).br_if(id);
+        });
+        builder.finish(vec![cond], &mut module.funcs);
+
+        assert_eq!(run(&mut module, &mvp()), 1);
+        assert_eq!(br_ifs(&module), 0);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+}
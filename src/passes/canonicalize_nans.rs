@@ -0,0 +1,295 @@
+//! Canonicalize the NaNs produced by floating point code.
+//!
+//! Which NaN a floating point operation produces isn't fully specified by
+//! WebAssembly, and engines, or the hardware they run on, differ in their
+//! choice of sign and payload bits. Embedders that need execution to be
+//! deterministic across engines can run this pass, which:
+//!
+//! * follows every floating point arithmetic operation and load with a check
+//!   that replaces a NaN result with the canonical NaN, and
+//! * rewrites floating point constants that are non-canonical NaNs.
+//!
+//! The check is the usual `select` between the result and the canonical NaN,
+//! depending on whether the result equals itself. The result is kept in a
+//! fresh local in between, so that the operation isn't evaluated twice. Checks
+//! of operations in a preamble become part of it.
+//!
+//! Operations that only move or flip bits, such as `f32.neg`, `f32.abs` and
+//! `f32.copysign`, produce deterministic results and are left alone. SIMD
+//! operations aren't canonicalized yet.
+
+use crate::ir::*;
+use crate::{FunctionId, LocalFunction, Module, ModuleLocals, ValType};
+
+/// The bits of the canonical `f32` NaN.
+const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+
+/// The bits of the canonical `f64` NaN.
+const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// Canonicalize the NaNs produced by every local function in the module,
+/// except for those listed in `skip`.
+///
+/// Returns the number of operations that were followed by a check, plus the
+/// number of constants that were rewritten.
+pub fn canonicalize_nans(module: &mut Module, skip: &[FunctionId]) -> usize {
+    let locals = &mut module.locals;
+    module
+        .funcs
+        .iter_modifiable_mut()
+        .filter(|(id, _)| !skip.contains(id))
+        .map(|(_, func)| run_func(func, locals))
+        .sum()
+}
+
+fn run_func(func: &mut LocalFunction, locals: &mut ModuleLocals) -> usize {
+    let mut v = Seqs::default();
+    dfs_in_order(&mut v, func, func.entry_block());
+
+    let mut f32_local = None;
+    let mut f64_local = None;
+    let mut rewritten = 0;
+    for seq in v.seqs {
+        let block = func.block_mut(seq);
+        let mut preamble_rewritten = false;
+        let mut i = 0;
+        while i < block.len() {
+            let preamble = block.preamble_len();
+            let instrs = &mut block.instrs;
+            if let Instr::Const(Const { value }) = &mut instrs[i].0 {
+                if let Some(canonical) = canonical_const(*value) {
+                    *value = canonical;
+                    rewritten += 1;
+                    preamble_rewritten |= i < preamble;
+                }
+            }
+
+            let (local, nan) = match float_result(&instrs[i].0) {
+                Some(ValType::F32) => (
+                    *f32_local.get_or_insert_with(|| locals.add(ValType::F32)),
+                    Value::F32(f32::from_bits(CANONICAL_NAN_F32)),
+                ),
+                Some(ValType::F64) => (
+                    *f64_local.get_or_insert_with(|| locals.add(ValType::F64)),
+                    Value::F64(f64::from_bits(CANONICAL_NAN_F64)),
+                ),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let eq = match nan {
+                Value::F32(_) => BinaryOp::F32Eq,
+                _ => BinaryOp::F64Eq,
+            };
+            let loc = instrs[i].1;
+            let check: [Instr; 6] = [
+                LocalTee { local }.into(),
+                Const { value: nan }.into(),
+                LocalGet { local }.into(),
+                LocalGet { local }.into(),
+                Binop { op: eq }.into(),
+                Select { ty: None }.into(),
+            ];
+            instrs.splice(i + 1..i + 1, check.iter().map(|instr| (instr.clone(), loc)));
+            if i < preamble {
+                // A check of an operation in the preamble is part of it.
+                block.set_preamble_len(preamble + check.len());
+            }
+            i += 1 + check.len();
+            rewritten += 1;
+        }
+        if preamble_rewritten {
+            block.set_preamble_len(block.preamble_len());
+        }
+    }
+    return rewritten;
+
+    #[derive(Default)]
+    struct Seqs {
+        seqs: Vec<InstrSeqId>,
+    }
+
+    impl<'instr> Visitor<'instr> for Seqs {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            self.seqs.push(seq.id());
+        }
+    }
+}
+
+/// The canonical NaN to replace the given constant with, if it is a
+/// non-canonical NaN.
+fn canonical_const(value: Value) -> Option<Value> {
+    match value {
+        Value::F32(v) if v.is_nan() && v.to_bits() != CANONICAL_NAN_F32 => {
+            Some(Value::F32(f32::from_bits(CANONICAL_NAN_F32)))
+        }
+        Value::F64(v) if v.is_nan() && v.to_bits() != CANONICAL_NAN_F64 => {
+            Some(Value::F64(f64::from_bits(CANONICAL_NAN_F64)))
+        }
+        _ => None,
+    }
+}
+
+/// The type of the floating point value the instruction produces, if it may
+/// be a NaN with unspecified bits.
+fn float_result(instr: &Instr) -> Option<ValType> {
+    use self::BinaryOp::*;
+    use self::UnaryOp::*;
+    match instr {
+        Instr::Binop(Binop { op }) => match op {
+            F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max => Some(ValType::F32),
+            F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max => Some(ValType::F64),
+            _ => None,
+        },
+        Instr::Unop(Unop { op }) => match op {
+            F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F32DemoteF64 => {
+                Some(ValType::F32)
+            }
+            F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt | F64PromoteF32 => {
+                Some(ValType::F64)
+            }
+            _ => None,
+        },
+        Instr::Load(Load { kind, .. }) => match kind {
+            LoadKind::F32 => Some(ValType::F32),
+            LoadKind::F64 => Some(ValType::F64),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::wizen;
+    use crate::{FunctionBuilder, GlobalId, GlobalKind, InitExpr};
+
+    /// Build a start function that stores the results of some floating point
+    /// operations in globals.
+    fn build(module: &mut Module) -> Vec<GlobalId> {
+        let mut global = |ty, value| module.globals.add_local(ty, true, InitExpr::Value(value));
+        let sum = global(ValType::F32, Value::F32(0.0));
+        let zero_by_zero = global(ValType::F32, Value::F32(0.0));
+        let sqrt = global(ValType::F64, Value::F64(0.0));
+        let negated = global(ValType::F32, Value::F32(0.0));
+        let constant = global(ValType::F64, Value::F64(0.0));
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .f32_const(1.5)
+            .f32_const(2.25)
+            .binop(BinaryOp::F32Add)
+            .global_set(sum)
+            .f32_const(0.0)
+            .f32_const(0.0)
+            .binop(BinaryOp::F32Div)
+            .global_set(zero_by_zero)
+            .f64_const(-1.0)
+            .unop(UnaryOp::F64Sqrt)
+            .global_set(sqrt)
+            .f32_const(f32::from_bits(0x7fc0_0001))
+            .unop(UnaryOp::F32Neg)
+            .global_set(negated)
+            .f64_const(f64::from_bits(0xfff0_0000_0000_0001))
+            .global_set(constant);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+        vec![sum, zero_by_zero, sqrt, negated, constant]
+    }
+
+    fn value(module: &Module, global: GlobalId) -> Value {
+        match module.globals.get(global).kind {
+            GlobalKind::Local(InitExpr::Value(value)) => value,
+            ref other => panic!("unexpected global {:?}", other),
+        }
+    }
+
+    fn is_nan(value: Value) -> bool {
+        match value {
+            Value::F32(v) => v.is_nan(),
+            Value::F64(v) => v.is_nan(),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn canonicalizes_nan_results() {
+        let mut module = Module::default();
+        let globals = build(&mut module);
+        // The sum, the division and the square root, and both constants.
+        assert_eq!(canonicalize_nans(&mut module, &[]), 5);
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+        wizen::run(&mut module, None).unwrap();
+
+        let results = globals
+            .iter()
+            .map(|g| value(&module, *g))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [
+                Value::F32(3.75),
+                Value::F32(f32::from_bits(CANONICAL_NAN_F32)),
+                Value::F64(f64::from_bits(CANONICAL_NAN_F64)),
+                // Negation only flips the sign bit of the canonical NaN.
+                Value::F32(f32::from_bits(0xffc0_0000)),
+                Value::F64(f64::from_bits(CANONICAL_NAN_F64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_non_nan_results_and_skipped_functions_alone() {
+        let mut expected = Module::default();
+        let globals = build(&mut expected);
+        wizen::run(&mut expected, None).unwrap();
+
+        let mut skipped = Module::default();
+        build(&mut skipped);
+        let start = skipped.start.unwrap();
+        assert_eq!(canonicalize_nans(&mut skipped, &[start]), 0);
+        wizen::run(&mut skipped, None).unwrap();
+
+        let mut canonicalized = Module::default();
+        build(&mut canonicalized);
+        canonicalize_nans(&mut canonicalized, &[]);
+        wizen::run(&mut canonicalized, None).unwrap();
+
+        for global in globals {
+            let expected = value(&expected, global);
+            assert_eq!(value(&skipped, global), expected);
+            if !is_nan(expected) {
+                assert_eq!(value(&canonicalized, global), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        build(&mut module);
+        let start = module.start.unwrap();
+        module.funcs.get_mut(start).flags.no_modify = true;
+        assert_eq!(canonicalize_nans(&mut module, &[]), 0);
+    }
+
+    #[test]
+    fn rewrites_constants_in_preambles() {
+        let mut module = Module::default();
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", func);
+        let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        let entry = local.entry_block();
+        local.block_mut(entry).push_preamble(Const {
+            value: Value::F32(f32::from_bits(0x7fc0_0001)),
+        });
+        local.block_mut(entry).push_preamble(Drop {});
+
+        assert_eq!(canonicalize_nans(&mut module, &[]), 1);
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+    }
+}
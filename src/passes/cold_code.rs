@@ -0,0 +1,369 @@
+//! Move cold blocks out of the functions they are in.
+//!
+//! Code that rarely runs, such as error handling that ends in a trap, still
+//! takes up space in its function, which can keep an engine from inlining or
+//! optimizing the function as well as it could. This pass moves `block`s that
+//! a predicate considers cold into freshly created helper functions, and
+//! replaces each of them with a call.
+//!
+//! A helper takes the block's parameters, followed by every local the block
+//! uses, and returns the block's results, followed by every local the block
+//! writes, which are then written back into the caller's locals.
+//!
+//! Blocks containing a `return`, or branching to a label outside of
+//! themselves, are never outlined, and neither are blocks in a preamble.
+
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::{FunctionBuilder, FunctionId, LocalFunction, Module, ValType};
+
+/// The default heuristic for `outline_cold_blocks`: is this a block whose
+/// last instruction is an `unreachable`?
+///
+/// Such blocks are typically the result of a failed check, and end with a
+/// trap.
+pub fn ends_in_unreachable(block: &Block, func: &LocalFunction) -> bool {
+    match func.block(block.seq).instrs.last() {
+        Some((Instr::Unreachable(_), _)) => true,
+        _ => false,
+    }
+}
+
+/// Move every block for which `is_cold` returns `true` into a new function,
+/// and call that function in its place.
+///
+/// Blocks nested in a block that is outlined are moved along with it, and are
+/// not outlined separately. The new functions are not considered for
+/// outlining themselves.
+///
+/// Returns the number of blocks that were outlined.
+pub fn outline_cold_blocks(
+    module: &mut Module,
+    is_cold: impl Fn(&Block, &LocalFunction) -> bool,
+) -> usize {
+    let funcs = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .filter(|id| !module.funcs.get(*id).flags.no_modify)
+        .collect::<Vec<_>>();
+
+    let mut outlined = 0;
+    for id in funcs {
+        let func = module.funcs.get(id).kind.unwrap_local();
+        let mut candidates = Vec::new();
+        find_candidates(func, func.entry_block(), &is_cold, &mut candidates);
+
+        for (parent, block) in candidates {
+            let helper = outline(module, id, block, outlined);
+            let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+            let instrs = &mut func.block_mut(parent).instrs;
+            let index = instrs
+                .iter()
+                .position(|(instr, _)| matches!(instr, Instr::Block(b) if b.seq == block))
+                .unwrap();
+            let loc = instrs[index].1;
+            instrs.splice(
+                index..index + 1,
+                helper.into_iter().map(|instr| (instr, loc)),
+            );
+            outlined += 1;
+        }
+    }
+    outlined
+}
+
+/// Find the blocks in `seq` and the sequences nested in it that should be
+/// outlined, along with the sequence each one is in.
+fn find_candidates(
+    func: &LocalFunction,
+    seq: InstrSeqId,
+    is_cold: &impl Fn(&Block, &LocalFunction) -> bool,
+    candidates: &mut Vec<(InstrSeqId, InstrSeqId)>,
+) {
+    let preamble = func.block(seq).preamble_len();
+    for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+        match instr {
+            Instr::Block(block)
+                if index >= preamble && is_cold(block, func) && self_contained(func, block.seq) =>
+            {
+                candidates.push((seq, block.seq));
+            }
+            Instr::Block(Block { seq: inner }) | Instr::Loop(Loop { seq: inner }) => {
+                find_candidates(func, *inner, is_cold, candidates);
+            }
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                find_candidates(func, *consequent, is_cold, candidates);
+                find_candidates(func, *alternative, is_cold, candidates);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Does the code in `seq` only ever leave it by falling through, branching to
+/// it, or trapping?
+fn self_contained(func: &LocalFunction, seq: InstrSeqId) -> bool {
+    let mut v = Exits::default();
+    dfs_in_order(&mut v, func, seq);
+    return !v.returns && v.targets.iter().all(|target| v.seqs.contains(target));
+
+    #[derive(Default)]
+    struct Exits {
+        seqs: IdHashSet<InstrSeq>,
+        targets: Vec<InstrSeqId>,
+        returns: bool,
+    }
+
+    impl<'instr> Visitor<'instr> for Exits {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            self.seqs.insert(seq.id());
+        }
+
+        fn visit_instr(&mut self, instr: &'instr Instr, _: &'instr InstrLocId) {
+            match instr {
+                Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => self.targets.push(*block),
+                Instr::BrTable(BrTable { blocks, default }) => {
+                    self.targets.extend(blocks.iter().cloned());
+                    self.targets.push(*default);
+                }
+                Instr::Return(_) => self.returns = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Copy the block `block` of function `id` into a new function, and return
+/// the instructions that call it in the block's place.
+fn outline(module: &mut Module, id: FunctionId, block: InstrSeqId, n: usize) -> Vec<Instr> {
+    let func = module.funcs.get(id).kind.unwrap_local();
+    let ty = func.block(block).ty;
+    let (block_params, block_results) = match ty {
+        InstrSeqType::Simple(result) => (Vec::new(), result.into_iter().collect()),
+        InstrSeqType::MultiValue(ty) => {
+            let (params, results) = module.types.params_results(ty);
+            (params.to_vec(), results.to_vec())
+        }
+    };
+
+    let mut v = Locals::default();
+    dfs_in_order(&mut v, func, block);
+    let mut used = v.used.into_iter().collect::<Vec<_>>();
+    used.sort();
+    let mut written = v.written.into_iter().collect::<Vec<_>>();
+    written.sort();
+
+    let param_locals = block_params
+        .iter()
+        .map(|ty| module.locals.add(*ty))
+        .collect::<Vec<_>>();
+    let local_ty = |local: &LocalId| module.locals.get(*local).ty();
+    let params = block_params
+        .iter()
+        .cloned()
+        .chain(used.iter().map(local_ty))
+        .collect::<Vec<_>>();
+    let results = block_results
+        .iter()
+        .cloned()
+        .chain(written.iter().map(local_ty))
+        .collect::<Vec<ValType>>();
+
+    // The helper's body pushes the block's parameters, runs a copy of the
+    // block, and then pushes the locals the block wrote.
+    let mut builder = FunctionBuilder::new(&mut module.types, &params, &results);
+    if let Some(name) = &module.funcs.get(id).name {
+        builder.name(format!("{}.cold.{}", name, n));
+    }
+    let copy = builder.dangling_instr_seq(ty).id();
+    let mut seqs = IdHashMap::default();
+    seqs.insert(block, copy);
+    let func = module.funcs.get(id).kind.unwrap_local();
+    copy_seq(func, block, &mut builder, copy, &mut seqs);
+    let mut body = builder.func_body();
+    for local in param_locals.iter() {
+        body.local_get(*local);
+    }
+    body.instr(Block { seq: copy });
+    for local in written.iter() {
+        body.local_get(*local);
+    }
+    let args = param_locals
+        .into_iter()
+        .chain(used.iter().cloned())
+        .collect();
+    let helper = builder.finish(args, &mut module.funcs);
+
+    // The block's parameters are already on the stack.
+    let mut call = used
+        .iter()
+        .map(|local| LocalGet { local: *local }.into())
+        .collect::<Vec<Instr>>();
+    call.push(Call { func: helper }.into());
+    call.extend(
+        written
+            .iter()
+            .rev()
+            .map(|local| LocalSet { local: *local }.into()),
+    );
+    return call;
+
+    #[derive(Default)]
+    struct Locals {
+        used: IdHashSet<Local>,
+        written: IdHashSet<Local>,
+    }
+
+    impl<'instr> Visitor<'instr> for Locals {
+        fn visit_local_id(&mut self, local: &LocalId) {
+            self.used.insert(*local);
+        }
+
+        fn visit_local_set(&mut self, instr: &LocalSet) {
+            self.written.insert(instr.local);
+        }
+
+        fn visit_local_tee(&mut self, instr: &LocalTee) {
+            self.written.insert(instr.local);
+        }
+    }
+}
+
+/// Copy the instructions of `from` in `func` into the sequence `to` of
+/// `builder`, along with every sequence nested in it.
+///
+/// `seqs` maps the sequences that have already been copied to their copies,
+/// and must include `from`.
+fn copy_seq(
+    func: &LocalFunction,
+    from: InstrSeqId,
+    builder: &mut FunctionBuilder,
+    to: InstrSeqId,
+    seqs: &mut IdHashMap<InstrSeq, InstrSeqId>,
+) {
+    for (instr, loc) in func.block(from).instrs.iter() {
+        let instr = match instr {
+            Instr::Block(Block { seq }) => Block {
+                seq: copy_nested(func, *seq, builder, seqs),
+            }
+            .into(),
+            Instr::Loop(Loop { seq }) => Loop {
+                seq: copy_nested(func, *seq, builder, seqs),
+            }
+            .into(),
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => IfElse {
+                consequent: copy_nested(func, *consequent, builder, seqs),
+                alternative: copy_nested(func, *alternative, builder, seqs),
+            }
+            .into(),
+            Instr::Br(Br { block }) => Br { block: seqs[block] }.into(),
+            Instr::BrIf(BrIf { block }) => BrIf { block: seqs[block] }.into(),
+            Instr::BrTable(BrTable { blocks, default }) => BrTable {
+                blocks: blocks.iter().map(|block| seqs[block]).collect(),
+                default: seqs[default],
+            }
+            .into(),
+            other => other.clone(),
+        };
+        builder.instr_seq(to).instrs_mut().push((instr, *loc));
+    }
+    builder.arena[to].set_preamble_len(func.block(from).preamble_len());
+
+    fn copy_nested(
+        func: &LocalFunction,
+        seq: InstrSeqId,
+        builder: &mut FunctionBuilder,
+        seqs: &mut IdHashMap<InstrSeq, InstrSeqId>,
+    ) -> InstrSeqId {
+        let copy = builder.dangling_instr_seq(func.block(seq).ty).id();
+        seqs.insert(seq, copy);
+        copy_seq(func, seq, builder, copy, seqs);
+        copy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outlines_trapping_block() {
+        let mut module = Module::default();
+        let x = module.locals.add(ValType::I32);
+        let y = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder
+            .name("checked".to_string())
+            .func_body()
+            .local_get(x)
+            .local_set(y)
+            .local_get(x)
+            .if_else(
+                None,
+                |then| {
+                    then.block(None, |cold| {
+                        cold.local_get(x)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .local_set(y)
+                            .unreachable();
+                    });
+                },
+                |_| {},
+            )
+            .local_get(y);
+        let func = builder.finish(vec![x], &mut module.funcs);
+        module.exports.add("checked", func);
+
+        assert_eq!(outline_cold_blocks(&mut module, ends_in_unreachable), 1);
+        assert_eq!(module.funcs.iter().count(), 2);
+
+        let helper = module.funcs.by_name("checked.cold.0").unwrap();
+        let ty = module.funcs.get(helper).ty();
+        assert_eq!(module.types.params(ty), [ValType::I32, ValType::I32]);
+        assert_eq!(module.types.results(ty), [ValType::I32]);
+
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_blocks_that_escape() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let body = builder.func_body_id();
+        builder
+            .func_body()
+            .block(None, |returns| {
+                returns.return_().unreachable();
+            })
+            .block(None, |branches| {
+                branches.br(body).unreachable();
+            });
+        builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(outline_cold_blocks(&mut module, ends_in_unreachable), 0);
+        assert_eq!(module.funcs.iter().count(), 1);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().block(None, |cold| {
+            cold.unreachable();
+        });
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        assert_eq!(outline_cold_blocks(&mut module, ends_in_unreachable), 0);
+        assert_eq!(module.funcs.iter().count(), 1);
+    }
+}
@@ -0,0 +1,421 @@
+//! Instrument functions to count how often each of their blocks runs.
+//!
+//! Every function body, `block`, `loop` and arm of an `if/else` gets its own
+//! counter, which is incremented by the sequence's preamble each time it is
+//! entered; for a `loop`, that is on every iteration. The counters are `i32`s
+//! kept either in an array appended to a memory, or in one mutable global
+//! each.
+//!
+//! A `walrus.coverage` custom section records where the counters are and
+//! which block each one belongs to, so that a reporter can read them back
+//! from a running instance, or from a snapshot of its memory, and map the hit
+//! counts to source blocks. Its contents are, using the encoding of the wasm
+//! binary format:
+//!
+//! * a `u8` storage kind, followed by its location:
+//!   * `0` for memory: the `u32` index of the memory and the `u32` address of
+//!     the first counter, with counter `i` at `address + 4 * i`;
+//!   * `1` for globals: a `u32` count, then the `u32` index of each counter's
+//!     global, in counter order;
+//! * a `u32` count of counters, then for each counter the `u32` index of its
+//!   function, the function's name as a string (empty if it has none), and
+//!   the `u32` number of its block.
+//!
+//! Blocks are numbered within their function in the order their sequences
+//! are opened by the function's `Display` implementation: the body is `0`,
+//! and an `if/else`'s consequent comes right before its alternative.
+
+use crate::encoding::{write_string, write_u32};
+use crate::ir::*;
+use crate::module::{MAX_PAGES, PAGE_SIZE};
+use crate::passes::Roots;
+use crate::{CustomSection, FunctionId, GlobalId, IdsToIndices, InitExpr, LocalFunction};
+use crate::{MemoryId, Module, Result, TypedCustomSectionId, ValType};
+use anyhow::bail;
+use std::borrow::Cow;
+
+/// The name of the custom section describing the counters.
+pub const SECTION_NAME: &str = "walrus.coverage";
+
+/// Where to keep the counters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CounterStorage {
+    /// In an array of `i32`s, in pages added to the end of the memory's
+    /// initial size.
+    Memory(MemoryId),
+    /// In a new mutable `i32` global for each counter.
+    Globals,
+}
+
+/// Where the counters ended up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CounterLocation {
+    /// In an array of `i32`s in memory, starting at `base`.
+    Memory {
+        /// The memory holding the counters.
+        memory: MemoryId,
+        /// The address of the first counter.
+        base: u32,
+    },
+    /// In the given globals, one for each counter.
+    Globals(Vec<GlobalId>),
+}
+
+/// A block that is counted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Counter {
+    /// The function the block is in.
+    pub function: FunctionId,
+    /// The function's name, if it had one when it was instrumented.
+    pub function_name: Option<String>,
+    /// The block's number within the function.
+    pub block: u32,
+    /// The block's instruction sequence.
+    pub seq: InstrSeqId,
+}
+
+/// The `walrus.coverage` custom section.
+#[derive(Clone, Debug)]
+pub struct CoverageSection {
+    /// Where the counters are.
+    pub location: CounterLocation,
+    /// The counted blocks, in counter order.
+    pub counters: Vec<Counter>,
+}
+
+impl CustomSection for CoverageSection {
+    fn name(&self) -> &str {
+        SECTION_NAME
+    }
+
+    fn data(&self, ids: &IdsToIndices) -> Cow<[u8]> {
+        let mut data = Vec::new();
+        match &self.location {
+            CounterLocation::Memory { memory, base } => {
+                data.push(0);
+                write_u32(&mut data, ids.get_memory_index(*memory));
+                write_u32(&mut data, *base);
+            }
+            CounterLocation::Globals(globals) => {
+                data.push(1);
+                write_u32(&mut data, globals.len() as u32);
+                for global in globals {
+                    write_u32(&mut data, ids.get_global_index(*global));
+                }
+            }
+        }
+        write_u32(&mut data, self.counters.len() as u32);
+        for counter in self.counters.iter() {
+            write_u32(&mut data, ids.get_func_index(counter.function));
+            write_string(&mut data, counter.function_name.as_deref().unwrap_or(""));
+            write_u32(&mut data, counter.block);
+        }
+        data.into()
+    }
+
+    fn add_gc_roots(&self, roots: &mut Roots) {
+        match &self.location {
+            CounterLocation::Memory { memory, .. } => {
+                roots.push_memory(*memory);
+            }
+            CounterLocation::Globals(globals) => {
+                for global in globals {
+                    roots.push_global(*global);
+                }
+            }
+        }
+        for counter in self.counters.iter() {
+            roots.push_func(counter.function);
+        }
+    }
+}
+
+/// Add a counter to every block of every local function, and the
+/// `walrus.coverage` section describing them.
+///
+/// With `CounterStorage::Memory`, the memory's initial size is increased by
+/// enough pages to hold the counters, which start out as zero. Code that
+/// treats everything between some address and the initial end of the memory
+/// as its own, like a heap that starts out reaching to `memory.size`, would
+/// clobber them. Returns an error if the memory is imported, or if its
+/// maximum size leaves no room for the counters.
+pub fn instrument_coverage(
+    module: &mut Module,
+    storage: CounterStorage,
+) -> Result<TypedCustomSectionId<CoverageSection>> {
+    let mut counters = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        let name = module.funcs.get(id).name.clone();
+        for (block, seq) in numbered_seqs(func).into_iter().enumerate() {
+            counters.push(Counter {
+                function: id,
+                function_name: name.clone(),
+                block: block as u32,
+                seq,
+            });
+        }
+    }
+
+    let location = match storage {
+        CounterStorage::Memory(memory) => {
+            let mem = module.memories.get_mut(memory);
+            if mem.import.is_some() {
+                bail!("cannot put coverage counters in imported memory [{memory:?}]");
+            }
+            let bytes = 4 * counters.len() as u64;
+            let pages = (bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+            let initial = u64::from(mem.initial) + pages;
+            let maximum = mem.maximum.unwrap_or(MAX_PAGES).min(MAX_PAGES);
+            if initial > u64::from(maximum) || mem.initial >= MAX_PAGES {
+                bail!(
+                    "cannot grow memory [{memory:?}] by {} pages for coverage counters",
+                    pages
+                );
+            }
+            let base = mem.initial * PAGE_SIZE as u32;
+            mem.initial = initial as u32;
+            CounterLocation::Memory { memory, base }
+        }
+        CounterStorage::Globals => CounterLocation::Globals(
+            counters
+                .iter()
+                .map(|_| {
+                    module
+                        .globals
+                        .add_local(ValType::I32, true, InitExpr::i32_const(0))
+                })
+                .collect(),
+        ),
+    };
+
+    for (i, counter) in counters.iter().enumerate() {
+        let increment: Vec<Instr> = match &location {
+            CounterLocation::Memory { memory, base } => {
+                let address = Const {
+                    value: Value::I32((base + 4 * i as u32) as i32),
+                };
+                let arg = MemArg {
+                    align: 4,
+                    offset: 0,
+                };
+                vec![
+                    address.clone().into(),
+                    address.into(),
+                    Load {
+                        memory: *memory,
+                        kind: LoadKind::I32 { atomic: false },
+                        arg,
+                    }
+                    .into(),
+                    Const {
+                        value: Value::I32(1),
+                    }
+                    .into(),
+                    Binop {
+                        op: BinaryOp::I32Add,
+                    }
+                    .into(),
+                    Store {
+                        memory: *memory,
+                        kind: StoreKind::I32 { atomic: false },
+                        arg,
+                    }
+                    .into(),
+                ]
+            }
+            CounterLocation::Globals(globals) => vec![
+                GlobalGet { global: globals[i] }.into(),
+                Const {
+                    value: Value::I32(1),
+                }
+                .into(),
+                Binop {
+                    op: BinaryOp::I32Add,
+                }
+                .into(),
+                GlobalSet { global: globals[i] }.into(),
+            ],
+        };
+        let func = module
+            .funcs
+            .get_mut(counter.function)
+            .kind
+            .unwrap_local_mut();
+        let block = func.block_mut(counter.seq);
+        for instr in increment {
+            block.push_preamble(instr);
+        }
+    }
+
+    Ok(module.customs.add(CoverageSection { location, counters }))
+}
+
+/// The function's instruction sequences, in the order their blocks are
+/// numbered.
+fn numbered_seqs(func: &LocalFunction) -> Vec<InstrSeqId> {
+    let mut seqs = vec![func.entry_block()];
+    add_nested(func, func.entry_block(), &mut seqs);
+    return seqs;
+
+    fn add_nested(func: &LocalFunction, seq: InstrSeqId, seqs: &mut Vec<InstrSeqId>) {
+        for (instr, _) in func.block(seq).instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                    seqs.push(*seq);
+                    add_nested(func, *seq, seqs);
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    seqs.push(*consequent);
+                    add_nested(func, *consequent, seqs);
+                    seqs.push(*alternative);
+                    add_nested(func, *alternative, seqs);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::wizen;
+    use crate::{ActiveDataLocation, DataKind, FunctionBuilder};
+
+    /// A module whose start function calls `f` with `1`, `0` and `5`, and
+    /// then runs a loop three times. `f` has an `if` without an `else`.
+    fn module() -> (Module, MemoryId) {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+
+        let x = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder.func_body().local_get(x).if_else(
+            None,
+            |then| {
+                then.nop();
+            },
+            |_| {},
+        );
+        let f = builder.finish(vec![x], &mut module.funcs);
+
+        let i = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .call(f)
+            .i32_const(0)
+            .call(f)
+            .i32_const(5)
+            .call(f)
+            .loop_(None, |body| {
+                let id = body.id();
+                body.local_get(i)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_tee(i)
+                    .i32_const(3)
+                    .binop(BinaryOp::I32LtU)
+                    .br_if(id);
+            });
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        (module, memory)
+    }
+
+    /// The hit counts of `f`'s body, consequent and alternative, and of the
+    /// start function's body and loop.
+    const EXPECTED: [i32; 5] = [3, 2, 1, 1, 3];
+
+    #[test]
+    fn counts_blocks_in_memory() {
+        let (mut module, memory) = module();
+        let id = instrument_coverage(&mut module, CounterStorage::Memory(memory)).unwrap();
+        let section = module.customs.get(id).unwrap().clone();
+        assert_eq!(section.counters.len(), EXPECTED.len());
+        assert_eq!(
+            section.location,
+            CounterLocation::Memory {
+                memory,
+                base: 65536
+            }
+        );
+        assert_eq!(module.memories.get(memory).initial, 2);
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+
+        wizen::run(&mut module, None).unwrap();
+        let mut contents = vec![0; module.memories.get(memory).initial as usize * 65536];
+        for data in module.data.iter() {
+            if let DataKind::Active(active) = &data.kind {
+                if let ActiveDataLocation::Absolute(offset) = active.location {
+                    let offset = offset as usize;
+                    contents[offset..offset + data.value.len()].copy_from_slice(&data.value);
+                }
+            }
+        }
+        let counts = (0..EXPECTED.len())
+            .map(|i| {
+                let at = 65536 + 4 * i;
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&contents[at..at + 4]);
+                i32::from_le_bytes(bytes)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(counts, EXPECTED);
+    }
+
+    #[test]
+    fn counts_blocks_in_globals() {
+        let (mut module, _) = module();
+        let id = instrument_coverage(&mut module, CounterStorage::Globals).unwrap();
+        let section = module.customs.get(id).unwrap().clone();
+        let blocks = section
+            .counters
+            .iter()
+            .map(|counter| counter.block)
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, [0, 1, 2, 0, 1]);
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+
+        wizen::run(&mut module, None).unwrap();
+        let globals = match &section.location {
+            CounterLocation::Globals(globals) => globals,
+            CounterLocation::Memory { .. } => panic!("counters should be in globals"),
+        };
+        let counts = globals
+            .iter()
+            .map(|global| match module.globals.initial_value(*global) {
+                Some(Value::I32(count)) => count,
+                other => panic!("unexpected counter value: {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(counts, EXPECTED);
+    }
+
+    #[test]
+    fn rejects_full_memory() {
+        let (mut module, _) = module();
+        let memory = module.memories.add_local(false, 1, Some(1));
+        assert!(instrument_coverage(&mut module, CounterStorage::Memory(memory)).is_err());
+    }
+
+    #[test]
+    fn skips_no_modify_functions() {
+        let (mut module, _) = module();
+        let start = module.start.unwrap();
+        module.funcs.get_mut(start).flags.no_modify = true;
+        let id = instrument_coverage(&mut module, CounterStorage::Globals).unwrap();
+        let section = module.customs.get(id).unwrap();
+        assert_eq!(section.counters.len(), 3);
+        assert!(section.counters.iter().all(|c| c.function != start));
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+    }
+}
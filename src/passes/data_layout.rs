@@ -0,0 +1,552 @@
+//! Lay out data segments so that the module compresses better.
+//!
+//! Modules are usually shipped compressed with gzip or brotli, and their data
+//! segments are often most of what gets compressed. Toolchains emit one
+//! segment per object or section, in whatever order they were linked, which
+//! costs a few bytes of header for each segment and keeps similar contents
+//! apart, where the compressor's window can't see them together.
+//!
+//! `optimize_data_layout` changes where segments begin and end, and in what
+//! order passive segments come, but never what ends up at any address: active
+//! segments still initialize the same bytes, since code has their addresses
+//! baked in.
+
+use crate::ir::*;
+use crate::module::PAGE_SIZE;
+use crate::{ActiveData, ActiveDataLocation, DataId, DataKind, MemoryId, Module};
+use std::collections::{HashMap, HashSet};
+
+/// Configuration for `optimize_data_layout`.
+#[derive(Clone, Debug)]
+pub struct DataLayoutConfig {
+    /// Active segments are merged when the gap between them is shorter than
+    /// this many bytes, and filled with zeros. `0` disables merging.
+    pub max_merge_gap: u32,
+    /// Whether passive segments are reordered to put similar ones next to
+    /// each other.
+    pub sort_passive: bool,
+    /// Active segments longer than this many bytes, if any, are split where
+    /// they have at least `min_split_zeros` zeros in a row, which are left
+    /// out.
+    pub split_longer_than: Option<usize>,
+    /// The shortest run of zeros that a segment is split at.
+    pub min_split_zeros: usize,
+}
+
+impl Default for DataLayoutConfig {
+    fn default() -> DataLayoutConfig {
+        DataLayoutConfig {
+            max_merge_gap: 32,
+            sort_passive: true,
+            split_longer_than: None,
+            min_split_zeros: 64,
+        }
+    }
+}
+
+/// Merge, split and reorder the data segments of `module` as `config` says.
+///
+/// Only the active segments of local memories are merged and split, and only
+/// if all of the memory's segments are at constant offsets, don't overlap,
+/// and fit in its initial size, so that the gaps between them are known to
+/// be zero. Active segments used by `memory.init` or `data.drop` are left as
+/// they are.
+///
+/// Reordering passive segments gives them new ids, and every `memory.init`
+/// and `data.drop` is updated to use the new ones.
+///
+/// Replacing segments changes the indices of the segments after them, so
+/// nothing is replaced if that would move a segment used by a function
+/// flagged `no_modify`, or pinned to its original encoding.
+pub fn optimize_data_layout(module: &mut Module, config: &DataLayoutConfig) {
+    let used = module
+        .funcs
+        .iter_local()
+        .flat_map(|(_, func)| func.used_data_segments())
+        .collect::<HashSet<_>>();
+    let frozen = module
+        .funcs
+        .iter_local()
+        .filter(|(id, _)| {
+            module.funcs.get(*id).flags.no_modify || module.funcs.pinned(*id).is_some()
+        })
+        .flat_map(|(_, func)| func.used_data_segments())
+        .collect::<HashSet<_>>();
+
+    let memories = module.memories.iter().map(|m| m.id()).collect::<Vec<_>>();
+    for memory in memories {
+        let segments = match active_segments(module, memory) {
+            Some(segments) => segments,
+            None => continue,
+        };
+        relayout_active(module, memory, segments, &used, &frozen, config);
+    }
+
+    if config.sort_passive {
+        sort_passive(module, &frozen);
+    }
+}
+
+/// Would deleting the `deleted` segments change the index of any of the
+/// `frozen` ones?
+fn moves_any(module: &Module, deleted: &[DataId], frozen: &HashSet<DataId>) -> bool {
+    module
+        .data
+        .iter()
+        .skip_while(|data| !deleted.contains(&data.id()))
+        .any(|data| frozen.contains(&data.id()))
+}
+
+/// The active segments of `memory`, with their offsets and sorted by them, if
+/// it is a local memory that they initialize without overlapping.
+fn active_segments(module: &Module, memory: MemoryId) -> Option<Vec<(u32, DataId)>> {
+    let mem = module.memories.get(memory);
+    if mem.import.is_some() {
+        return None;
+    }
+    let mut segments = Vec::new();
+    for data in module.data.iter() {
+        match &data.kind {
+            DataKind::Active(active) if active.memory == memory => match active.location {
+                ActiveDataLocation::Absolute(offset) => segments.push((offset, data.id())),
+                ActiveDataLocation::Relative(_) => return None,
+            },
+            _ => {}
+        }
+    }
+    segments.sort();
+
+    let size = u64::from(mem.initial) * PAGE_SIZE;
+    let mut end = 0;
+    for (offset, data) in &segments {
+        let offset = u64::from(*offset);
+        if offset < end {
+            return None;
+        }
+        end = offset + module.data.get(*data).value.len() as u64;
+    }
+    if end > size {
+        return None;
+    }
+    Some(segments)
+}
+
+/// Merge and split the `segments` of `memory`, except those in `used`,
+/// unless that would move any of the `frozen` segments.
+fn relayout_active(
+    module: &mut Module,
+    memory: MemoryId,
+    segments: Vec<(u32, DataId)>,
+    used: &HashSet<DataId>,
+    frozen: &HashSet<DataId>,
+    config: &DataLayoutConfig,
+) {
+    // Each run of segments to merge, as its offset, contents and name.
+    let mut merged: Vec<(u32, Vec<u8>, Option<String>)> = Vec::new();
+    let mut old = Vec::new();
+    let mut can_extend = false;
+    for (offset, id) in segments {
+        if used.contains(&id) {
+            can_extend = false;
+            continue;
+        }
+        let data = module.data.get(id);
+        old.push(id);
+        if can_extend {
+            let (start, value, _) = merged.last_mut().unwrap();
+            let gap = offset - *start - value.len() as u32;
+            if gap < config.max_merge_gap {
+                value.resize(value.len() + gap as usize, 0);
+                value.extend_from_slice(&data.value);
+                continue;
+            }
+        }
+        merged.push((offset, data.value.clone(), data.name.clone()));
+        can_extend = true;
+    }
+
+    let mut changed = merged.len() != old.len();
+    let mut pieces = Vec::new();
+    for (offset, value, mut name) in merged {
+        match config.split_longer_than {
+            Some(max) if value.len() > max => {
+                let ranges = split_at_zeros(&value, config.min_split_zeros);
+                changed |= ranges != [(0, value.len())];
+                for (start, end) in ranges {
+                    let value = value[start..end].to_vec();
+                    // The first piece keeps the name.
+                    pieces.push((offset + start as u32, value, name.take()));
+                }
+            }
+            _ => pieces.push((offset, value, name)),
+        }
+    }
+
+    if !changed || moves_any(module, &old, frozen) {
+        // Keep the old ids.
+        return;
+    }
+    for id in old {
+        module.memories.get_mut(memory).data_segments.remove(&id);
+        module.data.delete(id);
+    }
+    for (offset, value, name) in pieces {
+        let kind = DataKind::Active(ActiveData {
+            memory,
+            location: ActiveDataLocation::Absolute(offset),
+        });
+        let id = module.data.add(kind, value);
+        module.data.get_mut(id).name = name;
+        module.memories.get_mut(memory).data_segments.insert(id);
+    }
+}
+
+/// The ranges of `value` that are left when leaving out every run of at
+/// least `min_zeros` zeros.
+fn split_at_zeros(value: &[u8], min_zeros: usize) -> Vec<(usize, usize)> {
+    let min_zeros = min_zeros.max(1);
+    let mut ranges = Vec::new();
+    let mut start = None;
+    let mut zeros = 0;
+    for (i, byte) in value.iter().enumerate() {
+        if *byte != 0 {
+            if start.is_none() {
+                start = Some(i);
+            }
+            zeros = 0;
+            continue;
+        }
+        zeros += 1;
+        if zeros == min_zeros {
+            if let Some(start) = start.take() {
+                ranges.push((start, i + 1 - zeros));
+            }
+        }
+    }
+    if let Some(start) = start {
+        let end = value.len() - if zeros >= min_zeros { zeros } else { 0 };
+        ranges.push((start, end));
+    }
+    ranges
+}
+
+/// Reorder the passive segments so that each is followed by the remaining
+/// one most similar to it, starting with the first, unless that would move
+/// any of the `frozen` segments.
+fn sort_passive(module: &mut Module, frozen: &HashSet<DataId>) {
+    let passive = module
+        .data
+        .iter()
+        .filter(|data| data.is_passive())
+        .map(|data| (data.id(), signature(&data.value)))
+        .collect::<Vec<_>>();
+    if passive.len() < 3 {
+        return;
+    }
+
+    let mut order = vec![0];
+    let mut left = (1..passive.len()).collect::<Vec<_>>();
+    while !left.is_empty() {
+        let last = &passive[*order.last().unwrap()].1;
+        let mut best = 0;
+        let mut best_similarity = -1.0;
+        for (i, candidate) in left.iter().enumerate() {
+            let similarity = similarity(last, &passive[*candidate].1);
+            if similarity > best_similarity {
+                best = i;
+                best_similarity = similarity;
+            }
+        }
+        order.push(left.remove(best));
+    }
+    if order.iter().enumerate().all(|(i, j)| i == *j) {
+        return;
+    }
+    let deleted = passive.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    if moves_any(module, &deleted, frozen) {
+        return;
+    }
+
+    let mut new_ids = HashMap::new();
+    for i in order {
+        let old = passive[i].0;
+        let data = module.data.get_mut(old);
+        let value = std::mem::take(&mut data.value);
+        let name = data.name.take();
+        module.data.delete(old);
+        let new = module.data.add(DataKind::Passive, value);
+        module.data.get_mut(new).name = name;
+        new_ids.insert(old, new);
+    }
+
+    // Functions that can't be rewritten don't use any of the renamed
+    // segments.
+    for (_, func) in module.funcs.iter_modifiable_mut() {
+        let entry = func.entry_block();
+        dfs_pre_order_mut(&mut Remap { new_ids: &new_ids }, func, entry);
+    }
+
+    struct Remap<'a> {
+        new_ids: &'a HashMap<DataId, DataId>,
+    }
+
+    impl VisitorMut for Remap<'_> {
+        fn visit_data_id_mut(&mut self, id: &mut DataId) {
+            if let Some(new) = self.new_ids.get(id) {
+                *id = *new;
+            }
+        }
+    }
+}
+
+/// The bits set by the rolling hash of each 4-byte window of `value`, so
+/// that segments sharing many substrings share many bits.
+fn signature(value: &[u8]) -> [u64; 4] {
+    const WINDOW: usize = 4;
+    const BASE: u64 = 257;
+    let out = BASE.pow(WINDOW as u32);
+    let mut signature = [0; 4];
+    let mut hash = 0u64;
+    for (i, byte) in value.iter().enumerate() {
+        hash = hash.wrapping_mul(BASE).wrapping_add(u64::from(*byte));
+        if i >= WINDOW {
+            hash = hash.wrapping_sub(out.wrapping_mul(u64::from(value[i - WINDOW])));
+        }
+        if i + 1 >= WINDOW || i + 1 == value.len() {
+            let bit = (hash.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as usize;
+            signature[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+    signature
+}
+
+/// The share of the bits set in either signature that are set in both.
+fn similarity(a: &[u64; 4], b: &[u64; 4]) -> f64 {
+    let both = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (a & b).count_ones())
+        .sum::<u32>();
+    let either = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (a | b).count_ones())
+        .sum::<u32>();
+    if either == 0 {
+        1.0
+    } else {
+        f64::from(both) / f64::from(either)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ModuleConfig};
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// What the active segments of `module` initialize its first memory to.
+    fn image(module: &Module) -> Vec<u8> {
+        let memory = module.memories.iter().next().unwrap();
+        let mut image = vec![0; memory.initial as usize * PAGE_SIZE as usize];
+        for data in module.data.iter() {
+            if let DataKind::Active(ActiveData {
+                location: ActiveDataLocation::Absolute(offset),
+                ..
+            }) = data.kind
+            {
+                let offset = offset as usize;
+                image[offset..offset + data.value.len()].copy_from_slice(&data.value);
+            }
+        }
+        image
+    }
+
+    fn active(module: &Module) -> Vec<(u32, usize)> {
+        let mut segments = module
+            .data
+            .iter()
+            .filter_map(|data| match data.kind {
+                DataKind::Active(ActiveData {
+                    location: ActiveDataLocation::Absolute(offset),
+                    ..
+                }) => Some((offset, data.value.len())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        segments.sort();
+        segments
+    }
+
+    fn add_active(module: &mut Module, memory: MemoryId, offset: u32, value: &[u8]) -> DataId {
+        let kind = DataKind::Active(ActiveData {
+            memory,
+            location: ActiveDataLocation::Absolute(offset),
+        });
+        let id = module.data.add(kind, value.to_vec());
+        module.memories.get_mut(memory).data_segments.insert(id);
+        id
+    }
+
+    #[test]
+    fn merges_and_splits_active_segments() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        add_active(&mut module, memory, 16, b"hello");
+        add_active(&mut module, memory, 24, b"world");
+        add_active(&mut module, memory, 100, b"far away");
+        let mut big = vec![7; 40];
+        big.extend(vec![0; 80]);
+        big.extend(vec![9; 40]);
+        add_active(&mut module, memory, 1000, &big);
+        let before = image(&module);
+
+        optimize_data_layout(&mut module, &DataLayoutConfig::default());
+        assert_eq!(active(&module), [(16, 13), (100, 8), (1000, 160)]);
+        assert_eq!(image(&module), before);
+
+        let config = DataLayoutConfig {
+            split_longer_than: Some(100),
+            ..DataLayoutConfig::default()
+        };
+        optimize_data_layout(&mut module, &config);
+        assert_eq!(
+            active(&module),
+            [(16, 13), (100, 8), (1000, 40), (1120, 40)]
+        );
+        assert_eq!(image(&module), before);
+        assert_eq!(module.memories.get(memory).data_segments.len(), 4);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn leaves_overlapping_segments_alone() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        add_active(&mut module, memory, 0, b"abcd");
+        add_active(&mut module, memory, 2, b"xy");
+        add_active(&mut module, memory, 8, b"efgh");
+        optimize_data_layout(&mut module, &DataLayoutConfig::default());
+        assert_eq!(active(&module), [(0, 4), (2, 2), (8, 4)]);
+    }
+
+    #[test]
+    fn sorts_passive_segments_by_similarity() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let contents: &[&[u8]] = &[
+            b"the quick brown fox jumps over the lazy dog",
+            b"0123456789 0123456789 0123456789",
+            b"the quick brown fox jumps over the lazy cat",
+        ];
+        let segments = contents
+            .iter()
+            .map(|value| module.data.add(DataKind::Passive, value.to_vec()))
+            .collect::<Vec<_>>();
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(0)
+            .i32_const(0)
+            .i32_const(4)
+            .memory_init(memory, segments[1])
+            .data_drop(segments[2]);
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        optimize_data_layout(&mut module, &DataLayoutConfig::default());
+        let values = module
+            .data
+            .iter()
+            .map(|data| data.value.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(values, [contents[0], contents[2], contents[1]]);
+
+        // The function still uses the same contents.
+        let func = module.funcs.get(func).kind.unwrap_local();
+        let mut used = func
+            .used_data_segments()
+            .into_iter()
+            .map(|id| module.data.get(id).value.as_slice())
+            .collect::<Vec<_>>();
+        used.sort();
+        assert_eq!(used, [contents[1], contents[2]]);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn keeps_segments_of_pinned_functions_in_place() {
+        let mut module = Module::default();
+        let contents: &[&[u8]] = &[
+            b"the quick brown fox jumps over the lazy dog",
+            b"0123456789 0123456789 0123456789",
+            b"the quick brown fox jumps over the lazy cat",
+        ];
+        let segments = contents
+            .iter()
+            .map(|value| module.data.add(DataKind::Passive, value.to_vec()))
+            .collect::<Vec<_>>();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().data_drop(segments[2]);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", func);
+        let wasm = module.emit_wasm();
+
+        let values = |module: &Module| {
+            module
+                .data
+                .iter()
+                .map(|data| data.value.clone())
+                .collect::<Vec<_>>()
+        };
+        for pin in [false, true].iter() {
+            let mut module = ModuleConfig::new()
+                .preserve_original_function_bodies(true)
+                .parse(&wasm)
+                .unwrap();
+            if *pin {
+                let func = module.exports.get_func_by_name("f").unwrap();
+                module.funcs.pin_original_encoding(func).unwrap();
+            }
+            optimize_data_layout(&mut module, &DataLayoutConfig::default());
+            let expected = if *pin {
+                [contents[0], contents[1], contents[2]]
+            } else {
+                [contents[0], contents[2], contents[1]]
+            };
+            assert_eq!(values(&module), expected);
+            module.try_emit_wasm().unwrap();
+        }
+    }
+
+    #[test]
+    fn merging_segments_compresses_better() {
+        fn deflated_len(wasm: &[u8]) -> usize {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(wasm).unwrap();
+            encoder.finish().unwrap().len()
+        }
+
+        // Many short records a few bytes apart, as a toolchain emitting one
+        // segment per object would lay them out.
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        for i in 0..200 {
+            let record = format!("item {:03}", i);
+            add_active(&mut module, memory, i * 12, record.as_bytes());
+        }
+        let before = module.emit_wasm();
+
+        optimize_data_layout(&mut module, &DataLayoutConfig::default());
+        assert_eq!(active(&module), [(0, 199 * 12 + 8)]);
+        let after = module.emit_wasm();
+        assert!(
+            deflated_len(&after) < deflated_len(&before),
+            "{} bytes deflated before, {} after",
+            deflated_len(&before),
+            deflated_len(&after)
+        );
+    }
+}
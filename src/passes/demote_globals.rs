@@ -0,0 +1,607 @@
+//! Turn globals that are written once during initialization into constants.
+//!
+//! Toolchains often keep values that are only known at link or start-up time
+//! in mutable globals, which are set once by the start function or by
+//! `__wasm_call_ctors` and only read afterwards. Such a global is effectively
+//! a constant: this pass replaces every read of it with the value it is set
+//! to, removes the write, and deletes the global.
+//!
+//! A global is only demoted when:
+//!
+//! * it is mutable, defined in this module, and not exported,
+//! * it is written exactly once in the whole module, by a `global.set` at the
+//!   top level of the start function or of `__wasm_call_ctors`,
+//! * the value written is an `*.const`, or a `global.get` of an immutable
+//!   global, immediately before the `global.set`,
+//! * nothing before the write in that function reads the global or calls
+//!   another function, which could observe its initial value, or returns or
+//!   branches out of the function, which would leave it unwritten,
+//! * if the write is in `__wasm_call_ctors`, the start function, which runs
+//!   first, doesn't read the global or call another function, and
+//! * the write isn't part of the function's preamble.
+//!
+//! `__wasm_call_ctors` is assumed to run before any other code, as it does in
+//! modules produced by `wasm-ld`.
+//!
+//! `propagate_read_only_globals` handles the simpler case of globals that are
+//! never written at all: their reads are replaced with their constant
+//! initializer.
+
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::passes::instr_stats::global_usage;
+use crate::{
+    ActiveData, ActiveDataLocation, DataKind, ElementKind, ExportItem, FunctionId, FunctionKind,
+    GlobalId, GlobalKind, InitExpr, LocalFunction, Module,
+};
+
+/// Demote every global that is written once during initialization to a
+/// constant.
+///
+/// Returns the number of globals that were removed.
+pub fn demote_single_write_globals(module: &mut Module) -> usize {
+    let mut writes = IdHashMap::default();
+    for (id, func) in module.funcs.iter_local() {
+        for seq in func.instr_seqs() {
+            for (i, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+                if let Instr::GlobalSet(GlobalSet { global }) = instr {
+                    let writes = writes.entry(*global).or_insert_with(Vec::new);
+                    writes.push((id, seq, i));
+                }
+            }
+        }
+    }
+
+    let initializers = module
+        .start
+        .into_iter()
+        .chain(module.funcs.by_name("__wasm_call_ctors"))
+        .collect::<Vec<_>>();
+
+    let protected = protected_globals(module);
+    let mut demoted = IdHashMap::default();
+    let mut removed = Vec::new();
+    for (global, writes) in writes {
+        if writes.len() != 1 || !demotable(module, global) || protected.contains(&global) {
+            continue;
+        }
+        let (func, seq, i) = writes[0];
+        if !initializers.contains(&func) {
+            continue;
+        }
+        let local = module.funcs.get(func).kind.unwrap_local();
+        if seq != local.entry_block() || i == 0 || i - 1 < local.block(seq).preamble_len() {
+            continue;
+        }
+        let replacement = match &local.block(seq).instrs[i - 1].0 {
+            Instr::Const(Const { value }) => Instr::Const(Const { value: *value }),
+            Instr::GlobalGet(GlobalGet { global: source })
+                if !module.globals.get(*source).mutable =>
+            {
+                Instr::GlobalGet(GlobalGet { global: *source })
+            }
+            _ => continue,
+        };
+        if observed_before(local, i - 1, global) {
+            continue;
+        }
+        match module.start {
+            Some(start) if start != func => match &module.funcs.get(start).kind {
+                FunctionKind::Local(start_func) if !observed_by(start_func, global) => {}
+                _ => continue,
+            },
+            _ => {}
+        }
+        demoted.insert(global, replacement);
+        removed.push((func, i));
+    }
+
+    // Remove the writes, back to front so that the indices of the ones still
+    // to be removed stay put.
+    removed.sort_by(|a, b| b.cmp(a));
+    for (func, i) in removed {
+        let func = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.block_mut(entry).instrs.drain(i - 1..=i);
+    }
+
+    for (_, func) in module.funcs.iter_modifiable_mut() {
+        let entry = func.entry_block();
+        dfs_pre_order_mut(&mut Replace { demoted: &demoted }, func, entry);
+        // Reads in preambles are replaced too, since the global is gone.
+        func.keep_rewritten_preambles();
+    }
+    for global in demoted.keys() {
+        module.globals.delete(*global);
+    }
+    return demoted.len();
+
+    struct Replace<'a> {
+        demoted: &'a IdHashMap<crate::Global, Instr>,
+    }
+
+    impl VisitorMut for Replace<'_> {
+        fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+            if let Instr::GlobalGet(GlobalGet { global }) = instr {
+                if let Some(replacement) = self.demoted.get(global) {
+                    *instr = replacement.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Replace every `global.get` of a global that always holds its constant
+/// initializer with that constant, and delete the globals that are no longer
+/// used.
+///
+/// A global always holds its initializer if it is defined in this module,
+/// initialized with an `*.const`, and either immutable, or mutable but never
+/// written by a `global.set` and not exported, so that the embedder can't
+/// write it either. A global is only deleted if it isn't exported, and isn't
+/// used by the initializer of another global, or by the offset of a data or
+/// element segment.
+///
+/// Returns the number of `global.get`s that were replaced.
+pub fn propagate_read_only_globals(module: &mut Module) -> usize {
+    let protected = protected_globals(module);
+    let mut constants = IdHashMap::default();
+    let mut replaced = 0;
+    for (global, (reads, writes)) in global_usage(module) {
+        if protected.contains(&global) {
+            continue;
+        }
+        let g = module.globals.get(global);
+        let value = match g.kind {
+            GlobalKind::Local(InitExpr::Value(value)) => value,
+            _ => continue,
+        };
+        if reads == 0 || (g.mutable && (writes > 0 || exported(module, global))) {
+            continue;
+        }
+        constants.insert(global, Instr::Const(Const { value }));
+        replaced += reads;
+    }
+
+    for (_, func) in module.funcs.iter_modifiable_mut() {
+        let entry = func.entry_block();
+        dfs_pre_order_mut(
+            &mut ReplaceReads {
+                constants: &constants,
+            },
+            func,
+            entry,
+        );
+        func.keep_rewritten_preambles();
+    }
+    for global in constants.keys() {
+        if !exported(module, *global) && !used_outside_functions(module, *global) {
+            module.globals.delete(*global);
+        }
+    }
+    return replaced;
+
+    struct ReplaceReads<'a> {
+        constants: &'a IdHashMap<crate::Global, Instr>,
+    }
+
+    impl VisitorMut for ReplaceReads<'_> {
+        fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+            if let Instr::GlobalGet(GlobalGet { global }) = instr {
+                if let Some(constant) = self.constants.get(global) {
+                    *instr = constant.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Could the given global be replaced by a constant, as far as anything
+/// outside of function bodies is concerned?
+fn demotable(module: &Module, global: GlobalId) -> bool {
+    let g = module.globals.get(global);
+    if !g.mutable || !matches!(g.kind, GlobalKind::Local(_)) {
+        return false;
+    }
+    !exported(module, global) && !used_outside_functions(module, global)
+}
+
+/// The globals used by functions flagged `no_modify`, whose reads and writes
+/// can't be rewritten.
+fn protected_globals(module: &Module) -> IdHashSet<crate::Global> {
+    let mut protected = IdHashSet::default();
+    for (id, func) in module.funcs.iter_local() {
+        if !module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        for seq in func.instr_seqs() {
+            for (instr, _) in func.block(seq).instrs.iter() {
+                match instr {
+                    Instr::GlobalGet(GlobalGet { global })
+                    | Instr::GlobalSet(GlobalSet { global }) => {
+                        protected.insert(*global);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    protected
+}
+
+/// Is the given global exported?
+fn exported(module: &Module, global: GlobalId) -> bool {
+    module
+        .exports
+        .iter()
+        .any(|e| matches!(e.item, ExportItem::Global(id) if id == global))
+}
+
+/// Is the given global used by another global's initializer, or by the
+/// offset of a data or element segment?
+fn used_outside_functions(module: &Module, global: GlobalId) -> bool {
+    let in_initializer = module
+        .globals
+        .iter()
+        .any(|g| matches!(g.kind, GlobalKind::Local(InitExpr::Global(id)) if id == global));
+    let in_data = module.data.iter().any(|d| {
+        matches!(
+            d.kind,
+            DataKind::Active(ActiveData {
+                location: ActiveDataLocation::Relative(id),
+                ..
+            }) if id == global
+        )
+    });
+    let in_elements = module.elements.iter().any(|e| {
+        matches!(
+            e.kind,
+            ElementKind::Active {
+                offset: InitExpr::Global(id),
+                ..
+            } if id == global
+        )
+    });
+    in_initializer || in_data || in_elements
+}
+
+/// Does anything among the first `end` instructions of the function's entry
+/// block read the given global, call another function, or leave the function?
+fn observed_before(func: &LocalFunction, end: usize, global: GlobalId) -> bool {
+    let mut v = Observes {
+        global,
+        entry: Some(func.entry_block()),
+        observed: false,
+    };
+    for (instr, _) in func.block(func.entry_block()).instrs[..end].iter() {
+        match instr {
+            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                dfs_in_order(&mut v, func, *seq);
+            }
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                dfs_in_order(&mut v, func, *consequent);
+                dfs_in_order(&mut v, func, *alternative);
+            }
+            _ => instr.visit(&mut v),
+        }
+    }
+    v.observed
+}
+
+/// Does anything in the function read the given global, or call another
+/// function?
+fn observed_by(func: &LocalFunction, global: GlobalId) -> bool {
+    let mut v = Observes {
+        global,
+        entry: None,
+        observed: false,
+    };
+    dfs_in_order(&mut v, func, func.entry_block());
+    v.observed
+}
+
+struct Observes {
+    global: GlobalId,
+    /// The function's entry block, if leaving the function counts too.
+    entry: Option<InstrSeqId>,
+    observed: bool,
+}
+
+impl<'instr> Visitor<'instr> for Observes {
+    fn visit_global_get(&mut self, instr: &GlobalGet) {
+        self.observed |= instr.global == self.global;
+    }
+
+    fn visit_call(&mut self, _: &Call) {
+        self.observed = true;
+    }
+
+    fn visit_call_indirect(&mut self, _: &CallIndirect) {
+        self.observed = true;
+    }
+
+    fn visit_return(&mut self, _: &Return) {
+        self.observed |= self.entry.is_some();
+    }
+
+    fn visit_br(&mut self, instr: &Br) {
+        self.observed |= self.entry == Some(instr.block);
+    }
+
+    fn visit_br_if(&mut self, instr: &BrIf) {
+        self.observed |= self.entry == Some(instr.block);
+    }
+
+    fn visit_br_table(&mut self, instr: &BrTable) {
+        if let Some(entry) = self.entry {
+            self.observed |= instr.default == entry || instr.blocks.contains(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    fn reads(module: &Module, func: FunctionId) -> Vec<Instr> {
+        let func = module.funcs.get(func).kind.unwrap_local();
+        func.block(func.entry_block())
+            .iter()
+            .map(|(instr, _)| instr.clone())
+            .collect()
+    }
+
+    #[test]
+    fn demotes_global_set_by_start() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(0));
+        let global = module.globals.add_local(ValType::I32, true, init);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(42).global_set(global);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().global_get(global);
+        let read = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("read", read);
+
+        assert_eq!(demote_single_write_globals(&mut module), 1);
+        assert_eq!(module.globals.iter().count(), 0);
+        assert!(reads(&module, start).is_empty());
+        assert_eq!(
+            reads(&module, read),
+            [Instr::Const(Const {
+                value: Value::I32(42)
+            })]
+        );
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_globals_observed_or_written_again() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(0));
+        let read_early = module.globals.add_local(ValType::I32, true, init);
+        let written_twice = module.globals.add_local(ValType::I32, true, init);
+        let exported = module.globals.add_local(ValType::I32, true, init);
+        module.exports.add("g", exported);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .global_get(read_early)
+            .drop()
+            .i32_const(1)
+            .global_set(read_early)
+            .i32_const(2)
+            .global_set(written_twice)
+            .i32_const(3)
+            .global_set(exported);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(4).global_set(written_twice);
+        let other = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("other", other);
+
+        assert_eq!(demote_single_write_globals(&mut module), 0);
+        assert_eq!(module.globals.iter().count(), 3);
+    }
+
+    #[test]
+    fn propagates_immutable_global() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(42));
+        let global = module.globals.add_local(ValType::I32, false, init);
+
+        let mut callers = Vec::new();
+        for name in ["a", "b"].iter() {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+            builder.func_body().global_get(global);
+            let func = builder.finish(vec![], &mut module.funcs);
+            module.exports.add(name, func);
+            callers.push(func);
+        }
+
+        assert_eq!(propagate_read_only_globals(&mut module), 2);
+        assert_eq!(module.globals.iter().count(), 0);
+        for func in callers {
+            assert_eq!(
+                reads(&module, func),
+                [Instr::Const(Const {
+                    value: Value::I32(42)
+                })]
+            );
+        }
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_written_and_exported_globals() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(7));
+        let written = module.globals.add_local(ValType::I32, true, init);
+        let exported_mutable = module.globals.add_local(ValType::I32, true, init);
+        module.exports.add("m", exported_mutable);
+        let exported_immutable = module.globals.add_local(ValType::I32, false, init);
+        module.exports.add("i", exported_immutable);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .global_set(written)
+            .global_get(written)
+            .global_get(exported_mutable)
+            .binop(BinaryOp::I32Add)
+            .global_get(exported_immutable)
+            .binop(BinaryOp::I32Add);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", func);
+
+        // Only the read of the immutable global is replaced, and the global
+        // is kept since it is exported.
+        assert_eq!(propagate_read_only_globals(&mut module), 1);
+        assert_eq!(module.globals.iter().count(), 3);
+        assert_eq!(
+            reads(&module, func)[5],
+            Instr::Const(Const {
+                value: Value::I32(7)
+            })
+        );
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_globals_read_by_no_modify_functions() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(0));
+        let global = module.globals.add_local(ValType::I32, true, init);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(42).global_set(global);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().global_get(global);
+        let read = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("read", read);
+        module.funcs.get_mut(read).flags.no_modify = true;
+
+        assert_eq!(demote_single_write_globals(&mut module), 0);
+        assert_eq!(module.globals.iter().count(), 1);
+    }
+
+    #[test]
+    fn rewrites_reads_in_preambles() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(0));
+        let written = module.globals.add_local(ValType::I32, true, init);
+        let init = InitExpr::Value(Value::I32(7));
+        let immutable = module.globals.add_local(ValType::I32, false, init);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(42).global_set(written);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", func);
+        let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        let entry = local.entry_block();
+        for global in [written, immutable].iter() {
+            let block = local.block_mut(entry);
+            block.push_preamble(GlobalGet { global: *global });
+            block.push_preamble(Drop {});
+        }
+
+        assert_eq!(demote_single_write_globals(&mut module), 1);
+        assert_eq!(propagate_read_only_globals(&mut module), 1);
+        assert_eq!(
+            reads(&module, func),
+            [
+                Instr::Const(Const {
+                    value: Value::I32(42)
+                }),
+                Instr::Drop(Drop {}),
+                Instr::Const(Const {
+                    value: Value::I32(7)
+                }),
+                Instr::Drop(Drop {}),
+            ]
+        );
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_globals_whose_write_can_be_skipped() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(0));
+        let after_return = module.globals.add_local(ValType::I32, true, init);
+        let after_br_if = module.globals.add_local(ValType::I32, true, init);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let entry = builder.func_body_id();
+        builder
+            .func_body()
+            .i32_const(0)
+            .if_else(
+                None,
+                |then| {
+                    then.return_();
+                },
+                |_| {},
+            )
+            .i32_const(1)
+            .global_set(after_return)
+            .i32_const(0)
+            .br_if(entry)
+            .i32_const(2)
+            .global_set(after_br_if);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        assert_eq!(demote_single_write_globals(&mut module), 0);
+        assert_eq!(module.globals.iter().count(), 2);
+    }
+
+    #[test]
+    fn keeps_globals_read_by_start_before_ctors() {
+        let mut module = Module::default();
+        let init = InitExpr::Value(Value::I32(0));
+        let global = module.globals.add_local(ValType::I32, true, init);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(42).global_set(global);
+        let ctors = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(ctors).name = Some("__wasm_call_ctors".to_string());
+        module.exports.add("_initialize", ctors);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().global_get(global).drop();
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        assert_eq!(demote_single_write_globals(&mut module), 0);
+        assert_eq!(module.globals.iter().count(), 1);
+
+        // Without the read, the start function can't tell.
+        let start = module.funcs.get_mut(start).kind.unwrap_local_mut();
+        let entry = start.entry_block();
+        start.block_mut(entry).instrs.clear();
+        assert_eq!(demote_single_write_globals(&mut module), 1);
+    }
+}
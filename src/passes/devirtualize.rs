@@ -0,0 +1,316 @@
+//! Replace indirect calls through tables that never change with direct calls.
+//!
+//! A `call_indirect` can only reach the functions of the right type that its
+//! table holds. When the table is defined in this module, isn't exported, and
+//! is never written by any instruction, those are exactly the functions put
+//! there by its active element segments. If there are few enough of them, the
+//! call is replaced with a chain of comparisons of the table index, each
+//! making a direct call:
+//!
+//! ```wat
+//! local.set $index
+//! local.get $index
+//! i32.const 3  ;; a slot holding $a
+//! i32.eq
+//! if (param ...) (result ...)
+//!   call $a
+//! else
+//!   ;; ... the same for the other functions ...
+//!   local.get $index
+//!   call_indirect
+//! end
+//! ```
+//!
+//! The final `call_indirect` handles every other index, which still traps the
+//! way it did before. The direct calls can then be inlined or optimized like
+//! any other call.
+
+use crate::ir::*;
+use crate::{ExportItem, FunctionId, InstrSeqBuilder, Module, TableId, TypeId, ValType};
+use std::collections::HashMap;
+
+/// The functions an indirect call of type `ty` through `table` can reach,
+/// each with the table slots holding it, in the order of their first slot.
+///
+/// Returns `None` if the table's contents aren't statically known, because it
+/// is imported, exported, written by some instruction, or filled by an element
+/// segment with a non-constant offset.
+pub fn analyze_indirect_call_targets(
+    module: &Module,
+    table: TableId,
+    ty: TypeId,
+) -> Option<Vec<(FunctionId, Vec<u32>)>> {
+    known_targets(module, table, ty, &written_tables(module))
+}
+
+/// `analyze_indirect_call_targets`, given the tables written by some
+/// instruction, which takes a walk over the whole module to find.
+fn known_targets(
+    module: &Module,
+    table: TableId,
+    ty: TypeId,
+    written: &[TableId],
+) -> Option<Vec<(FunctionId, Vec<u32>)>> {
+    if module.tables.get(table).import.is_some()
+        || module
+            .exports
+            .iter()
+            .any(|e| matches!(e.item, ExportItem::Table(id) if id == table))
+        || written.contains(&table)
+    {
+        return None;
+    }
+    let entries = module.table_entries(table).ok()?;
+    let signature = module.types.params_results(ty);
+
+    let mut targets: Vec<(FunctionId, Vec<u32>)> = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let func = match entry {
+            Some(func) => func,
+            None => continue,
+        };
+        if module.types.params_results(module.funcs.get(func).ty()) != signature {
+            continue;
+        }
+        match targets.iter_mut().find(|(f, _)| *f == func) {
+            Some((_, indices)) => indices.push(index as u32),
+            None => targets.push((func, vec![index as u32])),
+        }
+    }
+    Some(targets)
+}
+
+/// Replace every indirect call that can reach at most `max_targets` functions
+/// with direct calls to them.
+///
+/// Returns the number of indirect calls that were replaced.
+pub fn devirtualize(module: &mut Module, max_targets: usize) -> usize {
+    let written = written_tables(module);
+    let mut sites = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        for seq in func.instr_seqs() {
+            for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+                if let Instr::CallIndirect(call) = instr {
+                    if !written.contains(&call.table) {
+                        sites.push((id, seq, index, call.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Calls of the same type through the same table reach the same
+    // functions, and rewriting calls doesn't change what tables hold.
+    let mut analyzed = HashMap::new();
+    let mut rewritten = 0;
+    let mut index_locals = Vec::new();
+    for (id, seq, index, call) in sites {
+        let targets = analyzed
+            .entry((call.table, call.ty))
+            .or_insert_with(|| known_targets(module, call.table, call.ty, &written));
+        let targets = match targets {
+            Some(targets) if targets.len() <= max_targets => targets.clone(),
+            _ => continue,
+        };
+        let (params, results) = module.types.params_results(call.ty);
+        let (params, results) = (params.to_vec(), results.to_vec());
+        let arm_ty = InstrSeqType::new(&mut module.types, &params, &results);
+        let block_ty = InstrSeqType::new(
+            &mut module.types,
+            &[&params[..], &[ValType::I32]].concat(),
+            &results,
+        );
+        // One local holding the table index is enough for all of the calls
+        // in a function, since each reads it right after setting it.
+        let local = match index_locals.iter().find(|(f, _)| *f == id) {
+            Some((_, local)) => *local,
+            None => {
+                let local = module.locals.add(ValType::I32);
+                index_locals.push((id, local));
+                local
+            }
+        };
+
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let mut block = func.builder_mut().dangling_instr_seq(block_ty);
+        let block_id = block.id();
+        block.local_set(local);
+        dispatch(&mut block, arm_ty, local, &call, &targets);
+        let block = func.block_mut(seq);
+        block.instrs[index].0 = Instr::Block(Block { seq: block_id });
+        if index < block.preamble_len() {
+            block.set_preamble_len(block.preamble_len());
+        }
+        rewritten += 1;
+    }
+    rewritten
+}
+
+/// Call the first of `targets` if the table index matches one of its slots,
+/// and otherwise go on with the rest of them.
+fn dispatch(
+    builder: &mut InstrSeqBuilder,
+    ty: InstrSeqType,
+    local: LocalId,
+    call: &CallIndirect,
+    targets: &[(FunctionId, Vec<u32>)],
+) {
+    let ((func, indices), rest) = match targets.split_first() {
+        Some(split) => split,
+        None => {
+            builder.local_get(local).call_indirect(call.ty, call.table);
+            return;
+        }
+    };
+    for (i, index) in indices.iter().enumerate() {
+        builder
+            .local_get(local)
+            .i32_const(*index as i32)
+            .binop(BinaryOp::I32Eq);
+        if i > 0 {
+            builder.binop(BinaryOp::I32Or);
+        }
+    }
+    builder.if_else(
+        ty,
+        |then| {
+            then.call(*func);
+        },
+        |else_| dispatch(else_, ty, local, call, rest),
+    );
+}
+
+/// The tables written by an instruction somewhere in the module.
+fn written_tables(module: &Module) -> Vec<TableId> {
+    let mut tables = Vec::new();
+    for (_, func) in module.funcs.iter_local() {
+        for seq in func.instr_seqs() {
+            for (instr, _) in func.block(seq).instrs.iter() {
+                let table = match instr {
+                    Instr::TableSet(TableSet { table })
+                    | Instr::TableGrow(TableGrow { table })
+                    | Instr::TableFill(TableFill { table })
+                    | Instr::TableInit(TableInit { table, .. })
+                    | Instr::TableCopy(TableCopy { dst: table, .. }) => *table,
+                    _ => continue,
+                };
+                if !tables.contains(&table) {
+                    tables.push(table);
+                }
+            }
+        }
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::wizen;
+    use crate::{ElementKind, FunctionBuilder, GlobalId, GlobalKind, InitExpr};
+
+    /// A module whose start function calls the function in slot 1 of a table
+    /// holding three functions adding 10, 20 and 30 to their argument,
+    /// through a `call_indirect`, and stores the result in a global.
+    fn module() -> (Module, GlobalId) {
+        let mut module = Module::default();
+        let mut funcs = Vec::new();
+        for n in &[10, 20, 30] {
+            let arg = module.locals.add(ValType::I32);
+            let mut builder =
+                FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+            builder
+                .func_body()
+                .local_get(arg)
+                .i32_const(*n)
+                .binop(BinaryOp::I32Add);
+            funcs.push(Some(builder.finish(vec![arg], &mut module.funcs)));
+        }
+        let table = module.tables.add_local(4, None, ValType::Funcref);
+        module.elements.add(
+            ElementKind::Active {
+                table,
+                offset: InitExpr::Value(Value::I32(0)),
+            },
+            ValType::Funcref,
+            funcs,
+        );
+
+        let global = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+        let ty = module.types.add(&[ValType::I32], &[ValType::I32]);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .i32_const(1)
+            .call_indirect(ty, table)
+            .global_set(global);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+        (module, global)
+    }
+
+    #[test]
+    fn calls_directly() {
+        let (mut module, global) = module();
+        assert_eq!(devirtualize(&mut module, 3), 1);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+
+        // Running the start function doesn't need the indirect call anymore.
+        wizen::run(&mut module, None).unwrap();
+        match module.globals.get(global).kind {
+            GlobalKind::Local(InitExpr::Value(Value::I32(n))) => assert_eq!(n, 21),
+            ref other => panic!("unexpected global: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keeps_calls_with_many_targets() {
+        let (mut module, _) = module();
+        assert_eq!(devirtualize(&mut module, 2), 0);
+    }
+
+    #[test]
+    fn keeps_calls_through_written_tables() {
+        let (mut module, _) = module();
+        let table = module.tables.iter().next().unwrap().id();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(0)
+            .ref_null(ValType::Funcref)
+            .table_set(table);
+        builder.finish(vec![], &mut module.funcs);
+        assert_eq!(devirtualize(&mut module, 3), 0);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let (mut module, _) = module();
+        let start = module.start.unwrap();
+        module.funcs.get_mut(start).flags.no_modify = true;
+        assert_eq!(devirtualize(&mut module, 3), 0);
+    }
+
+    #[test]
+    fn rewrites_calls_in_preambles() {
+        let (mut module, global) = module();
+        let start = module.start.unwrap();
+        let local = module.funcs.get_mut(start).kind.unwrap_local_mut();
+        let entry = local.block_mut(local.entry_block());
+        entry.set_preamble_len(entry.len());
+
+        assert_eq!(devirtualize(&mut module, 3), 1);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+        wizen::run(&mut module, None).unwrap();
+        match module.globals.get(global).kind {
+            GlobalKind::Local(InitExpr::Value(Value::I32(n))) => assert_eq!(n, 21),
+            ref other => panic!("unexpected global: {:?}", other),
+        }
+    }
+}
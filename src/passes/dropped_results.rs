@@ -0,0 +1,145 @@
+//! Find calls to multi-value functions whose results are all dropped.
+//!
+//! Code generators drop the results of a call they don't need one `drop` at
+//! a time, sometimes with `nop`s left in between by earlier rewrites:
+//!
+//! ```wat
+//! call $returns_two
+//! drop
+//! nop
+//! drop
+//! ```
+//!
+//! This pass puts such runs into a canonical form, where the call is
+//! directly followed by exactly one `drop` per result, and reports where they
+//! are. A later pass can then call a variant of the callee that returns
+//! nothing.
+
+use crate::ir::*;
+use crate::{FunctionId, Module};
+
+/// A call whose results are all dropped right away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DroppedCall {
+    /// The function containing the call.
+    pub func: FunctionId,
+    /// The sequence containing the call.
+    pub seq: InstrSeqId,
+    /// The index of the call within `seq`, followed by its `drop`s.
+    pub index: usize,
+    /// The function called.
+    pub callee: FunctionId,
+}
+
+/// Find every call to a function with more than one result, all of which
+/// are dropped right after it, and remove any `nop`s between the `drop`s.
+///
+/// Functions flagged `no_modify` are left alone.
+pub fn run(module: &mut Module) -> Vec<DroppedCall> {
+    let mut dropped = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        for seq in func.instr_seqs() {
+            let instrs = &func.block(seq).instrs;
+            let preamble = func.block(seq).preamble_len();
+            for (index, (instr, _)) in instrs.iter().enumerate().skip(preamble) {
+                let callee = match instr {
+                    Instr::Call(Call { func }) => *func,
+                    _ => continue,
+                };
+                let results = module.types.results(module.funcs.get(callee).ty()).len();
+                if results > 1 && drops_after(instrs, index) >= results {
+                    dropped.push(DroppedCall {
+                        func: id,
+                        seq,
+                        index,
+                        callee,
+                    });
+                }
+            }
+        }
+    }
+
+    // Removing `nop`s shifts the calls after them in the same sequence.
+    let mut shift = (None, 0);
+    for call in dropped.iter_mut() {
+        if shift.0 != Some((call.func, call.seq)) {
+            shift = (Some((call.func, call.seq)), 0);
+        }
+        call.index -= shift.1;
+        let results = module
+            .types
+            .results(module.funcs.get(call.callee).ty())
+            .len();
+        let func = module.funcs.get_mut(call.func).kind.unwrap_local_mut();
+        let instrs = &mut func.block_mut(call.seq).instrs;
+        let mut drops = 0;
+        let mut i = call.index + 1;
+        while drops < results {
+            if instrs[i].0.is_nop() {
+                instrs.remove(i);
+                shift.1 += 1;
+            } else {
+                drops += 1;
+                i += 1;
+            }
+        }
+    }
+    dropped
+}
+
+/// The number of `drop`s right after `index`, skipping over `nop`s.
+fn drops_after(instrs: &[(Instr, InstrLocId)], index: usize) -> usize {
+    instrs[index + 1..]
+        .iter()
+        .map(|(instr, _)| instr)
+        .take_while(|instr| instr.is_drop() || instr.is_nop())
+        .filter(|instr| instr.is_drop())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn finds_two_dropped_results() {
+        let mut module = Module::default();
+        let mut builder =
+            FunctionBuilder::new(&mut module.types, &[], &[ValType::I32, ValType::I64]);
+        builder.func_body().i32_const(1).i64_const(2);
+        let callee = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .call(callee)
+            .drop()
+            .nop()
+            .drop()
+            // Only one of these results is dropped.
+            .call(callee)
+            .drop();
+        let caller = builder.finish(vec![], &mut module.funcs);
+
+        let dropped = run(&mut module);
+        let entry = module.funcs.get(caller).kind.unwrap_local().entry_block();
+        assert_eq!(
+            dropped,
+            [DroppedCall {
+                func: caller,
+                seq: entry,
+                index: 0,
+                callee,
+            }]
+        );
+        let func = module.funcs.get(caller).kind.unwrap_local();
+        let block = func.block(entry);
+        assert_eq!(block.len(), 5);
+        assert!(block[1].0.is_drop() && block[2].0.is_drop());
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+}
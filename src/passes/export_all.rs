@@ -0,0 +1,145 @@
+//! Export local functions so a test harness can call them.
+//!
+//! Differential testing and fuzzing call a module's functions from outside
+//! and compare the results across engines, which only works for exported
+//! functions. Rather than maintaining a list of exports by hand, `export_all`
+//! exports every local function of interest whose signature the host can
+//! call, and returns the new exports so they can be removed again afterwards.
+
+use crate::{ExportId, Function, FunctionKind, Module, ValType};
+use std::collections::HashSet;
+use wasmparser::WasmFeatures;
+
+/// Export every local function for which `predicate` returns `true`, and
+/// whose signature a host supporting `features` can call.
+///
+/// Each export is named `prefix` followed by the function's name, or by
+/// `FunctionId::index` if it has none. That index is stable while functions
+/// are added and deleted, but isn't necessarily the function's index in the
+/// emitted wasm. When that name is already taken, `_1`, `_2` and so on are
+/// appended until it isn't.
+///
+/// A signature with `v128`s needs `simd`, one with references needs
+/// `reference_types`, and one with more than one result needs `multi_value`.
+///
+/// Returns the ids of the added exports, in the order of the functions.
+pub fn export_all(
+    module: &mut Module,
+    prefix: &str,
+    features: &WasmFeatures,
+    mut predicate: impl FnMut(&Function) -> bool,
+) -> Vec<ExportId> {
+    let mut taken = module
+        .exports
+        .iter()
+        .map(|e| e.name.clone())
+        .collect::<HashSet<_>>();
+    let mut to_export = Vec::new();
+    for func in module.funcs.iter() {
+        match func.kind {
+            FunctionKind::Local(_) => {}
+            _ => continue,
+        }
+        if !predicate(func) {
+            continue;
+        }
+        let (params, results) = module.types.params_results(func.ty());
+        let supported = params.iter().chain(results).all(|ty| match ty {
+            ValType::V128 => features.simd,
+            ValType::Externref | ValType::Funcref => features.reference_types,
+            ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64 => true,
+        });
+        if !supported || (results.len() > 1 && !features.multi_value) {
+            continue;
+        }
+
+        let base = match &func.name {
+            Some(name) => format!("{}{}", prefix, name),
+            None => format!("{}{}", prefix, func.id().index()),
+        };
+        let mut name = base.clone();
+        let mut suffix = 0;
+        while taken.contains(&name) {
+            suffix += 1;
+            name = format!("{}_{}", base, suffix);
+        }
+        taken.insert(name.clone());
+        to_export.push((name, func.id()));
+    }
+
+    to_export
+        .into_iter()
+        .map(|(name, func)| {
+            let added = module.exports.try_add(&name, func);
+            added.expect("names are only used once")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::manager::mvp;
+    use crate::{Export, ExportItem, FunctionBuilder};
+
+    #[test]
+    fn exports_callable_functions() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[], &[]);
+        module.add_import_func("env", "imported", ty);
+        let mut funcs = Vec::new();
+        for (name, params) in &[
+            (Some("add"), &[ValType::I32][..]),
+            (None, &[ValType::F64][..]),
+            (Some("simd"), &[ValType::V128][..]),
+            (Some("skipped"), &[][..]),
+        ] {
+            let mut builder = FunctionBuilder::new(&mut module.types, params, &[]);
+            if let Some(name) = name {
+                builder.name(name.to_string());
+            }
+            let args = params.iter().map(|ty| module.locals.add(*ty)).collect();
+            funcs.push(builder.finish(args, &mut module.funcs));
+        }
+        module.exports.add("test_add", funcs[0]);
+
+        let added = export_all(&mut module, "test_", &mvp(), |f| {
+            f.name.as_deref() != Some("skipped")
+        });
+        let exports = added
+            .iter()
+            .map(|id| match module.exports.get(*id) {
+                Export {
+                    name,
+                    item: ExportItem::Function(f),
+                    ..
+                } => (name.as_str(), *f),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(exports, [("test_add_1", funcs[0]), ("test_2", funcs[1])]);
+
+        for id in added {
+            module.exports.delete(id);
+        }
+        assert_eq!(module.exports.iter().count(), 1);
+    }
+
+    #[test]
+    fn names_unnamed_functions_by_id() {
+        let mut module = Module::default();
+        let mut funcs = Vec::new();
+        for _ in 0..2 {
+            let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            funcs.push(builder.finish(vec![], &mut module.funcs));
+        }
+        // The remaining function is the first one in the module, but keeps
+        // its id.
+        module.funcs.delete(funcs[0]);
+
+        let added = export_all(&mut module, "f", &mvp(), |_| true);
+        assert_eq!(added.len(), 1);
+        assert_eq!(module.exports.get(added[0]).name, "f1");
+        assert_eq!(funcs[1].index(), 1);
+    }
+}
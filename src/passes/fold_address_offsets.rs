@@ -0,0 +1,219 @@
+//! Fold constants added to the address of a load or store into its offset.
+//!
+//! Compilers and passes building addresses sometimes add a constant to a
+//! pointer instead of using the static offset of the access:
+//!
+//! ```wat
+//! local.get $p
+//! i32.const 16
+//! i32.add
+//! i32.load offset=4
+//! ```
+//!
+//! which is shorter, and easier for engines to reason about, as:
+//!
+//! ```wat
+//! local.get $p
+//! i32.load offset=20
+//! ```
+//!
+//! These only behave the same as long as `$p + 16` fits in 32 bits. Past that
+//! point the `i32.add` wraps around to an address below 16, and the first
+//! version accesses memory just above offset 4, while the effective address of
+//! the second one is computed without wrapping, goes beyond 4 GiB, and traps.
+//! So a constant is only folded when that can't make a difference:
+//!
+//! * when the other operand is a constant too, and their sum doesn't wrap, or
+//! * when the caller promises that the module never accesses memory below
+//!   some address, and every address the wrapped-around sum could have
+//!   accessed is below it. Toolchains usually leave the first kilobyte of
+//!   memory unused, to catch null pointer dereferences.
+//!
+//! A constant is never folded if the new offset would overflow a `u32`, nor
+//! when it is negative, since offsets can't be.
+
+use crate::ir::*;
+use crate::{LocalFunction, Module};
+use std::collections::HashSet;
+
+/// Fold the constants added to the address of every load and store into its
+/// offset, when that doesn't change which accesses trap.
+///
+/// `low_memory_unused` is the address below which the module promises to
+/// never access memory, or `0` for no such promise, in which case only sums
+/// of two constants are folded.
+///
+/// Functions flagged `no_modify` are left alone, and so are preambles.
+///
+/// Returns the number of loads and stores that were changed.
+pub fn run(module: &mut Module, low_memory_unused: u32) -> usize {
+    let mut folds = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        for seq in func.instr_seqs() {
+            let block = func.block(seq);
+            let mut accesses = Vec::new();
+            let mut removed = HashSet::new();
+            for (index, (instr, _)) in block.instrs.iter().enumerate() {
+                let (arg, depth) = match instr {
+                    Instr::Load(Load { arg, .. }) => (arg, 0),
+                    // The address is below the value to store.
+                    Instr::Store(Store { arg, .. }) => (arg, 1),
+                    _ => continue,
+                };
+                let (constant, remove) = match constant_addend(module, func, seq, index, depth) {
+                    Some(addend) => addend,
+                    None => continue,
+                };
+                let offset = match arg.offset.checked_add(constant) {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+                let [other, _, _] = remove;
+                let exact = match block.instrs[other].0 {
+                    Instr::Const(Const {
+                        value: Value::I32(other),
+                    }) => (other as u32).checked_add(constant).is_some(),
+                    _ => false,
+                };
+                if !exact && offset > low_memory_unused {
+                    continue;
+                }
+                if remove[1..].iter().any(|i| *i < block.preamble_len()) {
+                    continue;
+                }
+                accesses.push((index, constant));
+                removed.extend(remove[1..].iter().copied());
+            }
+            if !accesses.is_empty() {
+                folds.push((id, seq, accesses, removed));
+            }
+        }
+    }
+
+    let mut folded = 0;
+    for (id, seq, accesses, removed) in folds {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let instrs = &mut func.block_mut(seq).instrs;
+        for (index, constant) in accesses {
+            match &mut instrs[index].0 {
+                Instr::Load(Load { arg, .. }) | Instr::Store(Store { arg, .. }) => {
+                    arg.offset += constant
+                }
+                _ => unreachable!(),
+            }
+            folded += 1;
+        }
+        let mut index = 0;
+        instrs.retain(|_| {
+            index += 1;
+            !removed.contains(&(index - 1))
+        });
+    }
+    folded
+}
+
+/// The non-negative constant added to the address of the access at `index`
+/// of `seq`, which is `depth` values below the top of the stack there,
+/// along with the indices of the instruction pushing the other operand, of
+/// the `i32.const`, and of the `i32.add`.
+fn constant_addend(
+    module: &Module,
+    func: &LocalFunction,
+    seq: InstrSeqId,
+    index: usize,
+    depth: usize,
+) -> Option<(u32, [usize; 3])> {
+    let instrs = &func.block(seq).instrs;
+    let add = func.producer(module, seq, index, depth)?;
+    match instrs[add].0 {
+        Instr::Binop(Binop {
+            op: BinaryOp::I32Add,
+        }) => {}
+        _ => return None,
+    }
+    let rhs = func.producer(module, seq, add, 0)?;
+    let lhs = func.producer(module, seq, add, 1)?;
+    // If both are constants, the one on the right is folded.
+    for &(constant, other) in &[(rhs, lhs), (lhs, rhs)] {
+        match instrs[constant].0 {
+            Instr::Const(Const {
+                value: Value::I32(c),
+            }) if c >= 0 => return Some((c as u32, [other, constant, add])),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, FunctionId, ValType};
+
+    /// A function loading from `address` plus `constant`, at `offset`, and
+    /// the module it is in.
+    fn load(address: Option<i32>, constant: i32, offset: u32) -> (Module, FunctionId) {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let p = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let mut body = builder.func_body();
+        match address {
+            Some(address) => body.i32_const(address),
+            None => body.local_get(p),
+        };
+        body.i32_const(constant).binop(BinaryOp::I32Add).load(
+            memory,
+            LoadKind::I32 { atomic: false },
+            MemArg { align: 4, offset },
+        );
+        let func = builder.finish(vec![p], &mut module.funcs);
+        (module, func)
+    }
+
+    fn offsets(module: &Module, func: FunctionId) -> (Vec<u32>, usize) {
+        let func = module.funcs.get(func).kind.unwrap_local();
+        let block = func.block(func.entry_block());
+        let offsets = block
+            .iter()
+            .filter_map(|(instr, _)| match instr {
+                Instr::Load(Load { arg, .. }) => Some(arg.offset),
+                _ => None,
+            })
+            .collect();
+        (offsets, block.len())
+    }
+
+    #[test]
+    fn folds_into_unused_low_memory() {
+        let (mut module, func) = load(None, 16, 4);
+        assert_eq!(run(&mut module, 0), 0);
+        assert_eq!(run(&mut module, 16), 0);
+        assert_eq!(run(&mut module, 1024), 1);
+        assert_eq!(offsets(&module, func), (vec![20], 2));
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+
+        let (mut module, _) = load(None, -16, 4);
+        assert_eq!(run(&mut module, 1024), 0);
+    }
+
+    #[test]
+    fn folds_constant_sums_up_to_4gib() {
+        // The last address below 4 GiB, so the sum doesn't wrap.
+        let (mut module, func) = load(Some(0xffff_fff0_u32 as i32), 0xf, 0);
+        assert_eq!(run(&mut module, 0), 1);
+        assert_eq!(offsets(&module, func), (vec![0xf], 2));
+
+        // This one wraps around to 0.
+        let (mut module, func) = load(Some(0xffff_fff0_u32 as i32), 0x10, 0);
+        assert_eq!(run(&mut module, 0), 0);
+        assert_eq!(offsets(&module, func), (vec![0], 4));
+
+        // The offset itself would overflow.
+        let (mut module, _) = load(Some(0), 16, u32::MAX - 8);
+        assert_eq!(run(&mut module, 0), 0);
+    }
+}
@@ -0,0 +1,250 @@
+//! Fold `if/else`s whose condition is a constant into the taken arm.
+//!
+//! When the condition of an `if/else` is an `i32.const`, only one arm can ever
+//! run. This pass removes the condition and replaces the `if/else` with the
+//! taken arm. If nothing branches to the arm, its instructions are inlined
+//! into the enclosing sequence; otherwise they are kept in a `block` with the
+//! same type, so that those branches still have a target.
+//!
+//! `if/else`s whose condition is in a preamble are left alone, and an arm with
+//! a preamble of its own is always kept in a `block`, so that its preamble
+//! still runs on entry to a sequence of its own.
+
+use crate::ir::*;
+use crate::{LocalFunction, Module};
+use std::cmp;
+use std::mem;
+
+/// Fold constant-condition `if/else`s in every local function in the module.
+///
+/// Returns the number of `if/else`s that were folded.
+pub fn run(module: &mut Module) -> usize {
+    module
+        .funcs
+        .iter_modifiable_mut()
+        .map(|(_, func)| run_func(func))
+        .sum()
+}
+
+/// Fold constant-condition `if/else`s in a single function.
+///
+/// Returns the number of `if/else`s that were folded.
+pub fn run_func(func: &mut LocalFunction) -> usize {
+    let targeted = func.branch_targets();
+    let mut folded = 0;
+    let mut worklist = vec![func.entry_block()];
+
+    while let Some(seq) = worklist.pop() {
+        // The condition is the instruction just before the `if/else`.
+        let mut i = 1;
+        while i < func.block(seq).len() {
+            let instrs = &func.block(seq).instrs;
+            let taken = match (&instrs[i - 1].0, &instrs[i].0) {
+                (
+                    Instr::Const(Const {
+                        value: Value::I32(condition),
+                    }),
+                    Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    }),
+                ) => {
+                    if *condition != 0 {
+                        *consequent
+                    } else {
+                        *alternative
+                    }
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            if i - 1 < func.block(seq).preamble_len() {
+                i += 1;
+                continue;
+            }
+            folded += 1;
+
+            // Drop the condition, and put the taken arm in the `if/else`'s
+            // place.
+            let keep_block = targeted.contains(&taken) || func.block(taken).preamble_len() > 0;
+            let instrs = &mut func.block_mut(seq).instrs;
+            instrs.remove(i - 1);
+            if keep_block {
+                instrs[i - 1].0 = Block { seq: taken }.into();
+            } else {
+                // The inlined instructions are revisited, since they may
+                // contain more foldable `if/else`s.
+                let arm = mem::take(&mut func.block_mut(taken).instrs);
+                func.block_mut(seq).instrs.splice(i - 1..i, arm);
+                i = cmp::max(i - 1, 1);
+            }
+        }
+
+        for (instr, _) in func.block(seq).instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => worklist.push(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    worklist.push(*consequent);
+                    worklist.push(*alternative);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    fn entry_instrs(module: &Module, func: crate::FunctionId) -> Vec<Instr> {
+        let local = module.funcs.get(func).kind.unwrap_local();
+        local
+            .block(local.entry_block())
+            .iter()
+            .map(|(instr, _)| instr.clone())
+            .collect()
+    }
+
+    #[test]
+    fn folds_constant_condition() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(1).if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(7);
+            },
+            |else_| {
+                else_.i32_const(9);
+            },
+        );
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(run(&mut module), 1);
+        match &entry_instrs(&module, func)[..] {
+            [Instr::Const(Const {
+                value: Value::I32(7),
+            })] => {}
+            other => panic!("unexpected instructions: {:?}", other),
+        }
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_block_for_branch_targets() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(0).if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(7);
+            },
+            |else_| {
+                let id = else_.id();
+                else_.i32_const(9).br(id);
+            },
+        );
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(run(&mut module), 1);
+        match &entry_instrs(&module, func)[..] {
+            [Instr::Block(_)] => {}
+            other => panic!("unexpected instructions: {:?}", other),
+        }
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_block_for_preambles() {
+        let mut module = Module::default();
+        let global =
+            module
+                .globals
+                .add_local(ValType::I32, true, crate::InitExpr::Value(Value::I32(0)));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        let mut consequent = None;
+        builder.func_body().i32_const(1).if_else(
+            ValType::I32,
+            |then| {
+                consequent = Some(then.id());
+                then.i32_const(7);
+            },
+            |else_| {
+                else_.i32_const(9);
+            },
+        );
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        // Instrument the arm, as a profiler counting its entries would.
+        let consequent = consequent.unwrap();
+        let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        local
+            .block_mut(consequent)
+            .push_preamble(GlobalGet { global });
+        local.block_mut(consequent).push_preamble(Const {
+            value: Value::I32(1),
+        });
+        local.block_mut(consequent).push_preamble(Binop {
+            op: BinaryOp::I32Add,
+        });
+        local
+            .block_mut(consequent)
+            .push_preamble(GlobalSet { global });
+
+        assert_eq!(run(&mut module), 1);
+        match &entry_instrs(&module, func)[..] {
+            [Instr::Block(Block { seq })] => assert_eq!(*seq, consequent),
+            other => panic!("unexpected instructions: {:?}", other),
+        }
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(local.block(consequent).preamble_len(), 4);
+        assert_eq!(local.block(consequent).len(), 5);
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn leaves_dynamic_condition() {
+        let mut module = Module::default();
+        let arg = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder
+            .func_body()
+            .local_get(arg)
+            .if_else(None, |_| {}, |_| {});
+        builder.finish(vec![arg], &mut module.funcs);
+
+        assert_eq!(run(&mut module), 0);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(1).if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(7);
+            },
+            |else_| {
+                else_.i32_const(9);
+            },
+        );
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        assert_eq!(run(&mut module), 0);
+        assert_eq!(entry_instrs(&module, func).len(), 2);
+    }
+}
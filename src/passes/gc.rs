@@ -60,3 +60,25 @@ fn unused<T>(used: &IdHashSet<T>, all: impl Iterator<Item = Id<T>>) -> Vec<Id<T>
     }
     unused
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    #[test]
+    fn keeps_keep_alive_functions() {
+        let mut module = Module::default();
+        let mut funcs = Vec::new();
+        for _ in 0..2 {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            builder.func_body();
+            funcs.push(builder.finish(vec![], &mut module.funcs));
+        }
+        module.funcs.get_mut(funcs[0]).flags.keep_alive = true;
+
+        run(&mut module);
+        assert!(module.funcs.contains(funcs[0]));
+        assert!(!module.funcs.contains(funcs[1]));
+    }
+}
@@ -0,0 +1,291 @@
+//! Statistics about which instructions and globals a module uses, and how
+//! often.
+//!
+//! Knowing which instructions and operators dominate real-world modules helps
+//! decide which ones are worth a fast path in an interpreter or compiler.
+
+use crate::ir::*;
+use crate::{GlobalId, LocalFunction, Module};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// How often each kind of instruction appears in a module's local functions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstrFrequency {
+    /// The number of instructions of each kind. Kinds that don't appear in
+    /// the module are left out.
+    pub counts: HashMap<InstrKind, usize>,
+    /// The total number of instructions.
+    pub total: usize,
+    /// The number of local functions.
+    pub functions: usize,
+    /// The largest number of instructions in a single local function.
+    pub max_per_function: usize,
+}
+
+impl InstrFrequency {
+    /// The average number of instructions per local function, or zero if
+    /// there are no local functions.
+    pub fn average_per_function(&self) -> f64 {
+        if self.functions == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.functions as f64
+        }
+    }
+
+    /// The kinds of instructions that appear in the module with their counts,
+    /// most frequent first.
+    pub fn sorted(&self) -> Vec<(InstrKind, usize)> {
+        sorted(&self.counts)
+    }
+}
+
+impl fmt::Display for InstrFrequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (kind, count) in self.sorted() {
+            let percent = 100.0 * count as f64 / self.total as f64;
+            writeln!(
+                f,
+                "{:<20} {:>10} {:>6.2}%",
+                format!("{:?}", kind),
+                count,
+                percent
+            )?;
+        }
+        writeln!(f, "{:<20} {:>10}", "total", self.total)?;
+        writeln!(
+            f,
+            "{:<20} {:>10.2}",
+            "average per function",
+            self.average_per_function()
+        )?;
+        write!(
+            f,
+            "{:<20} {:>10}",
+            "max per function", self.max_per_function
+        )
+    }
+}
+
+/// Count the instructions of each kind in the module's local functions.
+///
+/// With the `parallel` feature, functions are counted in parallel.
+pub fn instr_frequency(module: &Module) -> InstrFrequency {
+    let mut frequency = InstrFrequency::default();
+    for counts in per_function(module, |func| {
+        count_instrs(func, |instr| Some(instr.kind()))
+    }) {
+        let in_func = counts.values().sum();
+        merge(&mut frequency.counts, counts);
+        frequency.total += in_func;
+        frequency.functions += 1;
+        frequency.max_per_function = frequency.max_per_function.max(in_func);
+    }
+    frequency
+}
+
+/// Count how often each binary operator is used in the module's local
+/// functions.
+pub fn binop_frequency(module: &Module) -> HashMap<BinaryOp, usize> {
+    let mut counts = HashMap::new();
+    let per_func = per_function(module, |func| {
+        count_instrs(func, |instr| match instr {
+            Instr::Binop(Binop { op }) => Some(*op),
+            _ => None,
+        })
+    });
+    for in_func in per_func {
+        merge(&mut counts, in_func);
+    }
+    counts
+}
+
+/// Count how often each unary operator is used in the module's local
+/// functions.
+pub fn unop_frequency(module: &Module) -> HashMap<UnaryOp, usize> {
+    let mut counts = HashMap::new();
+    let per_func = per_function(module, |func| {
+        count_instrs(func, |instr| match instr {
+            Instr::Unop(Unop { op }) => Some(*op),
+            _ => None,
+        })
+    });
+    for in_func in per_func {
+        merge(&mut counts, in_func);
+    }
+    counts
+}
+
+/// Count how many times each global is read with `global.get` and written
+/// with `global.set` in the module's local functions, as `(reads, writes)`.
+///
+/// Every global is included, even if it isn't used. Uses outside of function
+/// bodies, such as in the initializers of other globals, aren't counted.
+pub fn global_usage(module: &Module) -> HashMap<GlobalId, (usize, usize)> {
+    let mut usage = module
+        .globals
+        .iter()
+        .map(|global| (global.id(), (0, 0)))
+        .collect::<HashMap<_, _>>();
+    let per_func = per_function(module, |func| {
+        count_instrs(func, |instr| match instr {
+            Instr::GlobalGet(GlobalGet { global }) => Some((*global, false)),
+            Instr::GlobalSet(GlobalSet { global }) => Some((*global, true)),
+            _ => None,
+        })
+    });
+    for in_func in per_func {
+        for ((global, write), n) in in_func {
+            let entry = usage.entry(global).or_insert((0, 0));
+            if write {
+                entry.1 += n;
+            } else {
+                entry.0 += n;
+            }
+        }
+    }
+    usage
+}
+
+/// Run `f` on each of the module's local functions, in parallel with the
+/// `parallel` feature, and collect the results.
+fn per_function<T: Send>(module: &Module, f: impl Fn(&LocalFunction) -> T + Send + Sync) -> Vec<T> {
+    let funcs = &module.funcs;
+    maybe_parallel!(funcs.(iter_local | par_iter_local))
+        .map(|(_, func)| f(func))
+        .collect()
+}
+
+/// Count the instructions of the function that `key` maps to the same key,
+/// leaving out those it maps to `None`.
+fn count_instrs<K: Eq + Hash>(
+    func: &LocalFunction,
+    key: impl Fn(&Instr) -> Option<K>,
+) -> HashMap<K, usize> {
+    let mut counts = HashMap::new();
+    for_each_instr(func, |instr| {
+        if let Some(k) = key(instr) {
+            *counts.entry(k).or_insert(0) += 1;
+        }
+    });
+    counts
+}
+
+/// Add the counts in `from` to those in `into`.
+fn merge<K: Eq + Hash>(into: &mut HashMap<K, usize>, from: HashMap<K, usize>) {
+    for (k, n) in from {
+        *into.entry(k).or_insert(0) += n;
+    }
+}
+
+/// The entries of `counts`, most frequent first, and in key order among
+/// entries with the same count.
+fn sorted<K: Copy + Ord + Hash>(counts: &HashMap<K, usize>) -> Vec<(K, usize)> {
+    let mut entries = counts.iter().map(|(k, n)| (*k, *n)).collect::<Vec<_>>();
+    entries.sort_by(|(a, m), (b, n)| n.cmp(m).then(a.cmp(b)));
+    entries
+}
+
+/// Call `f` on every instruction of the function.
+fn for_each_instr(func: &LocalFunction, f: impl FnMut(&Instr)) {
+    dfs_in_order(&mut EachInstr { f }, func, func.entry_block());
+
+    struct EachInstr<F> {
+        f: F,
+    }
+
+    impl<'instr, F: FnMut(&Instr)> Visitor<'instr> for EachInstr<F> {
+        fn visit_instr(&mut self, instr: &'instr Instr, _: &'instr InstrLocId) {
+            (self.f)(instr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn counts_instructions_and_operators() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .i32_const(2)
+            .binop(BinaryOp::I32Add)
+            .block(ValType::I32, |block| {
+                block
+                    .i32_const(3)
+                    .unop(UnaryOp::I32Eqz)
+                    .i32_const(4)
+                    .binop(BinaryOp::I32Add);
+            })
+            .binop(BinaryOp::I32Mul);
+        builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().nop();
+        builder.finish(vec![], &mut module.funcs);
+
+        let frequency = instr_frequency(&module);
+        assert_eq!(frequency.total, 10);
+        assert_eq!(frequency.functions, 2);
+        assert_eq!(frequency.max_per_function, 9);
+        assert_eq!(frequency.average_per_function(), 5.0);
+        assert_eq!(
+            frequency.sorted(),
+            [
+                (InstrKind::Const, 4),
+                (InstrKind::Binop, 3),
+                (InstrKind::Block, 1),
+                (InstrKind::Unop, 1),
+                (InstrKind::Nop, 1),
+            ]
+        );
+        let table = frequency.to_string();
+        assert!(table.starts_with("Const"));
+        assert!(table.contains("max per function"));
+
+        let binops = binop_frequency(&module);
+        assert_eq!(binops.len(), 2);
+        assert_eq!(binops[&BinaryOp::I32Add], 2);
+        assert_eq!(binops[&BinaryOp::I32Mul], 1);
+        let unops = unop_frequency(&module);
+        assert_eq!(unops.len(), 1);
+        assert_eq!(unops[&UnaryOp::I32Eqz], 1);
+    }
+
+    #[test]
+    fn counts_global_reads_and_writes() {
+        let mut module = Module::default();
+        let counter =
+            module
+                .globals
+                .add_local(ValType::I32, true, crate::InitExpr::Value(Value::I32(0)));
+        let unused =
+            module
+                .globals
+                .add_local(ValType::I64, false, crate::InitExpr::Value(Value::I64(0)));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .global_get(counter)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .global_set(counter)
+            .global_get(counter);
+        builder.finish(vec![], &mut module.funcs);
+
+        let usage = global_usage(&module);
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[&counter], (2, 1));
+        assert_eq!(usage[&unused], (0, 0));
+    }
+}
@@ -0,0 +1,412 @@
+//! Clean up redundant writes to locals.
+//!
+//! Code that has been through other passes often stores a value in a local
+//! only to load it right back, or stores values that are never read. This pass
+//! cleans up such patterns where they appear next to each other in an
+//! instruction sequence, without needing any analysis of control flow:
+//!
+//! * `local.set $x; local.get $x` becomes `local.tee $x`, or disappears
+//!   entirely if that `local.get` is the only read of `$x`,
+//! * `local.tee $x; drop` becomes `local.set $x`,
+//! * writes to a local that is never read are removed, leaving a `drop` in
+//!   place of a `local.set`, and
+//! * a pure value stored to a local that is overwritten by the next
+//!   instruction pair, before it could be read, is removed along with its
+//!   store.
+//!
+//! Instructions in a sequence's preamble are left alone.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::{LocalFunction, Module};
+use std::cmp;
+use std::ops::AddAssign;
+
+/// How many of each kind of rewrite the pass made.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rewrites {
+    /// `local.set`/`local.get` pairs that were merged into a `local.tee` or
+    /// removed.
+    pub set_get_pairs: usize,
+    /// `local.tee`s whose result was dropped, that were turned into
+    /// `local.set`s.
+    pub downgraded_tees: usize,
+    /// Writes to locals that are never read, that were removed.
+    pub dead_writes: usize,
+    /// Stores of pure values that were overwritten before being read, that
+    /// were removed.
+    pub overwritten_sets: usize,
+}
+
+impl Rewrites {
+    /// The total number of rewrites.
+    pub fn total(&self) -> usize {
+        self.set_get_pairs + self.downgraded_tees + self.dead_writes + self.overwritten_sets
+    }
+}
+
+impl AddAssign for Rewrites {
+    fn add_assign(&mut self, other: Rewrites) {
+        self.set_get_pairs += other.set_get_pairs;
+        self.downgraded_tees += other.downgraded_tees;
+        self.dead_writes += other.dead_writes;
+        self.overwritten_sets += other.overwritten_sets;
+    }
+}
+
+/// Clean up redundant writes to locals in every local function in the
+/// module.
+pub fn run(module: &mut Module) -> Rewrites {
+    let mut rewrites = Rewrites::default();
+    for (_, func) in module.funcs.iter_modifiable_mut() {
+        rewrites += run_func(func);
+    }
+    rewrites
+}
+
+/// Clean up redundant writes to locals in a single function.
+pub fn run_func(func: &mut LocalFunction) -> Rewrites {
+    let mut v = Scan::default();
+    dfs_in_order(&mut v, func, func.entry_block());
+    let mut reads = v.reads;
+
+    let mut rewrites = Rewrites::default();
+    for seq in v.seqs {
+        let preamble = func.block(seq).preamble_len();
+        let instrs = &mut func.block_mut(seq).instrs;
+        let mut i = preamble;
+        while i < instrs.len() {
+            if rewrite(instrs, i, &mut reads, &mut rewrites) {
+                // A rewrite may complete a pattern that starts a little
+                // earlier.
+                i = cmp::max(i.saturating_sub(3), preamble);
+            } else {
+                i += 1;
+            }
+        }
+    }
+    return rewrites;
+
+    #[derive(Default)]
+    struct Scan {
+        seqs: Vec<InstrSeqId>,
+        reads: IdHashMap<Local, usize>,
+    }
+
+    impl<'instr> Visitor<'instr> for Scan {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            self.seqs.push(seq.id());
+        }
+
+        fn visit_local_get(&mut self, instr: &LocalGet) {
+            *self.reads.entry(instr.local).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Apply the first rewrite that matches at `instrs[i]`, if any.
+fn rewrite(
+    instrs: &mut Vec<(Instr, InstrLocId)>,
+    i: usize,
+    reads: &mut IdHashMap<Local, usize>,
+    rewrites: &mut Rewrites,
+) -> bool {
+    let read_count =
+        |reads: &IdHashMap<Local, usize>, local: LocalId| reads.get(&local).copied().unwrap_or(0);
+    let next = |n: usize| instrs.get(i + n).map(|(instr, _)| instr);
+
+    match (&instrs[i].0, next(1)) {
+        (Instr::LocalSet(LocalSet { local }), Some(Instr::LocalGet(LocalGet { local: get })))
+            if local == get =>
+        {
+            let local = *local;
+            *reads.get_mut(&local).unwrap() -= 1;
+            if read_count(reads, local) == 0 {
+                instrs.drain(i..i + 2);
+            } else {
+                instrs[i].0 = LocalTee { local }.into();
+                instrs.remove(i + 1);
+            }
+            rewrites.set_get_pairs += 1;
+            true
+        }
+        (Instr::LocalTee(LocalTee { local }), Some(Instr::Drop(_))) => {
+            instrs[i].0 = LocalSet { local: *local }.into();
+            instrs.remove(i + 1);
+            rewrites.downgraded_tees += 1;
+            true
+        }
+        (Instr::LocalTee(LocalTee { local }), _) if read_count(reads, *local) == 0 => {
+            instrs.remove(i);
+            rewrites.dead_writes += 1;
+            true
+        }
+        (Instr::LocalSet(LocalSet { local }), _) if read_count(reads, *local) == 0 => {
+            instrs[i].0 = Drop {}.into();
+            rewrites.dead_writes += 1;
+            true
+        }
+        (first, Some(Instr::LocalSet(LocalSet { local })))
+            if is_pure_value(first) && overwritten(instrs, i + 2, *local) =>
+        {
+            if let Instr::LocalGet(LocalGet { local }) = first {
+                *reads.get_mut(local).unwrap() -= 1;
+            }
+            instrs.drain(i..i + 2);
+            rewrites.overwritten_sets += 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Does the instruction push a single value without popping any, reading
+/// any state that could change, or having any side effects?
+fn is_pure_value(instr: &Instr) -> bool {
+    match instr {
+        Instr::Const(_)
+        | Instr::LocalGet(_)
+        | Instr::GlobalGet(_)
+        | Instr::RefNull(_)
+        | Instr::RefFunc(_) => true,
+        _ => false,
+    }
+}
+
+/// Are `instrs[i]` and `instrs[i + 1]` a pure value that doesn't read `local`
+/// followed by a `local.set` of `local`?
+fn overwritten(instrs: &[(Instr, InstrLocId)], i: usize, local: LocalId) -> bool {
+    match (instrs.get(i), instrs.get(i + 1)) {
+        (Some((value, _)), Some((Instr::LocalSet(set), _))) => {
+            is_pure_value(value)
+                && set.local == local
+                && !matches!(value, Instr::LocalGet(get) if get.local == local)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::wizen;
+    use crate::{FunctionBuilder, FunctionId, GlobalKind, InitExpr, ValType};
+
+    fn entry_instrs(module: &Module, func: FunctionId) -> Vec<Instr> {
+        let func = module.funcs.get(func).kind.unwrap_local();
+        func.block(func.entry_block())
+            .iter()
+            .map(|(instr, _)| instr.clone())
+            .collect()
+    }
+
+    #[test]
+    fn merges_set_get_pairs() {
+        let mut module = Module::default();
+        let once = module.locals.add(ValType::I32);
+        let twice = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .local_set(once)
+            .local_get(once)
+            .i32_const(2)
+            .local_set(twice)
+            .local_get(twice)
+            .local_get(twice)
+            .binop(BinaryOp::I32Add)
+            .binop(BinaryOp::I32Add);
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        let rewrites = run(&mut module);
+        assert_eq!(rewrites.set_get_pairs, 2);
+        assert_eq!(rewrites.total(), 2);
+        let expected: Vec<Instr> = vec![
+            Const {
+                value: Value::I32(1),
+            }
+            .into(),
+            Const {
+                value: Value::I32(2),
+            }
+            .into(),
+            LocalTee { local: twice }.into(),
+            LocalGet { local: twice }.into(),
+            Binop {
+                op: BinaryOp::I32Add,
+            }
+            .into(),
+            Binop {
+                op: BinaryOp::I32Add,
+            }
+            .into(),
+        ];
+        assert_eq!(entry_instrs(&module, func), expected);
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn removes_dead_and_overwritten_writes() {
+        let mut module = Module::default();
+        let dead = module.locals.add(ValType::I32);
+        let live = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .local_tee(dead)
+            .drop()
+            .i32_const(2)
+            .local_set(live)
+            .i32_const(3)
+            .local_set(live)
+            .local_get(live)
+            .local_get(live)
+            .binop(BinaryOp::I32Add);
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        let rewrites = run(&mut module);
+        assert_eq!(
+            rewrites,
+            Rewrites {
+                set_get_pairs: 1,
+                downgraded_tees: 1,
+                dead_writes: 1,
+                overwritten_sets: 1,
+            }
+        );
+        let expected: Vec<Instr> = vec![
+            Const {
+                value: Value::I32(1),
+            }
+            .into(),
+            Drop {}.into(),
+            Const {
+                value: Value::I32(3),
+            }
+            .into(),
+            LocalTee { local: live }.into(),
+            LocalGet { local: live }.into(),
+            Binop {
+                op: BinaryOp::I32Add,
+            }
+            .into(),
+        ];
+        assert_eq!(entry_instrs(&module, func), expected);
+        Module::from_buffer(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn leaves_preambles_alone() {
+        let mut module = Module::default();
+        let hook = module.locals.add(ValType::I32);
+        let dead = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(2).local_set(dead);
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        // A write that is never read, as instrumentation inserted it.
+        let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        let entry = local.entry_block();
+        local.block_mut(entry).push_preamble(Const {
+            value: Value::I32(1),
+        });
+        local
+            .block_mut(entry)
+            .push_preamble(LocalSet { local: hook });
+
+        let rewrites = run(&mut module);
+        assert_eq!(rewrites.dead_writes, 1);
+        assert_eq!(rewrites.total(), 1);
+        let expected: Vec<Instr> = vec![
+            Const {
+                value: Value::I32(1),
+            }
+            .into(),
+            LocalSet { local: hook }.into(),
+            Const {
+                value: Value::I32(2),
+            }
+            .into(),
+            Drop {}.into(),
+        ];
+        assert_eq!(entry_instrs(&module, func), expected);
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(local.block(entry).preamble_len(), 2);
+    }
+
+    /// Build a start function that computes a value through locals and stores
+    /// it in a global.
+    fn build(module: &mut Module) {
+        let a = module.locals.add(ValType::I32);
+        let b = module.locals.add(ValType::I32);
+        let c = module.locals.add(ValType::I32);
+        let init = InitExpr::Value(Value::I32(0));
+        let result = module.globals.add_local(ValType::I32, true, init);
+        module.exports.add("result", result);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(5)
+            .local_set(a)
+            .local_get(a)
+            .local_tee(b)
+            .drop()
+            .i32_const(7)
+            .local_set(c)
+            .i32_const(11)
+            .local_set(c)
+            .local_get(b)
+            .local_get(c)
+            .binop(BinaryOp::I32Mul)
+            .local_tee(a)
+            .local_get(a)
+            .binop(BinaryOp::I32Add)
+            .local_tee(c)
+            .global_set(result);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+    }
+
+    fn result(module: &Module) -> Option<i32> {
+        module.globals.iter().find_map(|global| match global.kind {
+            GlobalKind::Local(InitExpr::Value(Value::I32(value))) => Some(value),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn preserves_behavior() {
+        let mut expected = Module::default();
+        build(&mut expected);
+        wizen::run(&mut expected, None).unwrap();
+
+        let mut cleaned = Module::default();
+        build(&mut cleaned);
+        assert!(run(&mut cleaned).total() > 0);
+        Module::from_buffer(&cleaned.emit_wasm()).unwrap();
+        wizen::run(&mut cleaned, None).unwrap();
+
+        assert_eq!(result(&expected), Some(110));
+        assert_eq!(result(&cleaned), result(&expected));
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let local = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .local_set(local)
+            .local_get(local);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        assert_eq!(run(&mut module).total(), 0);
+        assert_eq!(entry_instrs(&module, func).len(), 3);
+    }
+}
@@ -0,0 +1,347 @@
+//! Running a pipeline of passes for a target engine.
+//!
+//! Some passes introduce instructions from post-MVP proposals, and running
+//! them on a module meant for an engine without those proposals produces a
+//! module the engine rejects. Each `Pass` declares which features it needs the
+//! input module to be allowed to use, and which ones its output may use. The
+//! `PassManager` checks them against the target's features, and skips or
+//! refuses passes that the target doesn't support.
+
+use crate::error::Result;
+use crate::{EmitError, Module};
+use anyhow::bail;
+use std::fmt;
+use wasmparser::WasmFeatures;
+
+/// A transformation of a whole module, with the features it depends on.
+pub trait Pass {
+    /// The pass's name, for log messages.
+    fn name(&self) -> &str;
+
+    /// The features the target must support for this pass to run. By default,
+    /// none.
+    fn requires(&self) -> WasmFeatures {
+        mvp()
+    }
+
+    /// The features that the code this pass generates may use. By default,
+    /// none.
+    fn produces(&self) -> WasmFeatures {
+        mvp()
+    }
+
+    /// Run the pass.
+    fn run(&mut self, module: &mut Module) -> Result<()>;
+}
+
+/// A `Pass` that runs a closure.
+pub struct FnPass<F> {
+    name: String,
+    requires: WasmFeatures,
+    produces: WasmFeatures,
+    f: F,
+}
+
+impl<F> FnPass<F>
+where
+    F: FnMut(&mut Module) -> Result<()>,
+{
+    /// Create a new pass with the given name, which runs `f`, and which
+    /// neither requires nor produces any features.
+    pub fn new(name: impl Into<String>, f: F) -> FnPass<F> {
+        FnPass {
+            name: name.into(),
+            requires: mvp(),
+            produces: mvp(),
+            f,
+        }
+    }
+
+    /// Set the features this pass requires.
+    pub fn requires(mut self, features: WasmFeatures) -> FnPass<F> {
+        self.requires = features;
+        self
+    }
+
+    /// Set the features this pass produces.
+    pub fn produces(mut self, features: WasmFeatures) -> FnPass<F> {
+        self.produces = features;
+        self
+    }
+}
+
+impl<F> Pass for FnPass<F>
+where
+    F: FnMut(&mut Module) -> Result<()>,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn requires(&self) -> WasmFeatures {
+        self.requires
+    }
+
+    fn produces(&self) -> WasmFeatures {
+        self.produces
+    }
+
+    fn run(&mut self, module: &mut Module) -> Result<()> {
+        (self.f)(module)
+    }
+}
+
+/// What to do with a pass that needs features the target doesn't support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnUnsupported {
+    /// Skip the pass, and carry on with the rest of the pipeline.
+    Skip,
+    /// Stop the pipeline with an error.
+    Error,
+}
+
+/// Something that happened while running a pipeline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PassEvent {
+    /// The pass ran.
+    Ran {
+        /// The pass's name.
+        pass: String,
+    },
+    /// The pass was skipped, because the target doesn't support some of the
+    /// features it requires or produces.
+    Skipped {
+        /// The pass's name.
+        pass: String,
+        /// The features the target is missing.
+        missing: Vec<&'static str>,
+    },
+}
+
+impl fmt::Display for PassEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassEvent::Ran { pass } => write!(f, "ran pass `{}`", pass),
+            PassEvent::Skipped { pass, missing } => write!(
+                f,
+                "skipped pass `{}`: the target doesn't support {}",
+                pass,
+                missing.join(", ")
+            ),
+        }
+    }
+}
+
+/// A pipeline of passes, run for a target with a given set of features.
+pub struct PassManager {
+    target: WasmFeatures,
+    on_unsupported: OnUnsupported,
+    passes: Vec<Box<dyn Pass>>,
+    events: Vec<PassEvent>,
+}
+
+impl PassManager {
+    /// Create an empty pipeline for a target supporting the given features.
+    /// Unsupported passes are skipped by default.
+    pub fn new(target: WasmFeatures) -> PassManager {
+        PassManager {
+            target,
+            on_unsupported: OnUnsupported::Skip,
+            passes: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Set what to do with passes that need features the target doesn't
+    /// support.
+    pub fn on_unsupported(&mut self, on_unsupported: OnUnsupported) -> &mut PassManager {
+        self.on_unsupported = on_unsupported;
+        self
+    }
+
+    /// Append a pass to the pipeline.
+    pub fn add(&mut self, pass: impl Pass + 'static) -> &mut PassManager {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// The features of the target.
+    pub fn target(&self) -> WasmFeatures {
+        self.target
+    }
+
+    /// Run every pass in the pipeline, in order.
+    ///
+    /// A pass that requires or produces a feature the target doesn't support
+    /// is skipped, or stops the pipeline with an error, depending on
+    /// `on_unsupported`. Either way, nothing is run after an error.
+    pub fn run(&mut self, module: &mut Module) -> Result<()> {
+        for pass in self.passes.iter_mut() {
+            let mut unsupported = missing(pass.requires(), self.target);
+            for feature in missing(pass.produces(), self.target) {
+                if !unsupported.contains(&feature) {
+                    unsupported.push(feature);
+                }
+            }
+            if !unsupported.is_empty() {
+                if self.on_unsupported == OnUnsupported::Error {
+                    bail!(
+                        "cannot run pass `{}`: the target doesn't support {}",
+                        pass.name(),
+                        unsupported.join(", ")
+                    );
+                }
+                let event = PassEvent::Skipped {
+                    pass: pass.name().to_string(),
+                    missing: unsupported,
+                };
+                log::info!("{}", event);
+                self.events.push(event);
+                continue;
+            }
+
+            log::debug!("running pass `{}`", pass.name());
+            pass.run(module)?;
+            self.events.push(PassEvent::Ran {
+                pass: pass.name().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Everything that happened in the runs of this pipeline so far.
+    pub fn events(&self) -> &[PassEvent] {
+        &self.events
+    }
+
+    /// Emit the module, checking that the result is valid for the target.
+    pub fn emit_wasm(&self, module: &mut Module) -> Result<Vec<u8>, EmitError> {
+        module.emit_wasm_checked(self.target)
+    }
+}
+
+/// The features of an engine that only supports the MVP.
+pub fn mvp() -> WasmFeatures {
+    WasmFeatures {
+        reference_types: false,
+        multi_value: false,
+        bulk_memory: false,
+        module_linking: false,
+        simd: false,
+        threads: false,
+        tail_call: false,
+        deterministic_only: false,
+        multi_memory: false,
+        exceptions: false,
+        memory64: false,
+    }
+}
+
+/// The names of the features in `needed` that `target` doesn't have.
+///
+/// `deterministic_only` restricts the instructions that may be used rather
+/// than allowing more, so it is ignored.
+fn missing(needed: WasmFeatures, target: WasmFeatures) -> Vec<&'static str> {
+    let features = [
+        (
+            "reference-types",
+            needed.reference_types,
+            target.reference_types,
+        ),
+        ("multi-value", needed.multi_value, target.multi_value),
+        ("bulk-memory", needed.bulk_memory, target.bulk_memory),
+        (
+            "module-linking",
+            needed.module_linking,
+            target.module_linking,
+        ),
+        ("simd", needed.simd, target.simd),
+        ("threads", needed.threads, target.threads),
+        ("tail-call", needed.tail_call, target.tail_call),
+        ("multi-memory", needed.multi_memory, target.multi_memory),
+        ("exceptions", needed.exceptions, target.exceptions),
+        ("memory64", needed.memory64, target.memory64),
+    ];
+    features
+        .iter()
+        .filter(|(_, needed, target)| *needed && !*target)
+        .map(|(name, _, _)| *name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    /// A pass that adds a function using `memory.fill`.
+    fn fill_pass() -> impl Pass {
+        FnPass::new("fill", |module: &mut Module| {
+            let memory = module.memories.add_local(false, 1, None);
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            builder
+                .func_body()
+                .i32_const(0)
+                .i32_const(0)
+                .i32_const(16)
+                .memory_fill(memory);
+            let func = builder.finish(vec![], &mut module.funcs);
+            module.exports.add("fill", func);
+            Ok(())
+        })
+        .produces(WasmFeatures {
+            bulk_memory: true,
+            ..mvp()
+        })
+    }
+
+    #[test]
+    fn skips_passes_the_target_does_not_support() {
+        let mut module = Module::default();
+        let mut manager = PassManager::new(mvp());
+        manager
+            .add(fill_pass())
+            .add(FnPass::new("nothing", |_: &mut Module| Ok(())));
+        manager.run(&mut module).unwrap();
+        assert_eq!(
+            manager.events(),
+            [
+                PassEvent::Skipped {
+                    pass: "fill".to_string(),
+                    missing: vec!["bulk-memory"],
+                },
+                PassEvent::Ran {
+                    pass: "nothing".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            manager.events()[0].to_string(),
+            "skipped pass `fill`: the target doesn't support bulk-memory"
+        );
+        assert!(module.exports.iter().next().is_none());
+        manager.emit_wasm(&mut module).unwrap();
+
+        // With bulk memory, the pass runs, and the output validates.
+        let mut manager = PassManager::new(WasmFeatures::default());
+        manager.add(fill_pass());
+        manager.run(&mut module).unwrap();
+        assert!(module.exports.get_func_by_name("fill").is_some());
+        manager.emit_wasm(&mut module).unwrap();
+
+        // The output doesn't validate for an MVP target.
+        assert!(module.emit_wasm_checked(mvp()).is_err());
+    }
+
+    #[test]
+    fn errors_on_unsupported_passes() {
+        let mut module = Module::default();
+        let mut manager = PassManager::new(mvp());
+        manager
+            .on_unsupported(OnUnsupported::Error)
+            .add(fill_pass())
+            .add(FnPass::new("nothing", |_: &mut Module| Ok(())));
+        assert!(manager.run(&mut module).is_err());
+        assert!(manager.events().is_empty());
+    }
+}
@@ -1,5 +1,33 @@
 //! Passes over whole modules or individual functions.
 
+pub mod alignment_lint;
+pub mod anonymize;
+pub mod br_if_to_if;
+pub mod canonicalize_nans;
+pub mod cold_code;
+pub mod coverage;
+pub mod data_layout;
+pub mod demote_globals;
+pub mod devirtualize;
+pub mod dropped_results;
+pub mod export_all;
+pub mod fold_address_offsets;
+pub mod fold_const_if;
 pub mod gc;
+pub mod instr_stats;
+pub mod local_cleanup;
+pub mod manager;
+pub mod narrow_block_results;
+pub mod nop_padding;
+pub mod recognize_memcpy;
+pub mod recognize_memset;
+pub mod remove_nop;
+pub mod remove_unreachable;
+pub mod shadow_stack;
+pub mod specialize_mem_base;
+pub mod stack_reduce;
+pub mod thread_local;
 mod used;
+pub mod v128_const_policy;
+pub mod wizen;
 pub use self::used::Roots;
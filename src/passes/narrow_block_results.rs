@@ -0,0 +1,235 @@
+//! Narrow the results of blocks whose values are never used.
+//!
+//! After dead code elimination it is common to end up with a `block` that
+//! produces a value which is immediately thrown away by a `drop`. This pass
+//! rewrites such blocks to produce no result at all, dropping the value at
+//! each of the block's exits (its fallthrough and every `br` targeting it)
+//! instead of after the block.
+//!
+//! Only single-result blocks are narrowed, and only when every branch that
+//! targets the block is an unconditional `br`. Blocks targeted by `br_if` or
+//! `br_table` are left alone, since the other path of a `br_if` and the other
+//! targets of a `br_table` constrain the block's result type.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::{LocalFunction, Module};
+use std::cmp;
+
+/// Narrow dropped block results in every local function in the module.
+///
+/// Returns the number of blocks whose result was removed.
+pub fn run(module: &mut Module) -> usize {
+    module
+        .funcs
+        .iter_modifiable_mut()
+        .map(|(_, func)| run_func(func))
+        .sum()
+}
+
+/// Narrow dropped block results in a single function.
+///
+/// Narrowing a block may expose a nested block whose value is now dropped
+/// too, so this runs to a fixed point. Returns the number of blocks whose
+/// result was removed.
+pub fn run_func(func: &mut LocalFunction) -> usize {
+    let mut total = 0;
+    loop {
+        let narrowed = narrow_once(func);
+        if narrowed == 0 {
+            return total;
+        }
+        total += narrowed;
+    }
+}
+
+/// The branches that target a particular block.
+#[derive(Default)]
+struct Targets {
+    /// The locations of every `br` that targets the block.
+    brs: Vec<(InstrSeqId, usize)>,
+    /// Is the block targeted by any `br_if` or `br_table`?
+    conditional: bool,
+}
+
+fn narrow_once(func: &mut LocalFunction) -> usize {
+    let mut targets: IdHashMap<InstrSeq, Targets> = Default::default();
+    let mut candidates = Vec::new();
+
+    for seq_id in func.instr_seqs() {
+        let instrs = &func.block(seq_id).instrs;
+        for (i, (instr, _)) in instrs.iter().enumerate() {
+            match instr {
+                Instr::Br(Br { block }) => {
+                    targets.entry(*block).or_default().brs.push((seq_id, i));
+                }
+                Instr::BrIf(BrIf { block }) => {
+                    targets.entry(*block).or_default().conditional = true;
+                }
+                Instr::BrTable(BrTable { blocks, default }) => {
+                    for block in blocks.iter().chain(Some(default)) {
+                        targets.entry(*block).or_default().conditional = true;
+                    }
+                }
+                Instr::Block(Block { seq }) => {
+                    let dropped = match instrs.get(i + 1) {
+                        Some((Instr::Drop(_), _)) => true,
+                        _ => false,
+                    };
+                    let single_result = match func.block(*seq).ty {
+                        InstrSeqType::Simple(Some(_)) => true,
+                        _ => false,
+                    };
+                    if dropped && single_result {
+                        candidates.push((seq_id, i + 1, *seq));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // For each instruction sequence, the positions at which to either insert
+    // a new `drop` (`true`) or remove an existing one (`false`).
+    let mut edits: IdHashMap<InstrSeq, Vec<(usize, bool)>> = Default::default();
+    let mut narrowed = 0;
+
+    for (parent, drop_position, seq) in candidates {
+        let targets = targets.remove(&seq).unwrap_or_default();
+        if targets.conditional {
+            continue;
+        }
+        // Removing the `drop` or adding new ones can't touch a preamble.
+        let in_preamble =
+            |(site, position): (InstrSeqId, usize)| position < func.block(site).preamble_len();
+        if in_preamble((parent, drop_position)) || targets.brs.iter().cloned().any(in_preamble) {
+            continue;
+        }
+        narrowed += 1;
+
+        let block = func.block_mut(seq);
+        block.ty = InstrSeqType::Simple(None);
+        let fallthrough = block.len();
+
+        edits
+            .entry(parent)
+            .or_default()
+            .push((drop_position, false));
+        edits.entry(seq).or_default().push((fallthrough, true));
+        for (site, position) in targets.brs {
+            edits.entry(site).or_default().push((position, true));
+        }
+    }
+
+    for (seq, mut edits) in edits {
+        // Apply edits back to front so that earlier positions stay valid.
+        edits.sort_by_key(|&(position, _)| cmp::Reverse(position));
+        let instrs = &mut func.block_mut(seq).instrs;
+        for (position, insert) in edits {
+            if insert {
+                instrs.insert(position, (Drop {}.into(), Default::default()));
+            } else {
+                instrs.remove(position);
+            }
+        }
+    }
+
+    narrowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, FunctionId, ValType};
+
+    fn narrow(module: &mut Module, func: FunctionId) -> usize {
+        let narrowed = run_func(module.funcs.get_mut(func).kind.unwrap_local_mut());
+        // The rewritten module must still be valid wasm.
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+        narrowed
+    }
+
+    #[test]
+    fn narrows_dropped_block_with_br() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut outer = None;
+        builder
+            .func_body()
+            .block(ValType::I32, |block| {
+                let id = block.id();
+                outer = Some(id);
+                block
+                    .block(ValType::I32, |inner| {
+                        inner.i32_const(1).br(id);
+                    })
+                    .i32_const(2)
+                    .binop(BinaryOp::I32Add);
+            })
+            .drop();
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(narrow(&mut module, func), 1);
+
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(local.block(outer.unwrap()).ty, InstrSeqType::Simple(None));
+        assert_eq!(local.block(local.entry_block()).len(), 1);
+    }
+
+    #[test]
+    fn keeps_block_targeted_by_br_if() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .block(ValType::I32, |block| {
+                let id = block.id();
+                block.i32_const(1).i32_const(0).br_if(id);
+            })
+            .drop();
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(narrow(&mut module, func), 0);
+    }
+
+    #[test]
+    fn narrows_nested_blocks_to_fixed_point() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .block(ValType::I32, |outer| {
+                outer.block(ValType::I32, |inner| {
+                    inner.i32_const(1);
+                });
+            })
+            .drop();
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(narrow(&mut module, func), 2);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut block = None;
+        builder
+            .func_body()
+            .block(ValType::I32, |b| {
+                block = Some(b.id());
+                b.i32_const(1);
+            })
+            .drop();
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        assert_eq!(run(&mut module), 0);
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(
+            local.block(block.unwrap()).ty,
+            InstrSeqType::Simple(Some(ValType::I32))
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Pad function entries with `nop`s so that their code starts at aligned
+//! offsets.
+//!
+//! Some ahead-of-time compilers lay out their native code to mirror the wasm
+//! code, and produce better code when each function's first instruction sits
+//! at an aligned offset in the binary. This pass inserts `nop`s at the start
+//! of every function's body until that holds.
+
+use crate::ir::*;
+use crate::{FunctionId, Module};
+use std::mem;
+use wasmparser::{Operator, Parser, Payload};
+
+/// How many times to re-emit the module while waiting for padding to settle.
+/// Each round only grows bodies, so this is only reached in pathological cases.
+const MAX_ROUNDS: usize = 8;
+
+/// Insert `nop`s at the start of every local function, so that in the binary
+/// produced by `Module::emit_wasm` the offset of each function's first
+/// instruction after the padding is a multiple of `alignment`.
+///
+/// Functions flagged `no_modify` are not padded. Returns the functions whose
+/// entries are still misaligned, which are those flagged `no_modify` unless
+/// padding failed to settle.
+///
+/// Panics if `alignment` is zero.
+pub fn pad_function_entries(module: &mut Module, alignment: usize) -> Vec<FunctionId> {
+    assert!(alignment > 0, "alignment must be non-zero");
+
+    for _ in 0..MAX_ROUNDS {
+        // Padding a function moves every function after it, which we account
+        // for as we go. It can also grow the LEB128s encoding the body and
+        // section sizes, and change the order functions are emitted in, so go
+        // around again until everything has settled.
+        let mut shift = 0;
+        for (func, offset) in entry_offsets(module) {
+            let padding = (alignment - (offset + shift) % alignment) % alignment;
+            if padding == 0 || module.funcs.get(func).flags.no_modify {
+                continue;
+            }
+            shift += padding;
+
+            let func = module.funcs.get_mut(func).kind.unwrap_local_mut();
+            let entry = func.entry_block();
+            let nops = (0..padding).map(|_| (Nop {}.into(), Default::default()));
+            let block = func.block_mut(entry);
+            let preamble = block.preamble_len();
+            block.instrs.splice(0..0, nops);
+            if preamble > 0 {
+                // The preamble has to stay at the start of the sequence.
+                block.set_preamble_len(preamble + padding);
+            }
+        }
+        if shift == 0 {
+            break;
+        }
+    }
+
+    entry_offsets(module)
+        .into_iter()
+        .filter(|(_, offset)| offset % alignment != 0)
+        .map(|(func, _)| func)
+        .collect()
+}
+
+/// The offset of each local function's first instruction other than a `nop`
+/// in the module's emitted binary, in the order that they appear there.
+///
+/// Skipping leading `nop`s means that padding a function moves its offset.
+fn entry_offsets(module: &mut Module) -> Vec<(FunctionId, usize)> {
+    // Emitting consumes the custom sections, but they all come after the code
+    // section, so leaving them out doesn't move anything.
+    let customs = mem::take(&mut module.customs);
+    let emitted = module.emit_wasm_with_code_transform();
+    module.customs = customs;
+    let (wasm, code_transform) = match emitted {
+        Ok(emitted) => emitted,
+        Err(e) => panic!("{:?}", e),
+    };
+
+    let mut funcs = code_transform.function_ranges;
+    funcs.sort_by_key(|(_, range)| range.start);
+    let bodies = Parser::new(0)
+        .parse_all(&wasm)
+        .filter_map(|payload| match payload {
+            Ok(Payload::CodeSectionEntry(body)) => Some(body),
+            _ => None,
+        });
+    funcs
+        .into_iter()
+        .zip(bodies)
+        .map(|((id, _), body)| {
+            let mut operators = body
+                .get_operators_reader()
+                .expect("emitted function bodies are well-formed");
+            loop {
+                let (op, offset) = operators
+                    .read_with_offset()
+                    .expect("emitted function bodies are well-formed");
+                // Every body ends with an `end`, so this terminates.
+                if !matches!(op, Operator::Nop) {
+                    break (id, offset);
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn aligns_function_entries() {
+        let mut module = Module::default();
+        let local = module.locals.add(ValType::I64);
+        for n in 0..3 {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            let mut body = builder.func_body();
+            for _ in 0..n {
+                body.i64_const(-1).local_set(local);
+            }
+            builder.finish(vec![], &mut module.funcs);
+        }
+
+        assert!(pad_function_entries(&mut module, 16).is_empty());
+
+        let offsets = entry_offsets(&mut module);
+        assert_eq!(offsets.len(), 3);
+        for (_, offset) in offsets {
+            assert_eq!(offset % 16, 0);
+        }
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn padding_moves_the_entry() {
+        let mut module = Module::default();
+        for n in 0..5 {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+            let mut body = builder.func_body();
+            body.i32_const(n);
+            for _ in 0..n {
+                body.i32_const(n).binop(BinaryOp::I32Add);
+            }
+            builder.finish(vec![], &mut module.funcs);
+        }
+        let before = entry_offsets(&mut module);
+        assert!(before.iter().any(|(_, offset)| offset % 128 != 0));
+
+        assert!(pad_function_entries(&mut module, 128).is_empty());
+
+        let mut nops = 0;
+        module.walk_instrs(|_, _, _, instr| nops += instr.is_nop() as usize);
+        assert!(nops > 0);
+        for (_, offset) in entry_offsets(&mut module) {
+            assert_eq!(offset % 128, 0);
+        }
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn reports_no_modify_functions() {
+        let mut module = Module::default();
+        for n in 0..3 {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            let mut body = builder.func_body();
+            for _ in 0..n {
+                body.nop();
+            }
+            let func = builder.finish(vec![], &mut module.funcs);
+            module.funcs.get_mut(func).flags.no_modify = true;
+        }
+        let misaligned = entry_offsets(&mut module)
+            .into_iter()
+            .filter(|(_, offset)| offset % 128 != 0)
+            .map(|(func, _)| func)
+            .collect::<Vec<_>>();
+        assert!(!misaligned.is_empty());
+
+        assert_eq!(pad_function_entries(&mut module, 128), misaligned);
+        let mut nops = 0;
+        module.walk_instrs(|_, _, _, instr| nops += instr.is_nop() as usize);
+        assert_eq!(nops, 3);
+    }
+}
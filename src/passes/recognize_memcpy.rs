@@ -0,0 +1,660 @@
+//! Recognize loops that copy memory one byte at a time, and copy with
+//! `memory.copy` instead.
+//!
+//! Code compiled without bulk memory implements `memcpy` and `memmove` as a
+//! loop that loads and stores a byte at a time. Two shapes of loop are
+//! recognized, counting up to a length:
+//!
+//! ```wat
+//! loop $l
+//!   local.get $i
+//!   local.get $len
+//!   i32.lt_u
+//!   if
+//!     local.get $dst
+//!     local.get $i
+//!     i32.add
+//!     local.get $src
+//!     local.get $i
+//!     i32.add
+//!     i32.load8_u
+//!     i32.store8
+//!     local.get $i
+//!     i32.const 1
+//!     i32.add
+//!     local.set $i
+//!     br $l
+//!   end
+//! end
+//! ```
+//!
+//! and counting down to zero:
+//!
+//! ```wat
+//! loop $l
+//!   local.get $i
+//!   if
+//!     local.get $i
+//!     i32.const 1
+//!     i32.sub
+//!     local.set $i
+//!     local.get $dst
+//!     local.get $i
+//!     i32.add
+//!     local.get $src
+//!     local.get $i
+//!     i32.add
+//!     i32.load8_u
+//!     i32.store8
+//!     br $l
+//!   end
+//! end
+//! ```
+//!
+//! The remaining bytes are then copied with a single `memory.copy`, after
+//! which the counter is set to its final value and the loop exits.
+//!
+//! `memory.copy` copies as if through a temporary buffer, while a byte loop
+//! reads bytes it has already written when the destination overlaps the part
+//! of the source it has yet to read: after the source for a loop counting
+//! up, or before it for a loop counting down. When the source and
+//! destination are in the same memory, the `memory.copy` is therefore guarded
+//! by a check that they don't overlap that way, and the byte loop is kept to
+//! handle the case where they do. Otherwise the byte loop is replaced.
+//!
+//! A loop that goes out of bounds traps after copying the bytes before the
+//! first out of bounds one, while `memory.copy` traps without copying
+//! anything.
+
+use super::manager::{mvp, Pass};
+use crate::error::Result;
+use crate::ir::*;
+use crate::{LocalFunction, Module};
+use wasmparser::WasmFeatures;
+
+/// Copy with `memory.copy` in the byte copy loops of every local function in
+/// the module.
+///
+/// Nothing is done if the module is limited to stable features, since
+/// `memory.copy` is part of the bulk memory proposal. Returns the number of
+/// loops that were rewritten.
+pub fn recognize_and_replace_memcpy_loops(module: &mut Module) -> usize {
+    if module.config.only_stable_features {
+        return 0;
+    }
+    run_module(module)
+}
+
+fn run_module(module: &mut Module) -> usize {
+    module
+        .funcs
+        .iter_modifiable_mut()
+        .map(|(_, func)| run_func(func))
+        .sum()
+}
+
+/// Copy with `memory.copy` in byte copy loops, as a `Pass` for a `PassManager`.
+///
+/// Since it produces `memory.copy`, the manager only runs it for targets with
+/// bulk memory. The module's config isn't consulted.
+#[derive(Debug)]
+pub struct RecognizeMemcpy;
+
+impl Pass for RecognizeMemcpy {
+    fn name(&self) -> &str {
+        "recognize-memcpy"
+    }
+
+    fn requires(&self) -> WasmFeatures {
+        bulk_memory()
+    }
+
+    fn produces(&self) -> WasmFeatures {
+        bulk_memory()
+    }
+
+    fn run(&mut self, module: &mut Module) -> Result<()> {
+        let rewritten = run_module(module);
+        log::debug!("rewrote {} byte copy loops", rewritten);
+        Ok(())
+    }
+}
+
+/// The MVP features plus bulk memory.
+pub(super) fn bulk_memory() -> WasmFeatures {
+    WasmFeatures {
+        bulk_memory: true,
+        ..mvp()
+    }
+}
+
+/// Copy with `memory.copy` in the byte copy loops of a single function.
+///
+/// Returns the number of loops that were rewritten.
+pub fn run_func(func: &mut LocalFunction) -> usize {
+    let mut rewritten = 0;
+    for seq in loops(func) {
+        if let Some(copy) = match_copy_loop(func, seq) {
+            rewrite(func, seq, &copy);
+            rewritten += 1;
+        }
+    }
+    rewritten
+}
+
+/// Which way a byte loop counts.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Direction {
+    /// From the counter's value up to the value of the given local.
+    Up { len: LocalId },
+    /// From the counter's value down to zero.
+    Down,
+}
+
+/// A loop that runs its body once for each byte, with a counter going up to
+/// a length or down to zero in steps of one, in the shapes described in the
+/// module docs.
+#[derive(Debug)]
+pub(super) struct ByteLoop<'a> {
+    pub(super) direction: Direction,
+    /// The `if` arm holding the loop body.
+    pub(super) body: InstrSeqId,
+    pub(super) counter: LocalId,
+    /// The instructions of the body that handle a single byte, without the
+    /// counter update and the branch back to the loop.
+    pub(super) work: Vec<&'a Instr>,
+}
+
+/// A loop recognized as copying memory a byte at a time.
+#[derive(Debug)]
+struct CopyLoop {
+    direction: Direction,
+    body: InstrSeqId,
+    counter: LocalId,
+    dst: LocalId,
+    src: LocalId,
+    dst_memory: MemoryId,
+    src_memory: MemoryId,
+}
+
+/// Every `loop` sequence in the function.
+pub(super) fn loops(func: &LocalFunction) -> Vec<InstrSeqId> {
+    let mut loops = Vec::new();
+    let mut worklist = vec![func.entry_block()];
+    while let Some(seq) = worklist.pop() {
+        for (instr, _) in func.block(seq).instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) => worklist.push(*seq),
+                Instr::Loop(Loop { seq }) => {
+                    loops.push(*seq);
+                    worklist.push(*seq);
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    worklist.push(*consequent);
+                    worklist.push(*alternative);
+                }
+                _ => {}
+            }
+        }
+    }
+    loops
+}
+
+/// Match the given `loop` sequence against the byte loop shapes.
+pub(super) fn match_byte_loop(func: &LocalFunction, seq: InstrSeqId) -> Option<ByteLoop> {
+    let block = func.block(seq);
+    if block.ty != InstrSeqType::Simple(None) {
+        return None;
+    }
+    let instrs = block.iter().map(|(i, _)| i).collect::<Vec<_>>();
+    let (counter, len, (consequent, alternative)) = match instrs.as_slice() {
+        [i, len, lt, if_] if is_binop(lt, BinaryOp::I32LtU) => {
+            (local_get(i)?, Some(local_get(len)?), if_else(if_)?)
+        }
+        [i, if_] => (local_get(i)?, None, if_else(if_)?),
+        _ => return None,
+    };
+    if func.block(consequent).ty != InstrSeqType::Simple(None)
+        || !func.block(alternative).is_empty()
+        || len == Some(counter)
+    {
+        return None;
+    }
+
+    let body = func
+        .block(consequent)
+        .iter()
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if body.len() < 5 {
+        return None;
+    }
+    let n = body.len();
+    let (work, step, step_op) = match len {
+        Some(_) => (&body[..n - 5], &body[n - 5..n - 1], BinaryOp::I32Add),
+        None => (&body[4..n - 1], &body[..4], BinaryOp::I32Sub),
+    };
+    match step {
+        [get, one, op, set]
+            if local_get(get) == Some(counter)
+                && is_i32_const(one, 1)
+                && is_binop(op, step_op)
+                && local_set(set) == Some(counter) => {}
+        _ => return None,
+    }
+    match body[n - 1] {
+        Instr::Br(Br { block }) if *block == seq => {}
+        _ => return None,
+    }
+
+    return Some(ByteLoop {
+        direction: match len {
+            Some(len) => Direction::Up { len },
+            None => Direction::Down,
+        },
+        body: consequent,
+        counter,
+        work: work.to_vec(),
+    });
+
+    fn local_set(instr: &Instr) -> Option<LocalId> {
+        match instr {
+            Instr::LocalSet(LocalSet { local }) => Some(*local),
+            _ => None,
+        }
+    }
+
+    fn if_else(instr: &Instr) -> Option<(InstrSeqId, InstrSeqId)> {
+        match instr {
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => Some((*consequent, *alternative)),
+            _ => None,
+        }
+    }
+}
+
+/// The local read by a `local.get`.
+pub(super) fn local_get(instr: &Instr) -> Option<LocalId> {
+    match instr {
+        Instr::LocalGet(LocalGet { local }) => Some(*local),
+        _ => None,
+    }
+}
+
+/// Is this the given binary operator?
+pub(super) fn is_binop(instr: &Instr, op: BinaryOp) -> bool {
+    match instr {
+        Instr::Binop(binop) => binop.op == op,
+        _ => false,
+    }
+}
+
+/// Is this an `i32.const` of the given value?
+pub(super) fn is_i32_const(instr: &Instr, value: i32) -> bool {
+    match instr {
+        Instr::Const(Const {
+            value: Value::I32(v),
+        }) => *v == value,
+        _ => false,
+    }
+}
+
+/// Match the given `loop` sequence against the copy loop shapes.
+fn match_copy_loop(func: &LocalFunction, seq: InstrSeqId) -> Option<CopyLoop> {
+    let ByteLoop {
+        direction,
+        body,
+        counter,
+        work,
+    } = match_byte_loop(func, seq)?;
+    let (dst, src, load, store) = match work.as_slice() {
+        [dst, i, add, src, j, add2, load, store]
+            if local_get(i) == Some(counter)
+                && local_get(j) == Some(counter)
+                && is_binop(add, BinaryOp::I32Add)
+                && is_binop(add2, BinaryOp::I32Add) =>
+        {
+            (local_get(dst)?, local_get(src)?, load, store)
+        }
+        _ => return None,
+    };
+    let src_memory = match load {
+        Instr::Load(Load {
+            memory,
+            kind: LoadKind::I32_8 { kind },
+            arg: MemArg { offset: 0, .. },
+        }) if !kind.atomic() => *memory,
+        _ => return None,
+    };
+    let dst_memory = match store {
+        Instr::Store(Store {
+            memory,
+            kind: StoreKind::I32_8 { atomic: false },
+            arg: MemArg { offset: 0, .. },
+        }) => *memory,
+        _ => return None,
+    };
+    // The counter is the only local the loop writes to.
+    if dst == counter || src == counter {
+        return None;
+    }
+
+    Some(CopyLoop {
+        direction,
+        body,
+        counter,
+        dst,
+        src,
+        dst_memory,
+        src_memory,
+    })
+}
+
+/// Copy the rest of the bytes with `memory.copy` on entry to the loop body.
+fn rewrite(func: &mut LocalFunction, seq: InstrSeqId, copy: &CopyLoop) {
+    let builder = func.builder_mut();
+    let mut fast = builder.dangling_instr_seq(None);
+    let fast_id = fast.id();
+    match copy.direction {
+        Direction::Up { len } => {
+            fast.local_get(copy.dst)
+                .local_get(copy.counter)
+                .binop(BinaryOp::I32Add)
+                .local_get(copy.src)
+                .local_get(copy.counter)
+                .binop(BinaryOp::I32Add)
+                .local_get(len)
+                .local_get(copy.counter)
+                .binop(BinaryOp::I32Sub)
+                .instr(MemoryCopy {
+                    src: copy.src_memory,
+                    dst: copy.dst_memory,
+                })
+                .local_get(len)
+                .local_set(copy.counter);
+        }
+        Direction::Down => {
+            fast.local_get(copy.dst)
+                .local_get(copy.src)
+                .local_get(copy.counter)
+                .instr(MemoryCopy {
+                    src: copy.src_memory,
+                    dst: copy.dst_memory,
+                })
+                .i32_const(0)
+                .local_set(copy.counter);
+        }
+    }
+    fast.br(seq);
+
+    if copy.src_memory != copy.dst_memory {
+        let instrs = func.block_mut(fast_id).instrs.drain(..).collect();
+        func.block_mut(copy.body).instrs = instrs;
+        return;
+    }
+
+    // The byte loop differs from `memory.copy` when the distance from the
+    // start of the part left to read to the start of the part left to write,
+    // in the direction of the loop, is less than the number of bytes left.
+    let (ahead, behind) = match copy.direction {
+        Direction::Up { .. } => (copy.dst, copy.src),
+        Direction::Down => (copy.src, copy.dst),
+    };
+    let empty = func.builder_mut().dangling_instr_seq(None).id();
+    let mut guard = func.builder_mut().dangling_instr_seq(None);
+    guard
+        .local_get(ahead)
+        .local_get(behind)
+        .binop(BinaryOp::I32Sub)
+        .i32_const(1)
+        .binop(BinaryOp::I32Sub);
+    match copy.direction {
+        Direction::Up { len } => {
+            guard
+                .local_get(len)
+                .local_get(copy.counter)
+                .binop(BinaryOp::I32Sub);
+        }
+        Direction::Down => {
+            guard.local_get(copy.counter);
+        }
+    }
+    guard
+        .i32_const(1)
+        .binop(BinaryOp::I32Sub)
+        .binop(BinaryOp::I32GeU)
+        .instr(IfElse {
+            consequent: fast_id,
+            alternative: empty,
+        });
+    let guard_id = guard.id();
+    let guard = func
+        .block_mut(guard_id)
+        .instrs
+        .drain(..)
+        .collect::<Vec<_>>();
+    func.block_mut(copy.body).instrs.splice(0..0, guard);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::manager::PassManager;
+    use crate::testing::{self, HostImports};
+    use crate::{FunctionBuilder, FunctionId, ModuleConfig, ValType};
+
+    /// A function `(dst, src, n)` copying `n` bytes from `src` to `dst` in
+    /// a new memory, with a loop counting up or down.
+    fn copy_func(module: &mut Module, up: bool) -> FunctionId {
+        let memory = module.memories.add_local(false, 1, None);
+        let dst = module.locals.add(ValType::I32);
+        let src = module.locals.add(ValType::I32);
+        let n = module.locals.add(ValType::I32);
+        let i = module.locals.add(ValType::I32);
+        let arg = MemArg {
+            align: 1,
+            offset: 0,
+        };
+        let load = LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        };
+        let store = StoreKind::I32_8 { atomic: false };
+        let mut builder = FunctionBuilder::new(
+            &mut module.types,
+            &[ValType::I32, ValType::I32, ValType::I32],
+            &[],
+        );
+        let mut body = builder.func_body();
+        if !up {
+            body.local_get(n).local_set(i);
+        }
+        body.loop_(None, |l| {
+            let id = l.id();
+            if up {
+                l.local_get(i).local_get(n).binop(BinaryOp::I32LtU);
+            } else {
+                l.local_get(i);
+            }
+            l.if_else(
+                None,
+                |then| {
+                    if !up {
+                        then.local_get(i)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Sub)
+                            .local_set(i);
+                    }
+                    then.local_get(dst)
+                        .local_get(i)
+                        .binop(BinaryOp::I32Add)
+                        .local_get(src)
+                        .local_get(i)
+                        .binop(BinaryOp::I32Add)
+                        .load(memory, load, arg)
+                        .store(memory, store, arg);
+                    if up {
+                        then.local_get(i)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .local_set(i);
+                    }
+                    then.br(id);
+                },
+                |_| {},
+            );
+        });
+        builder.finish(vec![dst, src, n], &mut module.funcs)
+    }
+
+    fn copies(module: &Module, func: FunctionId) -> usize {
+        let local = module.funcs.get(func).kind.unwrap_local();
+        let mut count = 0;
+        let mut worklist = vec![local.entry_block()];
+        while let Some(seq) = worklist.pop() {
+            for (instr, _) in local.block(seq).instrs.iter() {
+                match instr {
+                    Instr::MemoryCopy(_) => count += 1,
+                    Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => worklist.push(*seq),
+                    Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    }) => {
+                        worklist.push(*consequent);
+                        worklist.push(*alternative);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn rewrites_loops_in_both_directions() {
+        for up in &[true, false] {
+            let mut module = Module::default();
+            let func = copy_func(&mut module, *up);
+            assert_eq!(recognize_and_replace_memcpy_loops(&mut module), 1);
+            assert_eq!(copies(&module, func), 1);
+            wasmparser::validate(&module.emit_wasm()).unwrap();
+        }
+    }
+
+    #[test]
+    fn leaves_other_loops_alone() {
+        let mut module = Module::default();
+        let func = copy_func(&mut module, true);
+        // Writing to the destination local in the loop body breaks the
+        // pattern.
+        let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        let seq = loops(local)[0];
+        let body = match local.block(seq).instrs[3].0 {
+            Instr::IfElse(IfElse { consequent, .. }) => consequent,
+            _ => unreachable!(),
+        };
+        let dst = local.args[0];
+        local.block_mut(body).instrs[11].0 = LocalSet { local: dst }.into();
+        assert_eq!(recognize_and_replace_memcpy_loops(&mut module), 0);
+
+        let mut config = ModuleConfig::new();
+        config.only_stable_features(true);
+        let mut module = Module::with_config(config);
+        copy_func(&mut module, false);
+        assert_eq!(recognize_and_replace_memcpy_loops(&mut module), 0);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let func = copy_func(&mut module, true);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        assert_eq!(recognize_and_replace_memcpy_loops(&mut module), 0);
+        assert_eq!(copies(&module, func), 0);
+    }
+
+    /// Export `f(dst, src, n)`, which fills the first 16 bytes of memory
+    /// with 1 to 16, copies with `copy`, and returns those bytes.
+    fn export_driver(module: &mut Module, copy: FunctionId) {
+        let memory = module.memories.iter().next().unwrap().id();
+        let params = [ValType::I32, ValType::I32, ValType::I32];
+        let args = params
+            .iter()
+            .map(|ty| module.locals.add(*ty))
+            .collect::<Vec<_>>();
+        let mut builder =
+            FunctionBuilder::new(&mut module.types, &params, &[ValType::I64, ValType::I64]);
+        let mut body = builder.func_body();
+        let arg = MemArg {
+            align: 8,
+            offset: 0,
+        };
+        for (address, bytes) in &[(0, 0x0807_0605_0403_0201), (8, 0x100f_0e0d_0c0b_0a09)] {
+            body.i32_const(*address).i64_const(*bytes).store(
+                memory,
+                StoreKind::I64 { atomic: false },
+                arg,
+            );
+        }
+        for local in args.iter() {
+            body.local_get(*local);
+        }
+        body.call(copy);
+        for address in &[0, 8] {
+            body.i32_const(*address)
+                .load(memory, LoadKind::I64 { atomic: false }, arg);
+        }
+        let f = builder.finish(args, &mut module.funcs);
+        module.exports.add("f", f);
+    }
+
+    #[test]
+    fn copies_overlapping_ranges_like_the_loop() {
+        // Copying 8 bytes two bytes forward, and two bytes back.
+        let ranges = [(2, 0, 8), (0, 2, 8)];
+        for up in &[true, false] {
+            let mut module = Module::default();
+            let func = copy_func(&mut module, *up);
+            export_driver(&mut module, func);
+            let run = |module: &Module, (dst, src, n): (i32, i32, i32)| {
+                let args = [Value::I32(dst), Value::I32(src), Value::I32(n)];
+                testing::run(module, "f", &args, &mut HostImports::new()).unwrap()
+            };
+            let before = ranges.iter().map(|r| run(&module, *r)).collect::<Vec<_>>();
+
+            assert_eq!(recognize_and_replace_memcpy_loops(&mut module), 1);
+            let after = ranges.iter().map(|r| run(&module, *r)).collect::<Vec<_>>();
+            assert_eq!(
+                before,
+                after,
+                "counting {}",
+                if *up { "up" } else { "down" }
+            );
+        }
+    }
+
+    #[test]
+    fn runs_as_a_pass_for_bulk_memory_targets() {
+        let mut module = Module::default();
+        let func = copy_func(&mut module, true);
+        let mut manager = PassManager::new(mvp());
+        manager.add(RecognizeMemcpy);
+        manager.run(&mut module).unwrap();
+        assert_eq!(copies(&module, func), 0);
+
+        let mut manager = PassManager::new(bulk_memory());
+        manager.add(RecognizeMemcpy);
+        manager.run(&mut module).unwrap();
+        assert_eq!(
+            manager.events()[0].to_string(),
+            "ran pass `recognize-memcpy`"
+        );
+        assert_eq!(copies(&module, func), 1);
+        manager.emit_wasm(&mut module).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Recognize loops that fill memory one byte at a time, and fill with
+//! `memory.fill` instead.
+//!
+//! This is the `memset` counterpart of the `recognize_memcpy` pass, and
+//! recognizes loops of the same two shapes, counting up to a length or down
+//! to zero, whose body stores the same byte at each address:
+//!
+//! ```wat
+//! local.get $dst
+//! local.get $i
+//! i32.add
+//! local.get $value  ;; or `i32.const 0`
+//! i32.store8
+//! ```
+//!
+//! The value is either an `i32.const`, or a local other than the counter,
+//! optionally masked with `i32.const 255` and `i32.and`. Only its low byte is
+//! stored either way, as with `memory.fill`.
+//!
+//! The loop body is replaced with a single `memory.fill` of the remaining
+//! bytes, after which the counter is set to its final value and the loop
+//! exits. A loop that goes out of bounds traps after filling the bytes before
+//! the first out of bounds one, while `memory.fill` traps without filling
+//! anything.
+
+use super::manager::Pass;
+use super::recognize_memcpy::{bulk_memory, is_binop, is_i32_const, local_get, loops};
+use super::recognize_memcpy::{match_byte_loop, ByteLoop, Direction};
+use crate::error::Result;
+use crate::ir::*;
+use crate::{LocalFunction, Module};
+use wasmparser::WasmFeatures;
+
+/// Fill with `memory.fill` in the byte fill loops of every local function in
+/// the module.
+///
+/// Nothing is done if the module is limited to stable features, since
+/// `memory.fill` is part of the bulk memory proposal. Returns the number of
+/// loops that were rewritten.
+pub fn recognize_memset_loops(module: &mut Module) -> usize {
+    if module.config.only_stable_features {
+        return 0;
+    }
+    run_module(module)
+}
+
+fn run_module(module: &mut Module) -> usize {
+    module
+        .funcs
+        .iter_modifiable_mut()
+        .map(|(_, func)| run_func(func))
+        .sum()
+}
+
+/// Fill with `memory.fill` in byte fill loops, as a `Pass` for a `PassManager`.
+///
+/// Since it produces `memory.fill`, the manager only runs it for targets with
+/// bulk memory. The module's config isn't consulted.
+#[derive(Debug)]
+pub struct RecognizeMemset;
+
+impl Pass for RecognizeMemset {
+    fn name(&self) -> &str {
+        "recognize-memset"
+    }
+
+    fn requires(&self) -> WasmFeatures {
+        bulk_memory()
+    }
+
+    fn produces(&self) -> WasmFeatures {
+        bulk_memory()
+    }
+
+    fn run(&mut self, module: &mut Module) -> Result<()> {
+        let rewritten = run_module(module);
+        log::debug!("rewrote {} byte fill loops", rewritten);
+        Ok(())
+    }
+}
+
+/// Fill with `memory.fill` in the byte fill loops of a single function.
+///
+/// Returns the number of loops that were rewritten.
+pub fn run_func(func: &mut LocalFunction) -> usize {
+    let mut rewritten = 0;
+    for seq in loops(func) {
+        if let Some(fill) = match_fill_loop(func, seq) {
+            rewrite(func, seq, fill);
+            rewritten += 1;
+        }
+    }
+    rewritten
+}
+
+/// A loop recognized as filling memory a byte at a time.
+#[derive(Debug)]
+struct FillLoop {
+    direction: Direction,
+    body: InstrSeqId,
+    counter: LocalId,
+    dst: LocalId,
+    /// The instructions computing the stored value.
+    value: Vec<Instr>,
+    memory: MemoryId,
+}
+
+/// Match the given `loop` sequence against the fill loop shapes.
+fn match_fill_loop(func: &LocalFunction, seq: InstrSeqId) -> Option<FillLoop> {
+    let ByteLoop {
+        direction,
+        body,
+        counter,
+        work,
+    } = match_byte_loop(func, seq)?;
+    if work.len() < 5 {
+        return None;
+    }
+    let (address, value, store) = (&work[..3], &work[3..work.len() - 1], work[work.len() - 1]);
+    let dst = match address {
+        [dst, i, add] if local_get(i) == Some(counter) && is_binop(add, BinaryOp::I32Add) => {
+            local_get(dst)?
+        }
+        _ => return None,
+    };
+    let value_ok = match value {
+        [Instr::Const(Const {
+            value: Value::I32(_),
+        })] => true,
+        [get] => local_get(get).map_or(false, |v| v != counter),
+        [get, mask, and] => {
+            local_get(get).map_or(false, |v| v != counter)
+                && is_i32_const(mask, 0xff)
+                && is_binop(and, BinaryOp::I32And)
+        }
+        _ => false,
+    };
+    let memory = match store {
+        Instr::Store(Store {
+            memory,
+            kind: StoreKind::I32_8 { atomic: false },
+            arg: MemArg { offset: 0, .. },
+        }) => *memory,
+        _ => return None,
+    };
+    // The counter is the only local the loop writes to.
+    if !value_ok || dst == counter {
+        return None;
+    }
+
+    Some(FillLoop {
+        direction,
+        body,
+        counter,
+        dst,
+        value: value.iter().map(|instr| (*instr).clone()).collect(),
+        memory,
+    })
+}
+
+/// Replace the loop body with a `memory.fill` of the rest of the bytes.
+fn rewrite(func: &mut LocalFunction, seq: InstrSeqId, fill: FillLoop) {
+    let mut body = func.builder_mut().dangling_instr_seq(None);
+    let body_id = body.id();
+    body.local_get(fill.dst);
+    if let Direction::Up { .. } = fill.direction {
+        body.local_get(fill.counter).binop(BinaryOp::I32Add);
+    }
+    for instr in fill.value {
+        body.instr(instr);
+    }
+    match fill.direction {
+        Direction::Up { len } => {
+            body.local_get(len)
+                .local_get(fill.counter)
+                .binop(BinaryOp::I32Sub)
+                .memory_fill(fill.memory)
+                .local_get(len)
+                .local_set(fill.counter);
+        }
+        Direction::Down => {
+            body.local_get(fill.counter)
+                .memory_fill(fill.memory)
+                .i32_const(0)
+                .local_set(fill.counter);
+        }
+    }
+    body.br(seq);
+
+    let instrs = func.block_mut(body_id).instrs.drain(..).collect();
+    func.block_mut(fill.body).instrs = instrs;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::manager::{mvp, PassManager};
+    use crate::passes::wizen;
+    use crate::{ActiveDataLocation, DataKind, FunctionBuilder, ModuleConfig, ValType};
+
+    /// A start function filling `n` bytes at `dst` with `value`, with a loop
+    /// counting up or down. The value is masked if `mask` is set.
+    fn fill_module(config: ModuleConfig, up: bool, mask: bool) -> Module {
+        let mut module = Module::with_config(config);
+        let memory = module.memories.add_local(false, 1, None);
+        let i = module.locals.add(ValType::I32);
+        let value = module.locals.add(ValType::I32);
+        let (dst, n) = (16, 10);
+        let arg = MemArg {
+            align: 1,
+            offset: 0,
+        };
+        let store = StoreKind::I32_8 { atomic: false };
+
+        // The loops read the destination and length from locals.
+        let dst_local = module.locals.add(ValType::I32);
+        let n_local = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut body = builder.func_body();
+        body.i32_const(dst)
+            .local_set(dst_local)
+            .i32_const(n)
+            .local_set(n_local)
+            .i32_const(0x1ab)
+            .local_set(value);
+        if !up {
+            body.local_get(n_local).local_set(i);
+        }
+        body.loop_(None, |l| {
+            let id = l.id();
+            if up {
+                l.local_get(i).local_get(n_local).binop(BinaryOp::I32LtU);
+            } else {
+                l.local_get(i);
+            }
+            l.if_else(
+                None,
+                |then| {
+                    if !up {
+                        then.local_get(i)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Sub)
+                            .local_set(i);
+                    }
+                    then.local_get(dst_local)
+                        .local_get(i)
+                        .binop(BinaryOp::I32Add)
+                        .local_get(value);
+                    if mask {
+                        then.i32_const(0xff).binop(BinaryOp::I32And);
+                    }
+                    then.store(memory, store, arg);
+                    if up {
+                        then.local_get(i)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .local_set(i);
+                    }
+                    then.br(id);
+                },
+                |_| {},
+            );
+        });
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+        module
+    }
+
+    #[test]
+    fn fills_in_both_directions() {
+        for &(up, mask) in &[(true, false), (false, true)] {
+            let mut module = fill_module(ModuleConfig::new(), up, mask);
+            assert_eq!(recognize_memset_loops(&mut module), 1);
+            wasmparser::validate(&module.emit_wasm()).unwrap();
+
+            // The filled bytes end up in a single data segment.
+            wizen::run(&mut module, None).unwrap();
+            let segments = module
+                .data
+                .iter()
+                .map(|data| match &data.kind {
+                    DataKind::Active(active) => (active.location, data.value.clone()),
+                    DataKind::Passive => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(segments.len(), 1);
+            assert_eq!(segments[0].0, ActiveDataLocation::Absolute(16));
+            assert_eq!(segments[0].1, [0xab; 10]);
+        }
+    }
+
+    #[test]
+    fn needs_bulk_memory() {
+        let mut config = ModuleConfig::new();
+        config.only_stable_features(true);
+        let mut module = fill_module(config, true, false);
+        assert_eq!(recognize_memset_loops(&mut module), 0);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = fill_module(ModuleConfig::new(), true, false);
+        let start = module.start.unwrap();
+        module.funcs.get_mut(start).flags.no_modify = true;
+
+        assert_eq!(recognize_memset_loops(&mut module), 0);
+    }
+
+    #[test]
+    fn runs_as_a_pass_for_bulk_memory_targets() {
+        let mut module = fill_module(ModuleConfig::new(), true, false);
+        let mut manager = PassManager::new(mvp());
+        manager.add(RecognizeMemset);
+        manager.run(&mut module).unwrap();
+        assert_eq!(
+            manager.events()[0].to_string(),
+            "skipped pass `recognize-memset`: the target doesn't support bulk-memory"
+        );
+
+        let mut manager = PassManager::new(bulk_memory());
+        manager.add(RecognizeMemset);
+        manager.run(&mut module).unwrap();
+        manager.emit_wasm(&mut module).unwrap();
+        assert_eq!(recognize_memset_loops(&mut module), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Remove `nop` instructions.
+//!
+//! Passes that delete an instruction without shifting the others around often
+//! leave a `nop` in its place. They do nothing, but take up room in the IR and
+//! in the emitted code, so this pass is meant to run at the end of a pipeline,
+//! before the module is emitted.
+//!
+//! Like other passes, it leaves preambles alone. It does remove the `nop`s
+//! that `nop_padding` inserts on purpose into functions without one, so it
+//! should run before that pass.
+
+use crate::ir::*;
+use crate::{LocalFunction, Module};
+
+/// Remove the `nop`s of every local function in the module.
+///
+/// Returns the number of instructions that were removed.
+pub fn run(module: &mut Module) -> usize {
+    module
+        .funcs
+        .iter_modifiable_mut()
+        .map(|(_, func)| remove_nop_expressions(func))
+        .sum()
+}
+
+/// Remove the `nop`s from every instruction sequence of a function, except
+/// for those in preambles.
+///
+/// Returns the number of instructions that were removed.
+pub fn remove_nop_expressions(func: &mut LocalFunction) -> usize {
+    let mut removed = 0;
+    let mut worklist = vec![func.entry_block()];
+    while let Some(seq) = worklist.pop() {
+        let block = func.block_mut(seq);
+        let len = block.instrs.len();
+        let mut index = 0;
+        let preamble = block.preamble_len();
+        block.instrs.retain(|(instr, _)| {
+            index += 1;
+            index <= preamble || !instr.is_nop()
+        });
+        removed += len - block.instrs.len();
+
+        for (instr, _) in block.instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => worklist.push(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    worklist.push(*consequent);
+                    worklist.push(*alternative);
+                }
+                _ => {}
+            }
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn removes_nops_everywhere() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .nop()
+            .block(ValType::I32, |b| {
+                b.nop().i32_const(1).nop();
+            })
+            .nop();
+        let id = builder.finish(vec![], &mut module.funcs);
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.block_mut(entry).push_preamble(Nop {});
+
+        assert_eq!(run(&mut module), 4);
+        let func = module.funcs.get(id).kind.unwrap_local();
+        let block = func.block(entry);
+        assert_eq!(block.preamble_len(), 1);
+        assert!(block.instrs[0].0.is_nop());
+        let inner = match &block.instrs[1].0 {
+            Instr::Block(Block { seq }) => *seq,
+            other => panic!("unexpected instruction: {:?}", other),
+        };
+        assert_eq!(block.instrs.len(), 2);
+        assert_eq!(func.block(inner).instrs.len(), 1);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().nop();
+        let id = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(id).flags.no_modify = true;
+
+        assert_eq!(run(&mut module), 0);
+    }
+}
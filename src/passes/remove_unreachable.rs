@@ -0,0 +1,245 @@
+//! Remove the functions that can't be reached from a set of roots.
+//!
+//! Unlike `gc`, which keeps everything reachable from the module's exports,
+//! this pass lets the caller decide which functions must be kept, which is
+//! useful for tree-shaking a module down to part of its API. It only removes
+//! local functions, and the types that are no longer used once they are gone.
+//! Imported functions are always kept, since removing them would change what
+//! the module needs to be instantiated.
+//!
+//! Besides the given roots, the start function, functions flagged
+//! `keep_alive`, every function in an element segment (which may be called
+//! through a table), and every function a global's initializer refers to are
+//! kept, along with every function they call or refer to, transitively.
+//! Exports of removed functions are removed as well.
+
+use crate::ir::*;
+use crate::map::IdHashSet;
+use crate::{ExportItem, FunctionId, FunctionKind, GlobalKind, InitExpr, Module};
+
+/// What `remove_unreachable_functions` removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RemovedStats {
+    /// The number of local functions that were removed.
+    pub functions: usize,
+    /// The number of types that were removed.
+    pub types: usize,
+}
+
+/// Remove every local function that can't be reached from `roots`, and every
+/// type that is no longer used afterwards.
+pub fn remove_unreachable_functions(
+    module: &mut Module,
+    roots: impl IntoIterator<Item = FunctionId>,
+) -> RemovedStats {
+    let mut stack = roots.into_iter().collect::<Vec<_>>();
+    stack.extend(module.start);
+    stack.extend(
+        module
+            .funcs
+            .iter()
+            .filter(|f| f.flags.keep_alive)
+            .map(|f| f.id()),
+    );
+    for elem in module.elements.iter() {
+        stack.extend(elem.members.iter().filter_map(|f| *f));
+    }
+    for global in module.globals.iter() {
+        if let GlobalKind::Local(InitExpr::RefFunc(f)) = global.kind {
+            stack.push(f);
+        }
+    }
+
+    let mut reachable = IdHashSet::default();
+    while let Some(f) = stack.pop() {
+        if !reachable.insert(f) {
+            continue;
+        }
+        if let FunctionKind::Local(func) = &module.funcs.get(f).kind {
+            let mut v = Callees { stack: &mut stack };
+            dfs_in_order(&mut v, func, func.entry_block());
+        }
+    }
+
+    let unreachable = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .filter(|id| !reachable.contains(id))
+        .collect::<Vec<_>>();
+    let exports = module
+        .exports
+        .iter()
+        .filter(|e| matches!(e.item, ExportItem::Function(f) if unreachable.contains(&f)))
+        .map(|e| e.id())
+        .collect::<Vec<_>>();
+    for id in exports {
+        module.exports.delete(id);
+    }
+    for id in unreachable.iter() {
+        module.funcs.delete(*id);
+    }
+
+    let used = used_types(module);
+    let unused = module
+        .types
+        .iter()
+        .filter(|ty| !ty.is_for_function_entry() && !used.contains(&ty.id()))
+        .map(|ty| ty.id())
+        .collect::<Vec<_>>();
+    for id in unused.iter() {
+        module.types.delete(*id);
+    }
+
+    return RemovedStats {
+        functions: unreachable.len(),
+        types: unused.len(),
+    };
+
+    struct Callees<'a> {
+        stack: &'a mut Vec<FunctionId>,
+    }
+
+    impl<'instr> Visitor<'instr> for Callees<'_> {
+        fn visit_function_id(&mut self, &func: &FunctionId) {
+            self.stack.push(func);
+        }
+    }
+}
+
+/// Every type that a function's signature, a tag, a `call_indirect`, or a
+/// block type refers to.
+fn used_types(module: &Module) -> IdHashSet<crate::Type> {
+    let mut v = Types::default();
+    for tag in module.tags.iter() {
+        v.types.insert(tag.ty);
+    }
+    for func in module.funcs.iter() {
+        v.types.insert(func.ty());
+        if let FunctionKind::Local(local) = &func.kind {
+            v.entry = Some(local.entry_block());
+            dfs_in_order(&mut v, local, local.entry_block());
+        }
+    }
+    return v.types;
+
+    #[derive(Default)]
+    struct Types {
+        entry: Option<InstrSeqId>,
+        types: IdHashSet<crate::Type>,
+    }
+
+    impl<'instr> Visitor<'instr> for Types {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            if let InstrSeqType::MultiValue(ty) = seq.ty {
+                if Some(seq.id()) != self.entry {
+                    self.types.insert(ty);
+                }
+            }
+        }
+
+        fn visit_type_id(&mut self, &ty: &crate::TypeId) {
+            self.types.insert(ty);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    #[test]
+    fn removes_functions_unreachable_from_roots() {
+        let mut module = Module::default();
+        let import_ty = module.types.add(&[ValType::F64], &[]);
+        let (import, _) = module.add_import_func("env", "unused", import_ty);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let leaf = builder.finish(vec![], &mut module.funcs);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().call(leaf);
+        let root = builder.finish(vec![], &mut module.funcs);
+
+        // Only called by a function that is itself unreachable.
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I64], &[]);
+        builder.func_body();
+        let callee = builder.finish(vec![module.locals.add(ValType::I64)], &mut module.funcs);
+        let callee_ty = module.funcs.get(callee).ty();
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i64_const(0).call(callee);
+        let caller = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("caller", caller);
+
+        let stats = remove_unreachable_functions(&mut module, vec![root]);
+        assert_eq!(
+            stats,
+            RemovedStats {
+                functions: 2,
+                types: 1
+            }
+        );
+        assert!(module.funcs.contains(root));
+        assert!(module.funcs.contains(leaf));
+        assert!(module.funcs.contains(import));
+        assert!(!module.funcs.contains(caller));
+        assert!(!module.funcs.contains(callee));
+        assert!(module.types.iter().all(|ty| ty.id() != callee_ty));
+        assert!(module.types.iter().any(|ty| ty.id() == import_ty));
+        assert!(module.exports.iter().next().is_none());
+
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn keeps_functions_in_tables() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let in_table = builder.finish(vec![], &mut module.funcs);
+        let table = module.tables.add_local(1, None, ValType::Funcref);
+        module.elements.add(
+            crate::ElementKind::Active {
+                table,
+                offset: InitExpr::Value(Value::I32(0)),
+            },
+            ValType::Funcref,
+            vec![Some(in_table)],
+        );
+
+        let stats = remove_unreachable_functions(&mut module, None);
+        assert_eq!(stats, RemovedStats::default());
+        assert!(module.funcs.contains(in_table));
+    }
+
+    #[test]
+    fn keeps_keep_alive_functions() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let kept = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(kept).flags.keep_alive = true;
+
+        let stats = remove_unreachable_functions(&mut module, Vec::<FunctionId>::new());
+        assert_eq!(stats.functions, 0);
+        assert!(module.funcs.contains(kept));
+    }
+
+    #[test]
+    fn keeps_types_of_tags() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[ValType::I32], &[]);
+        module.tags.add(ty);
+
+        let stats = remove_unreachable_functions(&mut module, None);
+        assert_eq!(stats, RemovedStats::default());
+        assert!(module.types.iter().any(|t| t.id() == ty));
+
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+//! Instrument functions to keep their locals on an explicit shadow stack.
+//!
+//! Some runtimes can't inspect the native wasm stack, so profilers and
+//! unwinders (like Emscripten's Asyncify) need each function's state spilled
+//! to linear memory where they can find it. This is a simplified version of
+//! that transformation: on entry, every function pushes a frame onto a shadow
+//! stack in linear memory and saves its live locals into it, and on every
+//! exit it restores them from the frame and pops the frame again. Functions
+//! without any locals to save are left alone.
+//!
+//! The shadow stack grows downwards, and its top is kept in an `i32` global.
+
+use crate::ir::*;
+use crate::map::IdHashSet;
+use crate::{GlobalId, LocalFunction, MemoryId, Module, ModuleLocals, ModuleTypes, ValType};
+use std::cmp;
+use std::mem;
+
+/// Frames are kept aligned to this many bytes.
+const FRAME_ALIGN: u32 = 16;
+
+/// Instrument every local function in `module` to save its live locals to a
+/// shadow stack in `stack_mem`, whose stack pointer is `sp_global`.
+///
+/// Panics if `sp_global` is not a mutable `i32` global.
+pub fn instrument_shadow_stack(module: &mut Module, stack_mem: MemoryId, sp_global: GlobalId) {
+    let sp = module.globals.get(sp_global);
+    assert!(
+        sp.ty == ValType::I32 && sp.mutable,
+        "the shadow stack pointer must be a mutable i32 global"
+    );
+
+    let types = &mut module.types;
+    let locals = &module.locals;
+    for (_, func) in module.funcs.iter_modifiable_mut() {
+        instrument_func(func, types, locals, stack_mem, sp_global);
+    }
+}
+
+fn instrument_func(
+    func: &mut LocalFunction,
+    types: &mut ModuleTypes,
+    locals: &ModuleLocals,
+    stack_mem: MemoryId,
+    sp: GlobalId,
+) {
+    // Lay out a slot for each live local, aligned to its size.
+    let live = live_on_entry(func);
+    let mut slots = Vec::new();
+    let mut frame_size = 0;
+    for &local in func.args.iter().filter(|a| live.contains(a)) {
+        let (store, load, size) = match locals.get(local).ty() {
+            ValType::I32 => (
+                StoreKind::I32 { atomic: false },
+                LoadKind::I32 { atomic: false },
+                4,
+            ),
+            ValType::I64 => (
+                StoreKind::I64 { atomic: false },
+                LoadKind::I64 { atomic: false },
+                8,
+            ),
+            ValType::F32 => (StoreKind::F32, LoadKind::F32, 4),
+            ValType::F64 => (StoreKind::F64, LoadKind::F64, 8),
+            ValType::V128 => (StoreKind::V128, LoadKind::V128, 16),
+            // References can't be written to linear memory.
+            ValType::Externref | ValType::Funcref => continue,
+        };
+        let offset = align_up(frame_size, size);
+        frame_size = offset + size;
+        slots.push((
+            local,
+            store,
+            load,
+            MemArg {
+                align: size,
+                offset,
+            },
+        ));
+    }
+    if slots.is_empty() {
+        return;
+    }
+    let frame_size = align_up(frame_size, FRAME_ALIGN) as i32;
+
+    let entry = func.entry_block();
+    let (mut returns, branches) = function_exits(func);
+
+    // Branches to the function's body also leave the function, but the frame
+    // can't be popped right before a conditional branch. Wrap the body in a
+    // block for them to target instead, so they leave through its end.
+    if !branches.is_empty() {
+        let results = types.results(func.ty()).to_vec();
+        let ty = InstrSeqType::new(types, &[], &results);
+        let body = func.builder_mut().dangling_instr_seq(ty).id();
+        let instrs = mem::take(&mut func.block_mut(entry).instrs);
+        let preamble = func.block(entry).preamble_len();
+        func.block_mut(body).instrs = instrs;
+        func.block_mut(body).set_preamble_len(preamble);
+        func.block_mut(entry).set_preamble_len(0);
+        func.block_mut(entry)
+            .instrs
+            .push((Block { seq: body }.into(), Default::default()));
+
+        let moved = |seq| if seq == entry { body } else { seq };
+        for (seq, index) in branches {
+            match &mut func.block_mut(moved(seq)).instrs[index].0 {
+                Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => *block = body,
+                Instr::BrTable(BrTable { blocks, default }) => {
+                    for block in blocks.iter_mut().chain(Some(default)) {
+                        if *block == entry {
+                            *block = body;
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        for (seq, _) in returns.iter_mut() {
+            *seq = moved(*seq);
+        }
+    }
+
+    // Restore the locals and pop the frame wherever control leaves the
+    // function. This doesn't touch the operand stack, so it can go right
+    // before each exit.
+    let mut exits = returns;
+    exits.push((entry, func.block(entry).len()));
+    // Insert back to front so that earlier positions stay valid.
+    exits.sort_by_key(|&(seq, index)| cmp::Reverse((seq.index(), index)));
+    for (seq, index) in exits {
+        let mut epilogue = Vec::new();
+        for &(local, _, kind, arg) in slots.iter() {
+            epilogue.push((GlobalGet { global: sp }.into(), Default::default()));
+            let load = Load {
+                memory: stack_mem,
+                kind,
+                arg,
+            };
+            epilogue.push((load.into(), Default::default()));
+            epilogue.push((LocalSet { local }.into(), Default::default()));
+        }
+        epilogue.extend(adjust_sp(sp, frame_size, BinaryOp::I32Add));
+        let epilogue_len = epilogue.len();
+        let block = func.block_mut(seq);
+        let preamble = block.preamble_len();
+        block.instrs.splice(index..index, epilogue);
+        if index < preamble {
+            block.set_preamble_len(preamble + epilogue_len);
+        }
+    }
+
+    // Push the frame and spill the live locals into it on entry, as part of
+    // the entry block's preamble so that later passes keep it in place.
+    let mut prologue = adjust_sp(sp, frame_size, BinaryOp::I32Sub);
+    for (local, kind, _, arg) in slots {
+        prologue.push((GlobalGet { global: sp }.into(), Default::default()));
+        prologue.push((LocalGet { local }.into(), Default::default()));
+        let store = Store {
+            memory: stack_mem,
+            kind,
+            arg,
+        };
+        prologue.push((store.into(), Default::default()));
+    }
+    let block = func.block_mut(entry);
+    for (instr, _) in prologue {
+        block.push_preamble(instr);
+    }
+}
+
+/// Instructions performing `sp = sp <op> frame_size`.
+fn adjust_sp(sp: GlobalId, frame_size: i32, op: BinaryOp) -> Vec<(Instr, InstrLocId)> {
+    vec![
+        (GlobalGet { global: sp }.into(), Default::default()),
+        (
+            Const {
+                value: Value::I32(frame_size),
+            }
+            .into(),
+            Default::default(),
+        ),
+        (Binop { op }.into(), Default::default()),
+        (GlobalSet { global: sp }.into(), Default::default()),
+    ]
+}
+
+fn align_up(n: u32, align: u32) -> u32 {
+    (n + align - 1) / align * align
+}
+
+/// The arguments whose incoming values may be used by the function.
+///
+/// Every other local starts out zeroed, so arguments are the only locals that
+/// can be live on entry. This is conservative: an argument counts as live if
+/// it is read anywhere, even if every read is preceded by a write.
+fn live_on_entry(func: &LocalFunction) -> IdHashSet<Local> {
+    let mut v = Reads::default();
+    dfs_in_order(&mut v, func, func.entry_block());
+    return v.reads;
+
+    #[derive(Default)]
+    struct Reads {
+        reads: IdHashSet<Local>,
+    }
+
+    impl<'instr> Visitor<'instr> for Reads {
+        fn visit_local_get(&mut self, instr: &LocalGet) {
+            self.reads.insert(instr.local);
+        }
+    }
+}
+
+/// Find the `return`s in the function, and the branches that target its body.
+fn function_exits(func: &LocalFunction) -> (Vec<(InstrSeqId, usize)>, Vec<(InstrSeqId, usize)>) {
+    let entry = func.entry_block();
+    let mut v = Exits {
+        entry,
+        returns: Vec::new(),
+        branches: Vec::new(),
+    };
+    dfs_in_order(&mut v, func, entry);
+    return (v.returns, v.branches);
+
+    struct Exits {
+        entry: InstrSeqId,
+        returns: Vec<(InstrSeqId, usize)>,
+        branches: Vec<(InstrSeqId, usize)>,
+    }
+
+    impl<'instr> Visitor<'instr> for Exits {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            for (index, (instr, _)) in seq.instrs.iter().enumerate() {
+                let branches = match instr {
+                    Instr::Return(_) => {
+                        self.returns.push((seq.id(), index));
+                        continue;
+                    }
+                    Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => *block == self.entry,
+                    Instr::BrTable(BrTable { blocks, default }) => {
+                        *default == self.entry || blocks.contains(&self.entry)
+                    }
+                    _ => false,
+                };
+                if branches {
+                    self.branches.push((seq.id(), index));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{run, HostImports};
+    use crate::{FunctionBuilder, InitExpr};
+
+    #[test]
+    fn spills_live_arguments() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let sp = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(65536)));
+
+        let used = module.locals.add(ValType::I64);
+        let unused = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(
+            &mut module.types,
+            &[ValType::I64, ValType::I32],
+            &[ValType::I64],
+        );
+        builder
+            .func_body()
+            .local_get(used)
+            .i64_const(0)
+            .binop(BinaryOp::I64Eq)
+            .if_else(
+                None,
+                |then| {
+                    then.i64_const(1).return_();
+                },
+                |_| {},
+            )
+            .local_get(used);
+        let func = builder.finish(vec![used, unused], &mut module.funcs);
+
+        instrument_shadow_stack(&mut module, memory, sp);
+
+        let local = module.funcs.get(func).kind.unwrap_local();
+        let entry = local.block(local.entry_block());
+        let stores = entry.iter().filter(|(instr, _)| instr.is_store()).count();
+        assert_eq!(stores, 1);
+        // Popped both at the `return` and at the end of the body.
+        let pops = local
+            .block(local.entry_block())
+            .iter()
+            .chain(local.block(*local_if(local)).iter())
+            .filter(|(instr, _)| match instr {
+                Instr::Binop(Binop {
+                    op: BinaryOp::I32Add,
+                }) => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(pops, 2);
+
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    fn local_if(func: &LocalFunction) -> &InstrSeqId {
+        func.block(func.entry_block())
+            .iter()
+            .find_map(|(instr, _)| match instr {
+                Instr::IfElse(IfElse { consequent, .. }) => Some(consequent),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn branches_to_body_pop_the_frame() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let sp = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(65536)));
+
+        let arg = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let body = builder.func_body_id();
+        builder
+            .func_body()
+            .i32_const(1)
+            .local_get(arg)
+            .br_if(body)
+            .drop()
+            .i32_const(2);
+        let func = builder.finish(vec![arg], &mut module.funcs);
+
+        instrument_shadow_stack(&mut module, memory, sp);
+
+        // The body was wrapped in a block that the `br_if` now targets.
+        let local = module.funcs.get(func).kind.unwrap_local();
+        let entry = local.block(local.entry_block());
+        let inner = entry
+            .iter()
+            .find_map(|(instr, _)| match instr {
+                Instr::Block(Block { seq }) => Some(*seq),
+                _ => None,
+            })
+            .unwrap();
+        assert!(local.block(inner).iter().any(|(instr, _)| match instr {
+            Instr::BrIf(BrIf { block }) => *block == inner,
+            _ => false,
+        }));
+
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let sp = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(65536)));
+        let arg = module.locals.add(ValType::I64);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I64], &[ValType::I64]);
+        builder.func_body().local_get(arg);
+        let func = builder.finish(vec![arg], &mut module.funcs);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        instrument_shadow_stack(&mut module, memory, sp);
+
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(local.block(local.entry_block()).len(), 1);
+    }
+
+    #[test]
+    fn restores_locals_and_skips_empty_frames() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let sp = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(65536)));
+
+        let arg = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder
+            .func_body()
+            .local_get(arg)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add);
+        let inc = builder.finish(vec![arg], &mut module.funcs);
+        module.exports.add("inc", inc);
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(7);
+        let seven = builder.finish(vec![], &mut module.funcs);
+
+        instrument_shadow_stack(&mut module, memory, sp);
+
+        let local = module.funcs.get(inc).kind.unwrap_local();
+        let restores = local
+            .block(local.entry_block())
+            .iter()
+            .filter(|(instr, _)| match instr {
+                Instr::LocalSet(LocalSet { local }) => *local == arg,
+                _ => false,
+            })
+            .count();
+        assert_eq!(restores, 1);
+        let local = module.funcs.get(seven).kind.unwrap_local();
+        assert_eq!(local.block(local.entry_block()).len(), 1);
+
+        let results = run(&module, "inc", &[Value::I32(41)], &mut HostImports::new()).unwrap();
+        assert_eq!(format!("{:?}", results), "[I32(42)]");
+    }
+}
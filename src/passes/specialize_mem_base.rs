@@ -0,0 +1,213 @@
+//! Fold a fixed memory base address into the offsets of loads and stores.
+//!
+//! Code compiled to be relocatable, like the side modules of dynamic linking,
+//! computes addresses relative to an immutable global holding where its data
+//! starts, `__memory_base` by convention:
+//!
+//! ```wat
+//! global.get $__memory_base
+//! local.get $p
+//! i32.add
+//! i32.load offset=8
+//! ```
+//!
+//! When the embedding always places that data at the same address, the
+//! `global.get` and `i32.add` can go, with the base added to the static
+//! offset instead:
+//!
+//! ```wat
+//! local.get $p
+//! i32.load offset=8+base
+//! ```
+//!
+//! This isn't quite the same: the `i32.add` wraps around, while the offset is
+//! added without wrapping, so an address that used to wrap past the end of
+//! the address space now traps. Code relative to a base pointer doesn't rely
+//! on such wrapping.
+
+use crate::ir::*;
+use crate::{GlobalId, LocalFunction, MemoryId, Module, ValType};
+use std::collections::HashSet;
+
+/// Fold `base`, the value of `global`, into the loads and stores of `memory`
+/// whose address is `global` plus some other value.
+///
+/// Nothing is folded unless `global` is an immutable `i32`, nor when the
+/// offset would overflow. Functions flagged `no_modify` are left alone.
+///
+/// Returns the number of loads and stores that were changed.
+pub fn specialize_memory_base(
+    module: &mut Module,
+    memory: MemoryId,
+    global: GlobalId,
+    base: u32,
+) -> usize {
+    let g = module.globals.get(global);
+    if g.mutable || g.ty != ValType::I32 {
+        return 0;
+    }
+
+    // The accesses to fold, and the instructions to remove, of each
+    // sequence.
+    let mut folds = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify {
+            continue;
+        }
+        for seq in func.instr_seqs() {
+            let block = func.block(seq);
+            let mut accesses = Vec::new();
+            let mut removed = HashSet::new();
+            for (index, (instr, _)) in block.instrs.iter().enumerate() {
+                let (mem, arg, depth) = match instr {
+                    Instr::Load(Load { memory, arg, .. }) => (*memory, arg, 0),
+                    // The address is below the value to store.
+                    Instr::Store(Store { memory, arg, .. }) => (*memory, arg, 1),
+                    _ => continue,
+                };
+                if mem != memory || arg.offset.checked_add(base).is_none() {
+                    continue;
+                }
+                let base_add = match base_add(module, func, seq, index, depth, global) {
+                    Some(base_add) => base_add,
+                    None => continue,
+                };
+                if base_add.iter().any(|i| *i < block.preamble_len()) {
+                    continue;
+                }
+                accesses.push(index);
+                removed.extend(base_add.iter().copied());
+            }
+            if !accesses.is_empty() {
+                folds.push((id, seq, accesses, removed));
+            }
+        }
+    }
+
+    let mut folded = 0;
+    for (id, seq, accesses, removed) in folds {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let instrs = &mut func.block_mut(seq).instrs;
+        for index in accesses {
+            match &mut instrs[index].0 {
+                Instr::Load(Load { arg, .. }) | Instr::Store(Store { arg, .. }) => {
+                    arg.offset += base
+                }
+                _ => unreachable!(),
+            }
+            folded += 1;
+        }
+        let mut index = 0;
+        instrs.retain(|_| {
+            index += 1;
+            !removed.contains(&(index - 1))
+        });
+    }
+    folded
+}
+
+/// The indices of the `global.get` of `global` and the `i32.add` computing the
+/// address of the access at `index` of `seq`, which is `depth` values
+/// below the top of the stack there.
+fn base_add(
+    module: &Module,
+    func: &LocalFunction,
+    seq: InstrSeqId,
+    index: usize,
+    depth: usize,
+    global: GlobalId,
+) -> Option<[usize; 2]> {
+    let instrs = &func.block(seq).instrs;
+    let add = func.producer(module, seq, index, depth)?;
+    match instrs[add].0 {
+        Instr::Binop(Binop {
+            op: BinaryOp::I32Add,
+        }) => {}
+        _ => return None,
+    }
+    for operand in 0..2 {
+        let get = func.producer(module, seq, add, operand)?;
+        match instrs[get].0 {
+            Instr::GlobalGet(GlobalGet { global: g }) if g == global => return Some([get, add]),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, InitExpr};
+
+    #[test]
+    fn folds_base_into_offsets() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let base = module
+            .globals
+            .add_local(ValType::I32, false, InitExpr::Value(Value::I32(1024)));
+        let p = module.locals.add(ValType::I32);
+        let arg = MemArg {
+            align: 4,
+            offset: 8,
+        };
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder
+            .func_body()
+            // Store to `p + base` the value loaded from `base + p`.
+            .local_get(p)
+            .global_get(base)
+            .binop(BinaryOp::I32Add)
+            .global_get(base)
+            .local_get(p)
+            .binop(BinaryOp::I32Add)
+            .load(memory, LoadKind::I32 { atomic: false }, arg)
+            .store(memory, StoreKind::I32 { atomic: false }, arg)
+            // Not relative to the base.
+            .local_get(p)
+            .load(memory, LoadKind::I32 { atomic: false }, arg)
+            .drop();
+        let func = builder.finish(vec![p], &mut module.funcs);
+
+        assert_eq!(specialize_memory_base(&mut module, memory, base, 1024), 2);
+        let func = module.funcs.get(func).kind.unwrap_local();
+        let offsets = func
+            .block(func.entry_block())
+            .iter()
+            .filter_map(|(instr, _)| match instr {
+                Instr::Load(Load { arg, .. }) | Instr::Store(Store { arg, .. }) => Some(arg.offset),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [1032, 1032, 8]);
+        assert_eq!(func.block(func.entry_block()).len(), 7);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn needs_an_immutable_base() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let base = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(1024)));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .global_get(base)
+            .i32_const(4)
+            .binop(BinaryOp::I32Add)
+            .load(
+                memory,
+                LoadKind::I32 { atomic: false },
+                MemArg {
+                    align: 4,
+                    offset: 0,
+                },
+            );
+        builder.finish(vec![], &mut module.funcs);
+
+        assert_eq!(specialize_memory_base(&mut module, memory, base, 1024), 0);
+    }
+}
@@ -0,0 +1,344 @@
+//! Reduce how many values a function keeps on the operand stack at once.
+//!
+//! Some engines have little room for their value stack, and deeply nested
+//! expressions can overflow it. This pass finds the point where a function's
+//! stack is deepest, and moves a value that sits below it into a fresh local
+//! with a `local.set` right after the value is produced. Right before the
+//! instruction that uses the value, the values above it are moved into fresh
+//! locals as well, and everything is pushed back in the original order. The
+//! order in which the function's instructions run doesn't change.
+//!
+//! Values that are block parameters, or that are produced in a preamble,
+//! aren't moved.
+
+use crate::error::Result;
+use crate::ir::*;
+use crate::{FunctionId, LocalFunction, Module, ValType};
+use anyhow::bail;
+
+/// The largest number of values on the operand stack at once while running
+/// the function, counting the values of enclosing blocks.
+///
+/// Code that follows an unconditional branch, `return` or `unreachable` in
+/// its sequence isn't counted.
+pub fn max_stack_depth(module: &Module, func: &LocalFunction) -> usize {
+    Depths::new(module, func).peak
+}
+
+/// Move values into fresh locals until the function's peak stack depth, as
+/// computed by `max_stack_depth`, is at most `max_depth`.
+///
+/// Returns the number of values that were moved. Returns an error if the
+/// function isn't a local function, if it is too deep but flagged
+/// `no_modify`, or if its stack can't be made shallow enough, for example
+/// because a single instruction takes more than `max_depth` operands. Values
+/// that were moved before that is found out stay moved; the function is still
+/// valid.
+pub fn reduce_stack_depth(
+    module: &mut Module,
+    func: FunctionId,
+    max_depth: usize,
+) -> Result<usize> {
+    let mut moved = 0;
+    loop {
+        let local = match &module.funcs.get(func).kind {
+            crate::FunctionKind::Local(local) => local,
+            _ => bail!("cannot reduce the stack depth of [{func:?}], it is not a local function"),
+        };
+        let depths = Depths::new(module, local);
+        if depths.peak <= max_depth {
+            return Ok(moved);
+        }
+        if module.funcs.get(func).flags.no_modify {
+            bail!(
+                "cannot reduce the stack depth of {:?}, it is flagged `no_modify`",
+                func
+            );
+        }
+        let spill = match find_spill(module, local, &depths.at_peak) {
+            Some(spill) => spill,
+            None => bail!(
+                "cannot reduce the stack depth of [{func:?}] from {} to {}",
+                depths.peak,
+                max_depth
+            ),
+        };
+
+        let value = module.locals.add(spill.ty);
+        let above = spill
+            .above
+            .iter()
+            .map(|ty| module.locals.add(*ty))
+            .collect::<Vec<_>>();
+        let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+        let instrs = &mut local.block_mut(spill.seq).instrs;
+
+        let loc = instrs[spill.consumer].1;
+        let restore = above
+            .iter()
+            .rev()
+            .map(|&local| Instr::from(LocalSet { local }))
+            .chain(Some(LocalGet { local: value }.into()))
+            .chain(above.iter().map(|&local| LocalGet { local }.into()))
+            .map(|instr| (instr, loc))
+            .collect::<Vec<_>>();
+        instrs.splice(spill.consumer..spill.consumer, restore);
+        let loc = instrs[spill.producer].1;
+        instrs.insert(spill.producer + 1, (LocalSet { local: value }.into(), loc));
+        moved += 1;
+    }
+}
+
+/// A value on the stack, and the instruction in its sequence that pushed it.
+#[derive(Clone, Debug)]
+struct Slot {
+    ty: Option<ValType>,
+    /// `None` for the sequence's parameters.
+    producer: Option<usize>,
+}
+
+/// The values a sequence has on the stack at some instruction.
+#[derive(Clone, Debug)]
+struct Level {
+    seq: InstrSeqId,
+    /// The values pushed within the sequence, excluding the parameters of the
+    /// nested sequence, if `index` is a block.
+    stack: Vec<Slot>,
+    /// The instruction after which the stack looks like this, or the nested
+    /// block that is running.
+    index: usize,
+}
+
+/// The peak stack depth of a function, and where it is reached.
+struct Depths<'a> {
+    module: &'a Module,
+    func: &'a LocalFunction,
+    peak: usize,
+    /// The sequences enclosing the point where the peak is first reached,
+    /// outermost first.
+    at_peak: Vec<Level>,
+    path: Vec<Level>,
+}
+
+impl<'a> Depths<'a> {
+    fn new(module: &'a Module, func: &'a LocalFunction) -> Depths<'a> {
+        let mut depths = Depths {
+            module,
+            func,
+            peak: 0,
+            at_peak: Vec::new(),
+            path: Vec::new(),
+        };
+        depths.walk(func.entry_block(), Vec::new(), 0);
+        depths
+    }
+
+    fn walk(&mut self, seq: InstrSeqId, params: Vec<Option<ValType>>, base: usize) {
+        let mut stack = params
+            .into_iter()
+            .map(|ty| Slot { ty, producer: None })
+            .collect::<Vec<_>>();
+        let func = self.func;
+        for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+            let pops = match self.func.stack_effect(self.module, instr) {
+                Some((pops, _)) if pops <= stack.len() => pops,
+                _ => break,
+            };
+            let popped = stack
+                .split_off(stack.len() - pops)
+                .into_iter()
+                .map(|slot| slot.ty)
+                .collect::<Vec<_>>();
+
+            let nested = match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                    vec![(*seq, popped.clone())]
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    let params = popped[..popped.len() - 1].to_vec();
+                    vec![(*consequent, params.clone()), (*alternative, params)]
+                }
+                _ => Vec::new(),
+            };
+            for (inner, params) in nested {
+                self.path.push(Level {
+                    seq,
+                    stack: stack.clone(),
+                    index,
+                });
+                self.walk(inner, params, base + stack.len());
+                self.path.pop();
+            }
+
+            let pushed = self.func.pushed_types(self.module, instr, &popped);
+            stack.extend(pushed.into_iter().map(|ty| Slot {
+                ty,
+                producer: Some(index),
+            }));
+            if base + stack.len() > self.peak {
+                self.peak = base + stack.len();
+                self.at_peak = self.path.clone();
+                self.at_peak.push(Level {
+                    seq,
+                    stack: stack.clone(),
+                    index,
+                });
+            }
+        }
+    }
+}
+
+/// A value to move into a local.
+struct Spill {
+    seq: InstrSeqId,
+    ty: ValType,
+    /// The instruction that pushes the value.
+    producer: usize,
+    /// The instruction that pops the value.
+    consumer: usize,
+    /// The types of the values above it when it is popped.
+    above: Vec<ValType>,
+}
+
+/// Pick a value that is on the stack at the peak, and that can be moved into
+/// a local for the whole time the peak lasts. Values of outer sequences are
+/// preferred, then values that are lower on the stack.
+fn find_spill(module: &Module, func: &LocalFunction, at_peak: &[Level]) -> Option<Spill> {
+    for (i, level) in at_peak.iter().enumerate() {
+        let innermost = i + 1 == at_peak.len();
+        let preamble = func.block(level.seq).preamble_len();
+        for (position, slot) in level.stack.iter().enumerate() {
+            let (ty, producer) = match (slot.ty, slot.producer) {
+                (Some(ty), Some(producer)) if producer >= preamble => (ty, producer),
+                _ => continue,
+            };
+            let (consumer, above) = match find_consumer(module, func, level.seq, position, producer)
+            {
+                Some(found) => found,
+                None => continue,
+            };
+            // Pushing the values back right after the peak would just
+            // recreate it.
+            if innermost && consumer <= level.index + 1 {
+                continue;
+            }
+            return Some(Spill {
+                seq: level.seq,
+                ty,
+                producer,
+                consumer,
+                above,
+            });
+        }
+    }
+    None
+}
+
+/// Find the instruction in `seq` that pops the value that `producer` pushes
+/// at `position` on the sequence's stack, along with the types of the values
+/// above it at that point.
+///
+/// Returns `None` if the value isn't the last one `producer` pushes, if it
+/// isn't popped within the sequence, or if the types of the values above it
+/// can't be determined.
+fn find_consumer(
+    module: &Module,
+    func: &LocalFunction,
+    seq: InstrSeqId,
+    position: usize,
+    producer: usize,
+) -> Option<(usize, Vec<ValType>)> {
+    let mut stack: Vec<Option<ValType>> = match func.block(seq).ty {
+        InstrSeqType::Simple(_) => Vec::new(),
+        InstrSeqType::MultiValue(ty) => {
+            module.types.params(ty).iter().map(|ty| Some(*ty)).collect()
+        }
+    };
+    for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+        let pops = match func.stack_effect(module, instr) {
+            Some((pops, _)) if pops <= stack.len() => pops,
+            _ => return None,
+        };
+        if index > producer && stack.len() - pops <= position {
+            return stack[position + 1..]
+                .iter()
+                .copied()
+                .collect::<Option<Vec<_>>>()
+                .map(|above| (index, above));
+        }
+        let popped = stack.split_off(stack.len() - pops);
+        stack.extend(func.pushed_types(module, instr, &popped));
+        if index == producer && stack.len() != position + 1 {
+            return None;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    #[test]
+    fn reduces_stack_depth() {
+        let mut module = Module::default();
+        let x = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        // 1 + (2 + (3 + (x + (block (result i32) 4 + 5))))
+        builder
+            .func_body()
+            .i32_const(1)
+            .i32_const(2)
+            .i32_const(3)
+            .local_get(x)
+            .block(ValType::I32, |block| {
+                block.i32_const(4).i32_const(5).binop(BinaryOp::I32Add);
+            })
+            .binop(BinaryOp::I32Add)
+            .binop(BinaryOp::I32Add)
+            .binop(BinaryOp::I32Add)
+            .binop(BinaryOp::I32Add);
+        let func = builder.finish(vec![x], &mut module.funcs);
+        let depth =
+            |module: &Module| max_stack_depth(module, module.funcs.get(func).kind.unwrap_local());
+        assert_eq!(depth(&module), 6);
+
+        let moved = reduce_stack_depth(&mut module, func, 3).unwrap();
+        assert_eq!(moved, 3);
+        assert_eq!(depth(&module), 3);
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+
+        // The reduction can go as far as the operands of a binary operator.
+        reduce_stack_depth(&mut module, func, 2).unwrap();
+        assert_eq!(depth(&module), 2);
+        assert!(reduce_stack_depth(&mut module, func, 1).is_err());
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_modify_no_modify_functions() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder
+            .func_body()
+            .i32_const(1)
+            .i32_const(2)
+            .i32_const(3)
+            .binop(BinaryOp::I32Add)
+            .binop(BinaryOp::I32Add);
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.funcs.get_mut(func).flags.no_modify = true;
+
+        // Already shallow enough is fine, but anything else is an error.
+        assert_eq!(reduce_stack_depth(&mut module, func, 3).unwrap(), 0);
+        assert!(reduce_stack_depth(&mut module, func, 2).is_err());
+        let local = module.funcs.get(func).kind.unwrap_local();
+        assert_eq!(max_stack_depth(&module, local), 3);
+    }
+}
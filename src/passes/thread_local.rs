@@ -0,0 +1,308 @@
+//! Lower thread-local globals to memory relative to a per-thread base.
+//!
+//! With `wasi-threads`, every thread runs its own instance of the module over
+//! a shared memory, so globals are already per-thread, but an instance can't
+//! hand out the address of a global, nor can the host set up many of them at
+//! once. Toolchains therefore keep thread-local variables in a block of
+//! memory per thread, found through a base pointer global such as
+//! `__tls_base` that each thread points at its own block.
+//!
+//! This pass moves the globals listed in a `walrus.thread_locals` custom
+//! section into that block: each is given an offset, and every `global.get`
+//! and `global.set` of it becomes a load or store at that offset from the
+//! base pointer. Setting up each thread's block, starting with the globals'
+//! initial values, is left to the runtime, using the returned layout.
+
+use crate::ir::*;
+use crate::{GlobalId, LocalId, Module, Result, ValType};
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+
+/// Where `instrument_thread_locals` put each thread-local global.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ThreadLocalLayout {
+    /// Each global that was moved, and its offset from the base pointer, in
+    /// the order they are listed.
+    pub offsets: Vec<(GlobalId, u32)>,
+    /// The size of each thread's block, in bytes.
+    pub size: u32,
+    /// The alignment each thread's block needs, in bytes.
+    pub align: u32,
+}
+
+/// Take the `walrus.thread_locals` custom section out of `module`, and move
+/// the globals it lists to memory relative to `tls_base_global`.
+///
+/// The section is UTF-8 text with the name of one global per line. Globals
+/// are laid out in that order, each aligned to its size. Blank lines are
+/// ignored. The memory is the module's only memory.
+///
+/// Returns an error, without changing anything but taking out the section,
+/// if a listed global doesn't exist, is imported, exported or the base
+/// itself, or holds a reference, if the base isn't an `i32`, or if a function
+/// flagged `no_modify` accesses a listed global.
+pub fn instrument_thread_locals(
+    module: &mut Module,
+    tls_base_global: GlobalId,
+) -> Result<ThreadLocalLayout> {
+    let section = match module.customs.remove_raw("walrus.thread_locals") {
+        Some(section) => section,
+        None => return Ok(ThreadLocalLayout::default()),
+    };
+    let text = std::str::from_utf8(&section.data)
+        .context("the `walrus.thread_locals` section isn't valid UTF-8")?;
+
+    if module.globals.get(tls_base_global).ty != ValType::I32 {
+        bail!("the thread-local base pointer must be an `i32` global");
+    }
+    let memory = module.get_memory_id()?;
+
+    let mut layout = ThreadLocalLayout {
+        offsets: Vec::new(),
+        size: 0,
+        align: 1,
+    };
+    let mut moved = HashMap::new();
+    for name in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let global = match module
+            .globals
+            .iter()
+            .find(|g| g.name.as_deref() == Some(name))
+        {
+            Some(global) => global,
+            None => bail!("cannot make global {:?} thread-local, there is none", name),
+        };
+        let id = global.id();
+        if id == tls_base_global {
+            bail!(
+                "the thread-local base pointer {:?} can't be thread-local",
+                name
+            );
+        }
+        if global.import.is_some() || module.exports.get_exported_global(id).is_some() {
+            bail!("cannot make global {:?} thread-local, it is shared", name);
+        }
+        let size = match global.ty.byte_size() {
+            Some(size) if global.ty.is_num() || global.ty.is_vec() => size,
+            _ => bail!("cannot make reference global {:?} thread-local", name),
+        };
+        let offset = (layout.size + size - 1) / size * size;
+        layout.size = offset + size;
+        layout.align = layout.align.max(size);
+        layout.offsets.push((id, offset));
+        moved.insert(id, (global.ty, offset));
+    }
+
+    for (id, func) in module.funcs.iter_local() {
+        if module.funcs.get(id).flags.no_modify && accesses(func, &moved) {
+            bail!(
+                "cannot lower thread-local globals accessed by {:?}, it is flagged `no_modify`",
+                id
+            );
+        }
+    }
+
+    let locals = &mut module.locals;
+    for (_, func) in module.funcs.iter_local_mut() {
+        if !accesses(func, &moved) {
+            continue;
+        }
+        // One scratch local per type holds the values being stored, since
+        // the address has to go below them.
+        let mut scratch: HashMap<ValType, LocalId> = HashMap::new();
+        let seqs = func
+            .builder()
+            .arena
+            .iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for seq in seqs {
+            let block = func.block_mut(seq);
+            let preamble = block.preamble_len();
+            let mut new_preamble = 0;
+            let mut instrs = Vec::with_capacity(block.instrs.len());
+            for (index, (instr, loc)) in block.instrs.drain(..).enumerate() {
+                let access = match instr {
+                    Instr::GlobalGet(GlobalGet { global }) => {
+                        moved.get(&global).map(|m| (*m, false))
+                    }
+                    Instr::GlobalSet(GlobalSet { global }) => {
+                        moved.get(&global).map(|m| (*m, true))
+                    }
+                    _ => None,
+                };
+                let ((ty, offset), set) = match access {
+                    Some(access) => access,
+                    None => {
+                        instrs.push((instr, loc));
+                        if index < preamble {
+                            new_preamble += 1;
+                        }
+                        continue;
+                    }
+                };
+                let arg = MemArg {
+                    align: ty.byte_size().unwrap(),
+                    offset,
+                };
+                let base = GlobalGet {
+                    global: tls_base_global,
+                };
+                let lowered: Vec<Instr> = if set {
+                    let local = *scratch.entry(ty).or_insert_with(|| locals.add(ty));
+                    vec![
+                        LocalSet { local }.into(),
+                        base.into(),
+                        LocalGet { local }.into(),
+                        Store {
+                            memory,
+                            kind: store_kind(ty),
+                            arg,
+                        }
+                        .into(),
+                    ]
+                } else {
+                    vec![
+                        base.into(),
+                        Load {
+                            memory,
+                            kind: load_kind(ty),
+                            arg,
+                        }
+                        .into(),
+                    ]
+                };
+                if index < preamble {
+                    new_preamble += lowered.len();
+                }
+                instrs.extend(lowered.into_iter().map(|instr| (instr, loc)));
+            }
+            block.instrs = instrs;
+            block.set_preamble_len(new_preamble);
+        }
+    }
+
+    Ok(layout)
+}
+
+/// Whether `func` reads or writes any of the `moved` globals.
+fn accesses(func: &crate::LocalFunction, moved: &HashMap<GlobalId, (ValType, u32)>) -> bool {
+    func.builder().arena.iter().any(|(_, seq)| {
+        seq.instrs.iter().any(|(instr, _)| match instr {
+            Instr::GlobalGet(GlobalGet { global }) | Instr::GlobalSet(GlobalSet { global }) => {
+                moved.contains_key(global)
+            }
+            _ => false,
+        })
+    })
+}
+
+fn load_kind(ty: ValType) -> LoadKind {
+    match ty {
+        ValType::I32 => LoadKind::I32 { atomic: false },
+        ValType::I64 => LoadKind::I64 { atomic: false },
+        ValType::F32 => LoadKind::F32,
+        ValType::F64 => LoadKind::F64,
+        ValType::V128 => LoadKind::V128,
+        ValType::Externref | ValType::Funcref => unreachable!(),
+    }
+}
+
+fn store_kind(ty: ValType) -> StoreKind {
+    match ty {
+        ValType::I32 => StoreKind::I32 { atomic: false },
+        ValType::I64 => StoreKind::I64 { atomic: false },
+        ValType::F32 => StoreKind::F32,
+        ValType::F64 => StoreKind::F64,
+        ValType::V128 => StoreKind::V128,
+        ValType::Externref | ValType::Funcref => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, InitExpr, RawCustomSection};
+
+    fn module() -> (Module, GlobalId) {
+        let mut module = Module::default();
+        module.memories.add_local(false, 1, None);
+        let base = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(1024)));
+        for (name, ty, value) in &[
+            ("a", ValType::I32, Value::I32(0)),
+            ("b", ValType::I64, Value::I64(0)),
+            ("c", ValType::I32, Value::I32(0)),
+        ] {
+            let id = module.globals.add_local(*ty, true, InitExpr::Value(*value));
+            module.globals.get_mut(id).name = Some(name.to_string());
+        }
+        module.customs.add(RawCustomSection {
+            name: "walrus.thread_locals".to_string(),
+            data: b"a\nb\n".to_vec(),
+        });
+        (module, base)
+    }
+
+    fn global(module: &Module, name: &str) -> GlobalId {
+        let global = module
+            .globals
+            .iter()
+            .find(|g| g.name.as_deref() == Some(name));
+        global.unwrap().id()
+    }
+
+    #[test]
+    fn moves_globals_to_memory() {
+        let (mut module, base) = module();
+        let (a, b, c) = (
+            global(&module, "a"),
+            global(&module, "b"),
+            global(&module, "c"),
+        );
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .global_get(a)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .global_set(a)
+            .global_get(b)
+            .drop()
+            .global_get(c)
+            .global_set(c);
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        let layout = instrument_thread_locals(&mut module, base).unwrap();
+        assert_eq!(layout.offsets, [(a, 0), (b, 8)]);
+        assert_eq!(layout.size, 16);
+        assert_eq!(layout.align, 8);
+        assert!(module.customs.remove_raw("walrus.thread_locals").is_none());
+
+        let func = module.funcs.get(func).kind.unwrap_local();
+        let instrs = &func.block(func.entry_block()).instrs;
+        let globals = instrs
+            .iter()
+            .filter_map(|(instr, _)| match instr {
+                Instr::GlobalGet(GlobalGet { global }) | Instr::GlobalSet(GlobalSet { global }) => {
+                    Some(*global)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(globals, [base, base, base, c, c]);
+        wasmparser::validate(&module.emit_wasm()).unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_globals() {
+        let (mut module, base) = module();
+        module.customs.remove_raw("walrus.thread_locals");
+        module.customs.add(RawCustomSection {
+            name: "walrus.thread_locals".to_string(),
+            data: b"nope\n".to_vec(),
+        });
+        assert!(instrument_thread_locals(&mut module, base).is_err());
+    }
+}
@@ -120,6 +120,11 @@ impl Used {
             stack.push_func(f);
         }
 
+        // And so are the functions flagged to be kept
+        for func in module.funcs.iter().filter(|f| f.flags.keep_alive) {
+            stack.push_func(func.id());
+        }
+
         // Initialization of memories or tables is a side-effectful operation
         // because they can be out-of-bounds, so keep all active segments.
         for data in module.data.iter() {
@@ -139,6 +144,12 @@ impl Used {
             }
         }
 
+        // Tags aren't referenced by any instruction walrus supports yet, so
+        // keep all of them, along with their types.
+        for tag in module.tags.iter() {
+            stack.used.types.insert(tag.ty);
+        }
+
         // And finally ask custom sections for their roots
         for (_id, section) in module.customs.iter() {
             section.add_gc_roots(&mut stack);
@@ -0,0 +1,405 @@
+//! Choose where the `v128.const`s of a function are materialized.
+//!
+//! A `v128.const` carries a 16-byte immediate, so repeating it in a loop body
+//! bloats the code, while reading it from a global or a local instead costs
+//! an extra access on every use, which some engines handle worse than an
+//! inline constant. Rather than picking one for everybody, this pass lets the
+//! caller choose a `V128ConstPolicy` per function.
+
+use crate::ir::*;
+use crate::{FunctionId, GlobalKind, InitExpr, LocalFunction, Module, ValType};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Where to materialize `v128` constants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum V128ConstPolicy {
+    /// Use a `v128.const` at every use, folding reads of constant `v128`
+    /// globals, and of locals that are only set once to a constant, back
+    /// into `v128.const`s.
+    InlineAlways,
+    /// Set a local to each distinct constant used in a loop just before the
+    /// outermost enclosing loop, and read the local in the loop.
+    /// Constants outside of loops are left inline.
+    HoistToLocalOutsideLoops,
+    /// Read every constant from an immutable global, reusing an existing
+    /// global with the same value if there is one.
+    HoistToGlobal,
+}
+
+/// What applying a `V128ConstPolicy` to a function changed.
+///
+/// Sizes are estimated like `ModuleStats::estimated_size`, at two bytes per
+/// instruction, plus the 16 bytes of each `v128.const`'s immediate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct V128ConstReport {
+    /// The number of instructions that were replaced.
+    pub rewritten: usize,
+    /// The number of instructions in the function before the pass.
+    pub instrs_before: usize,
+    /// The number of instructions in the function after the pass.
+    pub instrs_after: usize,
+    /// The estimated size of the function body before the pass, in bytes.
+    pub estimated_size_before: usize,
+    /// The estimated size of the function body after the pass, in bytes.
+    pub estimated_size_after: usize,
+}
+
+/// Apply `policy` to the `v128` constants of the local function `func`.
+///
+/// Instructions in preambles are left alone, and so are loops in preambles,
+/// which nothing is hoisted out of. Nothing is rewritten if the function is
+/// flagged `no_modify`.
+pub fn run(module: &mut Module, func: FunctionId, policy: V128ConstPolicy) -> V128ConstReport {
+    let (instrs_before, estimated_size_before) =
+        measure(module.funcs.get(func).kind.unwrap_local());
+    let rewritten = if module.funcs.get(func).flags.no_modify {
+        0
+    } else {
+        match policy {
+            V128ConstPolicy::InlineAlways => inline(module, func),
+            V128ConstPolicy::HoistToLocalOutsideLoops => hoist_to_locals(module, func),
+            V128ConstPolicy::HoistToGlobal => hoist_to_globals(module, func),
+        }
+    };
+    let (instrs_after, estimated_size_after) = measure(module.funcs.get(func).kind.unwrap_local());
+    V128ConstReport {
+        rewritten,
+        instrs_before,
+        instrs_after,
+        estimated_size_before,
+        estimated_size_after,
+    }
+}
+
+/// The position of an instruction: its sequence and index within it.
+type Position = (InstrSeqId, usize);
+
+/// Call `f` on every instruction of the function, with the positions of the
+/// instructions enclosing it, outermost first, and then its own.
+fn walk<'a>(func: &'a LocalFunction, mut f: impl FnMut(&[Position], &'a Instr)) {
+    let mut path = Vec::new();
+    walk_seq(func, func.entry_block(), &mut path, &mut f);
+    return;
+
+    fn walk_seq<'a>(
+        func: &'a LocalFunction,
+        seq: InstrSeqId,
+        path: &mut Vec<Position>,
+        f: &mut impl FnMut(&[Position], &'a Instr),
+    ) {
+        for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+            path.push((seq, index));
+            f(path, instr);
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                    walk_seq(func, *seq, path, f)
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    walk_seq(func, *consequent, path, f);
+                    walk_seq(func, *alternative, path, f);
+                }
+                _ => {}
+            }
+            path.pop();
+        }
+    }
+}
+
+/// Is the instruction at this position in its sequence's preamble?
+fn in_preamble(func: &LocalFunction, (seq, index): Position) -> bool {
+    index < func.block(seq).preamble_len()
+}
+
+/// The number of instructions in the function, and its estimated size.
+fn measure(func: &LocalFunction) -> (usize, usize) {
+    let (mut instrs, mut size) = (0, 0);
+    walk(func, |_, instr| {
+        instrs += 1;
+        size += match instr {
+            Instr::Const(Const {
+                value: Value::V128(_),
+            }) => 18,
+            _ => 2,
+        };
+    });
+    (instrs, size)
+}
+
+/// The positions of the function's `v128.const`s outside of preambles, with
+/// their values and the position of their outermost enclosing `loop`, if
+/// any.
+fn v128_consts(func: &LocalFunction) -> Vec<(Position, u128, Option<Position>)> {
+    let mut consts = Vec::new();
+    walk(func, |path, instr| {
+        let here = path[path.len() - 1];
+        if let Instr::Const(Const {
+            value: Value::V128(value),
+        }) = instr
+        {
+            if !in_preamble(func, here) {
+                let outer_loop = path[..path.len() - 1]
+                    .iter()
+                    .copied()
+                    .find(|&(seq, index)| {
+                        matches!(func.block(seq).instrs[index].0, Instr::Loop(_))
+                    });
+                consts.push((here, *value, outer_loop));
+            }
+        }
+    });
+    consts
+}
+
+fn hoist_to_locals(module: &mut Module, func: FunctionId) -> usize {
+    let local = module.funcs.get(func).kind.unwrap_local();
+    let mut hoisted = HashMap::new();
+    let mut uses = Vec::new();
+    for (at, value, outer_loop) in v128_consts(local) {
+        let outer_loop = match outer_loop {
+            Some(outer_loop) if !in_preamble(local, outer_loop) => outer_loop,
+            _ => continue,
+        };
+        let id = *hoisted
+            .entry((outer_loop, value))
+            .or_insert_with(|| module.locals.add(ValType::V128));
+        uses.push((at, id));
+    }
+
+    let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+    for ((seq, index), id) in uses.iter() {
+        local.block_mut(*seq).instrs[*index].0 = LocalGet { local: *id }.into();
+    }
+    // Insert from the back, so that the positions of the loops that are yet
+    // to be handled don't move.
+    let mut sets = hoisted.into_iter().collect::<Vec<_>>();
+    sets.sort_by_key(|(((_, index), value), _)| (Reverse(*index), *value));
+    for (((seq, index), value), id) in sets {
+        let set = vec![
+            (
+                Const {
+                    value: Value::V128(value),
+                }
+                .into(),
+                Default::default(),
+            ),
+            (LocalSet { local: id }.into(), Default::default()),
+        ];
+        local.block_mut(seq).instrs.splice(index..index, set);
+    }
+    uses.len()
+}
+
+fn hoist_to_globals(module: &mut Module, func: FunctionId) -> usize {
+    let mut globals = module
+        .globals
+        .iter()
+        .filter_map(|global| match global.kind {
+            GlobalKind::Local(InitExpr::Value(Value::V128(value))) if !global.mutable => {
+                Some((value, global.id()))
+            }
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let consts = v128_consts(module.funcs.get(func).kind.unwrap_local());
+    let mut uses = Vec::new();
+    for (at, value, _) in consts {
+        let global = *globals.entry(value).or_insert_with(|| {
+            module
+                .globals
+                .add_local(ValType::V128, false, InitExpr::v128_const(value))
+        });
+        uses.push((at, global));
+    }
+
+    let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+    for ((seq, index), global) in uses.iter() {
+        local.block_mut(*seq).instrs[*index].0 = GlobalGet { global: *global }.into();
+    }
+    uses.len()
+}
+
+fn inline(module: &mut Module, func: FunctionId) -> usize {
+    let local = module.funcs.get(func).kind.unwrap_local();
+
+    // Every access to each local, with the positions enclosing it.
+    let mut accesses: HashMap<LocalId, Vec<(Vec<Position>, &Instr)>> = HashMap::new();
+    let mut global_reads = Vec::new();
+    walk(local, |path, instr| match instr {
+        Instr::LocalGet(LocalGet { local: id })
+        | Instr::LocalSet(LocalSet { local: id })
+        | Instr::LocalTee(LocalTee { local: id }) => {
+            accesses
+                .entry(*id)
+                .or_default()
+                .push((path.to_vec(), instr));
+        }
+        Instr::GlobalGet(GlobalGet { global }) => {
+            if let GlobalKind::Local(InitExpr::Value(Value::V128(value))) =
+                module.globals.get(*global).kind
+            {
+                if !module.globals.get(*global).mutable && !in_preamble(local, path[path.len() - 1])
+                {
+                    global_reads.push((path[path.len() - 1], value));
+                }
+            }
+        }
+        _ => {}
+    });
+
+    // A local can be replaced by its value if its only write sets it to a
+    // constant, and every read comes after that in the same sequence.
+    let mut reads = global_reads;
+    let mut removed_sets = Vec::new();
+    for (id, accesses) in accesses.iter() {
+        if local.args.contains(id) {
+            continue;
+        }
+        let mut writes = accesses
+            .iter()
+            .filter(|(_, instr)| !matches!(instr, Instr::LocalGet(_)));
+        let set = match (writes.next(), writes.next()) {
+            (Some((path, Instr::LocalSet(_))), None) => path[path.len() - 1],
+            _ => continue,
+        };
+        let (seq, index) = set;
+        let value = match index.checked_sub(1).map(|i| &local.block(seq).instrs[i].0) {
+            Some(Instr::Const(Const {
+                value: Value::V128(value),
+            })) => *value,
+            _ => continue,
+        };
+        if in_preamble(local, (seq, index - 1)) {
+            continue;
+        }
+        let after_set = |path: &Vec<Position>| path.iter().any(|&(s, i)| s == seq && i > index);
+        let gets = accesses
+            .iter()
+            .filter(|(_, instr)| matches!(instr, Instr::LocalGet(_)))
+            .collect::<Vec<_>>();
+        if !gets
+            .iter()
+            .all(|(path, _)| after_set(path) && !in_preamble(local, path[path.len() - 1]))
+        {
+            continue;
+        }
+        reads.extend(gets.iter().map(|(path, _)| (path[path.len() - 1], value)));
+        removed_sets.push(set);
+    }
+
+    let rewritten = reads.len();
+    let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+    for ((seq, index), value) in reads {
+        local.block_mut(seq).instrs[index].0 = Const {
+            value: Value::V128(value),
+        }
+        .into();
+    }
+    // Remove the sets from the back, so that the positions of the other sets
+    // in the same sequence don't move.
+    removed_sets.sort_by_key(|&(_, index)| Reverse(index));
+    for (seq, index) in removed_sets {
+        local.block_mut(seq).instrs.drain(index - 1..index + 1);
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionBuilder;
+
+    const A: u128 = 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10;
+    const B: u128 = 0xffff_0000_ffff_0000_ffff_0000_ffff_0000;
+
+    /// A function that uses `A` before a loop, and `A`, `A` and `B` in it.
+    fn module() -> (Module, FunctionId) {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .v128_const(A)
+            .drop()
+            .loop_(None, |body| {
+                body.v128_const(A)
+                    .drop()
+                    .v128_const(A)
+                    .drop()
+                    .v128_const(B)
+                    .drop();
+            });
+        let func = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("f", func);
+        (module, func)
+    }
+
+    fn validate(module: &mut Module) {
+        let mut validator = wasmparser::Validator::new();
+        validator.wasm_features(wasmparser::WasmFeatures {
+            simd: true,
+            ..Default::default()
+        });
+        validator.validate_all(&module.emit_wasm()).unwrap();
+    }
+
+    fn count_consts(module: &Module, func: FunctionId) -> usize {
+        let mut count = 0;
+        walk(module.funcs.get(func).kind.unwrap_local(), |_, instr| {
+            if let Instr::Const(Const {
+                value: Value::V128(_),
+            }) = instr
+            {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    #[test]
+    fn hoists_to_locals_and_inlines_back() {
+        let (mut module, func) = module();
+        let report = run(&mut module, func, V128ConstPolicy::HoistToLocalOutsideLoops);
+        assert_eq!(report.rewritten, 3);
+        assert_eq!(report.instrs_after, report.instrs_before + 4);
+        assert!(report.estimated_size_after < report.estimated_size_before);
+        // One constant stays in front of the loop, and one is set for each
+        // distinct value used in it.
+        assert_eq!(count_consts(&module, func), 3);
+        validate(&mut module);
+
+        let report = run(&mut module, func, V128ConstPolicy::InlineAlways);
+        assert_eq!(report.rewritten, 3);
+        assert_eq!(report.instrs_after, report.instrs_before - 4);
+        assert_eq!(count_consts(&module, func), 4);
+        validate(&mut module);
+    }
+
+    #[test]
+    fn hoists_to_globals_and_inlines_back() {
+        let (mut module, func) = module();
+        let report = run(&mut module, func, V128ConstPolicy::HoistToGlobal);
+        assert_eq!(report.rewritten, 4);
+        assert_eq!(report.instrs_after, report.instrs_before);
+        assert_eq!(count_consts(&module, func), 0);
+        assert_eq!(module.globals.iter().count(), 2);
+        validate(&mut module);
+
+        let report = run(&mut module, func, V128ConstPolicy::InlineAlways);
+        assert_eq!(report.rewritten, 4);
+        assert_eq!(count_consts(&module, func), 4);
+        validate(&mut module);
+    }
+
+    #[test]
+    fn leaves_no_modify_functions_alone() {
+        let (mut module, func) = module();
+        module.funcs.get_mut(func).flags.no_modify = true;
+        let report = run(&mut module, func, V128ConstPolicy::HoistToLocalOutsideLoops);
+        assert_eq!(report.rewritten, 0);
+        assert_eq!(report.instrs_after, report.instrs_before);
+        assert_eq!(count_consts(&module, func), 4);
+    }
+}
@@ -0,0 +1,1108 @@
+//! Pre-initialize a module by running its initialization code ahead of time.
+//!
+//! This is the same idea as [Wizer](https://github.com/bytecodealliance/wizer):
+//! the start function (and optionally an exported initialization function) is
+//! executed with a small interpreter against the module's initial memories and
+//! globals. The state it leaves behind is then snapshotted back into the
+//! module, as new data segments and global initializers, and the
+//! initialization code is no longer run at instantiation time.
+//!
+//! Only initialization code that is self-contained can be run this way. If it
+//! calls an imported function, touches an imported memory or global, or uses
+//! an instruction the interpreter doesn't support, the pass fails without
+//! modifying the module.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::module::{MAX_PAGES, PAGE_SIZE};
+use crate::Table;
+use crate::{ActiveData, ActiveDataLocation, DataKind, ElementKind, FunctionId, FunctionKind};
+use crate::{Global, GlobalKind, InitExpr, LocalFunction, Memory, MemoryId, Module, Result};
+use anyhow::{anyhow, bail, Context};
+
+/// How many instructions the interpreter may execute before it gives up.
+const FUEL: u64 = 100_000_000;
+
+/// How deeply calls and blocks may nest before the interpreter traps, so that
+/// runaway recursion fails instead of overflowing the native stack.
+const MAX_DEPTH: usize = 256;
+
+/// The most pages the interpreter lets a memory have, so that a large
+/// `memory.grow` fails instead of allocating gigabytes.
+const MAX_MEMORY_PAGES: u32 = 4096;
+
+/// Runs of fewer than this many zero bytes between non-zero bytes are kept in
+/// the same data segment, rather than starting a new one.
+const MIN_SEGMENT_GAP: usize = 8;
+
+/// Run the start function, and then the exported function named
+/// `init_export` if there is one, and snapshot the resulting state into the
+/// module.
+///
+/// Afterwards the module no longer has a start function, and the init export
+/// is removed. The contents of every local memory are rewritten as a new set
+/// of active data segments, and every local mutable global's initializer is
+/// set to its final value.
+pub fn run(module: &mut Module, init_export: Option<&str>) -> Result<()> {
+    let init = match init_export {
+        Some(name) => Some(module.exports.get_func_by_name(name)?),
+        None => None,
+    };
+
+    let mut interp = Interpreter::new(module).context("initialization failed")?;
+    if let Some(start) = module.start {
+        interp
+            .call(start, &[])
+            .context("initialization failed in the start function")?;
+    }
+    if let Some(init) = init {
+        let (params, results) = module.types.params_results(module.funcs.get(init).ty());
+        if !params.is_empty() || !results.is_empty() {
+            bail!("the init export must not take any parameters or return any results");
+        }
+        interp
+            .call(init, &[])
+            .context("initialization failed in the init export")?;
+    }
+    let Interpreter {
+        memories, globals, ..
+    } = interp;
+
+    module.start = None;
+    if let Some(name) = init_export {
+        module.exports.delete_func_by_name(name)?;
+    }
+
+    for (id, value) in globals {
+        let global = module.globals.get_mut(id);
+        if !global.mutable {
+            continue;
+        }
+        if let GlobalKind::Local(init) = &mut global.kind {
+            *init = InitExpr::Value(value);
+        }
+    }
+
+    // Sort so that the new data segments are created in a deterministic
+    // order.
+    let mut memories = memories.into_iter().collect::<Vec<_>>();
+    memories.sort_by_key(|(id, _)| *id);
+    for (id, contents) in memories {
+        snapshot_memory(module, id, &contents);
+    }
+    Ok(())
+}
+
+/// Replace the active data segments of the given memory with ones that
+/// initialize it to `contents`.
+fn snapshot_memory(module: &mut Module, id: MemoryId, contents: &[u8]) {
+    let old = module
+        .data
+        .iter()
+        .filter(|data| match &data.kind {
+            DataKind::Active(active) => active.memory == id,
+            DataKind::Passive => false,
+        })
+        .map(|data| data.id())
+        .collect::<Vec<_>>();
+    let memory = module.memories.get_mut(id);
+    for data in old {
+        memory.data_segments.remove(&data);
+        module.data.delete(data);
+    }
+    memory.initial = (contents.len() / PAGE_SIZE as usize) as u32;
+
+    let mut start = 0;
+    while let Some(offset) = contents[start..].iter().position(|b| *b != 0) {
+        let segment_start = start + offset;
+        // Extend the segment until the next long enough run of zeros.
+        let mut end = segment_start;
+        let mut zeros = 0;
+        for (i, byte) in contents[segment_start..].iter().enumerate() {
+            if *byte != 0 {
+                end = segment_start + i + 1;
+                zeros = 0;
+            } else {
+                zeros += 1;
+                if zeros >= MIN_SEGMENT_GAP {
+                    break;
+                }
+            }
+        }
+
+        let kind = DataKind::Active(ActiveData {
+            memory: id,
+            location: ActiveDataLocation::Absolute(segment_start as u32),
+        });
+        let data = module.data.add(kind, contents[segment_start..end].to_vec());
+        module.memories.get_mut(id).data_segments.insert(data);
+        start = end;
+    }
+}
+
+/// What control flow does after executing an instruction sequence.
+enum Control {
+    /// Fall through to the next instruction.
+    Next,
+    /// Branch to the given instruction sequence.
+    Branch(InstrSeqId),
+    /// Return from the current function.
+    Return,
+}
+
+/// A host implementation of an imported function, given the function and
+/// its arguments.
+pub(crate) type Host<'a> = dyn FnMut(FunctionId, &[Value]) -> Result<Vec<Value>> + 'a;
+
+/// An interpreter for the subset of wasm that initialization code typically
+/// uses.
+///
+/// This is also what `crate::testing` runs modules with.
+pub(crate) struct Interpreter<'a> {
+    module: &'a Module,
+    /// The contents of each local memory.
+    memories: IdHashMap<Memory, Vec<u8>>,
+    /// The value of each global whose value is known without instantiating
+    /// the module.
+    globals: IdHashMap<Global, Value>,
+    /// The functions in each local table, once `tables` has worked them out.
+    tables: Option<IdHashMap<Table, Vec<Option<FunctionId>>>>,
+    /// Calls imported functions, if they can be called at all.
+    host: Option<&'a mut Host<'a>>,
+    /// The function, sequence and index of the instruction that failed, once
+    /// one has.
+    pub(crate) trap_site: Option<(FunctionId, InstrSeqId, usize)>,
+    fuel: u64,
+    /// How many calls and blocks are being executed.
+    depth: usize,
+}
+
+/// The state of a single function activation.
+struct Frame {
+    func: FunctionId,
+    locals: IdHashMap<Local, Value>,
+    stack: Vec<Value>,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Set up the state that the module has right after instantiation, before
+    /// its start function runs.
+    pub(crate) fn new(module: &'a Module) -> Result<Interpreter<'a>> {
+        let mut globals = IdHashMap::default();
+        for global in module.globals.iter() {
+            let value = match global.kind {
+                GlobalKind::Local(InitExpr::Value(value)) => value,
+                GlobalKind::Local(InitExpr::Global(other)) => match globals.get(&other) {
+                    Some(value) => *value,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            globals.insert(global.id(), value);
+        }
+
+        let mut memories = IdHashMap::default();
+        for memory in module.memories.iter() {
+            if memory.import.is_none() {
+                if memory.initial > MAX_MEMORY_PAGES {
+                    bail!("memory is larger than {} pages", MAX_MEMORY_PAGES);
+                }
+                memories.insert(
+                    memory.id(),
+                    vec![0; memory.initial as usize * PAGE_SIZE as usize],
+                );
+            }
+        }
+
+        for data in module.data.iter() {
+            let active = match &data.kind {
+                DataKind::Active(active) => active,
+                DataKind::Passive => continue,
+            };
+            let memory = match memories.get_mut(&active.memory) {
+                Some(memory) => memory,
+                None => continue,
+            };
+            let offset = match active.location {
+                ActiveDataLocation::Absolute(offset) => offset,
+                ActiveDataLocation::Relative(global) => match globals.get(&global) {
+                    Some(Value::I32(offset)) => *offset as u32,
+                    _ => bail!("data segment offset depends on an imported global"),
+                },
+            } as usize;
+            match memory.get_mut(offset..offset + data.value.len()) {
+                Some(dst) => dst.copy_from_slice(&data.value),
+                None => bail!("data segment does not fit in its memory"),
+            }
+        }
+
+        Ok(Interpreter {
+            module,
+            memories,
+            globals,
+            tables: None,
+            host: None,
+            trap_site: None,
+            fuel: FUEL,
+            depth: 0,
+        })
+    }
+
+    /// Apply the active element segments now, as instantiation does, rather
+    /// than when a table is first used.
+    pub(crate) fn init_tables_eagerly(&mut self) -> Result<()> {
+        self.tables().map(|_| ())
+    }
+
+    /// The functions in each local table, after applying the active element
+    /// segments.
+    ///
+    /// Unless `init_tables_eagerly` is called, these are only worked out when
+    /// a table is first used, so that element segments that can't be applied
+    /// only fail code that calls through them.
+    fn tables(&mut self) -> Result<&IdHashMap<Table, Vec<Option<FunctionId>>>> {
+        if self.tables.is_none() {
+            self.tables = Some(self.init_tables()?);
+        }
+        Ok(self.tables.as_ref().unwrap())
+    }
+
+    fn init_tables(&self) -> Result<IdHashMap<Table, Vec<Option<FunctionId>>>> {
+        let module = self.module;
+        let mut tables = IdHashMap::default();
+        for table in module.tables.iter() {
+            if table.import.is_none() {
+                tables.insert(table.id(), vec![None; table.initial as usize]);
+            }
+        }
+
+        for elem in module.elements.iter() {
+            let (table, offset) = match &elem.kind {
+                ElementKind::Active { table, offset } => (table, offset),
+                ElementKind::Passive | ElementKind::Declared => continue,
+            };
+            let table = match tables.get_mut(table) {
+                Some(table) => table,
+                None => continue,
+            };
+            let offset = match offset {
+                InitExpr::Value(Value::I32(offset)) => *offset as u32,
+                InitExpr::Global(global) => match self.globals.get(global) {
+                    Some(Value::I32(offset)) => *offset as u32,
+                    _ => bail!("element segment offset depends on an imported global"),
+                },
+                _ => bail!("element segment has an invalid offset"),
+            } as usize;
+            match table.get_mut(offset..offset + elem.members.len()) {
+                Some(dst) => dst.copy_from_slice(&elem.members),
+                None => bail!("element segment does not fit in its table"),
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Call imported functions with `host`, rather than failing.
+    pub(crate) fn set_host(&mut self, host: &'a mut Host<'a>) {
+        self.host = Some(host);
+    }
+
+    /// Call the given function with the given arguments, returning its
+    /// results.
+    pub(crate) fn call(&mut self, id: FunctionId, args: &[Value]) -> Result<Vec<Value>> {
+        let module = self.module;
+        let func = match &module.funcs.get(id).kind {
+            FunctionKind::Local(func) => func,
+            FunctionKind::Import(import) => {
+                if let Some(host) = &mut self.host {
+                    return host(id, args);
+                }
+                let import = module.imports.get(import.import);
+                bail!(
+                    "calls the imported function `{}.{}`, which isn't available",
+                    import.module,
+                    import.name
+                );
+            }
+            FunctionKind::Uninitialized(_) => unreachable!(),
+        };
+
+        let mut frame = Frame {
+            func: id,
+            locals: func
+                .args
+                .iter()
+                .cloned()
+                .zip(args.iter().cloned())
+                .collect(),
+            stack: Vec::new(),
+        };
+        let entry = func.entry_block();
+        self.block(func, &mut frame, entry, false)?;
+
+        let results = module.types.results(func.ty()).len();
+        Ok(frame.stack.split_off(frame.stack.len() - results))
+    }
+
+    /// Execute a `block`, `loop`, or `if` arm, or a function's body,
+    /// handling branches to it.
+    fn block(
+        &mut self,
+        func: &LocalFunction,
+        frame: &mut Frame,
+        seq: InstrSeqId,
+        is_loop: bool,
+    ) -> Result<Control> {
+        if self.depth == MAX_DEPTH {
+            return Err(trap("call stack exhausted"));
+        }
+        self.depth += 1;
+        let control = self.nested_block(func, frame, seq, is_loop);
+        self.depth -= 1;
+        control
+    }
+
+    fn nested_block(
+        &mut self,
+        func: &LocalFunction,
+        frame: &mut Frame,
+        seq: InstrSeqId,
+        is_loop: bool,
+    ) -> Result<Control> {
+        let (params, results) = match func.block(seq).ty {
+            InstrSeqType::Simple(ty) => (0, ty.is_some() as usize),
+            InstrSeqType::MultiValue(ty) => {
+                let (params, results) = self.module.types.params_results(ty);
+                (params.len(), results.len())
+            }
+        };
+        let height = frame.stack.len() - params;
+
+        loop {
+            match self.exec(func, frame, seq)? {
+                Control::Branch(target) if target == seq => {
+                    // Branches to a loop carry its parameters back to its
+                    // start, and branches to anything else carry its results
+                    // out of it.
+                    let carried = if is_loop { params } else { results };
+                    let top = frame.stack.len() - carried;
+                    frame.stack.drain(height..top);
+                    if !is_loop {
+                        return Ok(Control::Next);
+                    }
+                }
+                control => return Ok(control),
+            }
+        }
+    }
+
+    /// Execute the instructions in a single instruction sequence.
+    fn exec(
+        &mut self,
+        func: &LocalFunction,
+        frame: &mut Frame,
+        seq: InstrSeqId,
+    ) -> Result<Control> {
+        for (index, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+            match self.instr(func, frame, instr) {
+                Ok(Control::Next) => {}
+                Ok(control) => return Ok(control),
+                Err(e) => {
+                    // Nested sequences and callees record where they failed
+                    // first.
+                    if self.trap_site.is_none() {
+                        self.trap_site = Some((frame.func, seq, index));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Control::Next)
+    }
+
+    /// Execute a single instruction.
+    fn instr(&mut self, func: &LocalFunction, frame: &mut Frame, instr: &Instr) -> Result<Control> {
+        let module = self.module;
+        self.fuel = match self.fuel.checked_sub(1) {
+            Some(fuel) => fuel,
+            None => bail!("did not finish within {} instructions", FUEL),
+        };
+
+        match instr {
+            Instr::Block(Block { seq }) => match self.block(func, frame, *seq, false)? {
+                Control::Next => {}
+                control => return Ok(control),
+            },
+            Instr::Loop(Loop { seq }) => match self.block(func, frame, *seq, true)? {
+                Control::Next => {}
+                control => return Ok(control),
+            },
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                let arm = if pop_i32(frame)? != 0 {
+                    *consequent
+                } else {
+                    *alternative
+                };
+                match self.block(func, frame, arm, false)? {
+                    Control::Next => {}
+                    control => return Ok(control),
+                }
+            }
+            Instr::Br(Br { block }) => return Ok(Control::Branch(*block)),
+            Instr::BrIf(BrIf { block }) => {
+                if pop_i32(frame)? != 0 {
+                    return Ok(Control::Branch(*block));
+                }
+            }
+            Instr::BrTable(BrTable { blocks, default }) => {
+                let index = pop_i32(frame)? as u32 as usize;
+                return Ok(Control::Branch(*blocks.get(index).unwrap_or(default)));
+            }
+            Instr::Return(_) => return Ok(Control::Return),
+            Instr::Unreachable(_) => return Err(trap("unreachable executed")),
+            Instr::Nop(_) => {}
+
+            Instr::Call(Call { func }) => {
+                let params = module.types.params(module.funcs.get(*func).ty()).len();
+                let args = frame.stack.split_off(frame.stack.len() - params);
+                let results = self.call(*func, &args)?;
+                frame.stack.extend(results);
+            }
+
+            Instr::CallIndirect(CallIndirect { ty, table }) => {
+                let index = pop_i32(frame)? as u32 as usize;
+                let callee = match self.tables()?.get(table) {
+                    Some(table) => match table.get(index) {
+                        Some(Some(callee)) => *callee,
+                        Some(None) => return Err(trap("uninitialized element")),
+                        None => return Err(trap("undefined element")),
+                    },
+                    None => bail!("calls through an imported table"),
+                };
+                if module.types.get(module.funcs.get(callee).ty()) != module.types.get(*ty) {
+                    return Err(trap("indirect call type mismatch"));
+                }
+                let params = module.types.params(*ty).len();
+                let args = frame.stack.split_off(frame.stack.len() - params);
+                let results = self.call(callee, &args)?;
+                frame.stack.extend(results);
+            }
+
+            Instr::Const(Const { value }) => frame.stack.push(*value),
+            Instr::Drop(_) => {
+                pop(frame)?;
+            }
+            Instr::Select(_) => {
+                let condition = pop_i32(frame)?;
+                let alternative = pop(frame)?;
+                let consequent = pop(frame)?;
+                frame.stack.push(if condition != 0 {
+                    consequent
+                } else {
+                    alternative
+                });
+            }
+
+            Instr::LocalGet(LocalGet { local }) => {
+                let value = match frame.locals.get(local) {
+                    Some(value) => *value,
+                    None => module
+                        .locals
+                        .get(*local)
+                        .ty()
+                        .default_value()
+                        .ok_or_else(|| anyhow!("reference types are not supported"))?,
+                };
+                frame.stack.push(value);
+            }
+            Instr::LocalSet(LocalSet { local }) => {
+                let value = pop(frame)?;
+                frame.locals.insert(*local, value);
+            }
+            Instr::LocalTee(LocalTee { local }) => {
+                let value = pop(frame)?;
+                frame.locals.insert(*local, value);
+                frame.stack.push(value);
+            }
+            Instr::GlobalGet(GlobalGet { global }) => match self.globals.get(global) {
+                Some(value) => frame.stack.push(*value),
+                None => bail!("reads a global whose value isn't known"),
+            },
+            Instr::GlobalSet(GlobalSet { global }) => {
+                let value = pop(frame)?;
+                match self.globals.get_mut(global) {
+                    Some(slot) => *slot = value,
+                    None => bail!("writes an imported global"),
+                }
+            }
+
+            Instr::Binop(Binop { op }) => {
+                let rhs = pop(frame)?;
+                let lhs = pop(frame)?;
+                frame.stack.push(binop(*op, lhs, rhs)?);
+            }
+            Instr::Unop(Unop { op }) => {
+                let value = pop(frame)?;
+                frame.stack.push(unop(*op, value)?);
+            }
+
+            Instr::Load(Load { memory, kind, arg }) => {
+                let address = pop_i32(frame)?;
+                let width = kind.width();
+                let mut buf = [0; 16];
+                buf[..width as usize].copy_from_slice(self.memory(*memory, address, arg, width)?);
+                let bits = u128::from_le_bytes(buf);
+                let extend = |kind: &ExtendedLoad| match kind {
+                    ExtendedLoad::SignExtend => {
+                        let shift = 128 - 8 * width;
+                        ((bits << shift) as i128 >> shift) as i64
+                    }
+                    _ => bits as i64,
+                };
+                let value = match kind {
+                    LoadKind::I32 { .. } => Value::I32(bits as i32),
+                    LoadKind::I64 { .. } => Value::I64(bits as i64),
+                    LoadKind::F32 => Value::F32(f32::from_bits(bits as u32)),
+                    LoadKind::F64 => Value::F64(f64::from_bits(bits as u64)),
+                    LoadKind::V128 => Value::V128(bits),
+                    LoadKind::I32_8 { kind } | LoadKind::I32_16 { kind } => {
+                        Value::I32(extend(kind) as i32)
+                    }
+                    LoadKind::I64_8 { kind }
+                    | LoadKind::I64_16 { kind }
+                    | LoadKind::I64_32 { kind } => Value::I64(extend(kind)),
+                };
+                frame.stack.push(value);
+            }
+            Instr::Store(Store { memory, kind, arg }) => {
+                let bits = match pop(frame)? {
+                    Value::I32(v) => v as u32 as u128,
+                    Value::I64(v) => v as u64 as u128,
+                    Value::F32(v) => v.to_bits() as u128,
+                    Value::F64(v) => v.to_bits() as u128,
+                    Value::V128(v) => v,
+                };
+                let address = pop_i32(frame)?;
+                let bytes = self.memory(*memory, address, arg, kind.width())?;
+                let len = bytes.len();
+                bytes.copy_from_slice(&bits.to_le_bytes()[..len]);
+            }
+            Instr::MemorySize(MemorySize { memory }) => {
+                let pages = self.memory_contents(*memory)?.len() / PAGE_SIZE as usize;
+                frame.stack.push(Value::I32(pages as i32));
+            }
+            Instr::MemoryGrow(MemoryGrow { memory }) => {
+                let delta = pop_i32(frame)? as u32;
+                let maximum = module.memories.get(*memory).maximum.unwrap_or(MAX_PAGES);
+                let contents = self.memory_contents(*memory)?;
+                let pages = (contents.len() / PAGE_SIZE as usize) as u32;
+                let result = match pages.checked_add(delta) {
+                    Some(new) if new <= maximum => {
+                        if new > MAX_MEMORY_PAGES {
+                            bail!("grows a memory beyond {} pages", MAX_MEMORY_PAGES);
+                        }
+                        contents.resize(new as usize * PAGE_SIZE as usize, 0);
+                        pages as i32
+                    }
+                    _ => -1,
+                };
+                frame.stack.push(Value::I32(result));
+            }
+            Instr::MemoryFill(MemoryFill { memory }) => {
+                let len = pop_i32(frame)? as u32;
+                let value = pop_i32(frame)? as u8;
+                let address = pop_i32(frame)?;
+                let arg = MemArg {
+                    align: 1,
+                    offset: 0,
+                };
+                for byte in self.memory(*memory, address, &arg, len)? {
+                    *byte = value;
+                }
+            }
+            Instr::MemoryCopy(MemoryCopy { src, dst }) => {
+                let len = pop_i32(frame)? as u32;
+                let src_address = pop_i32(frame)?;
+                let dst_address = pop_i32(frame)?;
+                let arg = MemArg {
+                    align: 1,
+                    offset: 0,
+                };
+                let bytes = self.memory(*src, src_address, &arg, len)?.to_vec();
+                self.memory(*dst, dst_address, &arg, len)?
+                    .copy_from_slice(&bytes);
+            }
+
+            other => bail!("uses an unsupported instruction: {:?}", other),
+        }
+        Ok(Control::Next)
+    }
+
+    fn memory_contents(&mut self, memory: MemoryId) -> Result<&mut Vec<u8>> {
+        self.memories
+            .get_mut(&memory)
+            .ok_or_else(|| anyhow!("accesses an imported memory"))
+    }
+
+    /// The `len` bytes of `memory` accessed at `address` with `arg`.
+    fn memory(
+        &mut self,
+        memory: MemoryId,
+        address: i32,
+        arg: &MemArg,
+        len: u32,
+    ) -> Result<&mut [u8]> {
+        let start = address as u32 as u64 + arg.offset as u64;
+        let end = start + len as u64;
+        let contents = self.memory_contents(memory)?;
+        if end > contents.len() as u64 {
+            return Err(trap("out of bounds memory access"));
+        }
+        Ok(&mut contents[start as usize..end as usize])
+    }
+}
+
+fn pop(frame: &mut Frame) -> Result<Value> {
+    frame
+        .stack
+        .pop()
+        .ok_or_else(|| anyhow!("operand stack underflow"))
+}
+
+fn pop_i32(frame: &mut Frame) -> Result<i32> {
+    match pop(frame)? {
+        Value::I32(value) => Ok(value),
+        other => bail!("expected an i32 operand, found {}", other),
+    }
+}
+
+fn trap(reason: &str) -> anyhow::Error {
+    anyhow!("trapped: {}", reason)
+}
+
+fn binop(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value> {
+    use self::BinaryOp::*;
+    use self::Value::{F32 as F32V, F64 as F64V, I32 as I32V, I64 as I64V};
+
+    let divide_by_zero = || trap("integer divide by zero");
+    let overflow = || trap("integer overflow");
+
+    Ok(match (op, lhs, rhs) {
+        (I32Eq, I32V(a), I32V(b)) => I32V((a == b) as i32),
+        (I32Ne, I32V(a), I32V(b)) => I32V((a != b) as i32),
+        (I32LtS, I32V(a), I32V(b)) => I32V((a < b) as i32),
+        (I32LtU, I32V(a), I32V(b)) => I32V(((a as u32) < (b as u32)) as i32),
+        (I32GtS, I32V(a), I32V(b)) => I32V((a > b) as i32),
+        (I32GtU, I32V(a), I32V(b)) => I32V((a as u32 > b as u32) as i32),
+        (I32LeS, I32V(a), I32V(b)) => I32V((a <= b) as i32),
+        (I32LeU, I32V(a), I32V(b)) => I32V((a as u32 <= b as u32) as i32),
+        (I32GeS, I32V(a), I32V(b)) => I32V((a >= b) as i32),
+        (I32GeU, I32V(a), I32V(b)) => I32V((a as u32 >= b as u32) as i32),
+
+        (I64Eq, I64V(a), I64V(b)) => I32V((a == b) as i32),
+        (I64Ne, I64V(a), I64V(b)) => I32V((a != b) as i32),
+        (I64LtS, I64V(a), I64V(b)) => I32V((a < b) as i32),
+        (I64LtU, I64V(a), I64V(b)) => I32V(((a as u64) < (b as u64)) as i32),
+        (I64GtS, I64V(a), I64V(b)) => I32V((a > b) as i32),
+        (I64GtU, I64V(a), I64V(b)) => I32V((a as u64 > b as u64) as i32),
+        (I64LeS, I64V(a), I64V(b)) => I32V((a <= b) as i32),
+        (I64LeU, I64V(a), I64V(b)) => I32V((a as u64 <= b as u64) as i32),
+        (I64GeS, I64V(a), I64V(b)) => I32V((a >= b) as i32),
+        (I64GeU, I64V(a), I64V(b)) => I32V((a as u64 >= b as u64) as i32),
+
+        (F32Eq, F32V(a), F32V(b)) => I32V((a == b) as i32),
+        (F32Ne, F32V(a), F32V(b)) => I32V((a != b) as i32),
+        (F32Lt, F32V(a), F32V(b)) => I32V((a < b) as i32),
+        (F32Gt, F32V(a), F32V(b)) => I32V((a > b) as i32),
+        (F32Le, F32V(a), F32V(b)) => I32V((a <= b) as i32),
+        (F32Ge, F32V(a), F32V(b)) => I32V((a >= b) as i32),
+
+        (F64Eq, F64V(a), F64V(b)) => I32V((a == b) as i32),
+        (F64Ne, F64V(a), F64V(b)) => I32V((a != b) as i32),
+        (F64Lt, F64V(a), F64V(b)) => I32V((a < b) as i32),
+        (F64Gt, F64V(a), F64V(b)) => I32V((a > b) as i32),
+        (F64Le, F64V(a), F64V(b)) => I32V((a <= b) as i32),
+        (F64Ge, F64V(a), F64V(b)) => I32V((a >= b) as i32),
+
+        (I32Add, I32V(a), I32V(b)) => I32V(a.wrapping_add(b)),
+        (I32Sub, I32V(a), I32V(b)) => I32V(a.wrapping_sub(b)),
+        (I32Mul, I32V(a), I32V(b)) => I32V(a.wrapping_mul(b)),
+        (I32DivS, I32V(_), I32V(0)) => return Err(divide_by_zero()),
+        (I32DivS, I32V(a), I32V(b)) => I32V(a.checked_div(b).ok_or_else(overflow)?),
+        (I32DivU, I32V(_), I32V(0)) => return Err(divide_by_zero()),
+        (I32DivU, I32V(a), I32V(b)) => I32V((a as u32 / b as u32) as i32),
+        (I32RemS, I32V(_), I32V(0)) => return Err(divide_by_zero()),
+        (I32RemS, I32V(a), I32V(b)) => I32V(a.wrapping_rem(b)),
+        (I32RemU, I32V(_), I32V(0)) => return Err(divide_by_zero()),
+        (I32RemU, I32V(a), I32V(b)) => I32V((a as u32 % b as u32) as i32),
+        (I32And, I32V(a), I32V(b)) => I32V(a & b),
+        (I32Or, I32V(a), I32V(b)) => I32V(a | b),
+        (I32Xor, I32V(a), I32V(b)) => I32V(a ^ b),
+        (I32Shl, I32V(a), I32V(b)) => I32V(a.wrapping_shl(b as u32)),
+        (I32ShrS, I32V(a), I32V(b)) => I32V(a.wrapping_shr(b as u32)),
+        (I32ShrU, I32V(a), I32V(b)) => I32V((a as u32).wrapping_shr(b as u32) as i32),
+        (I32Rotl, I32V(a), I32V(b)) => I32V(a.rotate_left(b as u32)),
+        (I32Rotr, I32V(a), I32V(b)) => I32V(a.rotate_right(b as u32)),
+
+        (I64Add, I64V(a), I64V(b)) => I64V(a.wrapping_add(b)),
+        (I64Sub, I64V(a), I64V(b)) => I64V(a.wrapping_sub(b)),
+        (I64Mul, I64V(a), I64V(b)) => I64V(a.wrapping_mul(b)),
+        (I64DivS, I64V(_), I64V(0)) => return Err(divide_by_zero()),
+        (I64DivS, I64V(a), I64V(b)) => I64V(a.checked_div(b).ok_or_else(overflow)?),
+        (I64DivU, I64V(_), I64V(0)) => return Err(divide_by_zero()),
+        (I64DivU, I64V(a), I64V(b)) => I64V((a as u64 / b as u64) as i64),
+        (I64RemS, I64V(_), I64V(0)) => return Err(divide_by_zero()),
+        (I64RemS, I64V(a), I64V(b)) => I64V(a.wrapping_rem(b)),
+        (I64RemU, I64V(_), I64V(0)) => return Err(divide_by_zero()),
+        (I64RemU, I64V(a), I64V(b)) => I64V((a as u64 % b as u64) as i64),
+        (I64And, I64V(a), I64V(b)) => I64V(a & b),
+        (I64Or, I64V(a), I64V(b)) => I64V(a | b),
+        (I64Xor, I64V(a), I64V(b)) => I64V(a ^ b),
+        (I64Shl, I64V(a), I64V(b)) => I64V(a.wrapping_shl(b as u32)),
+        (I64ShrS, I64V(a), I64V(b)) => I64V(a.wrapping_shr(b as u32)),
+        (I64ShrU, I64V(a), I64V(b)) => I64V((a as u64).wrapping_shr(b as u32) as i64),
+        (I64Rotl, I64V(a), I64V(b)) => I64V(a.rotate_left(b as u32)),
+        (I64Rotr, I64V(a), I64V(b)) => I64V(a.rotate_right(b as u32)),
+
+        (F32Add, F32V(a), F32V(b)) => F32V(a + b),
+        (F32Sub, F32V(a), F32V(b)) => F32V(a - b),
+        (F32Mul, F32V(a), F32V(b)) => F32V(a * b),
+        (F32Div, F32V(a), F32V(b)) => F32V(a / b),
+        (F32Min, F32V(a), F32V(b)) => F32V(fmin(a.into(), b.into()) as f32),
+        (F32Max, F32V(a), F32V(b)) => F32V(fmax(a.into(), b.into()) as f32),
+        (F32Copysign, F32V(a), F32V(b)) => F32V(a.copysign(b)),
+
+        (F64Add, F64V(a), F64V(b)) => F64V(a + b),
+        (F64Sub, F64V(a), F64V(b)) => F64V(a - b),
+        (F64Mul, F64V(a), F64V(b)) => F64V(a * b),
+        (F64Div, F64V(a), F64V(b)) => F64V(a / b),
+        (F64Min, F64V(a), F64V(b)) => F64V(fmin(a, b)),
+        (F64Max, F64V(a), F64V(b)) => F64V(fmax(a, b)),
+        (F64Copysign, F64V(a), F64V(b)) => F64V(a.copysign(b)),
+
+        (op, _, _) => bail!("uses an unsupported operator: {:?}", op),
+    })
+}
+
+/// `fN.min`, which unlike `f64::min` propagates NaNs and orders `-0` before
+/// `+0`.
+fn fmin(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+/// `fN.max`, which unlike `f64::max` propagates NaNs and orders `+0` after
+/// `-0`.
+fn fmax(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+/// `fN.nearest`, which rounds halfway cases to even.
+fn nearest(x: f64) -> f64 {
+    let rounded = x.round();
+    if (x - x.trunc()).abs() == 0.5 {
+        2.0 * (x / 2.0).round()
+    } else {
+        rounded
+    }
+}
+
+/// Truncate `x` to an integer, trapping if it is NaN or not strictly between
+/// `lo` and `hi`.
+fn trunc(x: f64, lo: f64, hi: f64) -> Result<f64> {
+    if x.is_nan() {
+        return Err(trap("invalid conversion to integer"));
+    }
+    if x <= lo || x >= hi {
+        return Err(trap("integer overflow"));
+    }
+    Ok(x.trunc())
+}
+
+fn unop(op: UnaryOp, value: Value) -> Result<Value> {
+    use self::UnaryOp::*;
+    use self::Value::{F32 as F32V, F64 as F64V, I32 as I32V, I64 as I64V};
+
+    Ok(match (op, value) {
+        (I32Eqz, I32V(a)) => I32V((a == 0) as i32),
+        (I32Clz, I32V(a)) => I32V(a.leading_zeros() as i32),
+        (I32Ctz, I32V(a)) => I32V(a.trailing_zeros() as i32),
+        (I32Popcnt, I32V(a)) => I32V(a.count_ones() as i32),
+
+        (I64Eqz, I64V(a)) => I32V((a == 0) as i32),
+        (I64Clz, I64V(a)) => I64V(a.leading_zeros() as i64),
+        (I64Ctz, I64V(a)) => I64V(a.trailing_zeros() as i64),
+        (I64Popcnt, I64V(a)) => I64V(a.count_ones() as i64),
+
+        (F32Abs, F32V(a)) => F32V(a.abs()),
+        (F32Neg, F32V(a)) => F32V(-a),
+        (F32Ceil, F32V(a)) => F32V(a.ceil()),
+        (F32Floor, F32V(a)) => F32V(a.floor()),
+        (F32Trunc, F32V(a)) => F32V(a.trunc()),
+        (F32Nearest, F32V(a)) => F32V(nearest(a.into()) as f32),
+        (F32Sqrt, F32V(a)) => F32V(a.sqrt()),
+
+        (F64Abs, F64V(a)) => F64V(a.abs()),
+        (F64Neg, F64V(a)) => F64V(-a),
+        (F64Ceil, F64V(a)) => F64V(a.ceil()),
+        (F64Floor, F64V(a)) => F64V(a.floor()),
+        (F64Trunc, F64V(a)) => F64V(a.trunc()),
+        (F64Nearest, F64V(a)) => F64V(nearest(a)),
+        (F64Sqrt, F64V(a)) => F64V(a.sqrt()),
+
+        (I32WrapI64, I64V(_)) => value.i64_to_i32_wrap().unwrap(),
+        (I32TruncSF32, F32V(a)) => I32V(trunc(a.into(), -2147483649.0, 2147483648.0)? as i32),
+        (I32TruncUF32, F32V(a)) => I32V(trunc(a.into(), -1.0, 4294967296.0)? as u32 as i32),
+        (I32TruncSF64, F64V(a)) => I32V(trunc(a, -2147483649.0, 2147483648.0)? as i32),
+        (I32TruncUF64, F64V(a)) => I32V(trunc(a, -1.0, 4294967296.0)? as u32 as i32),
+        (I64ExtendSI32, I32V(a)) => I64V(a as i64),
+        (I64ExtendUI32, I32V(a)) => I64V(a as u32 as i64),
+        (I64TruncSF32, F32V(a)) => {
+            I64V(trunc(a.into(), -9223372036854777856.0, 9223372036854775808.0)? as i64)
+        }
+        (I64TruncUF32, F32V(a)) => {
+            I64V(trunc(a.into(), -1.0, 18446744073709551616.0)? as u64 as i64)
+        }
+        (I64TruncSF64, F64V(a)) => {
+            I64V(trunc(a, -9223372036854777856.0, 9223372036854775808.0)? as i64)
+        }
+        (I64TruncUF64, F64V(a)) => I64V(trunc(a, -1.0, 18446744073709551616.0)? as u64 as i64),
+
+        (F32ConvertSI32, I32V(a)) => F32V(a as f32),
+        (F32ConvertUI32, I32V(a)) => F32V(a as u32 as f32),
+        (F32ConvertSI64, I64V(a)) => F32V(a as f32),
+        (F32ConvertUI64, I64V(a)) => F32V(a as u64 as f32),
+        (F32DemoteF64, F64V(a)) => F32V(a as f32),
+        (F64ConvertSI32, I32V(a)) => F64V(a as f64),
+        (F64ConvertUI32, I32V(a)) => F64V(a as u32 as f64),
+        (F64ConvertSI64, I64V(a)) => F64V(a as f64),
+        (F64ConvertUI64, I64V(a)) => F64V(a as u64 as f64),
+        (F64PromoteF32, F32V(a)) => F64V(a.into()),
+
+        (I32ReinterpretF32, F32V(a)) => I32V(a.to_bits() as i32),
+        (I64ReinterpretF64, F64V(a)) => I64V(a.to_bits() as i64),
+        (F32ReinterpretI32, I32V(a)) => F32V(f32::from_bits(a as u32)),
+        (F64ReinterpretI64, I64V(a)) => F64V(f64::from_bits(a as u64)),
+
+        (I32Extend8S, I32V(a)) => I32V(a as i8 as i32),
+        (I32Extend16S, I32V(a)) => I32V(a as i16 as i32),
+        (I64Extend8S, I64V(a)) => I64V(a as i8 as i64),
+        (I64Extend16S, I64V(a)) => I64V(a as i16 as i64),
+        (I64Extend32S, I64V(a)) => I64V(a as i32 as i64),
+
+        (I32TruncSSatF32, F32V(_)) => value.f32_to_i32_sat().unwrap(),
+        (I32TruncUSatF32, F32V(_)) => value.f32_to_u32_sat().unwrap(),
+        (I32TruncSSatF64, F64V(_)) => value.f64_to_i32_sat().unwrap(),
+        (I32TruncUSatF64, F64V(_)) => value.f64_to_u32_sat().unwrap(),
+        (I64TruncSSatF32, F32V(_)) => value.f32_to_i64_sat().unwrap(),
+        (I64TruncUSatF32, F32V(_)) => value.f32_to_u64_sat().unwrap(),
+        (I64TruncSSatF64, F64V(_)) => value.f64_to_i64_sat().unwrap(),
+        (I64TruncUSatF64, F64V(_)) => value.f64_to_u64_sat().unwrap(),
+
+        (op, _) => bail!("uses an unsupported operator: {:?}", op),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FunctionBuilder, ValType};
+
+    /// A module whose start function fills in a table of squares and counts
+    /// how many entries it wrote, along with an exported getter.
+    fn squares() -> (Module, FunctionId) {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let kind = DataKind::Active(ActiveData {
+            memory,
+            location: ActiveDataLocation::Absolute(100),
+        });
+        module.data.add(kind, vec![1, 2, 3, 4]);
+        let counter = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+        let arg = MemArg {
+            align: 4,
+            offset: 0,
+        };
+
+        let i = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .loop_(None, |body| {
+                let id = body.id();
+                body.local_get(i)
+                    .i32_const(4)
+                    .binop(BinaryOp::I32Mul)
+                    .local_get(i)
+                    .local_get(i)
+                    .binop(BinaryOp::I32Mul)
+                    .store(memory, StoreKind::I32 { atomic: false }, arg)
+                    .local_get(i)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_tee(i)
+                    .i32_const(10)
+                    .binop(BinaryOp::I32LtU)
+                    .br_if(id);
+            })
+            .local_get(i)
+            .global_set(counter);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let address = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder
+            .func_body()
+            .local_get(address)
+            .load(memory, LoadKind::I32 { atomic: false }, arg)
+            .global_get(counter)
+            .binop(BinaryOp::I32Add);
+        let get = builder.finish(vec![address], &mut module.funcs);
+        module.exports.add("get", get);
+
+        (module, get)
+    }
+
+    #[test]
+    fn wizened_module_behaves_the_same() {
+        let (mut module, get) = squares();
+        run(&mut module, None).unwrap();
+        assert!(module.start.is_none());
+
+        // The squares of 0 to 9 as written by the start function, followed by
+        // the original data segment, as nothing else wrote there.
+        let mut memory = vec![0; 104];
+        for i in 0..10u32 {
+            memory[i as usize * 4..][..4].copy_from_slice(&(i * i).to_le_bytes());
+        }
+        memory[100..].copy_from_slice(&[1, 2, 3, 4]);
+        let mut snapshot = vec![0; 104];
+        for data in module.data.iter() {
+            match data.kind {
+                DataKind::Active(ActiveData {
+                    location: ActiveDataLocation::Absolute(offset),
+                    ..
+                }) => snapshot[offset as usize..][..data.value.len()].copy_from_slice(&data.value),
+                ref other => panic!("unexpected data segment: {:?}", other),
+            }
+        }
+        assert_eq!(snapshot, memory);
+
+        let mut interp = Interpreter::new(&module).unwrap();
+        for address in &[0, 4, 36, 40, 100] {
+            let loaded = u32::from_le_bytes([
+                memory[*address],
+                memory[*address + 1],
+                memory[*address + 2],
+                memory[*address + 3],
+            ]);
+            let expected = Value::I32(loaded as i32 + 10);
+            let actual = interp.call(get, &[Value::I32(*address as i32)]).unwrap();
+            assert_eq!(format!("{:?}", actual), format!("{:?}", [expected]));
+        }
+
+        let wasm = module.emit_wasm();
+        Module::from_buffer(&wasm).unwrap();
+    }
+
+    #[test]
+    fn runaway_recursion_traps() {
+        let mut module = Module::default();
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let recurse = builder.finish(vec![], &mut module.funcs);
+        let func = module.funcs.get_mut(recurse).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.builder_mut().instr_seq(entry).call(recurse);
+        module.start = Some(recurse);
+
+        let err = run(&mut module, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("call stack exhausted"));
+        assert_eq!(module.start, Some(recurse));
+    }
+
+    #[test]
+    fn growing_memory_is_capped() {
+        let mut module = Module::default();
+        let memory = module.memories.add_local(false, 1, None);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder
+            .func_body()
+            .i32_const(0xffff)
+            .memory_grow(memory)
+            .drop();
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let err = run(&mut module, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("grows a memory beyond"));
+    }
+
+    #[test]
+    fn calling_an_import_is_an_error() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[], &[]);
+        let (imported, _) = module.add_import_func("env", "f", ty);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().call(imported);
+        let start = builder.finish(vec![], &mut module.funcs);
+        module.start = Some(start);
+
+        let err = run(&mut module, None).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.starts_with("initialization failed"));
+        assert!(message.contains("`env.f`"));
+        assert_eq!(module.start, Some(start));
+    }
+
+    #[test]
+    fn tables_are_only_set_up_when_used() {
+        let mut module = Module::default();
+        let table = module.tables.add_local(1, None, ValType::Funcref);
+        let (offset, _) = module.add_import_global("env", "offset", ValType::I32, false);
+        let ty = module.types.add(&[], &[]);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let empty = builder.finish(vec![], &mut module.funcs);
+        let kind = ElementKind::Active {
+            table,
+            offset: InitExpr::Global(offset),
+        };
+        module
+            .elements
+            .add(kind, ValType::Funcref, vec![Some(empty)]);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(0).call_indirect(ty, table);
+        let indirect = builder.finish(vec![], &mut module.funcs);
+
+        // The element segment can't be applied without knowing the imported
+        // global, which only matters once the table is used.
+        let mut interp = Interpreter::new(&module).unwrap();
+        interp.call(empty, &[]).unwrap();
+        let err = interp.call(indirect, &[]).unwrap_err();
+        assert!(err.to_string().contains("imported global"));
+    }
+}
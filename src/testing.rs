@@ -0,0 +1,237 @@
+//! Running a module's exports, for smoke tests of passes.
+//!
+//! This is a testing utility, not a runtime: modules run in the small
+//! interpreter that `passes::wizen` uses, which is slow, and only supports
+//! the instructions that code for a single memory typically uses. Anything
+//! else, including imported memories, tables and globals, fails with a
+//! `Trap` saying so, as does recursion that nests too deeply.
+
+use crate::ir::{InstrSeqId, Value};
+use crate::passes::wizen::Interpreter;
+use crate::{FunctionId, FunctionKind, Module};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A host implementation of an imported function.
+pub type HostFunction<'a> = Box<dyn FnMut(&[Value]) -> crate::Result<Vec<Value>> + 'a>;
+
+/// Host implementations of imported functions, by the module and name they
+/// are imported from.
+pub type HostImports<'a> = HashMap<(String, String), HostFunction<'a>>;
+
+/// Why running a module failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trap {
+    /// What went wrong.
+    pub message: String,
+    /// The function that was running the instruction that failed, if any.
+    pub func: Option<FunctionId>,
+    /// The name of that function, if it has one.
+    pub func_name: Option<String>,
+    /// The instruction that failed, numbered by the line it is printed on by
+    /// the function's `Display` implementation.
+    pub instr: Option<usize>,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(func) = self.func {
+            match &self.func_name {
+                Some(name) => write!(f, "in {} ({:?})", name, func)?,
+                None => write!(f, "in {:?}", func)?,
+            }
+            if let Some(instr) = self.instr {
+                write!(f, " at instruction {}", instr)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// Instantiate `module`, run its start function, and then call the function
+/// it exports as `export` with `args`, returning its results.
+///
+/// Active data and element segments are applied first, as they are on
+/// instantiation. Calls to imported functions go to the function in
+/// `imports` under the import's module and name.
+pub fn run(
+    module: &Module,
+    export: &str,
+    args: &[Value],
+    imports: &mut HostImports,
+) -> Result<Vec<Value>, Trap> {
+    let func = module
+        .exports
+        .get_func_by_name(export)
+        .map_err(|e| trap(module, e, None))?;
+    let params = module.types.params(module.funcs.get(func).ty());
+    if params.len() != args.len() {
+        return Err(trap(
+            module,
+            format!(
+                "`{}` takes {} arguments, not {}",
+                export,
+                params.len(),
+                args.len()
+            ),
+            None,
+        ));
+    }
+
+    let mut host = |id: FunctionId, args: &[Value]| {
+        let import = match &module.funcs.get(id).kind {
+            FunctionKind::Import(import) => module.imports.get(import.import),
+            _ => unreachable!(),
+        };
+        let key = (import.module.clone(), import.name.clone());
+        match imports.get_mut(&key) {
+            Some(f) => f(args),
+            None => anyhow::bail!(
+                "the imported function `{}.{}` isn't provided",
+                import.module,
+                import.name
+            ),
+        }
+    };
+    let mut interp = Interpreter::new(module).map_err(|e| trap(module, e, None))?;
+    interp
+        .init_tables_eagerly()
+        .map_err(|e| trap(module, e, None))?;
+    interp.set_host(&mut host);
+    let mut results = Ok(Vec::new());
+    if let Some(start) = module.start {
+        results = interp.call(start, &[]);
+    }
+    if results.is_ok() {
+        results = interp.call(func, args);
+    }
+    results.map_err(|e| trap(module, e, interp.trap_site))
+}
+
+/// A `Trap` with `message`, which happened at `site`, if it is known.
+fn trap(
+    module: &Module,
+    message: impl fmt::Display,
+    site: Option<(FunctionId, InstrSeqId, usize)>,
+) -> Trap {
+    let mut trap = Trap {
+        message: message.to_string(),
+        func: None,
+        func_name: None,
+        instr: None,
+    };
+    if let Some((id, seq, index)) = site {
+        let func = module.funcs.get(id);
+        trap.func = Some(id);
+        trap.func_name = func.name.clone();
+        let local = func.kind.unwrap_local();
+        let instr = &local.block(seq).instrs[index].0;
+        trap.instr = local
+            .numbered_instrs()
+            .into_iter()
+            .find(|(_, i)| std::ptr::eq(*i, instr))
+            .map(|(line, _)| line);
+    }
+    trap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::BinaryOp;
+    use crate::{FunctionBuilder, InitExpr, ValType};
+
+    #[test]
+    fn runs_exports_with_host_imports() {
+        let mut module = Module::default();
+        let ty = module.types.add(&[ValType::I32], &[ValType::I32]);
+        let (double, _) = module.add_import_func("host", "double", ty);
+        let counter = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(10).global_set(counter);
+        module.start = Some(builder.finish(vec![], &mut module.funcs));
+
+        let x = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder
+            .name("compute".to_string())
+            .func_body()
+            .local_get(x)
+            .call(double)
+            .global_get(counter)
+            .binop(BinaryOp::I32DivU);
+        let compute = builder.finish(vec![x], &mut module.funcs);
+        module.exports.add("compute", compute);
+
+        let mut imports = HostImports::new();
+        imports.insert(
+            ("host".to_string(), "double".to_string()),
+            Box::new(|args: &[Value]| -> crate::Result<Vec<Value>> {
+                match args {
+                    [Value::I32(x)] => Ok(vec![Value::I32(x * 2)]),
+                    _ => unreachable!(),
+                }
+            }),
+        );
+        let results = run(&module, "compute", &[Value::I32(25)], &mut imports).unwrap();
+        assert_eq!(format!("{:?}", results), "[I32(5)]");
+
+        // Without the start function, the counter is still zero.
+        module.start = None;
+        let trap = run(&module, "compute", &[Value::I32(1)], &mut imports).unwrap_err();
+        assert_eq!(trap.func, Some(compute));
+        assert_eq!(trap.instr, Some(4));
+        assert_eq!(
+            trap.to_string(),
+            format!(
+                "in compute ({:?}) at instruction 4: trapped: integer divide by zero",
+                compute
+            )
+        );
+
+        let trap = run(&module, "compute", &[], &mut HostImports::new()).unwrap_err();
+        assert_eq!(trap.func, None);
+    }
+
+    #[test]
+    fn applies_element_segments_before_the_start_function() {
+        let mut module = Module::default();
+        let table = module.tables.add_local(1, None, ValType::Funcref);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body();
+        let empty = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("empty", empty);
+        let kind = crate::ElementKind::Active {
+            table,
+            offset: InitExpr::Value(Value::I32(1)),
+        };
+        module
+            .elements
+            .add(kind, ValType::Funcref, vec![Some(empty)]);
+
+        // Nothing uses the table, but instantiating already fails.
+        let trap = run(&module, "empty", &[], &mut HostImports::new()).unwrap_err();
+        assert_eq!(trap.message, "element segment does not fit in its table");
+    }
+
+    #[test]
+    fn runaway_recursion_traps() {
+        let mut module = Module::default();
+        let builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let recurse = builder.finish(vec![], &mut module.funcs);
+        let func = module.funcs.get_mut(recurse).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.builder_mut().instr_seq(entry).call(recurse);
+        module.exports.add("recurse", recurse);
+
+        let trap = run(&module, "recurse", &[], &mut HostImports::new()).unwrap_err();
+        assert_eq!(trap.func, Some(recurse));
+        assert_eq!(trap.message, "trapped: call stack exhausted");
+    }
+}
@@ -1,6 +1,7 @@
 //! WebAssembly function and value types.
 
 use crate::error::Result;
+use crate::ir::Value;
 use crate::tombstone_arena::Tombstone;
 use anyhow::bail;
 use id_arena::Id;
@@ -117,6 +118,16 @@ impl Type {
         &*self.results
     }
 
+    /// Can a function of this type be called through a signature of type
+    /// `other`?
+    ///
+    /// This is the one place that defines when two signatures match. For now
+    /// that means identical parameters and results, but it will need to
+    /// account for subtyping once reference types grow it.
+    pub fn is_call_compatible(&self, other: &Type) -> bool {
+        self.params == other.params && self.results == other.results
+    }
+
     pub(crate) fn is_for_function_entry(&self) -> bool {
         self.is_for_function_entry
     }
@@ -142,27 +153,146 @@ pub enum ValType {
 }
 
 impl ValType {
+    /// Get the default, zero value of this type.
+    ///
+    /// This is the value that a freshly declared local of this type holds.
+    /// Reference types default to `ref.null`, which can't be represented as a
+    /// `Value`, so `None` is returned for them.
+    pub fn default_value(&self) -> Option<Value> {
+        match self {
+            ValType::I32 => Some(Value::I32(0)),
+            ValType::I64 => Some(Value::I64(0)),
+            ValType::F32 => Some(Value::F32(0.0)),
+            ValType::F64 => Some(Value::F64(0.0)),
+            ValType::V128 => Some(Value::V128(0)),
+            ValType::Externref | ValType::Funcref => None,
+        }
+    }
+
+    /// Is this a number type: `i32`, `i64`, `f32` or `f64`?
+    pub fn is_num(&self) -> bool {
+        match self {
+            ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64 => true,
+            _ => false,
+        }
+    }
+
+    /// Is this an integer type: `i32` or `i64`?
+    pub fn is_integer(&self) -> bool {
+        match self {
+            ValType::I32 | ValType::I64 => true,
+            _ => false,
+        }
+    }
+
+    /// Is this a floating point type: `f32` or `f64`?
+    pub fn is_float(&self) -> bool {
+        match self {
+            ValType::F32 | ValType::F64 => true,
+            _ => false,
+        }
+    }
+
+    /// Is this the vector type, `v128`?
+    pub fn is_vec(&self) -> bool {
+        *self == ValType::V128
+    }
+
+    /// Is this a reference type?
+    pub fn is_ref(&self) -> bool {
+        match self {
+            ValType::Externref | ValType::Funcref => true,
+            _ => false,
+        }
+    }
+
+    /// The number of bytes a value of this type takes up in linear memory, or
+    /// `None` for reference types, which can't be stored there.
+    pub fn byte_size(&self) -> Option<u32> {
+        match self {
+            ValType::I32 | ValType::F32 => Some(4),
+            ValType::I64 | ValType::F64 => Some(8),
+            ValType::V128 => Some(16),
+            ValType::Externref | ValType::Funcref => None,
+        }
+    }
+
+    /// Decode a value type from its single-byte wasm binary encoding.
+    ///
+    /// Returns `None` if the byte does not encode a value type.
+    pub fn from_wasm_byte(byte: u8) -> Option<ValType> {
+        match byte {
+            0x7f => Some(ValType::I32),
+            0x7e => Some(ValType::I64),
+            0x7d => Some(ValType::F32),
+            0x7c => Some(ValType::F64),
+            0x7b => Some(ValType::V128),
+            0x70 => Some(ValType::Funcref),
+            0x6f => Some(ValType::Externref),
+            _ => None,
+        }
+    }
+
+    /// Get the single-byte wasm binary encoding of this value type.
+    pub fn to_wasm_byte(&self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+            ValType::F32 => 0x7d,
+            ValType::F64 => 0x7c,
+            ValType::V128 => 0x7b,
+            ValType::Funcref => 0x70,
+            ValType::Externref => 0x6f,
+        }
+    }
+
     pub(crate) fn from_wasmparser_type(ty: wasmparser::Type) -> Result<Box<[ValType]>> {
         let v = match ty {
             wasmparser::Type::EmptyBlockType => Vec::new(),
-            _ => vec![ValType::parse(&ty)?],
+            _ => vec![ValType::from_parser(&ty)?],
         };
         Ok(v.into_boxed_slice())
     }
 
-    pub(crate) fn to_wasmencoder_type(&self) -> wasm_encoder::ValType {
+    /// Convert this value type to the encoder's representation.
+    pub(crate) fn to_encoder(&self) -> wasm_encoder::ValType {
         match self {
             ValType::I32 => wasm_encoder::ValType::I32,
             ValType::I64 => wasm_encoder::ValType::I64,
             ValType::F32 => wasm_encoder::ValType::F32,
             ValType::F64 => wasm_encoder::ValType::F64,
             ValType::V128 => wasm_encoder::ValType::V128,
-            ValType::Externref => wasm_encoder::ValType::Ref(wasm_encoder::RefType::EXTERNREF),
-            ValType::Funcref => wasm_encoder::ValType::Ref(wasm_encoder::RefType::FUNCREF),
+            ValType::Externref | ValType::Funcref => {
+                wasm_encoder::ValType::Ref(self.to_encoder_ref())
+            }
         }
     }
 
-    pub(crate) fn parse(input: &wasmparser::Type) -> Result<ValType> {
+    /// Convert this reference type to the encoder's representation.
+    ///
+    /// Panics if this isn't a reference type.
+    pub(crate) fn to_encoder_ref(&self) -> wasm_encoder::RefType {
+        match self {
+            ValType::Externref => wasm_encoder::RefType::EXTERNREF,
+            ValType::Funcref => wasm_encoder::RefType::FUNCREF,
+            _ => panic!("{} is not a reference type", self),
+        }
+    }
+
+    /// Convert this reference type to the encoder's representation of the
+    /// heap type it refers to, as used by `ref.null`.
+    ///
+    /// Panics if this isn't a reference type.
+    pub(crate) fn to_encoder_heap(&self) -> wasm_encoder::HeapType {
+        match self {
+            ValType::Externref => wasm_encoder::HeapType::Extern,
+            ValType::Funcref => wasm_encoder::HeapType::Func,
+            _ => panic!("{} is not a reference type", self),
+        }
+    }
+
+    /// Convert a value type from the parser's representation.
+    pub(crate) fn from_parser(input: &wasmparser::Type) -> Result<ValType> {
         match input {
             wasmparser::Type::I32 => Ok(ValType::I32),
             wasmparser::Type::I64 => Ok(ValType::I64),
@@ -193,3 +323,79 @@ impl fmt::Display for ValType {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_values() {
+        match ValType::I32.default_value() {
+            Some(Value::I32(0)) => {}
+            other => panic!("unexpected i32 default: {:?}", other),
+        }
+        match ValType::I64.default_value() {
+            Some(Value::I64(0)) => {}
+            other => panic!("unexpected i64 default: {:?}", other),
+        }
+        match ValType::F32.default_value() {
+            Some(Value::F32(x)) => assert_eq!(x.to_bits(), 0),
+            other => panic!("unexpected f32 default: {:?}", other),
+        }
+        match ValType::F64.default_value() {
+            Some(Value::F64(x)) => assert_eq!(x.to_bits(), 0),
+            other => panic!("unexpected f64 default: {:?}", other),
+        }
+        match ValType::V128.default_value() {
+            Some(Value::V128(0)) => {}
+            other => panic!("unexpected v128 default: {:?}", other),
+        }
+        assert!(ValType::Externref.default_value().is_none());
+        assert!(ValType::Funcref.default_value().is_none());
+    }
+
+    #[test]
+    fn wasm_byte_round_trip() {
+        let all = [
+            ValType::I32,
+            ValType::I64,
+            ValType::F32,
+            ValType::F64,
+            ValType::V128,
+            ValType::Externref,
+            ValType::Funcref,
+        ];
+        for ty in all.iter() {
+            assert_eq!(ValType::from_wasm_byte(ty.to_wasm_byte()), Some(*ty));
+        }
+        assert_eq!(ValType::I32.to_wasm_byte(), 0x7f);
+        assert_eq!(ValType::V128.to_wasm_byte(), 0x7b);
+        assert_eq!(ValType::from_wasm_byte(0x40), None);
+        assert_eq!(ValType::from_wasm_byte(0x00), None);
+    }
+
+    #[test]
+    fn kinds_and_sizes() {
+        for ty in [ValType::I32, ValType::I64, ValType::F32, ValType::F64].iter() {
+            assert!(ty.is_num() && !ty.is_vec() && !ty.is_ref());
+        }
+        assert!(ValType::V128.is_vec() && !ValType::V128.is_num());
+        assert!(ValType::Externref.is_ref() && ValType::Funcref.is_ref());
+
+        assert_eq!(ValType::I32.byte_size(), Some(4));
+        assert_eq!(ValType::F64.byte_size(), Some(8));
+        assert_eq!(ValType::V128.byte_size(), Some(16));
+        assert_eq!(ValType::Funcref.byte_size(), None);
+    }
+
+    #[test]
+    fn type_groups() {
+        assert!(ValType::F32.is_float() && !ValType::F32.is_integer());
+        assert!(ValType::I64.is_integer() && !ValType::I64.is_float());
+        assert!(ValType::V128.is_vec() && !ValType::V128.is_num());
+        assert!(ValType::Funcref.is_ref() && !ValType::Funcref.is_integer());
+        for ty in &[ValType::I32, ValType::I64, ValType::F32, ValType::F64] {
+            assert_eq!(ty.is_num(), ty.is_integer() || ty.is_float());
+        }
+    }
+}